@@ -1,14 +1,17 @@
 use std::env;
+use std::fmt::Write;
+use std::fs;
 use std::path::PathBuf;
 
 use libbpf_cargo::SkeletonBuilder;
 
 const SRC: &str = "src/bpf/einat.bpf.c";
+const HEADERS: &[&str] = &["src/bpf/einat.h", "src/bpf/bpf_log.h"];
 
 fn main() {
-    let mut out =
+    let out_dir =
         PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR must be set in build script"));
-    out.push("einat.skel.rs");
+    let out = out_dir.join("einat.skel.rs");
 
     let mut c_args = vec![
         "-Wno-compare-distinct-pointer-types".to_string(),
@@ -21,9 +24,65 @@ fn main() {
 
     SkeletonBuilder::new()
         .source(SRC)
+        // kept for generating min core BTF, see scripts/gen-min-btf.sh
+        .obj(out_dir.join("einat.bpf.o"))
         .clang_args(c_args)
         .debug(true)
         .build_and_generate(&out)
         .unwrap();
     println!("cargo:rerun-if-changed={SRC}");
+    for header in HEADERS {
+        println!("cargo:rerun-if-changed={header}");
+    }
+
+    patch_skel_data(&out);
+    embed_btfs(&out_dir);
+}
+
+/// Makes generated skeleton open BPF object returned by `skel::object_data()`
+/// instead of always the embedded one, so an alternative BPF object can be
+/// loaded with the same skeleton.
+fn patch_skel_data(skel_path: &std::path::Path) {
+    let skel = fs::read_to_string(skel_path).unwrap();
+    let patched = skel
+        .replacen(
+            "ObjectSkeletonConfigBuilder::new(DATA)",
+            "ObjectSkeletonConfigBuilder::new(super::object_data())",
+            1,
+        )
+        .replacen(
+            "    const DATA: &[u8] = ",
+            "    pub(super) const DATA: &[u8] = ",
+            1,
+        );
+    assert_eq!(
+        skel.len() + "super::object_data()".len() - "DATA".len() + "pub(super) ".len(),
+        patched.len(),
+        "unexpected skeleton layout"
+    );
+    fs::write(skel_path, patched).unwrap();
+}
+
+/// Embeds min core BTF files named after kernel release, e.g.
+/// "5.15.0-91-generic.btf", under directory `EINAT_BTF_DIR` for loading on
+/// kernels without BTF.
+fn embed_btfs(out_dir: &std::path::Path) {
+    println!("cargo:rerun-if-env-changed=EINAT_BTF_DIR");
+
+    let mut blobs = String::from("&[\n");
+    if let Some(dir) = env::var_os("EINAT_BTF_DIR") {
+        println!("cargo:rerun-if-changed={}", dir.to_string_lossy());
+        let entries = fs::read_dir(&dir).expect("EINAT_BTF_DIR must be a readable directory");
+        for entry in entries {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "btf") {
+                let release = path.file_stem().unwrap().to_string_lossy();
+                let path = fs::canonicalize(&path).unwrap();
+                writeln!(blobs, "    ({release:?}, include_bytes!({path:?})),").unwrap();
+            }
+        }
+    }
+    blobs.push(']');
+
+    fs::write(out_dir.join("btfs.rs"), blobs).unwrap();
 }
@@ -0,0 +1,44 @@
+#!/bin/sh
+# Mirrors port mappings in lease file of miniupnpd to static bindings of einat
+# over control socket, see docs/guide/upnp.md.
+#
+# Environment variables:
+#   LEASE_FILE  `lease_file` of miniupnpd, default /run/miniupnpd.leases
+#   CONTROL     `control_socket` of einat, default /run/einat.sock
+#   INTERFACE   external interface, can be omitted if einat manages only one
+#   INTERVAL    seconds between polls of lease file, default 2
+set -u
+
+LEASE_FILE=${LEASE_FILE:-/run/miniupnpd.leases}
+CONTROL=${CONTROL:-/run/einat.sock}
+INTERFACE=${INTERFACE:-}
+INTERVAL=${INTERVAL:-2}
+
+state=$(mktemp)
+trap 'rm -f "$state" "$state.new"' EXIT
+trap 'exit 0' INT TERM
+
+binding() {
+    # shellcheck disable=SC2086
+    einat ctl --control "$CONTROL" binding "$@" $INTERFACE
+}
+
+while :; do
+    # lease entries are "<PROTO>:<external port>:<internal address>:<internal port>:<expiry>:<description>"
+    if [ -f "$LEASE_FILE" ]; then
+        awk -F: '($1 == "TCP" || $1 == "UDP") && NF >= 4 { print tolower($1), $2, $3 ":" $4 }' \
+            "$LEASE_FILE" | sort -u >"$state.new"
+    else
+        : >"$state.new"
+    fi
+
+    comm -23 "$state" "$state.new" | while read -r proto port _internal; do
+        binding del "$proto" "$port"
+    done
+    comm -13 "$state" "$state.new" | while read -r proto port internal; do
+        binding add "$proto" "$port" "$internal"
+    done
+
+    mv "$state.new" "$state"
+    sleep "$INTERVAL"
+done
@@ -0,0 +1,39 @@
+#!/usr/bin/env bash
+# SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+# SPDX-License-Identifier: GPL-2.0-or-later
+#
+# Generates min core BTF files for einat from full kernel BTF files, e.g.
+# extracted from BTFHub archive, named `<kernel release>.btf`.
+#
+# Usage: gen-min-btf.sh <full BTF dir> <output dir> [einat.bpf.o]
+#
+# Then build einat with `EINAT_BTF_DIR=<output dir>` to embed generated BTF
+# files, or copy them to target and point `btf_path` to the directory.
+set -euo pipefail
+
+if [ $# -lt 2 ]; then
+    echo "Usage: $0 <full BTF dir> <output dir> [einat.bpf.o]" >&2
+    exit 1
+fi
+
+in_dir=$1
+out_dir=$2
+obj=${3:-}
+
+if [ -z "$obj" ]; then
+    # pick the most recently built BPF object
+    obj=$(ls -t target/*/build/einat-*/out/einat.bpf.o \
+        target/*/*/build/einat-*/out/einat.bpf.o 2>/dev/null | head -n1 || true)
+fi
+if [ ! -f "$obj" ]; then
+    echo "BPF object not found, build einat first or specify it" >&2
+    exit 1
+fi
+
+mkdir -p "$out_dir"
+for btf in "$in_dir"/*.btf; do
+    [ -e "$btf" ] || continue
+    release=$(basename "$btf" .btf)
+    bpftool gen min_core_btf "$btf" "$out_dir/$release.btf" "$obj"
+    echo "generated $out_dir/$release.btf"
+done
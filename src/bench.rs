@@ -0,0 +1,272 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! Benchmarking of TC programs with `BPF_PROG_TEST_RUN` loops on synthetic
+//! packets of established flows
+#[cfg(feature = "ipv6")]
+use std::net::Ipv6Addr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use crate::config::{ConfigDefaults, ConfigNetIf, NetIfId};
+use crate::instance::{Instance, InstanceConfig};
+use crate::route::{IfAddresses, PacketEncap};
+
+pub const DEFAULT_REPEAT: u32 = 1_000_000;
+
+const ETH_HLEN: usize = 14;
+const PPPOE_HLEN: usize = 8;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+const ETH_P_PPP_SES: u16 = 0x8864;
+const PPP_IP: u16 = 0x0021;
+const PPP_IPV6: u16 = 0x0057;
+const TC_ACT_UNSPEC: i32 = -1;
+
+const V4_EXTERNAL: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 100);
+const V4_INTERNAL: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 100);
+const V4_REMOTE: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+#[cfg(feature = "ipv6")]
+const V6_EXTERNAL: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x100);
+#[cfg(feature = "ipv6")]
+const V6_INTERNAL: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x100);
+#[cfg(feature = "ipv6")]
+const V6_REMOTE: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 1);
+
+/// Computes Internet checksum of `data`, with `init` being partial sum of
+/// preceding data, e.g. the pseudo header.
+pub fn checksum(data: &[u8], init: u32) -> u16 {
+    let mut sum = init;
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            u16::from_be_bytes([chunk[0], chunk[1]])
+        } else {
+            u16::from_be_bytes([chunk[0], 0])
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Partial checksum of pseudo header of IP packet for L4 checksum.
+pub fn pseudo_header_sum(src: IpAddr, dst: IpAddr, protocol: u8, l4_len: usize) -> u32 {
+    let mut pseudo = Vec::with_capacity(40);
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            pseudo.extend(src.octets());
+            pseudo.extend(dst.octets());
+            pseudo.extend([0, protocol]);
+            pseudo.extend((l4_len as u16).to_be_bytes());
+        }
+        _ => {
+            pseudo.extend(ipv6_octets(src));
+            pseudo.extend(ipv6_octets(dst));
+            pseudo.extend((l4_len as u32).to_be_bytes());
+            pseudo.extend([0, 0, 0, protocol]);
+        }
+    }
+    !checksum(&pseudo, 0) as u32
+}
+
+fn ipv6_octets(addr: IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped().octets(),
+        IpAddr::V6(addr) => addr.octets(),
+    }
+}
+
+/// Returns offset of IP header in packet of `encap`.
+pub fn l3_offset(encap: &PacketEncap) -> usize {
+    match encap {
+        PacketEncap::Ethernet => ETH_HLEN,
+        PacketEncap::Pppoe => ETH_HLEN + PPPOE_HLEN,
+        _ => 0,
+    }
+}
+
+/// Builds UDP packet with valid checksums in encapsulation `encap`.
+pub fn udp_packet(
+    encap: &PacketEncap,
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend(src.port().to_be_bytes());
+    udp.extend(dst.port().to_be_bytes());
+    udp.extend((udp_len as u16).to_be_bytes());
+    udp.extend([0, 0]);
+    udp.extend(payload);
+    let proto = libc::IPPROTO_UDP as u8;
+    let sum = checksum(&udp, pseudo_header_sum(src.ip(), dst.ip(), proto, udp_len));
+    udp[6..8].copy_from_slice(&sum.to_be_bytes());
+
+    let mut ip = Vec::with_capacity(40);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            ip.extend([0x45, 0]);
+            ip.extend(((20 + udp_len) as u16).to_be_bytes());
+            ip.extend([0, 1, 0x40, 0, 64, proto, 0, 0]);
+            ip.extend(src.octets());
+            ip.extend(dst.octets());
+            let sum = checksum(&ip, 0);
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+        }
+        (src, dst) => {
+            ip.extend([0x60, 0, 0, 0]);
+            ip.extend((udp_len as u16).to_be_bytes());
+            ip.extend([proto, 64]);
+            ip.extend(ipv6_octets(src));
+            ip.extend(ipv6_octets(dst));
+        }
+    }
+    let is_ipv4 = src.is_ipv4();
+
+    let mut pkt = Vec::with_capacity(l3_offset(encap) + ip.len() + udp_len);
+    if matches!(encap, PacketEncap::Ethernet | PacketEncap::Pppoe) {
+        pkt.extend([0x02, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0x02]);
+        let eth_proto = match (encap, is_ipv4) {
+            (PacketEncap::Pppoe, _) => ETH_P_PPP_SES,
+            (_, true) => ETH_P_IP,
+            (_, false) => ETH_P_IPV6,
+        };
+        pkt.extend(eth_proto.to_be_bytes());
+    }
+    if *encap == PacketEncap::Pppoe {
+        pkt.extend([0x11, 0, 0, 1]);
+        pkt.extend(((2 + ip.len() + udp_len) as u16).to_be_bytes());
+        pkt.extend(if is_ipv4 { PPP_IP } else { PPP_IPV6 }.to_be_bytes());
+    }
+    pkt.extend(ip);
+    pkt.extend(udp);
+    pkt
+}
+
+/// Returns source and destination of UDP packet built by [`udp_packet`].
+pub fn udp_endpoints(encap: &PacketEncap, pkt: &[u8]) -> (SocketAddr, SocketAddr) {
+    let ip = &pkt[l3_offset(encap)..];
+    let (src, dst, l4) = if ip[0] >> 4 == 4 {
+        let addr = |b: &[u8]| IpAddr::from(<[u8; 4]>::try_from(b).unwrap());
+        (addr(&ip[12..16]), addr(&ip[16..20]), &ip[20..])
+    } else {
+        let addr = |b: &[u8]| IpAddr::from(<[u8; 16]>::try_from(b).unwrap());
+        (addr(&ip[8..24]), addr(&ip[24..40]), &ip[40..])
+    };
+    let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+    (
+        SocketAddr::new(src, port(&l4[0..2])),
+        SocketAddr::new(dst, port(&l4[2..4])),
+    )
+}
+
+fn load_instance(encap: &PacketEncap, is_ipv4: bool) -> Result<Instance> {
+    let if_config = ConfigNetIf {
+        interface: NetIfId::Index { if_index: 1 },
+        nat44: is_ipv4,
+        nat66: !is_ipv4,
+        default_externals: true,
+        ..Default::default()
+    };
+    let addresses = IfAddresses {
+        ipv4: vec![V4_EXTERNAL],
+        #[cfg(feature = "ipv6")]
+        ipv6: vec![V6_EXTERNAL],
+        attrs: Default::default(),
+        command_outputs: Default::default(),
+        route_sources: Default::default(),
+        nft_set_dests: Default::default(),
+    };
+    // interface index is only used for attaching, which is not done here
+    InstanceConfig::try_from(
+        1,
+        None,
+        encap.clone(),
+        &if_config,
+        &ConfigDefaults::default(),
+        &addresses,
+    )?
+    .load()
+}
+
+struct BenchResult {
+    egress: Duration,
+    ingress: Duration,
+}
+
+fn bench_one(encap: &PacketEncap, is_ipv4: bool, repeat: u32) -> Result<BenchResult> {
+    let inst = load_instance(encap, is_ipv4)?;
+
+    let (internal, remote): (IpAddr, IpAddr) = if is_ipv4 {
+        (V4_INTERNAL.into(), V4_REMOTE.into())
+    } else {
+        #[cfg(feature = "ipv6")]
+        {
+            (V6_INTERNAL.into(), V6_REMOTE.into())
+        }
+        #[cfg(not(feature = "ipv6"))]
+        unreachable!()
+    };
+    let internal = SocketAddr::new(internal, 5000);
+    let remote = SocketAddr::new(remote, 3478);
+    let payload = [0u8; 64];
+
+    // set up binding and CT first, so only established flow is measured
+    let egress_pkt = udp_packet(encap, internal, remote, &payload);
+    let (ret, out, _) = inst.test_run(false, &egress_pkt, 1)?;
+    let (mapped, _) = udp_endpoints(encap, &out);
+    if ret != TC_ACT_UNSPEC || mapped == internal {
+        return Err(anyhow!("packet not translated, return value {}", ret));
+    }
+    let ingress_pkt = udp_packet(encap, remote, mapped, &payload);
+    let (ret, out, _) = inst.test_run(true, &ingress_pkt, 1)?;
+    if ret != TC_ACT_UNSPEC || udp_endpoints(encap, &out).1 != internal {
+        return Err(anyhow!("reply not translated, return value {}", ret));
+    }
+
+    let (_, _, egress) = inst.test_run(false, &egress_pkt, repeat)?;
+    let (_, _, ingress) = inst.test_run(true, &ingress_pkt, repeat)?;
+    Ok(BenchResult { egress, ingress })
+}
+
+/// Benchmarks translation of UDP packets of established flow in each
+/// encapsulation and address family, and prints per-packet CPU cost.
+pub fn run(repeat: u32) -> Result<()> {
+    let mut families = vec![("IPv4", true)];
+    if cfg!(feature = "ipv6") {
+        families.push(("IPv6", false));
+    }
+
+    println!(
+        "{:<10} {:<6} {:<8} {:>10} {:>10}",
+        "encap", "family", "direction", "ns/pkt", "Mpps"
+    );
+    for (encap_name, encap) in [
+        ("Ethernet", PacketEncap::Ethernet),
+        ("PPPoE", PacketEncap::Pppoe),
+    ] {
+        for &(family_name, is_ipv4) in &families {
+            let res = bench_one(&encap, is_ipv4, repeat)?;
+            for (direction, cost) in [("egress", res.egress), ("ingress", res.ingress)] {
+                let ns = cost.as_nanos().max(1);
+                println!(
+                    "{:<10} {:<6} {:<8} {:>10} {:>10.2}",
+                    encap_name,
+                    family_name,
+                    direction,
+                    ns,
+                    1e3 / ns as f64
+                );
+            }
+        }
+    }
+    println!(
+        "\nBare IP encapsulation is not covered as BPF_PROG_TEST_RUN always feeds \
+         TC programs with Ethernet frames."
+    );
+    Ok(())
+}
@@ -14,32 +14,57 @@ enum bpf_log_level {
     BPF_LOG_LEVEL_TRACE,
     BPF_LOG_LEVEL_END,
 };
-#define _BPF_LOG_LEVEL_ERROR_TOKEN "ERROR"
-#define _BPF_LOG_LEVEL_WARN_TOKEN "WARN "
-#define _BPF_LOG_LEVEL_INFO_TOKEN "INFO "
-#define _BPF_LOG_LEVEL_DEBUG_TOKEN "DEBUG"
-#define _BPF_LOG_LEVEL_TRACE_TOKEN "TRACE"
 
 // can be overwritten with #undef and re #define on the same name
 #define BPF_LOG_LEVEL BPF_LOG_LEVEL_DEBUG;
+// Ceiling of `BPF_LOG_LEVEL` known at load time, e.g. a read-only global,
+// so logging sites above it are eliminated as dead code by the verifier
+#ifndef BPF_LOG_MAX_LEVEL
+#define BPF_LOG_MAX_LEVEL BPF_LOG_LEVEL_END
+#endif
 #define BPF_LOG_TOPIC "default"
 
-#define _bpf_vprintk_exists                                                    \
-    bpf_core_enum_value_exists(enum bpf_func_id, BPF_FUNC_trace_vprintk)
+// Log records are written to ring buffer `BPF_LOG_MAP` and decoded by
+// userspace, `BPF_LOG_EVENT` is the leading event type of records to tell
+// them apart from other events in the same ring buffer.
+#ifndef BPF_LOG_MAP
+#define BPF_LOG_MAP map_events
+#endif
+#ifndef BPF_LOG_EVENT
+#define BPF_LOG_EVENT 0
+#endif
+
+#define BPF_LOG_TOPIC_LEN 32
+#define BPF_LOG_MSG_LEN 128
+
+struct bpf_log_event {
+    u32 type;
+    u8 level;
+    u8 _pad[3];
+    // NUL terminated unless truncated
+    char topic[BPF_LOG_TOPIC_LEN];
+    // always NUL terminated
+    char msg[BPF_LOG_MSG_LEN];
+};
 
-#define _bpf_check_printk(...)                                                 \
-    ___bpf_nth(_, ##__VA_ARGS__, _bpf_vprintk_exists, _bpf_vprintk_exists,     \
-               _bpf_vprintk_exists, _bpf_vprintk_exists, _bpf_vprintk_exists,  \
-               _bpf_vprintk_exists, _bpf_vprintk_exists, _bpf_vprintk_exists,  \
-               _bpf_vprintk_exists, true /*3*/, true /*2*/, true /*1*/,        \
-               true /*0*/)
+#define _bpf_log_topic_len                                                     \
+    (sizeof(BPF_LOG_TOPIC) < BPF_LOG_TOPIC_LEN ? sizeof(BPF_LOG_TOPIC)         \
+                                               : BPF_LOG_TOPIC_LEN)
 
-#define _bpf_log_logv(level, fmt, args...)                                     \
+#define _bpf_log_logv(lvl, fmt, args...)                                       \
     ({                                                                         \
-        if (BPF_LOG_LEVEL >= level && _bpf_check_printk(args)) {               \
-            bpf_printk("[b-f-c-n][" _##level##_TOKEN "] " BPF_LOG_TOPIC        \
-                                                     " : " fmt,                \
-                       ##args);                                                \
+        if (BPF_LOG_MAX_LEVEL >= lvl && BPF_LOG_LEVEL >= lvl) {                \
+            struct bpf_log_event *_log = bpf_ringbuf_reserve(                  \
+                &BPF_LOG_MAP, sizeof(struct bpf_log_event), 0);                \
+            if (_log) {                                                        \
+                _log->type = BPF_LOG_EVENT;                                    \
+                _log->level = lvl;                                             \
+                __builtin_memcpy(_log->topic, BPF_LOG_TOPIC,                   \
+                                 _bpf_log_topic_len);                          \
+                _log->msg[0] = 0;                                              \
+                BPF_SNPRINTF(_log->msg, sizeof(_log->msg), fmt, ##args);       \
+                bpf_ringbuf_submit(_log, 0);                                   \
+            }                                                                  \
         }                                                                      \
     })
 
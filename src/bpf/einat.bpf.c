@@ -5,11 +5,17 @@
 #define DEFAULT_FRAG_TRACK_MAX_ENTRIES 65536
 #define DEFAULT_BINDING_MAX_ENTRIES (65536 * 2)
 #define DEFAULT_CONNTRACK_MAX_ENTRIES (65536 * 2)
+#define DEFAULT_HOST_MAX_ENTRIES 65536
 
-const volatile u8 LOG_LEVEL = BPF_LOG_LEVEL_DEBUG;
+// Max BPF log level `g_log_level` can be raised to at runtime, logging above
+// it is not verified nor run
+const volatile u8 MAX_LOG_LEVEL = BPF_LOG_LEVEL_NONE;
 
 // Bare IP packet if false
 const volatile u8 HAS_ETH_ENCAP = true;
+// IP packet is encapsulated in PPPoE session frame on Ethernet, only
+// effective if HAS_ETH_ENCAP is true
+const volatile u8 HAS_PPPOE_ENCAP = false;
 
 const volatile u8 INGRESS_IPV4 = true;
 const volatile u8 EGRESS_IPV4 = true;
@@ -18,6 +24,66 @@ const volatile u8 INGRESS_IPV6 = true;
 const volatile u8 EGRESS_IPV6 = true;
 #endif
 
+// Hairpin packets towards external addresses arriving on internal interfaces
+// by redirecting them to egress of HAIRPIN_REDIRECT_IFINDEX, i.e. external
+// interface, or PPPoE interface instead of its lower interface. Source MAC of
+// redirected frames is rewritten to HAIRPIN_REDIRECT_MAC, so they are
+// accepted as destined to external interface after being hairpinned back.
+const volatile u8 HAIRPIN_REDIRECT_IPV4 = false;
+const volatile u8 HAIRPIN_REDIRECT_IPV6 = false;
+const volatile u32 HAIRPIN_REDIRECT_IFINDEX = 0;
+const volatile u8 HAIRPIN_REDIRECT_MAC[6] = {0};
+// Alternatively set HAIRPIN_FWMARK on these packets and let a fwmark IP rule
+// route them to external interface.
+const volatile u8 HAIRPIN_FWMARK_IPV4 = false;
+const volatile u8 HAIRPIN_FWMARK_IPV6 = false;
+const volatile u32 HAIRPIN_FWMARK = 0;
+// Redirect packets of new outbound flows failed to be translated back to
+// ingress of external interface with REJECT_FWMARK set, so that kernel replies
+// ICMP errors by a "prohibit" IP rule of the mark. Zero disables rejection.
+const volatile u32 REJECT_FWMARK = 0;
+// Pass packets with BYPASS_FWMARK under BYPASS_FWMARK_MASK untouched, e.g.
+// marked by nftables rules. Zero disables bypassing.
+const volatile u32 BYPASS_FWMARK = 0;
+const volatile u32 BYPASS_FWMARK_MASK = 0xffffffff;
+
+// Stateless NETMAP-style translation of IPv4 prefixes in map_ipv4_netmap,
+// translated packets bypass NAT44
+const volatile u8 NETMAP = false;
+// Pass packets from internal source prefixes in map_ipv4_no_snat_source and
+// map_ipv6_no_snat_source untranslated
+const volatile u8 NO_SNAT_SOURCES = false;
+// Pass packets to TCP and UDP ports in map_no_snat_dport untranslated
+const volatile u8 NO_SNAT_DPORTS = false;
+// Rewrite DSCP of packets translated by egress_snat and ingress_rev_snat,
+// keeping ECN bits, e.g. to 0 for ISP links requiring it
+const volatile u8 EGRESS_DSCP = DSCP_KEEP;
+const volatile u8 INGRESS_DSCP = DSCP_KEEP;
+// Set checksum field of translated TCP and UDP packets with checksum offloaded
+// (CHECKSUM_PARTIAL) to pseudo header sum of translated packet for NIC to
+// complete, instead of relying on incremental checksum fixes, see
+// csum_offloaded_fix()
+const volatile u8 CSUM_OFFLOAD_COMPAT = false;
+
+#ifdef FEAT_IPV6
+// NPTv6 (RFC 6296) stateless prefix translation of IPv6 packets, rewrites
+// source addresses within g_npt_internal_prefix of outbound packets to
+// g_npt_external_prefix and destination addresses of inbound packets back,
+// NAT66 is skipped for translated packets.
+const volatile u8 NPTV6 = false;
+
+// 464XLAT CLAT (RFC 6877) on a TUN interface of CLAT_IPV4_ADDR, IPv4 packets
+// leaving it are translated to IPv6 packets from g_clat_ipv6_addr to
+// destinations embedded in g_clat_pref64 after NAT44 and sent out of IPv6-only
+// CLAT_UPLINK_IFINDEX. Program ingress_clat on uplink translates IPv6 packets
+// back and redirects them to ingress of CLAT_IFINDEX, i.e. the TUN interface.
+const volatile u8 CLAT = false;
+const volatile u32 CLAT_IFINDEX = 0;
+const volatile u32 CLAT_UPLINK_IFINDEX = 0;
+const volatile u8 CLAT_UPLINK_HAS_ETH = true;
+const volatile __be32 CLAT_IPV4_ADDR = 0;
+#endif
+
 // Lookup external source address from FIB instead of using
 // g_ipv4_external_addr, requires Linux kernel>=6.7
 const volatile u8 ENABLE_FIB_LOOKUP_SRC = false;
@@ -32,6 +98,91 @@ const volatile u8 ENABLE_FIB_LOOKUP_SRC = false;
 // for inbound and outbound directions respectively, so we can limit how much
 // ICMP IDs can be mapped.
 const volatile u8 ALLOW_INBOUND_ICMPX = true;
+// Prefer external port of same parity as internal port, see
+// https://datatracker.ietf.org/doc/html/rfc4787#section-4.2.2
+const volatile u8 PORT_PARITY = false;
+// Try internal port as external port first if it's free and within port
+// ranges, otherwise start searching from random port
+const volatile u8 PORT_PRESERVATION = true;
+const volatile u8 PORT_ALLOCATION = PORT_ALLOC_RANDOM;
+// Use the same external address for all bindings of an internal host, see
+// https://datatracker.ietf.org/doc/html/rfc4787#section-4.1
+const volatile u8 PAIRED_POOLING = false;
+const volatile u8 EXTERNAL_SELECTION = EXTERNAL_SELECT_FIRST;
+// Use next external address in pool if selected one runs out of ports
+const volatile u8 EXTERNAL_SPILLOVER = false;
+// Skip ports of listening sockets of this host in map_local_ports when
+// allocating ports on external addresses that are also host addresses, and
+// pass inbound packets to these ports through if there is no binding
+const volatile u8 LOCAL_PORTS = false;
+// Translate call IDs in PPTP control messages over TCP port 1723 and GRE
+// packets of PPTP calls from internal clients, see map_pptp_call
+const volatile u8 PPTP = false;
+// Pass IPsec ESP packets of internal clients through by pseudo-bindings
+// keyed by SPI in map_esp, and treat NAT-T flows to UDP port 4500 as streams
+const volatile u8 ESP_PASSTHROUGH = false;
+// Drop ICMP error messages with malformed embedded packets, or embedded source
+// address not matching outer destination address, instead of passing them
+// untranslated. They are counted in g_icmp_err_malformed either way.
+const volatile u8 DROP_MALFORMED_ICMP_ERR = false;
+// Whether any static 1:1 NAT address bindings are configured
+const volatile u8 ADDR_BINDINGS = false;
+// Forward unsolicited inbound TCP and UDP packets that match no binding to the
+// same port of DMZ host at DMZ_HOST_ADDR, which is of DMZ_HOST_IPV4 family
+const volatile u8 DMZ_HOST = false;
+const volatile u8 DMZ_HOST_IPV4 = true;
+const volatile __be32 DMZ_HOST_ADDR[4] = {0};
+// Allow inbound packets to refresh timeout of CTs, otherwise only outbound
+// packets and state transitions do, see
+// https://datatracker.ietf.org/doc/html/rfc4787#section-4.3
+const volatile u8 INBOUND_REFRESH = true;
+// Track remote addresses of CTs, required by address-dependent filtering
+const volatile u8 FILTER_ADDR_TRACKING = false;
+
+// Deterministic NAT per RFC 7422, internal IPv4 source address within
+// DET_NAT_NETWORK/DET_NAT_MASK(host byte order) is mapped to a fixed block of
+// DET_NAT_BLOCK_SIZE ports, the N-th host of network gets the N-th block
+// counted from start of the first port range. Disabled if block size is 0.
+// Ports of all blocks are never allocated to other hosts. Only TCP and UDP
+// ports are allocated this way, not ICMP query IDs.
+const volatile u32 DET_NAT_NETWORK = 0;
+const volatile u32 DET_NAT_MASK = 0;
+const volatile u16 DET_NAT_BLOCK_SIZE = 0;
+
+// Max number of CTs per internal host, 0 for unlimited
+const volatile u32 MAX_SESSIONS_PER_HOST = 0;
+// Max number of TCP CTs per internal host initiated by SYN and not yet
+// replied, i.e. half-open, 0 for unlimited
+const volatile u32 MAX_EMBRYONIC_PER_HOST = 0;
+// Reply SYNs beyond MAX_EMBRYONIC_PER_HOST with SYN-ACK of zero window instead
+// of dropping them, see tarpit_syn()
+const volatile u8 EMBRYONIC_TARPIT = false;
+
+// Max number of external ports per internal host for respective protocol,
+// 0 for unlimited
+const volatile u32 PORT_QUOTA_TCP = 0;
+const volatile u32 PORT_QUOTA_UDP = 0;
+const volatile u32 PORT_QUOTA_ICMP = 0;
+
+// Rate limiting of new bindings per internal host, in nanoseconds between
+// two new bindings at sustained rate, 0 for unlimited
+const volatile u64 BINDING_RATE_INTERVAL = 0;
+// Number of new bindings allowed in burst
+const volatile u32 BINDING_RATE_BURST = 1;
+// Rate limiting of ICMP errors replied to new flows of internal host rejected
+// with REJECT_FWMARK, or to unmatched TCP RST of remote host, likewise
+const volatile u64 REJECT_RATE_INTERVAL = 0;
+const volatile u32 REJECT_RATE_BURST = 1;
+// Rate limiting of new UDP bindings of all internal hosts together and of each
+// internal host, likewise
+const volatile u64 UDP_BINDING_RATE_INTERVAL = 0;
+const volatile u32 UDP_BINDING_RATE_BURST = 1;
+const volatile u64 UDP_HOST_BINDING_RATE_INTERVAL = 0;
+const volatile u32 UDP_HOST_BINDING_RATE_BURST = 1;
+// Once number of UDP CTs reaches UDP_HIGH_WATER, timeouts of UDP CTs are
+// clamped to UDP_EMERGENCY_TIMEOUT until it falls below again. Disabled if 0.
+const volatile u32 UDP_HIGH_WATER = 0;
+const volatile u64 UDP_EMERGENCY_TIMEOUT = 30E9;
 
 // at least FRAGMENT_MIN=2s,
 // https://datatracker.ietf.org/doc/html/rfc6146#section-4
@@ -39,23 +190,152 @@ const volatile u64 TIMEOUT_FRAGMENT = 2E9;
 
 const volatile u64 TIMEOUT_PKT_MIN = 120E9;
 const volatile u64 TIMEOUT_PKT_DEFAULT = 300E9;
+// UDP CT is promoted from single to stream timeout after seeing
+// UDP_STREAM_PACKETS packets since established
+const volatile u64 TIMEOUT_UDP_SINGLE = 300E9;
+const volatile u64 TIMEOUT_UDP_STREAM = 300E9;
+const volatile u8 UDP_STREAM_PACKETS = 2;
+// Timeout of ICMP query CT, uses timeouts of other connectionless CT if 0
+const volatile u64 TIMEOUT_ICMP = 0;
+// Look up timeout overrides of remote destination in dest config
+const volatile u8 DEST_TIMEOUTS = false;
 
 // https://datatracker.ietf.org/doc/html/rfc6146#section-4
 const volatile u64 TIMEOUT_TCP_TRANS = 240E9;
 const volatile u64 TIMEOUT_TCP_EST = 7440E9;
+// Timeout of TCP CT closed with TCP_TEARDOWN_TIME_WAIT
+const volatile u64 TIMEOUT_TCP_TIME_WAIT = 60E9;
+// Handling of inbound TCP RST to binding without matching CT
+const volatile u8 UNMATCHED_TCP_RST = UNMATCHED_RST_DROP;
+// Drop inbound TCP and UDP packets to external addresses out of binding range,
+// or to static 1:1 external addresses without binding, instead of passing them
+// to this host, except for those matching a socket of this host, i.e. local
+// services and flows initiated by this host, or local ports, see LOCAL_PORTS.
+// ICMP and other protocols are passed still.
+const volatile u8 DROP_UNSOLICITED = false;
+// Handling of TCP CT closed by FIN from both sides, or by RST
+const volatile u8 TCP_FIN_TEARDOWN = TCP_TEARDOWN_TRANS;
+const volatile u8 TCP_RST_TEARDOWN = TCP_TEARDOWN_TRANS;
+
+// Max lifetime of dynamic binding regardless of refreshes, CTs of the binding
+// are expired at latest by then so the binding would be deleted and
+// reallocated. 0 for unlimited.
+const volatile u64 MAX_BINDING_LIFETIME = 0;
+
+// Copy packets of internal hosts within CAPTURE_ADDR/CAPTURE_PREFIX_LEN to
+// userspace through map_capture before and after translation, for debugging.
+// CAPTURE_ADDR is of CAPTURE_ADDR_IPV4 family, any internal host is matched
+// if CAPTURE_PREFIX_LEN is 0.
+const volatile u8 CAPTURE = false;
+const volatile u8 CAPTURE_ADDR_IPV4 = true;
+const volatile u8 CAPTURE_PREFIX_LEN = 0;
+const volatile __be32 CAPTURE_ADDR[4] = {0};
+// Only capture packets of L4 protocol, 0 for any protocol, IPPROTO_ICMP
+// matches both ICMP and ICMPv6
+const volatile u8 CAPTURE_L4PROTO = 0;
+
+// Report per-packet trace events through map_events for packets matching
+// filter below, with either source or destination address within
+// TRACE_ADDR/TRACE_PREFIX_LEN and either source or destination port being
+// TRACE_PORT. For inbound packets, the translated destination is matched as
+// well. Filter of zero value matches any packet.
+const volatile u8 TRACE = false;
+const volatile u8 TRACE_ADDR_IPV4 = true;
+const volatile u8 TRACE_PREFIX_LEN = 0;
+const volatile __be32 TRACE_ADDR[4] = {0};
+const volatile u16 TRACE_PORT = 0;
+const volatile u8 TRACE_L4PROTO = 0;
 
 __be32 g_ipv4_external_addr SEC(".data") = 0;
 #ifdef FEAT_IPV6
 __be32 g_ipv6_external_addr[4] SEC(".data") = {0};
 #endif
 
+// All external addresses to select from, with the first one being
+// g_ipv4_external_addr or g_ipv6_external_addr
+__be32 g_ipv4_external_pool[MAX_EXTERNAL_POOL] SEC(".data") = {0};
+// Cumulative weights of external addresses in pool per protocol, see
+// pool_proto_idx(), addresses not serving the protocol add no weight
+u32 g_ipv4_external_pool_weight[EXTERNAL_POOL_PROTOS]
+                               [MAX_EXTERNAL_POOL] SEC(".data") = {0};
+// Index of the first address in pool serving the protocol
+u8 g_ipv4_external_pool_first[EXTERNAL_POOL_PROTOS] SEC(".data") = {0};
+// Index of the address serving the protocol next to each address in pool,
+// wrapped around
+u8 g_ipv4_external_pool_next[EXTERNAL_POOL_PROTOS]
+                            [MAX_EXTERNAL_POOL] SEC(".data") = {0};
+u8 g_ipv4_external_pool_len SEC(".data") = 0;
+#ifdef FEAT_IPV6
+__be32 g_ipv6_external_pool[MAX_EXTERNAL_POOL][4] SEC(".data") = {0};
+u32 g_ipv6_external_pool_weight[EXTERNAL_POOL_PROTOS]
+                               [MAX_EXTERNAL_POOL] SEC(".data") = {0};
+u8 g_ipv6_external_pool_first[EXTERNAL_POOL_PROTOS] SEC(".data") = {0};
+u8 g_ipv6_external_pool_next[EXTERNAL_POOL_PROTOS]
+                            [MAX_EXTERNAL_POOL] SEC(".data") = {0};
+u8 g_ipv6_external_pool_len SEC(".data") = 0;
+#endif
+
+#ifdef FEAT_IPV6
+// Prefixes of NPTv6 no longer than /64, zero prefix length disables
+// translation, e.g. external prefix is not discovered yet
+u8 g_npt_prefix_len SEC(".data") = 0;
+__be32 g_npt_internal_prefix[2] SEC(".data") = {0};
+__be32 g_npt_external_prefix[2] SEC(".data") = {0};
+// One's complement sum of internal prefix minus that of external prefix, added
+// to a 16-bit word outside of prefix on outbound translation and subtracted on
+// inbound translation so that upper layer checksums remain valid
+u16 g_npt_adjustment SEC(".data") = 0;
+// Addresses of external interface itself within external prefix, inbound
+// packets destined to them are not translated
+__be32 g_npt_local_addrs[MAX_NPT_LOCAL_ADDRS][4] SEC(".data") = {0};
+u8 g_npt_local_addrs_len SEC(".data") = 0;
+
+// First 96 bits of /96 NAT64 prefix, and IPv6 address of CLAT
+__be32 g_clat_pref64[3] SEC(".data") = {0};
+__be32 g_clat_ipv6_addr[4] SEC(".data") = {0};
+#endif
+
 u8 g_deleting_map_entries SEC(".data") = 0;
 
+// Max BPF log level, can be adjusted while programs are running up to
+// MAX_LOG_LEVEL
+u8 g_log_level SEC(".data") = BPF_LOG_LEVEL_NONE;
+
 u32 g_next_binding_seq = 0;
+// Cursor of sequential port allocation
+u32 g_next_port = 0;
+// Cursor of round-robin external address selection
+u32 g_next_external = 0;
+// Whether new bindings are being spilled over to next external address
+u8 g_external_spillover = false;
+// Number of ICMP error messages with malformed embedded packets
+u64 g_icmp_err_malformed SEC(".data") = 0;
+// Number of ICMP errors replied to rejected new flows and unmatched TCP RST,
+// and those dropped instead by REJECT_RATE_INTERVAL
+u64 g_icmp_err_generated SEC(".data") = 0;
+u64 g_icmp_err_rate_limited SEC(".data") = 0;
+// Number of SYNs dropped or tarpitted by MAX_EMBRYONIC_PER_HOST
+u64 g_embryonic_limited SEC(".data") = 0;
+// Number of new UDP flows dropped by UDP_*BINDING_RATE_INTERVAL
+u64 g_udp_binding_rate_limited SEC(".data") = 0;
+// Number of UDP CTs, only tracked if UDP_HIGH_WATER is set, and recounted by
+// userspace after deleting CTs
+u32 g_udp_cts SEC(".data") = 0;
+// Number of inbound packets dropped by DROP_UNSOLICITED
+u64 g_unsolicited_dropped SEC(".data") = 0;
+// Number of outbound packets dropped by DEST_DROP_FLAG
+u64 g_dest_blocked SEC(".data") = 0;
+// Number of packets with checksum offloaded whose incremental checksum fixes
+// were skipped by CSUM_OFFLOAD_COMPAT
+u64 g_csum_fix_skipped SEC(".data") = 0;
+// Theoretical arrival time of next new UDP binding of all internal hosts
+u64 g_udp_binding_tat = 0;
 
 #undef BPF_LOG_LEVEL
+#undef BPF_LOG_MAX_LEVEL
 #undef BPF_LOG_TOPIC
-#define BPF_LOG_LEVEL LOG_LEVEL
+#define BPF_LOG_LEVEL g_log_level
+#define BPF_LOG_MAX_LEVEL MAX_LOG_LEVEL
 
 struct {
     __uint(type, BPF_MAP_TYPE_LPM_TRIE);
@@ -73,6 +353,33 @@ struct {
     __uint(map_flags, BPF_F_NO_PREALLOC);
 } map_ipv4_dest_config SEC(".maps");
 
+struct {
+    __uint(type, BPF_MAP_TYPE_LPM_TRIE);
+    __type(key, struct ipv4_lpm_key);
+    __type(value, struct netmap_value);
+    __uint(max_entries, 1024);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} map_ipv4_netmap SEC(".maps");
+
+// External address for internal source prefix
+struct {
+    __uint(type, BPF_MAP_TYPE_LPM_TRIE);
+    __type(key, struct ipv4_lpm_key);
+    __type(value, union u_inet_addr);
+    __uint(max_entries, 1024);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} map_ipv4_source_policy SEC(".maps");
+
+// Internal source prefixes never translated, e.g. routed subnets with public
+// addresses of their own
+struct {
+    __uint(type, BPF_MAP_TYPE_LPM_TRIE);
+    __type(key, struct ipv4_lpm_key);
+    __type(value, u8);
+    __uint(max_entries, 1024);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} map_ipv4_no_snat_source SEC(".maps");
+
 #ifdef FEAT_IPV6
 struct {
     __uint(type, BPF_MAP_TYPE_LPM_TRIE);
@@ -89,6 +396,22 @@ struct {
     __uint(max_entries, 1024);
     __uint(map_flags, BPF_F_NO_PREALLOC);
 } map_ipv6_dest_config SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LPM_TRIE);
+    __type(key, struct ipv6_lpm_key);
+    __type(value, union u_inet_addr);
+    __uint(max_entries, 1024);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} map_ipv6_source_policy SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LPM_TRIE);
+    __type(key, struct ipv6_lpm_key);
+    __type(value, u8);
+    __uint(max_entries, 1024);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} map_ipv6_no_snat_source SEC(".maps");
 #endif
 
 struct {
@@ -118,6 +441,121 @@ struct {
     // __uint(pinning, LIBBPF_PIN_BY_NAME);
 } map_ct SEC(".maps");
 
+// Number of CTs of internal host, only tracked if MAX_SESSIONS_PER_HOST is set
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __type(key, struct map_host_key);
+    __type(value, u32);
+    __uint(max_entries, DEFAULT_HOST_MAX_ENTRIES);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} map_host_sessions SEC(".maps");
+
+// Number of half-open TCP CTs of internal host, only tracked if
+// MAX_EMBRYONIC_PER_HOST is set
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __type(key, struct map_host_key);
+    __type(value, u32);
+    __uint(max_entries, DEFAULT_HOST_MAX_ENTRIES);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} map_host_embryonic SEC(".maps");
+
+// Number of CTs between external endpoint and remote address, only tracked if
+// FILTER_ADDR_TRACKING is set
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __type(key, struct map_filter_addr_key);
+    __type(value, u32);
+    __uint(max_entries, DEFAULT_CONNTRACK_MAX_ENTRIES);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} map_filter_addr SEC(".maps");
+
+// Number of ports held by internal host, only tracked if any of PORT_QUOTA_*
+// is set
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __type(key, struct map_host_key);
+    __type(value, struct host_ports);
+    __uint(max_entries, DEFAULT_HOST_MAX_ENTRIES);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} map_host_ports SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __type(key, struct map_local_port_key);
+    __type(value, u8);
+    __uint(max_entries, 4096);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} map_local_ports SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __type(key, struct map_local_port_key);
+    __type(value, u8);
+    __uint(max_entries, 4096);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} map_no_snat_dport SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __type(key, struct map_pptp_call_key);
+    __type(value, struct map_pptp_call_value);
+    __uint(max_entries, 4096);
+} map_pptp_call SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __type(key, struct map_esp_key);
+    __type(value, __be32);
+    __uint(max_entries, 4096);
+} map_esp SEC(".maps");
+
+// External address assigned to internal host, only tracked if
+// PAIRED_POOLING is set
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __type(key, struct map_host_key);
+    __type(value, union u_inet_addr);
+    __uint(max_entries, DEFAULT_HOST_MAX_ENTRIES);
+} map_host_external SEC(".maps");
+
+// Theoretical arrival time of next new binding of internal host, see
+// https://en.wikipedia.org/wiki/Generic_cell_rate_algorithm
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __type(key, struct map_host_key);
+    __type(value, u64);
+    __uint(max_entries, DEFAULT_HOST_MAX_ENTRIES);
+} map_host_binding_rate SEC(".maps");
+
+// Theoretical arrival time of next ICMP error replied to internal host
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __type(key, struct map_host_key);
+    __type(value, u64);
+    __uint(max_entries, DEFAULT_HOST_MAX_ENTRIES);
+} map_host_reject_rate SEC(".maps");
+
+// Theoretical arrival time of next new UDP binding of internal host
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __type(key, struct map_host_key);
+    __type(value, u64);
+    __uint(max_entries, DEFAULT_HOST_MAX_ENTRIES);
+} map_host_udp_binding_rate SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_RINGBUF);
+    __uint(max_entries, 64 * 1024);
+} map_events SEC(".maps");
+
+// max_entries is set to number of CPUs by libbpf
+struct {
+    __uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);
+    __uint(key_size, sizeof(u32));
+    __uint(value_size, sizeof(u32));
+} map_capture SEC(".maps");
+
 enum {
     PKT_CONNLESS,
     PKT_TCP_DATA,
@@ -146,7 +584,13 @@ struct packet_info {
     int err_l4_off;
 };
 
-#define TC_SKB_L3_OFF() (HAS_ETH_ENCAP ? sizeof(struct ethhdr) : 0)
+// Value of err_l4_off if embedded packet of ICMP error message is malformed
+#define ICMP_ERR_MALFORMED -2
+
+#define TC_SKB_L3_OFF()                                                        \
+    (HAS_ETH_ENCAP ? sizeof(struct ethhdr) +                                   \
+                         (HAS_PPPOE_ENCAP ? sizeof(struct pppoe_ses_hdr) : 0)  \
+                   : 0)
 
 #ifdef FEAT_IPV6
 #define IS_IPV4(pkt) ((pkt)->is_ipv4)
@@ -477,6 +921,7 @@ static __always_inline int parse_packet(struct __sk_buff *skb, bool is_ipv4,
 
     int ret;
     u32 l3_header_len;
+    pkt->err_l4_off = -1;
     if (is_ipv4) {
         struct iphdr *iph;
         if (VALIDATE_PULL(skb, &iph, l3_off, sizeof(*iph))) {
@@ -498,7 +943,6 @@ static __always_inline int parse_packet(struct __sk_buff *skb, bool is_ipv4,
     }
 
     pkt->pkt_type = PKT_CONNLESS;
-    pkt->err_l4_off = -1;
     if (pkt->frag_type != FRAG_NONE && pkt->frag_off != 0) {
         // not the first fragment
         pkt->l4_off = -1;
@@ -544,7 +988,9 @@ static __always_inline int parse_packet(struct __sk_buff *skb, bool is_ipv4,
             ret = parse_packet_light(
                 skb, IS_IPV4(pkt), icmpx_err_l3_offset(pkt->l4_off), &err_tuple,
                 &pkt->nexthdr, &err_l3_hdr_len);
-            if (ret != TC_ACT_OK) {
+            if (ret == TC_ACT_SHOT) {
+                goto icmp_err_malformed;
+            } else if (ret != TC_ACT_OK) {
                 return ret;
             }
             pkt->err_l4_off = icmpx_err_l3_offset(pkt->l4_off) + err_l3_hdr_len;
@@ -557,7 +1003,7 @@ static __always_inline int parse_packet(struct __sk_buff *skb, bool is_ipv4,
             if (!inet_addr_equal(&pkt->tuple.daddr, &err_tuple.saddr)) {
                 bpf_log_error("IP destination address does not match source "
                               "address inside ICMP error message");
-                return TC_ACT_SHOT;
+                goto icmp_err_malformed;
             }
 
             COPY_ADDR6(pkt->tuple.saddr.all, err_tuple.daddr.all);
@@ -581,6 +1027,10 @@ static __always_inline int parse_packet(struct __sk_buff *skb, bool is_ipv4,
     }
 
     return TC_ACT_OK;
+icmp_err_malformed:
+    pkt->err_l4_off = ICMP_ERR_MALFORMED;
+    __sync_fetch_and_add(&g_icmp_err_malformed, 1);
+    return TC_ACT_SHOT;
 #undef BPF_LOG_TOPIC
 }
 
@@ -687,6 +1137,14 @@ static __always_inline bool dest_hairpin(struct dest_config *config) {
 static __always_inline bool dest_pass_nat(struct dest_config *config) {
     return config->flags & DEST_NO_SNAT_FLAG;
 }
+static __always_inline bool dest_blocked(struct dest_config *config) {
+    return config->flags & DEST_DROP_FLAG;
+}
+
+static __always_inline bool is_bypassed(const struct __sk_buff *skb) {
+    return BYPASS_FWMARK &&
+           (skb->mark & BYPASS_FWMARK_MASK) == BYPASS_FWMARK;
+}
 
 static __always_inline struct external_config *
 lookup_external_config(bool is_ipv4, const union u_inet_addr *external_addr) {
@@ -706,6 +1164,22 @@ lookup_external_config(bool is_ipv4, const union u_inet_addr *external_addr) {
     }
 }
 
+static __always_inline bool external_serves(struct external_config *config,
+                                            u8 l4proto) {
+    if (!config->protocols) {
+        return true;
+    }
+    switch (l4proto) {
+    case IPPROTO_TCP:
+        return config->protocols & EXTERNAL_PROTO_TCP_FLAG;
+    case IPPROTO_UDP:
+        return config->protocols & EXTERNAL_PROTO_UDP_FLAG;
+    default:
+        return is_icmpx(l4proto) &&
+               (config->protocols & EXTERNAL_PROTO_ICMP_FLAG);
+    }
+}
+
 static __always_inline bool external_pass_nat(struct external_config *config) {
     return config->flags & EXTERNAL_NO_SNAT_FLAG;
 }
@@ -715,6 +1189,275 @@ nat_check_external_config(struct external_config *config) {
         return TC_ACT_UNSPEC;
     return TC_ACT_OK;
 }
+static __always_inline bool
+external_one_to_one(struct external_config *config) {
+    return ADDR_BINDINGS && config &&
+           (config->flags & EXTERNAL_ONE_TO_ONE_FLAG);
+}
+
+static __always_inline void
+filter_addr_key_from_ct(const struct map_ct_key *ct_key,
+                        struct map_filter_addr_key *key) {
+    key->ifindex = ct_key->ifindex;
+    key->flags = ct_key->flags;
+    key->l4proto = ct_key->l4proto;
+    key->port = ct_key->external.sport;
+    COPY_ADDR6(key->external_addr.all, ct_key->external.saddr.all);
+    COPY_ADDR6(key->remote_addr.all, ct_key->external.daddr.all);
+}
+
+static __always_inline void filter_addr_inc(const struct map_ct_key *ct_key) {
+    if (!FILTER_ADDR_TRACKING) {
+        return;
+    }
+
+    struct map_filter_addr_key key;
+    filter_addr_key_from_ct(ct_key, &key);
+
+    u32 *count = bpf_map_lookup_elem(&map_filter_addr, &key);
+    if (!count) {
+        u32 init = 1;
+        if (!bpf_map_update_elem(&map_filter_addr, &key, &init, BPF_NOEXIST)) {
+            return;
+        }
+        count = bpf_map_lookup_elem(&map_filter_addr, &key);
+        if (!count) {
+            return;
+        }
+    }
+    __sync_fetch_and_add(count, 1);
+}
+
+static __always_inline void filter_addr_dec(const struct map_ct_key *ct_key) {
+    if (!FILTER_ADDR_TRACKING) {
+        return;
+    }
+
+    struct map_filter_addr_key key;
+    filter_addr_key_from_ct(ct_key, &key);
+
+    u32 *count = bpf_map_lookup_elem(&map_filter_addr, &key);
+    if (!count) {
+        return;
+    }
+    if (*count <= 1) {
+        bpf_map_delete_elem(&map_filter_addr, &key);
+    } else {
+        __sync_fetch_and_sub(count, 1);
+    }
+}
+
+// Returns whether an inbound packet from new remote endpoint is allowed to
+// initiate CT on existing binding, according to filtering behavior of the
+// remote destination if overridden, or otherwise of the external.
+static __always_inline bool
+nat_filter_allow_inbound(u32 ifindex, bool is_ipv4, u8 l4proto,
+                         const struct external_config *config,
+                         const struct inet_tuple *reply) {
+    bool adf = config->flags & EXTERNAL_FILTER_ADF_FLAG;
+    bool apdf = config->flags & EXTERNAL_FILTER_APDF_FLAG;
+
+    struct dest_config *dest_config =
+        lookup_dest_config(is_ipv4, &reply->saddr);
+    if (dest_config && (dest_config->flags & DEST_FILTER_OVERRIDE_FLAG)) {
+        adf = dest_config->flags & DEST_FILTER_ADF_FLAG;
+        apdf = dest_config->flags & DEST_FILTER_APDF_FLAG;
+    }
+
+    if (apdf) {
+        return false;
+    }
+    if (!adf) {
+        return true;
+    }
+
+    struct map_filter_addr_key key = {
+        .ifindex = ifindex,
+        .flags = is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG,
+        .l4proto = l4proto,
+        .port = reply->dport,
+    };
+    COPY_ADDR6(key.external_addr.all, reply->daddr.all);
+    COPY_ADDR6(key.remote_addr.all, reply->saddr.all);
+    return bpf_map_lookup_elem(&map_filter_addr, &key) != NULL;
+}
+
+static __always_inline u32 hash_inet_addr(const union u_inet_addr *addr) {
+    u32 hash = 0;
+#pragma unroll
+    for (int i = 0; i < sizeof(addr->all) / sizeof(addr->all[0]); i++) {
+        hash = (hash ^ addr->all[i]) * 0x9e3779b1;
+    }
+    return hash ^ (hash >> 16);
+}
+
+static __always_inline u32 pool_proto_idx(u8 l4proto) {
+    switch (l4proto) {
+    case IPPROTO_TCP:
+        return 0;
+    case IPPROTO_UDP:
+        return 1;
+    default:
+        return 2;
+    }
+}
+
+static __always_inline void
+select_external_addr(bool nat_x_4, u8 l4proto,
+                     const union u_inet_addr *from_addr,
+                     union u_inet_addr *to_addr) {
+    u32 proto_idx = pool_proto_idx(l4proto);
+    u32 len;
+    u32 *weights;
+    u32 idx;
+    if (nat_x_4) {
+        len = g_ipv4_external_pool_len;
+        weights = g_ipv4_external_pool_weight[proto_idx];
+        idx = g_ipv4_external_pool_first[proto_idx];
+    } else {
+#ifdef FEAT_IPV6
+        len = g_ipv6_external_pool_len;
+        weights = g_ipv6_external_pool_weight[proto_idx];
+        idx = g_ipv6_external_pool_first[proto_idx];
+#else
+        __bpf_unreachable();
+#endif
+    }
+
+    if (len > 1 && len <= MAX_EXTERNAL_POOL) {
+        u32 total = weights[(len - 1) & (MAX_EXTERNAL_POOL - 1)];
+        u32 seed = 0;
+        switch (EXTERNAL_SELECTION) {
+        case EXTERNAL_SELECT_HASH:
+            seed = hash_inet_addr(from_addr);
+            break;
+        case EXTERNAL_SELECT_ROUND_ROBIN:
+            seed = __sync_fetch_and_add(&g_next_external, 1);
+            break;
+        default:
+            total = 0;
+        }
+        if (total) {
+            // pick address whose cumulative weight range covers the seed
+            seed %= total;
+#pragma unroll
+            for (int i = 0; i < MAX_EXTERNAL_POOL; i++) {
+                if (i >= len) {
+                    break;
+                }
+                if (seed < weights[i]) {
+                    idx = i;
+                    break;
+                }
+            }
+        }
+    }
+    idx &= MAX_EXTERNAL_POOL - 1;
+
+    if (nat_x_4) {
+        inet_addr_set_ip(to_addr, idx ? g_ipv4_external_pool[idx]
+                                      : g_ipv4_external_addr);
+    } else {
+#ifdef FEAT_IPV6
+        inet_addr_set_ip6(to_addr, idx ? g_ipv6_external_pool[idx]
+                                       : g_ipv6_external_addr);
+#else
+        __bpf_unreachable();
+#endif
+    }
+}
+
+// Replaces `to_addr` with external address configured for internal source
+// prefix, returns false if there is none.
+static __always_inline bool
+source_policy_external_addr(bool is_ipv4, const union u_inet_addr *from_addr,
+                            union u_inet_addr *to_addr) {
+    union u_inet_addr *policy;
+    if (is_ipv4) {
+        struct ipv4_lpm_key key = {.prefixlen = 32, .ip = from_addr->ip};
+        policy = bpf_map_lookup_elem(&map_ipv4_source_policy, &key);
+    } else {
+#ifdef FEAT_IPV6
+        struct ipv6_lpm_key key;
+        key.prefixlen = 128;
+        COPY_ADDR6(key.ip6, from_addr->ip6);
+        policy = bpf_map_lookup_elem(&map_ipv6_source_policy, &key);
+#else
+        return false;
+#endif
+    }
+    if (!policy) {
+        return false;
+    }
+    COPY_ADDR6(to_addr->all, policy->all);
+    return true;
+}
+
+static __always_inline bool
+is_no_snat_source(bool is_ipv4, const union u_inet_addr *from_addr) {
+    if (!NO_SNAT_SOURCES) {
+        return false;
+    }
+    if (is_ipv4) {
+        struct ipv4_lpm_key key = {.prefixlen = 32, .ip = from_addr->ip};
+        return bpf_map_lookup_elem(&map_ipv4_no_snat_source, &key) != NULL;
+    }
+#ifdef FEAT_IPV6
+    struct ipv6_lpm_key key;
+    key.prefixlen = 128;
+    COPY_ADDR6(key.ip6, from_addr->ip6);
+    return bpf_map_lookup_elem(&map_ipv6_no_snat_source, &key) != NULL;
+#else
+    return false;
+#endif
+}
+
+static __always_inline bool is_no_snat_dport(u8 l4proto, __be16 port) {
+    if (!NO_SNAT_DPORTS || is_icmpx(l4proto)) {
+        return false;
+    }
+    struct map_local_port_key key = {
+        .l4proto = l4proto,
+        ._pad = 0,
+        .port = port,
+    };
+    return bpf_map_lookup_elem(&map_no_snat_dport, &key) != NULL;
+}
+
+// Replaces `to_addr` with external address previously assigned to internal
+// host if it's still valid, or assigns `to_addr` to the host otherwise. The
+// pairing is kept but not followed if the paired address does not serve the
+// protocol.
+static __always_inline void
+paired_external_addr(u32 ifindex, bool is_ipv4, bool nat_x_4, u8 l4proto,
+                     const union u_inet_addr *from_addr,
+                     union u_inet_addr *to_addr) {
+#define BPF_LOG_TOPIC "paired_external_addr"
+    struct map_host_key key = {
+        .ifindex = ifindex,
+        .flags = is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG,
+        ._pad = {0},
+    };
+    COPY_ADDR6(key.addr.all, from_addr->all);
+
+    union u_inet_addr *paired = bpf_map_lookup_elem(&map_host_external, &key);
+    if (paired) {
+        if (inet_addr_equal(paired, to_addr)) {
+            return;
+        }
+        struct external_config *config =
+            lookup_external_config(nat_x_4, paired);
+        if (nat_check_external_config(config) == TC_ACT_OK) {
+            if (external_serves(config, l4proto)) {
+                COPY_ADDR6(to_addr->all, paired->all);
+            }
+            return;
+        }
+        bpf_log_debug("paired external address gone, reassigning");
+    }
+    bpf_map_update_elem(&map_host_external, &key, to_addr, BPF_ANY);
+#undef BPF_LOG_TOPIC
+}
 
 static __always_inline bool nat_in_binding_range(struct external_config *config,
                                                  u8 nexthdr, u16 ext_port) {
@@ -777,8 +1520,8 @@ static __always_inline void ipv4_update_csum_icmp_err(
     // the update of embedded layer 4 checksum is not required but may helpful
     // for packet tracking the TCP checksum might not be included in IPv4
     // packet, check if it exists first
-    if (bpf_skb_load_bytes(skb, err_l4_csum_off, &prev_csum,
-                           sizeof(prev_csum))) {
+    if (!bpf_skb_load_bytes(skb, err_l4_csum_off, &prev_csum,
+                            sizeof(prev_csum))) {
         ipv4_update_csum_inner(skb, err_l4_csum_off, from_addr, from_port,
                                to_addr, to_port, err_l4_pseudo, l4_mangled_0);
 
@@ -818,14 +1561,15 @@ ipv6_update_csum_inner(struct __sk_buff *skb, u32 l4_csum_off,
 
 static __always_inline void
 ipv6_update_csum_icmp_err(struct __sk_buff *skb, u32 icmp_csum_off,
-                          u32 err_l4_csum_off, __be32 from_addr[4],
-                          __be16 from_port, __be32 to_addr[4], __be16 to_port) {
+                          u32 err_l4_csum_off, __be32 outer_from_addr[4],
+                          __be32 from_addr[4], __be16 from_port,
+                          __be32 to_addr[4], __be16 to_port) {
     // update of inner message
 #if 1
     u16 prev_csum;
     u16 curr_csum;
-    if (bpf_skb_load_bytes(skb, err_l4_csum_off, &prev_csum,
-                           sizeof(prev_csum))) {
+    if (!bpf_skb_load_bytes(skb, err_l4_csum_off, &prev_csum,
+                            sizeof(prev_csum))) {
 
         ipv6_update_csum_inner(skb, err_l4_csum_off, from_addr, from_port,
                                to_addr, to_port);
@@ -843,8 +1587,8 @@ ipv6_update_csum_icmp_err(struct __sk_buff *skb, u32 icmp_csum_off,
 
 #pragma unroll
     for (int i = 0; i < 4; i++) {
-        bpf_l4_csum_replace(skb, icmp_csum_off, from_addr[i], to_addr[i],
-                            4 | BPF_F_PSEUDO_HDR);
+        bpf_l4_csum_replace(skb, icmp_csum_off, outer_from_addr[i],
+                            to_addr[i], 4 | BPF_F_PSEUDO_HDR);
     }
 }
 
@@ -864,6 +1608,7 @@ insert_new_binding(const struct map_binding_key *key,
         .to_port = key->from_port,
         .to_addr = key->from_addr,
         .seq = val->seq,
+        .created = val->created,
     };
     ret = bpf_map_update_elem(&map_binding, key, val, BPF_ANY);
     if (ret) {
@@ -891,52 +1636,398 @@ error_update:
 #undef BPF_LOG_TOPIC
 }
 
-static __always_inline void delete_ct(struct map_ct_key *key) {
-#define BPF_LOG_TOPIC "delete_ct"
-    struct map_binding_key b_key_rev = {
-        .ifindex = key->ifindex,
-        .flags = key->flags,
-        .l4proto = key->l4proto,
-        .from_addr = key->external.saddr,
-        .from_port = key->external.sport,
+// Returns false if count of internal host in `map` has reached `limit`,
+// otherwise increments it. The limit is soft as concurrent events could pass
+// the check at the same time.
+static __always_inline bool host_count_inc(void *map, u32 limit, u32 ifindex,
+                                           u8 flags,
+                                           const union u_inet_addr *addr) {
+    struct map_host_key key = {
+        .ifindex = ifindex,
+        .flags = flags,
+        ._pad = {0},
     };
-    struct map_ct_value *ct_value = bpf_map_lookup_elem(&map_ct, key);
-    if (!ct_value) {
-        return;
-    }
-
-    struct map_binding_value *b_value_rev =
-        bpf_map_lookup_elem(&map_binding, &b_key_rev);
-    if (!b_value_rev || b_value_rev->seq != ct_value->seq) {
-        goto delete_ct;
-    }
+    COPY_ADDR6(key.addr.all, addr->all);
 
-    if (ct_value->state == CT_INIT_IN) {
-        if (__sync_sub_and_fetch(&b_value_rev->ref, 1) != 0) {
-            goto delete_ct;
+    u32 *count = bpf_map_lookup_elem(map, &key);
+    if (!count) {
+        u32 init = 1;
+        if (!bpf_map_update_elem(map, &key, &init, BPF_NOEXIST)) {
+            return true;
+        }
+        count = bpf_map_lookup_elem(map, &key);
+        if (!count) {
+            // don't block the host if we failed to count, e.g. map is full
+            return true;
         }
-    } else if (__sync_sub_and_fetch(&b_value_rev->use, 1),
-               __sync_sub_and_fetch(&b_value_rev->ref, 1) != 0) {
-        goto delete_ct;
     }
+    if (*count >= limit) {
+        return false;
+    }
+    __sync_fetch_and_add(count, 1);
+    return true;
+}
 
-    struct map_binding_key b_key_orig;
-    get_rev_dir_binding_key(&b_key_rev, b_value_rev, &b_key_orig);
-
-    bpf_map_delete_elem(&map_binding, &b_key_orig);
-    bpf_map_delete_elem(&map_binding, &b_key_rev);
+static __always_inline void host_count_dec(void *map, u32 ifindex, u8 flags,
+                                           const union u_inet_addr *addr) {
+    struct map_host_key key = {
+        .ifindex = ifindex,
+        .flags = flags,
+        ._pad = {0},
+    };
+    COPY_ADDR6(key.addr.all, addr->all);
 
-    bpf_log_debug("no ref, delete binding");
+    u32 *count = bpf_map_lookup_elem(map, &key);
+    if (!count) {
+        return;
+    }
+    // Racing with host_count_inc() could lose a count here, which only
+    // makes the limit looser.
+    if (*count <= 1) {
+        bpf_map_delete_elem(map, &key);
+    } else {
+        __sync_fetch_and_sub(count, 1);
+    }
+}
 
-delete_ct:
-    bpf_map_delete_elem(&map_ct, key);
+// Returns false if internal host has reached the session limit, otherwise
+// counts a new session of the host.
+static __always_inline bool host_sessions_inc(u32 ifindex, u8 flags,
+                                              const union u_inet_addr *addr) {
+#define BPF_LOG_TOPIC "host_sessions_inc"
+    if (!MAX_SESSIONS_PER_HOST) {
+        return true;
+    }
+    if (!host_count_inc(&map_host_sessions, MAX_SESSIONS_PER_HOST, ifindex,
+                        flags, addr)) {
+        bpf_log_warn("host reached session limit");
+        return false;
+    }
+    return true;
 #undef BPF_LOG_TOPIC
 }
 
-static int ct_timer_cb(void *_map_ct, struct map_ct_key *key,
-                       struct map_ct_value *value) {
-#define BPF_LOG_TOPIC "ct_timer_cb"
-    if (g_deleting_map_entries) {
+static __always_inline void host_sessions_dec(u32 ifindex, u8 flags,
+                                              const union u_inet_addr *addr) {
+    if (MAX_SESSIONS_PER_HOST) {
+        host_count_dec(&map_host_sessions, ifindex, flags, addr);
+    }
+}
+
+// Returns false if internal host has reached the half-open session limit,
+// otherwise counts a new half-open session of the host.
+static __always_inline bool host_embryonic_inc(u32 ifindex, u8 flags,
+                                               const union u_inet_addr *addr) {
+#define BPF_LOG_TOPIC "host_embryonic_inc"
+    if (!MAX_EMBRYONIC_PER_HOST) {
+        return true;
+    }
+    if (!host_count_inc(&map_host_embryonic, MAX_EMBRYONIC_PER_HOST, ifindex,
+                        flags, addr)) {
+        bpf_log_debug("host reached half-open session limit");
+        return false;
+    }
+    return true;
+#undef BPF_LOG_TOPIC
+}
+
+static __always_inline void host_embryonic_dec(u32 ifindex, u8 flags,
+                                               const union u_inet_addr *addr) {
+    if (MAX_EMBRYONIC_PER_HOST) {
+        host_count_dec(&map_host_embryonic, ifindex, flags, addr);
+    }
+}
+
+// Returns false if event at `now` exceeds rate of `interval` nanoseconds
+// between events with `burst` allowed, otherwise advances `tat`, the
+// theoretical arrival time of next event.
+static __always_inline bool rate_check(u64 *tat, u64 now, u64 interval,
+                                       u32 burst) {
+    u64 cur = *tat > now ? *tat : now;
+    u64 limit = now + interval * burst;
+    if (cur + interval > limit) {
+        return false;
+    }
+    *tat = cur + interval;
+    return true;
+}
+
+// Returns false if internal host exceeds rate of `interval` nanoseconds
+// between events with `burst` allowed, tracked in `map` of theoretical
+// arrival time of next event.
+static __always_inline bool host_rate_check(void *map, u32 ifindex, u8 flags,
+                                            const union u_inet_addr *addr,
+                                            u64 interval, u32 burst) {
+    struct map_host_key key = {
+        .ifindex = ifindex,
+        .flags = flags,
+        ._pad = {0},
+    };
+    COPY_ADDR6(key.addr.all, addr->all);
+
+    u64 now = bpf_ktime_get_ns();
+    u64 *tat = bpf_map_lookup_elem(map, &key);
+    if (!tat) {
+        u64 next = now + interval;
+        bpf_map_update_elem(map, &key, &next, BPF_ANY);
+        return true;
+    }
+    return rate_check(tat, now, interval, burst);
+}
+
+// Returns false if internal host is creating new bindings faster than
+// allowed.
+static __always_inline bool
+host_binding_rate_check(u32 ifindex, u8 flags, const union u_inet_addr *addr) {
+#define BPF_LOG_TOPIC "host_binding_rate_check"
+    if (!BINDING_RATE_INTERVAL) {
+        return true;
+    }
+    if (!host_rate_check(&map_host_binding_rate, ifindex, flags, addr,
+                         BINDING_RATE_INTERVAL, BINDING_RATE_BURST)) {
+        bpf_log_debug("host exceeded new binding rate");
+        return false;
+    }
+    return true;
+#undef BPF_LOG_TOPIC
+}
+
+// Returns false if internal host, or all internal hosts together, are creating
+// new UDP bindings faster than allowed.
+static __always_inline bool
+udp_binding_rate_check(u32 ifindex, u8 flags, const union u_inet_addr *addr) {
+#define BPF_LOG_TOPIC "udp_binding_rate_check"
+    if (UDP_HOST_BINDING_RATE_INTERVAL &&
+        !host_rate_check(&map_host_udp_binding_rate, ifindex, flags, addr,
+                         UDP_HOST_BINDING_RATE_INTERVAL,
+                         UDP_HOST_BINDING_RATE_BURST)) {
+        bpf_log_debug("host exceeded new UDP binding rate");
+        goto limited;
+    }
+    // racing updates could only let more bindings pass
+    if (UDP_BINDING_RATE_INTERVAL &&
+        !rate_check(&g_udp_binding_tat, bpf_ktime_get_ns(),
+                    UDP_BINDING_RATE_INTERVAL, UDP_BINDING_RATE_BURST)) {
+        bpf_log_debug("exceeded new UDP binding rate");
+        goto limited;
+    }
+    return true;
+limited:
+    __sync_fetch_and_add(&g_udp_binding_rate_limited, 1);
+    return false;
+#undef BPF_LOG_TOPIC
+}
+
+static __always_inline bool port_quota_enabled() {
+    return PORT_QUOTA_TCP || PORT_QUOTA_UDP || PORT_QUOTA_ICMP;
+}
+
+static __always_inline u32 port_quota(u8 l4proto) {
+    switch (l4proto) {
+    case IPPROTO_TCP:
+        return PORT_QUOTA_TCP;
+    case IPPROTO_UDP:
+        return PORT_QUOTA_UDP;
+    case IPPROTO_ICMP:
+    case NEXTHDR_ICMP:
+        return PORT_QUOTA_ICMP;
+    }
+    return 0;
+}
+
+static __always_inline u32 *host_ports_counter(struct host_ports *ports,
+                                               u8 l4proto) {
+    switch (l4proto) {
+    case IPPROTO_TCP:
+        return &ports->tcp;
+    case IPPROTO_UDP:
+        return &ports->udp;
+    case IPPROTO_ICMP:
+    case NEXTHDR_ICMP:
+        return &ports->icmp;
+    }
+    return NULL;
+}
+
+// Returns false if internal host has used up its port quota of the protocol,
+// and reports to userspace at most once per second for each host.
+static __always_inline bool
+host_ports_check(u32 ifindex, u8 flags, u8 l4proto,
+                 const union u_inet_addr *addr) {
+#define BPF_LOG_TOPIC "host_ports_check"
+    u32 quota = port_quota(l4proto);
+    if (!quota) {
+        return true;
+    }
+
+    struct map_host_key key = {
+        .ifindex = ifindex,
+        .flags = flags,
+        ._pad = {0},
+    };
+    COPY_ADDR6(key.addr.all, addr->all);
+
+    struct host_ports *ports = bpf_map_lookup_elem(&map_host_ports, &key);
+    if (!ports) {
+        return true;
+    }
+    u32 *counter = host_ports_counter(ports, l4proto);
+    if (!counter || *counter < quota) {
+        return true;
+    }
+
+    u64 now = bpf_ktime_get_ns();
+    if (now - ports->last_overflow >= 1E9) {
+        ports->last_overflow = now;
+        struct event *event =
+            bpf_ringbuf_reserve(&map_events, sizeof(struct event), 0);
+        if (event) {
+            event->type = EVENT_PORT_QUOTA_EXCEEDED;
+            event->ifindex = ifindex;
+            event->flags = flags;
+            event->l4proto = l4proto;
+            event->_pad = 0;
+            COPY_ADDR6(event->addr.all, addr->all);
+            bpf_ringbuf_submit(event, 0);
+        }
+    }
+    bpf_log_debug("host exceeded port quota");
+    return false;
+#undef BPF_LOG_TOPIC
+}
+
+static __always_inline void host_ports_inc(u32 ifindex, u8 flags, u8 l4proto,
+                                           const union u_inet_addr *addr) {
+    if (!port_quota_enabled()) {
+        return;
+    }
+
+    struct map_host_key key = {
+        .ifindex = ifindex,
+        .flags = flags,
+        ._pad = {0},
+    };
+    COPY_ADDR6(key.addr.all, addr->all);
+
+    struct host_ports *ports = bpf_map_lookup_elem(&map_host_ports, &key);
+    if (!ports) {
+        struct host_ports init = {0};
+        bpf_map_update_elem(&map_host_ports, &key, &init, BPF_NOEXIST);
+        ports = bpf_map_lookup_elem(&map_host_ports, &key);
+        if (!ports) {
+            return;
+        }
+    }
+    u32 *counter = host_ports_counter(ports, l4proto);
+    if (counter) {
+        __sync_fetch_and_add(counter, 1);
+    }
+}
+
+static __always_inline void host_ports_dec(u32 ifindex, u8 flags, u8 l4proto,
+                                           const union u_inet_addr *addr) {
+    if (!port_quota_enabled()) {
+        return;
+    }
+
+    struct map_host_key key = {
+        .ifindex = ifindex,
+        .flags = flags,
+        ._pad = {0},
+    };
+    COPY_ADDR6(key.addr.all, addr->all);
+
+    struct host_ports *ports = bpf_map_lookup_elem(&map_host_ports, &key);
+    if (!ports) {
+        return;
+    }
+    u32 *counter = host_ports_counter(ports, l4proto);
+    if (counter && *counter > 0) {
+        __sync_fetch_and_sub(counter, 1);
+    }
+}
+
+// Marks orig direction binding as ref counted by CT, and counts the port for
+// internal host once the binding is first referenced.
+static __always_inline void
+binding_orig_set_ref_counted(u32 ifindex, u8 flags, u8 l4proto,
+                             const union u_inet_addr *from_addr,
+                             struct map_binding_value *b_value_orig) {
+    if (__sync_bool_compare_and_swap(&b_value_orig->ref, 0,
+                                     BINDING_ORIG_REF_COUNTED)) {
+        host_ports_inc(ifindex, flags, l4proto, from_addr);
+    }
+}
+
+static __always_inline void delete_ct_entry(const struct map_ct_key *key,
+                                            const struct map_ct_value *value) {
+    u8 flags = value->flags;
+    bool is_embryonic =
+        key->l4proto == IPPROTO_TCP && value->state == CT_INIT_OUT;
+    union u_inet_addr saddr;
+    COPY_ADDR6(saddr.all, value->origin.saddr.all);
+    if (!bpf_map_delete_elem(&map_ct, key)) {
+        if (UDP_HIGH_WATER && key->l4proto == IPPROTO_UDP && g_udp_cts > 0) {
+            __sync_fetch_and_sub(&g_udp_cts, 1);
+        }
+        host_sessions_dec(key->ifindex, flags, &saddr);
+        if (is_embryonic) {
+            host_embryonic_dec(key->ifindex, flags, &saddr);
+        }
+        filter_addr_dec(key);
+    }
+}
+
+static __always_inline void delete_ct(struct map_ct_key *key) {
+#define BPF_LOG_TOPIC "delete_ct"
+    struct map_binding_key b_key_rev = {
+        .ifindex = key->ifindex,
+        .flags = key->flags,
+        .l4proto = key->l4proto,
+        .from_addr = key->external.saddr,
+        .from_port = key->external.sport,
+    };
+    struct map_ct_value *ct_value = bpf_map_lookup_elem(&map_ct, key);
+    if (!ct_value) {
+        return;
+    }
+
+    struct map_binding_value *b_value_rev =
+        bpf_map_lookup_elem(&map_binding, &b_key_rev);
+    if (!b_value_rev || b_value_rev->seq != ct_value->seq) {
+        goto delete_ct;
+    }
+
+    if (ct_value->state == CT_INIT_IN) {
+        if (__sync_sub_and_fetch(&b_value_rev->ref, 1) != 0) {
+            goto delete_ct;
+        }
+    } else if (__sync_sub_and_fetch(&b_value_rev->use, 1),
+               __sync_sub_and_fetch(&b_value_rev->ref, 1) != 0) {
+        goto delete_ct;
+    }
+
+    struct map_binding_key b_key_orig;
+    get_rev_dir_binding_key(&b_key_rev, b_value_rev, &b_key_orig);
+
+    if (!bpf_map_delete_elem(&map_binding, &b_key_orig)) {
+        host_ports_dec(key->ifindex,
+                       b_key_orig.flags & (ADDR_IPV4_FLAG | ADDR_IPV6_FLAG),
+                       key->l4proto, &b_key_orig.from_addr);
+    }
+    bpf_map_delete_elem(&map_binding, &b_key_rev);
+
+    bpf_log_debug("no ref, delete binding");
+
+delete_ct:
+    delete_ct_entry(key, ct_value);
+#undef BPF_LOG_TOPIC
+}
+
+static int ct_timer_cb(void *_map_ct, struct map_ct_key *key,
+                       struct map_ct_value *value) {
+#define BPF_LOG_TOPIC "ct_timer_cb"
+    if (g_deleting_map_entries) {
         // delay the CT deletion till g_deleting_map_entries became false
         bpf_timer_start(&value->timer, 1e9, 0);
         return 0;
@@ -950,9 +2041,45 @@ static int ct_timer_cb(void *_map_ct, struct map_ct_key *key,
 #undef BPF_LOG_TOPIC
 }
 
+// Returns TIMEOUT_ICMP for ICMP CT if set, otherwise the specified timeout
+static __always_inline u64 ct_timeout_pkt(u8 l4proto, u64 timeout) {
+    if (is_icmpx(l4proto) && TIMEOUT_ICMP) {
+        return TIMEOUT_ICMP;
+    }
+    return timeout;
+}
+
+// Returns timeout clamped to the remaining lifetime of binding if
+// MAX_BINDING_LIFETIME is set
+static __always_inline u64
+binding_clamp_timeout(const struct map_binding_value *b_value, u64 timeout) {
+    if (!MAX_BINDING_LIFETIME || b_value->is_static) {
+        return timeout;
+    }
+    u64 deadline = b_value->created * NSEC_PER_SEC + MAX_BINDING_LIFETIME;
+    u64 now = bpf_ktime_get_ns();
+    if (now >= deadline) {
+        return 0;
+    }
+    return timeout < deadline - now ? timeout : deadline - now;
+}
+
+// Returns timeout of CT clamped by binding_clamp_timeout(), and by
+// UDP_EMERGENCY_TIMEOUT for UDP CT if there are too many of them
+static __always_inline u64
+ct_clamp_timeout(u8 l4proto, const struct map_binding_value *b_value,
+                 u64 timeout) {
+    if (UDP_HIGH_WATER && l4proto == IPPROTO_UDP &&
+        g_udp_cts >= UDP_HIGH_WATER && timeout > UDP_EMERGENCY_TIMEOUT) {
+        timeout = UDP_EMERGENCY_TIMEOUT;
+    }
+    return binding_clamp_timeout(b_value, timeout);
+}
+
 static __always_inline struct map_ct_value *
 insert_new_ct(u8 l4proto, const struct map_ct_key *key,
-              const struct map_ct_value *val) {
+              const struct map_ct_value *val,
+              const struct map_binding_value *b_value) {
 #define BPF_LOG_TOPIC "insert_new_ct"
     int ret = bpf_map_update_elem(&map_ct, key, val, BPF_NOEXIST);
     if (ret) {
@@ -971,13 +2098,19 @@ insert_new_ct(u8 l4proto, const struct map_ct_key *key,
     if (ret) {
         goto delete_ct;
     }
-    ret = bpf_timer_start(
-        &value->timer,
-        l4proto == IPPROTO_TCP ? TIMEOUT_TCP_TRANS : TIMEOUT_PKT_MIN, 0);
+    u64 timeout = l4proto == IPPROTO_TCP
+                      ? TIMEOUT_TCP_TRANS
+                      : ct_timeout_pkt(l4proto, TIMEOUT_PKT_MIN);
+    timeout = ct_clamp_timeout(l4proto, b_value, timeout);
+    value->expires = bpf_ktime_get_ns() + timeout;
+    ret = bpf_timer_start(&value->timer, timeout, 0);
     if (ret) {
         goto delete_ct;
     }
 
+    if (UDP_HIGH_WATER && l4proto == IPPROTO_UDP) {
+        __sync_fetch_and_add(&g_udp_cts, 1);
+    }
     return value;
 delete_ct:
     bpf_log_error("setup timer err:%d", ret);
@@ -992,15 +2125,25 @@ modify_headers(struct __sk_buff *skb, bool is_ipv4, bool is_icmpx_error,
                bool is_modify_source, union u_inet_addr *from_addr,
                __be16 from_port, union u_inet_addr *to_addr, __be16 to_port) {
     int ret;
-    ret = bpf_write_inet_addr(
-        skb, is_ipv4, l3_off + get_l3_to_addr_off(is_ipv4, is_modify_source),
-        to_addr);
+    int l3_to_addr_off =
+        l3_off + get_l3_to_addr_off(is_ipv4, is_modify_source);
+    // Outer source of ICMP errors is whoever generated it, e.g. a router
+    // inside the internal network, rather than the embedded destination.
+    union u_inet_addr outer_from = *from_addr;
+    if (is_icmpx_error && is_modify_source) {
+        ret = bpf_read_inet_addr(skb, is_ipv4, l3_to_addr_off, &outer_from);
+        if (ret) {
+            return ret;
+        }
+    }
+
+    ret = bpf_write_inet_addr(skb, is_ipv4, l3_to_addr_off, to_addr);
     if (ret) {
         return ret;
     }
     if (is_ipv4) {
         ret = bpf_l3_csum_replace(skb, l3_off + offsetof(struct iphdr, check),
-                                  from_addr->ip, to_addr->ip, 4);
+                                  outer_from.ip, to_addr->ip, 4);
         if (ret) {
             return ret;
         }
@@ -1071,8 +2214,8 @@ modify_headers(struct __sk_buff *skb, bool is_ipv4, bool is_icmpx_error,
 #ifdef FEAT_IPV6
             ipv6_update_csum_icmp_err(
                 skb, l4_off + offsetof(struct icmphdr, checksum),
-                err_l4_off + l4_to_check_off, from_addr->ip6, from_port,
-                to_addr->ip6, to_port);
+                err_l4_off + l4_to_check_off, outer_from.ip6,
+                from_addr->ip6, from_port, to_addr->ip6, to_port);
 #else
             __bpf_unreachable();
 #endif
@@ -1100,19 +2243,66 @@ modify_headers(struct __sk_buff *skb, bool is_ipv4, bool is_icmpx_error,
 struct find_port_ctx {
     struct map_binding_key key;
     struct port_range range;
+    struct port_range excluded[MAX_PORT_RANGES];
+    u8 excluded_len;
+    bool exclude_local;
     int curr_remaining;
     u16 curr_port;
+    u16 parity;
+    bool match_parity;
     bool found;
 };
 
+static __always_inline bool
+exclude_local_ports(const struct external_config *ext_config, u8 l4proto) {
+    return LOCAL_PORTS && (ext_config->flags & EXTERNAL_LOCAL_FLAG) &&
+           !is_icmpx(l4proto);
+}
+
+static __always_inline bool is_local_port(u8 l4proto, __be16 port) {
+    struct map_local_port_key key = {
+        .l4proto = l4proto,
+        ._pad = 0,
+        .port = port,
+    };
+    return bpf_map_lookup_elem(&map_local_ports, &key) != NULL;
+}
+
+static __always_inline bool port_excluded(const struct find_port_ctx *ctx) {
+#pragma unroll
+    for (int i = 0; i < MAX_PORT_RANGES; i++) {
+        if (i >= ctx->excluded_len) {
+            break;
+        }
+        if (ctx->curr_port >= ctx->excluded[i].begin_port &&
+            ctx->curr_port <= ctx->excluded[i].end_port) {
+            return true;
+        }
+    }
+    return ctx->exclude_local &&
+           is_local_port(ctx->key.l4proto, bpf_htons(ctx->curr_port));
+}
+
+// Whether the port of binding is free to be reused, i.e. the binding is not
+// referenced by any CT nor held after being restored
+static __always_inline bool
+binding_port_free(const struct map_binding_value *value) {
+    return value->ref == 0 &&
+           (!value->held_until ||
+            value->held_until <= bpf_ktime_get_ns() / NSEC_PER_SEC);
+}
+
 static int find_port_cb(u32 index, struct find_port_ctx *ctx) {
 #define BPF_LOG_TOPIC "find_binding_port"
-    ctx->key.from_port = bpf_htons(ctx->curr_port);
-    struct map_binding_value *value =
-        bpf_map_lookup_elem(&map_binding, &ctx->key);
-    if (!value || value->ref == 0) {
-        ctx->found = true;
-        return BPF_LOOP_RET_BREAK;
+    if ((!ctx->match_parity || (ctx->curr_port & 1) == ctx->parity) &&
+        !port_excluded(ctx)) {
+        ctx->key.from_port = bpf_htons(ctx->curr_port);
+        struct map_binding_value *value =
+            bpf_map_lookup_elem(&map_binding, &ctx->key);
+        if (!value || binding_port_free(value)) {
+            ctx->found = true;
+            return BPF_LOOP_RET_BREAK;
+        }
     }
 
     if (ctx->curr_port != ctx->range.end_port) {
@@ -1136,12 +2326,21 @@ static __always_inline void find_port_fallback(struct find_port_ctx *ctx) {
     // of previous packets being dropped.
 #pragma unroll
     for (int i = 0; i < MAX_PORT_COLLISION_TRIES; i++) {
+        if (ctx->match_parity && (ctx->curr_port & 1) != ctx->parity) {
+            if (ctx->curr_port != ctx->range.end_port) {
+                ctx->curr_port++;
+            } else if (ctx->curr_port != ctx->range.begin_port) {
+                ctx->curr_port--;
+            }
+        }
         ctx->key.from_port = bpf_htons(ctx->curr_port);
-        struct map_binding_value *value =
-            bpf_map_lookup_elem(&map_binding, &ctx->key);
-        if (!value || value->ref == 0) {
-            ctx->found = true;
-            break;
+        if (!port_excluded(ctx)) {
+            struct map_binding_value *value =
+                bpf_map_lookup_elem(&map_binding, &ctx->key);
+            if (!value || binding_port_free(value)) {
+                ctx->found = true;
+                break;
+            }
         }
 
         ctx->curr_port = (bpf_get_prandom_u32() % ctx->curr_remaining) +
@@ -1150,22 +2349,55 @@ static __always_inline void find_port_fallback(struct find_port_ctx *ctx) {
 #undef BPF_LOG_TOPIC
 }
 
+static __always_inline void find_port_in_range(struct find_port_ctx *ctx) {
+    if (bpf_core_enum_value_exists(enum bpf_func_id, BPF_FUNC_loop)) {
+        // requires Linux kernel>=5.17
+        bpf_loop(65536, find_port_cb, ctx, 0);
+    } else {
+        find_port_fallback(ctx);
+    }
+}
+
+// Returns number for picking port range and port to start searching from
+static __always_inline u32 port_alloc_seed(const struct map_binding_key *key) {
+    switch (PORT_ALLOCATION) {
+    case PORT_ALLOC_SEQUENTIAL:
+        return __sync_fetch_and_add(&g_next_port, 1);
+    case PORT_ALLOC_HASHED:
+        return hash_inet_addr(&key->from_addr);
+    }
+    return bpf_get_prandom_u32();
+}
+
 static int __always_inline fill_unique_binding_port(
-    struct port_range *proto_range, u8 range_len,
-    const struct map_binding_key *key, struct map_binding_value *val) {
+    const struct external_config *ext_config, struct port_range *proto_range,
+    u8 range_len, const struct map_binding_key *key,
+    struct map_binding_value *val) {
 #define BPF_LOG_TOPIC "find_binding_port"
     struct find_port_ctx ctx;
 
     get_rev_dir_binding_key(key, val, &ctx.key);
+    ctx.excluded_len =
+        is_icmpx(key->l4proto) ? 0 : ext_config->excluded_range_len;
+    ctx.exclude_local = exclude_local_ports(ext_config, key->l4proto);
+#pragma unroll
+    for (int i = 0; i < MAX_PORT_RANGES; i++) {
+        ctx.excluded[i] = ext_config->excluded_range[i];
+    }
     ctx.curr_port = bpf_ntohs(ctx.key.from_port);
+    ctx.parity = ctx.curr_port & 1;
     ctx.found = false;
+    bool pick_port = !PORT_PRESERVATION;
+    u32 seed = port_alloc_seed(key);
 
     // Annotate as unsigned to avoid signed division on index calculation below
     u32 start_range_idx =
-        find_port_range_idx(ctx.curr_port, range_len, proto_range);
+        pick_port ? -1
+                  : find_port_range_idx(ctx.curr_port, range_len, proto_range);
     barrier_var(start_range_idx);
     if ((s32)start_range_idx < 0) {
-        start_range_idx = bpf_get_prandom_u32() % range_len;
+        start_range_idx =
+            PORT_ALLOCATION == PORT_ALLOC_SEQUENTIAL ? 0 : seed % range_len;
     }
 
 #pragma unroll
@@ -1179,17 +2411,19 @@ static int __always_inline fill_unique_binding_port(
         if (ctx.curr_remaining <= 0) {
             continue;
         }
-        if (ctx.curr_port < ctx.range.begin_port ||
+        if (pick_port || ctx.curr_port < ctx.range.begin_port ||
             ctx.curr_port > ctx.range.end_port) {
-            ctx.curr_port = (bpf_get_prandom_u32() % ctx.curr_remaining) +
-                            ctx.range.begin_port;
+            ctx.curr_port = (seed % ctx.curr_remaining) + ctx.range.begin_port;
+            pick_port = false;
         }
 
-        if (bpf_core_enum_value_exists(enum bpf_func_id, BPF_FUNC_loop)) {
-            // requires Linux kernel>=5.17
-            bpf_loop(65536, find_port_cb, &ctx, 0);
-        } else {
-            find_port_fallback(&ctx);
+        ctx.match_parity = PORT_PARITY && !is_icmpx(key->l4proto);
+        find_port_in_range(&ctx);
+        if (!ctx.found && ctx.match_parity) {
+            // no free port of same parity, fallback to any free port
+            ctx.curr_remaining = ctx.range.end_port - ctx.range.begin_port + 1;
+            ctx.match_parity = false;
+            find_port_in_range(&ctx);
         }
 
         if (ctx.found) {
@@ -1205,242 +2439,976 @@ static int __always_inline fill_unique_binding_port(
 #undef BPF_LOG_TOPIC
 }
 
-static __always_inline void
-partial_init_binding_value(bool is_ipv4, __be16 to_port,
-                           struct map_binding_value *val) {
+// Replaces `addr` with the external address serving the protocol next to it in
+// pool, returns false if there is no other such external address.
+static __always_inline bool
+next_external_addr(bool nat_x_4, u8 l4proto, union u_inet_addr *addr) {
+    u32 proto_idx = pool_proto_idx(l4proto);
+    u32 len;
+    u8 *nexts;
+    u32 next;
+    if (nat_x_4) {
+        len = g_ipv4_external_pool_len;
+        nexts = g_ipv4_external_pool_next[proto_idx];
+        next = g_ipv4_external_pool_first[proto_idx];
+    } else {
+#ifdef FEAT_IPV6
+        len = g_ipv6_external_pool_len;
+        nexts = g_ipv6_external_pool_next[proto_idx];
+        next = g_ipv6_external_pool_first[proto_idx];
+#else
+        __bpf_unreachable();
+#endif
+    }
+    if (len < 2 || len > MAX_EXTERNAL_POOL) {
+        return false;
+    }
 
-    val->flags = (is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG);
-    val->to_port = to_port;
-    val->is_static = false;
-    val->use = 0;
-    val->ref = 0;
-    val->seq = __sync_fetch_and_add(&g_next_binding_seq, 1);
-}
+#pragma unroll
+    for (int i = 0; i < MAX_EXTERNAL_POOL; i++) {
+        if (i >= len) {
+            break;
+        }
+        union u_inet_addr pool_addr;
+        if (nat_x_4) {
+            inet_addr_set_ip(&pool_addr, g_ipv4_external_pool[i]);
+        } else {
+#ifdef FEAT_IPV6
+            inet_addr_set_ip6(&pool_addr, g_ipv6_external_pool[i]);
+#endif
+        }
+        if (inet_addr_equal(&pool_addr, addr)) {
+            next = nexts[i];
+            if (next == i) {
+                return false;
+            }
+            break;
+        }
+    }
+    next &= MAX_EXTERNAL_POOL - 1;
 
-static __always_inline int
-ingress_lookup_or_new_binding(u32 ifindex, bool is_ipv4,
-                              struct external_config *ext_config, u8 l4proto,
-                              bool do_new, const struct inet_tuple *reply,
-                              struct map_binding_value **b_value_rev_) {
-    struct map_binding_key b_key = {
-        .ifindex = ifindex,
-        .flags = (is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG),
-        .l4proto = l4proto,
-        .from_port = reply->dport,
-        .from_addr = reply->daddr,
-    };
+    if (nat_x_4) {
+        inet_addr_set_ip(addr, g_ipv4_external_pool[next]);
+    } else {
+#ifdef FEAT_IPV6
+        inet_addr_set_ip6(addr, g_ipv6_external_pool[next]);
+#endif
+    }
+    return true;
+}
 
-    struct map_binding_value *b_value_rev =
-        bpf_map_lookup_elem(&map_binding, &b_key);
-    if (!b_value_rev) {
-        if (!do_new) {
-            return TC_ACT_SHOT;
+// Copies port ranges without ports of deterministic port blocks of all hosts
+// at start of the first range, for hosts out of deterministic NAT network to
+// allocate from, returns number of ranges left.
+static __always_inline u8
+det_nat_dynamic_ranges(const struct port_range *proto_range, u8 range_len,
+                       struct port_range ranges[MAX_PORT_RANGES]) {
+    u64 blocks_end = proto_range[0].begin_port +
+                     ((u64)~DET_NAT_MASK + 1) * DET_NAT_BLOCK_SIZE;
+    u8 len = 0;
+#pragma unroll
+    for (int i = 0; i < MAX_PORT_RANGES; i++) {
+        if (i >= range_len) {
+            break;
         }
-        struct map_binding_value b_value_new;
-        partial_init_binding_value(is_ipv4, b_key.from_port, &b_value_new);
-        COPY_ADDR6(b_value_new.to_addr.all, b_key.from_addr.all);
-
-        struct port_range *proto_range;
-        u8 range_len =
-            select_port_range(ext_config, l4proto, RANGE_INBOUND, &proto_range);
-        barrier_var(range_len);
-        if (range_len == 0) {
-            // range_len=0 is defined as the semantic of passthrough NAT for
-            // respective IP protocol
-            return TC_ACT_UNSPEC;
+        struct port_range range = proto_range[i];
+        if (i == 0) {
+            if (blocks_end > range.end_port) {
+                continue;
+            }
+            range.begin_port = blocks_end;
         }
+        ranges[len & MAX_PORT_RANGES_MASK] = range;
+        len++;
+    }
+    return len;
+}
 
-        int ret = fill_unique_binding_port(proto_range, range_len, &b_key,
-                                           &b_value_new);
-        if (ret != TC_ACT_OK) {
+// Retries finding binding port on next external address after selected one
+// ran out of ports.
+static __always_inline int
+spill_over_external(bool nat_x_4, u8 l4proto, const struct map_binding_key *key,
+                    struct map_binding_value *val) {
+#define BPF_LOG_TOPIC "spill_over_external"
+    union u_inet_addr exhausted = val->to_addr;
+    if (!next_external_addr(nat_x_4, l4proto, &val->to_addr)) {
+        return TC_ACT_SHOT;
+    }
+
+    struct external_config *ext_config =
+        lookup_external_config(nat_x_4, &val->to_addr);
+    if (nat_check_external_config(ext_config) != TC_ACT_OK) {
+        return TC_ACT_SHOT;
+    }
+    struct port_range *proto_range;
+    u8 range_len = select_port_range(ext_config, l4proto, RANGE_OUTBOUND,
+                                     &proto_range);
+    barrier_var(range_len);
+    if (range_len == 0) {
+        return TC_ACT_SHOT;
+    }
+    // only hosts out of deterministic NAT network spill over
+    struct port_range det_ranges[MAX_PORT_RANGES];
+    if (DET_NAT_BLOCK_SIZE && nat_x_4 && !is_icmpx(l4proto)) {
+        range_len = det_nat_dynamic_ranges(proto_range, range_len, det_ranges);
+        if (range_len == 0) {
             return TC_ACT_SHOT;
         }
+        proto_range = det_ranges;
+    }
 
-        b_value_rev = insert_new_binding(&b_key, &b_value_new, NULL);
-        if (!b_value_rev) {
-            barrier();
-            return TC_ACT_SHOT;
+    if (!g_external_spillover) {
+        g_external_spillover = true;
+        struct event *event =
+            bpf_ringbuf_reserve(&map_events, sizeof(struct event), 0);
+        if (event) {
+            event->type = EVENT_EXTERNAL_SPILLOVER;
+            event->ifindex = key->ifindex;
+            event->flags = nat_x_4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG;
+            event->l4proto = l4proto;
+            event->_pad = 0;
+            COPY_ADDR6(event->addr.all, exhausted.all);
+            bpf_ringbuf_submit(event, 0);
         }
     }
+    bpf_log_debug("spilling over to next external address");
 
-    *b_value_rev_ = b_value_rev;
-    return TC_ACT_OK;
+    return fill_unique_binding_port(ext_config, proto_range, range_len, key,
+                                    val);
+#undef BPF_LOG_TOPIC
 }
 
-int __always_inline egress_fib_lookup_src(struct __sk_buff *skb, bool is_ipv4,
-                                          const union u_inet_addr *saddr,
-                                          const union u_inet_addr *daddr,
-                                          union u_inet_addr *to_addr) {
-#define BPF_LOG_TOPIC "egress_fib_lookup_src"
-    struct bpf_fib_lookup params = {
-        .family = is_ipv4 ? AF_INET : AF_INET6,
-        .ifindex = skb->ifindex,
+// Narrows port ranges down to the deterministic port block of internal source
+// address, returns 0 if there is no deterministic port block for the address,
+// or -1 if the port block lies out of port range.
+static __always_inline int
+det_nat_port_block(const union u_inet_addr *from_addr,
+                   struct port_range *proto_range,
+                   struct port_range block[MAX_PORT_RANGES]) {
+#define BPF_LOG_TOPIC "det_nat_port_block"
+    u32 addr = bpf_ntohl(from_addr->ip);
+    if (!DET_NAT_BLOCK_SIZE || (addr & DET_NAT_MASK) != DET_NAT_NETWORK) {
+        return 0;
+    }
+
+    u32 host = addr & ~DET_NAT_MASK;
+    u32 begin_port = proto_range[0].begin_port + host * DET_NAT_BLOCK_SIZE;
+    u32 end_port = begin_port + DET_NAT_BLOCK_SIZE - 1;
+    if (end_port > proto_range[0].end_port) {
+        bpf_log_warn("no port block for %pI4", &from_addr->ip);
+        return -1;
+    }
+
+    block[0].begin_port = begin_port;
+    block[0].end_port = end_port;
+    return 1;
+#undef BPF_LOG_TOPIC
+}
+
+static __always_inline void
+partial_init_binding_value(bool is_ipv4, __be16 to_port,
+                           struct map_binding_value *val) {
+
+    val->flags = (is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG);
+    val->to_port = to_port;
+    val->is_static = false;
+    val->use = 0;
+    val->ref = 0;
+    val->seq = __sync_fetch_and_add(&g_next_binding_seq, 1);
+    val->created = bpf_ktime_get_ns() / NSEC_PER_SEC;
+    val->held_until = 0;
+}
+
+static __always_inline struct map_binding_value *
+lookup_addr_binding(u32 ifindex, bool is_ipv4, bool is_orig,
+                    const union u_inet_addr *from_addr) {
+    struct map_binding_key b_key = {
+        .ifindex = ifindex,
+        .flags = (is_orig ? BINDING_ORIG_DIR_FLAG : 0) |
+                 (is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG),
+        .l4proto = 0,
+        .from_port = 0,
     };
+    COPY_ADDR6(b_key.from_addr.all, from_addr->all);
+    return bpf_map_lookup_elem(&map_binding, &b_key);
+}
 
+// Translate address of packets of protocols other than TCP, UDP and ICMP by
+// static 1:1 NAT address bindings, without touching L4 headers
+static __always_inline int addr_binding_translate(struct __sk_buff *skb,
+                                                  bool is_ipv4,
+                                                  bool is_ingress) {
+#define BPF_LOG_TOPIC "addr_binding_translate"
+    u32 addr_off = TC_SKB_L3_OFF() + get_l3_to_addr_off(is_ipv4, !is_ingress);
+    union u_inet_addr from_addr = {};
+    int ret;
     if (is_ipv4) {
-        params.ipv4_src = saddr->ip;
-        params.ipv4_dst = daddr->ip;
+        ret = bpf_skb_load_bytes(skb, addr_off, &from_addr.ip,
+                                 sizeof(from_addr.ip));
     } else {
 #ifdef FEAT_IPV6
-        COPY_ADDR6(params.ipv6_src, saddr->ip6);
-        COPY_ADDR6(params.ipv6_dst, daddr->ip6);
+        ret = bpf_skb_load_bytes(skb, addr_off, from_addr.all,
+                                 sizeof(from_addr.all));
 #else
-        __bpf_unreachable();
+        return TC_ACT_UNSPEC;
 #endif
     }
-    int ret = bpf_fib_lookup(skb, &params, sizeof(params),
-                             BPF_FIB_LOOKUP_OUTPUT | BPF_FIB_LOOKUP_SKIP_NEIGH |
-                                 BPF_FIB_LOOKUP_SRC);
     if (ret) {
-        // The lookup would return -EINVAL if BPF_FIB_LOOKUP_SRC is not
-        // supported on current kernel, we then can fallback to use defined
-        // external address
-        if (ret > 0) {
-            bpf_log_error("FIB lookup failed, ret: %d", ret);
-        }
+        return TC_ACT_UNSPEC;
+    }
+
+    struct map_binding_value *b_value =
+        lookup_addr_binding(skb->ifindex, is_ipv4, !is_ingress, &from_addr);
+    if (!b_value) {
+        return TC_ACT_UNSPEC;
+    }
+
+    if (bpf_write_inet_addr(skb, is_ipv4, addr_off, &b_value->to_addr)) {
+        return TC_ACT_SHOT;
+    }
+    if (is_ipv4 && bpf_l3_csum_replace(
+                       skb, TC_SKB_L3_OFF() + offsetof(struct iphdr, check),
+                       from_addr.ip, b_value->to_addr.ip, 4)) {
         return TC_ACT_SHOT;
     }
+    bpf_log_trace("translated by address binding");
+    return TC_ACT_UNSPEC;
+#undef BPF_LOG_TOPIC
+}
 
-    if (is_ipv4) {
-        inet_addr_set_ip(to_addr, params.ipv4_src);
-    } else {
-#ifdef FEAT_IPV6
-        inet_addr_set_ip6(to_addr, params.ipv6_src);
-#else
-        __bpf_unreachable();
-#endif
+static __always_inline int ipv4_rewrite_addr(struct __sk_buff *skb,
+                                             bool is_source, __be32 from_addr,
+                                             __be32 to_addr) {
+    u32 l3_off = TC_SKB_L3_OFF();
+    if (bpf_skb_store_bytes(skb, l3_off + get_l3_to_addr_off(true, is_source),
+                            &to_addr, sizeof(to_addr), 0) ||
+        bpf_l3_csum_replace(skb, l3_off + offsetof(struct iphdr, check),
+                            from_addr, to_addr, 4)) {
+        return TC_ACT_SHOT;
     }
+    return TC_ACT_OK;
+}
 
+// Sets DSCP of IP header to `dscp`, keeping ECN bits.
+static __always_inline int rewrite_dscp(struct __sk_buff *skb, bool is_ipv4,
+                                        u8 dscp) {
+    u32 l3_off = TC_SKB_L3_OFF();
+    // DSCP lies in the second byte of IPv4 header, or spans first two bytes of
+    // IPv6 header as upper 6 bits of traffic class
+    u8 hdr[2];
+    if (bpf_skb_load_bytes(skb, l3_off, hdr, sizeof(hdr))) {
+        return TC_ACT_SHOT;
+    }
+    __be16 from = *(__be16 *)hdr;
     if (is_ipv4) {
-        bpf_log_trace("orig_src:%pI4, orig_dst:%pI4, src:%pI4, dst:%pI4",
-                      &saddr->ip, &daddr->ip, &params.ipv4_src,
-                      &params.ipv4_dst);
+        hdr[1] = (dscp << 2) | (hdr[1] & 0x3);
     } else {
-#ifdef FEAT_IPV6
-        bpf_log_trace("orig_src:%pI6, orig_dst:%pI6, src:%pI6, dst:%pI6",
-                      saddr->ip6, daddr->ip6, params.ipv6_src, params.ipv6_dst);
-#endif
+        hdr[0] = (hdr[0] & 0xf0) | (dscp >> 2);
+        hdr[1] = ((dscp & 0x3) << 6) | (hdr[1] & 0x3f);
+    }
+    __be16 to = *(__be16 *)hdr;
+    if (from == to) {
+        return TC_ACT_OK;
+    }
+    if (bpf_skb_store_bytes(skb, l3_off, hdr, sizeof(hdr), 0)) {
+        return TC_ACT_SHOT;
+    }
+    if (is_ipv4 &&
+        bpf_l3_csum_replace(skb, l3_off + offsetof(struct iphdr, check), from,
+                            to, 2)) {
+        return TC_ACT_SHOT;
     }
-
     return TC_ACT_OK;
-#undef BPF_LOG_TOPIC
 }
 
-static __always_inline int
-egress_lookup_or_new_binding(struct __sk_buff *skb, bool is_ipv4, u8 l4proto,
-                             bool do_new, const struct inet_tuple *origin,
-                             struct map_binding_value **b_value_orig_,
-                             struct map_binding_value **b_value_rev_) {
-#define BPF_LOG_TOPIC "egress_lookup_or_new_binding"
-    struct map_binding_key b_key = {
-        .ifindex = skb->ifindex,
-        .flags =
-            BINDING_ORIG_DIR_FLAG | (is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG),
-        .l4proto = l4proto,
-        .from_port = origin->sport,
-        .from_addr = origin->saddr,
-    };
+#define PPTP_CTRL_PORT 1723
+#define PPTP_CTRL_MSG 1
+#define PPTP_MAGIC_COOKIE 0x1a2b3c4d
+#define PPTP_OUT_CALL_REQUEST 7
+#define PPTP_OUT_CALL_REPLY 8
+#define PPTP_CALL_CLEAR_REQUEST 12
+#define PPTP_WAN_ERROR_NOTIFY 14
+#define PPTP_GRE_KEY_BIT 0x20
+#define PPTP_GRE_VERSION 1
+#define PPTP_GRE_PROTO_PPP 0x880b
+#define PPTP_CALL_ID_TRIES 8
+
+// Common part of PPTP control messages carrying call IDs, see
+// https://datatracker.ietf.org/doc/html/rfc2637#section-2
+struct pptp_ctrl_hdr {
+    __be16 length;
+    __be16 msg_type;
+    __be32 magic_cookie;
+    __be16 ctrl_type;
+    __be16 _reserved;
+    // Peer's Call ID in WAN-Error-Notify
+    __be16 call_id;
+    // Peer's Call ID in Outgoing-Call-Reply
+    __be16 peer_call_id;
+};
 
-    struct map_binding_value *b_value_rev = NULL;
-    struct map_binding_value *b_value_orig =
-        bpf_map_lookup_elem(&map_binding, &b_key);
-    if (!b_value_orig) {
-        if (!do_new) {
-            return TC_ACT_SHOT;
-        }
+// Enhanced GRE header, see
+// https://datatracker.ietf.org/doc/html/rfc2637#section-4.1
+struct pptp_gre_hdr {
+    u8 flags;
+    u8 version;
+    __be16 protocol;
+    __be16 payload_len;
+    __be16 call_id;
+};
 
-        // XXX: do NAT64 for origin->daddr if it has NAT64 prefix
-        bool nat_x_4 = is_ipv4;
-        struct map_binding_value b_value_new;
-        partial_init_binding_value(nat_x_4, b_key.from_port, &b_value_new);
+// Allocates call ID on external address for outgoing call of internal client
+// to server, preferring the original call ID of client
+static __always_inline int pptp_new_call(u32 ifindex, __be32 client_addr,
+                                         __be16 call_id, __be32 ext_addr,
+                                         __be32 server_addr,
+                                         __be16 *ext_call_id) {
+#define BPF_LOG_TOPIC "pptp_new_call"
+    struct map_pptp_call_key ctrl_key = {
+        .ifindex = ifindex,
+        .flags = PPTP_CALL_CTRL_FLAG,
+        .call_id = call_id,
+        .from_addr = client_addr,
+        .server_addr = server_addr,
+    };
+    struct map_pptp_call_value *value =
+        bpf_map_lookup_elem(&map_pptp_call, &ctrl_key);
+    if (value && value->to_addr == ext_addr) {
+        // retransmitted request
+        *ext_call_id = value->to_call_id;
+        return TC_ACT_OK;
+    }
 
-        // XXX: use 0 as source address in the case of NAT64
-        if (!ENABLE_FIB_LOOKUP_SRC ||
-            egress_fib_lookup_src(skb, nat_x_4, &origin->saddr, &origin->daddr,
-                                  &b_value_new.to_addr)) {
-            if (nat_x_4) {
-                inet_addr_set_ip(&b_value_new.to_addr, g_ipv4_external_addr);
-            } else {
-#ifdef FEAT_IPV6
-                inet_addr_set_ip6(&b_value_new.to_addr, g_ipv6_external_addr);
-#else
-                __bpf_unreachable();
-#endif
+    struct map_pptp_call_key rev_key = {
+        .ifindex = ifindex,
+        .flags = 0,
+        .from_addr = ext_addr,
+        .server_addr = server_addr,
+    };
+    struct map_pptp_call_value rev_value = {
+        .to_addr = client_addr,
+        .to_call_id = call_id,
+    };
+    u16 id = bpf_ntohs(call_id);
+    for (int i = 0; i < PPTP_CALL_ID_TRIES; i++) {
+        rev_key.call_id = bpf_htons(id);
+        if (!bpf_map_update_elem(&map_pptp_call, &rev_key, &rev_value,
+                                 BPF_NOEXIST)) {
+            struct map_pptp_call_value ctrl_value = {
+                .to_addr = ext_addr,
+                .to_call_id = rev_key.call_id,
+            };
+            if (bpf_map_update_elem(&map_pptp_call, &ctrl_key, &ctrl_value,
+                                    BPF_ANY)) {
+                bpf_map_delete_elem(&map_pptp_call, &rev_key);
+                return TC_ACT_SHOT;
             }
+            bpf_log_debug("PPTP call ID %d of %pI4 mapped to %d",
+                          bpf_ntohs(call_id), &client_addr, id);
+            *ext_call_id = rev_key.call_id;
+            return TC_ACT_OK;
         }
+        id = bpf_get_prandom_u32();
+    }
+    bpf_log_error("no free PPTP call ID");
+    return TC_ACT_SHOT;
+#undef BPF_LOG_TOPIC
+}
 
-        int ret;
-        struct external_config *ext_config =
-            lookup_external_config(nat_x_4, &b_value_new.to_addr);
-        if ((ret = nat_check_external_config(ext_config)) != TC_ACT_OK) {
-            return ret;
-        }
+// Translates call ID in PPTP control message at start of TCP payload of
+// translated packet, messages spanning TCP segments are not handled
+static __always_inline int pptp_ctrl_translate(struct __sk_buff *skb,
+                                               u32 l4_off, bool is_outbound,
+                                               __be32 client_addr,
+                                               __be32 ext_addr,
+                                               __be32 server_addr) {
+#define BPF_LOG_TOPIC "pptp_ctrl_translate"
+    struct tcphdr tcph;
+    struct pptp_ctrl_hdr hdr;
+    if (bpf_skb_load_bytes(skb, l4_off, &tcph, sizeof(tcph))) {
+        return TC_ACT_SHOT;
+    }
+    u32 msg_off = l4_off + tcph.doff * 4;
+    if (bpf_skb_load_bytes(skb, msg_off, &hdr, sizeof(hdr)) ||
+        hdr.msg_type != bpf_htons(PPTP_CTRL_MSG) ||
+        hdr.magic_cookie != bpf_htonl(PPTP_MAGIC_COOKIE)) {
+        return TC_ACT_OK;
+    }
 
-        struct port_range *proto_range;
-        u8 range_len = select_port_range(ext_config, l4proto, RANGE_OUTBOUND,
-                                         &proto_range);
-        barrier_var(range_len);
-        if (range_len == 0) {
-            // range_len=0 is defined as the semantic of passthrough NAT for
-            // respective IP protocol
-            return TC_ACT_UNSPEC;
+    struct map_pptp_call_key key = {
+        .ifindex = skb->ifindex,
+        .server_addr = server_addr,
+    };
+    struct map_pptp_call_value *value;
+    u32 id_off = offsetof(struct pptp_ctrl_hdr, call_id);
+    __be16 from_id = hdr.call_id;
+    __be16 to_id;
+    switch (bpf_ntohs(hdr.ctrl_type)) {
+    case PPTP_OUT_CALL_REQUEST:
+        if (!is_outbound) {
+            return TC_ACT_OK;
         }
-
-        ret = fill_unique_binding_port(proto_range, range_len, &b_key,
-                                       &b_value_new);
-        if (ret != TC_ACT_OK) {
+        if (pptp_new_call(skb->ifindex, client_addr, from_id, ext_addr,
+                          server_addr, &to_id)) {
             return TC_ACT_SHOT;
         }
+        break;
+    case PPTP_CALL_CLEAR_REQUEST:
+        if (!is_outbound) {
+            return TC_ACT_OK;
+        }
+        key.flags = PPTP_CALL_CTRL_FLAG;
+        key.call_id = from_id;
+        key.from_addr = client_addr;
+        value = bpf_map_lookup_elem(&map_pptp_call, &key);
+        if (!value) {
+            return TC_ACT_OK;
+        }
+        to_id = value->to_call_id;
+        break;
+    case PPTP_OUT_CALL_REPLY:
+        id_off = offsetof(struct pptp_ctrl_hdr, peer_call_id);
+        from_id = hdr.peer_call_id;
+        // fallthrough
+    case PPTP_WAN_ERROR_NOTIFY:
+        if (is_outbound) {
+            return TC_ACT_OK;
+        }
+        key.flags = 0;
+        key.call_id = from_id;
+        key.from_addr = ext_addr;
+        value = bpf_map_lookup_elem(&map_pptp_call, &key);
+        if (!value || value->to_addr != client_addr) {
+            return TC_ACT_OK;
+        }
+        to_id = value->to_call_id;
+        break;
+    default:
+        return TC_ACT_OK;
+    }
 
-        b_value_orig = insert_new_binding(&b_key, &b_value_new, &b_value_rev);
-        if (!b_value_orig) {
+    if (bpf_ntohs(hdr.ctrl_type) == PPTP_OUT_CALL_REPLY) {
+        // GRE packets from client carry call ID of server
+        struct map_pptp_call_key orig_key = {
+            .ifindex = skb->ifindex,
+            .flags = PPTP_CALL_ORIG_DIR_FLAG,
+            .call_id = hdr.call_id,
+            .from_addr = client_addr,
+            .server_addr = server_addr,
+        };
+        struct map_pptp_call_value orig_value = {
+            .to_addr = ext_addr,
+            .to_call_id = hdr.call_id,
+        };
+        if (bpf_map_update_elem(&map_pptp_call, &orig_key, &orig_value,
+                                BPF_ANY)) {
             return TC_ACT_SHOT;
         }
     }
 
-    *b_value_orig_ = b_value_orig;
-    *b_value_rev_ = b_value_rev;
+    if (bpf_skb_store_bytes(skb, msg_off + id_off, &to_id, sizeof(to_id), 0) ||
+        bpf_l4_csum_replace(skb, l4_off + offsetof(struct tcphdr, check),
+                            from_id, to_id, sizeof(to_id))) {
+        bpf_log_error("failed to rewrite call ID");
+        return TC_ACT_SHOT;
+    }
+    bpf_log_trace("PPTP control message %d, call ID %d->%d",
+                  bpf_ntohs(hdr.ctrl_type), bpf_ntohs(from_id),
+                  bpf_ntohs(to_id));
     return TC_ACT_OK;
 #undef BPF_LOG_TOPIC
 }
 
-enum {
-    LK_CT_ERROR_NEW,
-    LK_CT_NONE,
-    LK_CT_EXIST,
-    LK_CT_NEW,
-};
+// Translate address of enhanced GRE packets of PPTP calls in map_pptp_call,
+// and call ID of inbound ones. Returns TC_ACT_OK if the packet is not of any
+// call.
+static __always_inline int pptp_gre_translate(struct __sk_buff *skb,
+                                              bool is_ingress) {
+#define BPF_LOG_TOPIC "pptp_gre_translate"
+    u32 l3_off = TC_SKB_L3_OFF();
+    struct iphdr *iph;
+    if (VALIDATE_PULL(skb, &iph, l3_off, sizeof(*iph))) {
+        return TC_ACT_OK;
+    }
+    if (iph->version != 4 || iph->protocol != IPPROTO_GRE ||
+        (iph->frag_off & bpf_htons(IP_OFFSET))) {
+        return TC_ACT_OK;
+    }
+    u32 l4_off = l3_off + iph->ihl * 4;
+    struct map_pptp_call_key key = {
+        .ifindex = skb->ifindex,
+        .flags = is_ingress ? 0 : PPTP_CALL_ORIG_DIR_FLAG,
+        .from_addr = is_ingress ? iph->daddr : iph->saddr,
+        .server_addr = is_ingress ? iph->saddr : iph->daddr,
+    };
 
-static __always_inline int
-ingress_lookup_or_new_ct(u32 ifindex, bool is_ipv4, u8 l4proto, bool do_new,
-                         const struct inet_tuple *reply,
-                         struct map_binding_value *b_value_rev,
-                         struct map_ct_value **ct_value_) {
-#define BPF_LOG_TOPIC "ingress_lookup_or_new_ct"
-    struct map_ct_key ct_key;
-    ct_key.ifindex = ifindex;
-    ct_key.flags = is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG;
-    ct_key.l4proto = l4proto;
-    ct_key._pad = 0;
-    inet_tuple_rev_copy(&ct_key.external, reply);
+    struct pptp_gre_hdr greh;
+    if (bpf_skb_load_bytes(skb, l4_off, &greh, sizeof(greh)) ||
+        (greh.version & 0x7) != PPTP_GRE_VERSION ||
+        !(greh.flags & PPTP_GRE_KEY_BIT) ||
+        greh.protocol != bpf_htons(PPTP_GRE_PROTO_PPP)) {
+        return TC_ACT_OK;
+    }
+    key.call_id = greh.call_id;
 
-    struct map_ct_value *ct_value = bpf_map_lookup_elem(&map_ct, &ct_key);
-    if (ct_value) {
-        if (ct_value->seq == b_value_rev->seq) {
-            *ct_value_ = ct_value;
-            return LK_CT_EXIST;
-        }
-        // This CT could become dangling and not refcounted if the binding
-        // was deleted in delete_ct() at this point, due to the deleted
-        // binding is still available here because of RCU but we might add a
-        // new CT referencing the binding(through refcount). Though the CT
-        // would fade out eventually due to timeout. Thus we assign a unique
-        // sequence number for binding and related CTs to distinguish
-        // binding generations. And if the sequence number does not matches
+    struct map_pptp_call_value *value =
+        bpf_map_lookup_elem(&map_pptp_call, &key);
+    if (!value) {
+        return TC_ACT_OK;
+    }
+    __be16 to_call_id = value->to_call_id;
+
+    if (ipv4_rewrite_addr(skb, !is_ingress, key.from_addr, value->to_addr)) {
+        return TC_ACT_SHOT;
+    }
+    u32 call_id_off = l4_off + offsetof(struct pptp_gre_hdr, call_id);
+    if (is_ingress && bpf_skb_store_bytes(skb, call_id_off, &to_call_id,
+                                          sizeof(to_call_id), 0)) {
+        return TC_ACT_SHOT;
+    }
+    bpf_log_trace("translated GRE packet of PPTP call");
+    return TC_ACT_UNSPEC;
+#undef BPF_LOG_TOPIC
+}
+
+#define IPSEC_IKE_PORT 500
+#define IPSEC_NAT_T_PORT 4500
+
+// External address for ESP packets of internal client, following its IKE
+// binding if any as gateways expect both from the same address
+static __always_inline __be32 esp_external_addr(u32 ifindex,
+                                                __be32 client_addr) {
+    struct map_binding_key b_key = {
+        .ifindex = ifindex,
+        .flags = BINDING_ORIG_DIR_FLAG | ADDR_IPV4_FLAG,
+        .l4proto = IPPROTO_UDP,
+        .from_port = bpf_htons(IPSEC_IKE_PORT),
+    };
+    inet_addr_set_ip(&b_key.from_addr, client_addr);
+    struct map_binding_value *b_value =
+        bpf_map_lookup_elem(&map_binding, &b_key);
+    if (b_value) {
+        return b_value->to_addr.ip;
+    }
+
+    union u_inet_addr from_addr = {}, to_addr = {};
+    inet_addr_set_ip(&from_addr, client_addr);
+    select_external_addr(true, IPPROTO_UDP, &from_addr, &to_addr);
+    source_policy_external_addr(true, &from_addr, &to_addr);
+    return to_addr.ip;
+}
+
+// Translate address of ESP packets by pseudo-bindings in map_esp. SPI of
+// inbound SA is learned from the first inbound ESP packet from the gateway
+// after the client started an outbound SA, so concurrent SA negotiations of
+// multiple clients with the same gateway could be mixed up. Returns TC_ACT_OK
+// if the packet is not translated.
+static __always_inline int esp_translate(struct __sk_buff *skb,
+                                         bool is_ingress) {
+#define BPF_LOG_TOPIC "esp_translate"
+    u32 l3_off = TC_SKB_L3_OFF();
+    struct iphdr *iph;
+    if (VALIDATE_PULL(skb, &iph, l3_off, sizeof(*iph))) {
+        return TC_ACT_OK;
+    }
+    if (iph->version != 4 || iph->protocol != IPPROTO_ESP ||
+        (iph->frag_off & bpf_htons(IP_OFFSET))) {
+        return TC_ACT_OK;
+    }
+    __be32 from_addr = is_ingress ? iph->daddr : iph->saddr;
+    __be32 gateway_addr = is_ingress ? iph->saddr : iph->daddr;
+    struct map_esp_key key = {
+        .ifindex = skb->ifindex,
+        .flags = is_ingress ? 0 : ESP_ORIG_DIR_FLAG,
+        .from_addr = from_addr,
+        .gateway_addr = gateway_addr,
+    };
+    if (bpf_skb_load_bytes(skb, l3_off + iph->ihl * 4, &key.spi,
+                           sizeof(key.spi))) {
+        return TC_ACT_OK;
+    }
+    struct map_esp_key pending_key = {
+        .ifindex = skb->ifindex,
+        .flags = ESP_PENDING_FLAG,
+        .spi = 0,
+        .gateway_addr = gateway_addr,
+    };
+
+    __be32 to_addr;
+    __be32 *value = bpf_map_lookup_elem(&map_esp, &key);
+    if (value) {
+        to_addr = *value;
+    } else if (is_ingress) {
+        pending_key.from_addr = from_addr;
+        value = bpf_map_lookup_elem(&map_esp, &pending_key);
+        if (!value) {
+            return TC_ACT_OK;
+        }
+        to_addr = *value;
+        if (bpf_map_update_elem(&map_esp, &key, &to_addr, BPF_ANY)) {
+            return TC_ACT_SHOT;
+        }
+        bpf_map_delete_elem(&map_esp, &pending_key);
+        bpf_log_debug("inbound ESP SPI %x of %pI4 assigned to %pI4",
+                      bpf_ntohl(key.spi), &gateway_addr, &to_addr);
+    } else {
+        union u_inet_addr ext_addr = {};
+        inet_addr_set_ip(&ext_addr, from_addr);
+        if (lookup_external_config(true, &ext_addr)) {
+            // ESP of this host
+            return TC_ACT_OK;
+        }
+        to_addr = esp_external_addr(skb->ifindex, from_addr);
+        inet_addr_set_ip(&ext_addr, to_addr);
+        if (nat_check_external_config(lookup_external_config(
+                true, &ext_addr)) != TC_ACT_OK) {
+            return TC_ACT_OK;
+        }
+        pending_key.from_addr = to_addr;
+        if (bpf_map_update_elem(&map_esp, &key, &to_addr, BPF_ANY) ||
+            bpf_map_update_elem(&map_esp, &pending_key, &from_addr,
+                                BPF_ANY)) {
+            return TC_ACT_SHOT;
+        }
+        bpf_log_debug("outbound ESP SPI %x of %pI4 mapped to %pI4",
+                      bpf_ntohl(key.spi), &from_addr, &to_addr);
+    }
+
+    if (ipv4_rewrite_addr(skb, !is_ingress, from_addr, to_addr)) {
+        return TC_ACT_SHOT;
+    }
+    bpf_log_trace("translated ESP packet");
+    return TC_ACT_UNSPEC;
+#undef BPF_LOG_TOPIC
+}
+
+static __always_inline int
+ingress_lookup_or_new_binding(u32 ifindex, bool is_ipv4,
+                              struct external_config *ext_config, u8 l4proto,
+                              bool do_new, const struct inet_tuple *reply,
+                              struct map_binding_value **b_value_rev_) {
+    struct map_binding_key b_key = {
+        .ifindex = ifindex,
+        .flags = (is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG),
+        .l4proto = l4proto,
+        .from_port = reply->dport,
+        .from_addr = reply->daddr,
+    };
+
+    struct map_binding_value *b_value_rev =
+        bpf_map_lookup_elem(&map_binding, &b_key);
+    if (!b_value_rev) {
+        if (!do_new) {
+            return TC_ACT_SHOT;
+        }
+        struct map_binding_value b_value_new;
+        partial_init_binding_value(is_ipv4, b_key.from_port, &b_value_new);
+        COPY_ADDR6(b_value_new.to_addr.all, b_key.from_addr.all);
+
+        struct port_range *proto_range;
+        u8 range_len =
+            select_port_range(ext_config, l4proto, RANGE_INBOUND, &proto_range);
+        barrier_var(range_len);
+        if (range_len == 0) {
+            // range_len=0 is defined as the semantic of passthrough NAT for
+            // respective IP protocol
+            return TC_ACT_UNSPEC;
+        }
+
+        int ret = fill_unique_binding_port(ext_config, proto_range, range_len,
+                                           &b_key, &b_value_new);
+        if (ret != TC_ACT_OK) {
+            return TC_ACT_SHOT;
+        }
+
+        b_value_rev = insert_new_binding(&b_key, &b_value_new, NULL);
+        if (!b_value_rev) {
+            barrier();
+            return TC_ACT_SHOT;
+        }
+    }
+
+    *b_value_rev_ = b_value_rev;
+    return TC_ACT_OK;
+}
+
+static __always_inline int
+ingress_new_dmz_binding(u32 ifindex, bool is_ipv4, u8 l4proto,
+                        const struct inet_tuple *reply,
+                        struct map_binding_value **b_value_rev_) {
+#define BPF_LOG_TOPIC "ingress_new_dmz_binding"
+    struct map_binding_key b_key_orig = {
+        .ifindex = ifindex,
+        .flags = BINDING_ORIG_DIR_FLAG |
+                 (is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG),
+        .l4proto = l4proto,
+        .from_port = reply->dport,
+    };
+#pragma unroll
+    for (int i = 0; i < sizeof(b_key_orig.from_addr.all) /
+                        sizeof(b_key_orig.from_addr.all[0]);
+         i++) {
+        b_key_orig.from_addr.all[i] = DMZ_HOST_ADDR[i];
+    }
+    // the port of DMZ host is already mapped to another external port
+    if (bpf_map_lookup_elem(&map_binding, &b_key_orig)) {
+        return TC_ACT_SHOT;
+    }
+
+    struct map_binding_key b_key = {
+        .ifindex = ifindex,
+        .flags = (is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG),
+        .l4proto = l4proto,
+        .from_port = reply->dport,
+        .from_addr = reply->daddr,
+    };
+    struct map_binding_value b_value_new;
+    partial_init_binding_value(is_ipv4, reply->dport, &b_value_new);
+    COPY_ADDR6(b_value_new.to_addr.all, b_key_orig.from_addr.all);
+
+    struct map_binding_value *b_value_rev =
+        insert_new_binding(&b_key, &b_value_new, NULL);
+    if (!b_value_rev) {
+        return TC_ACT_SHOT;
+    }
+    bpf_log_debug("new DMZ binding on port %d", bpf_ntohs(reply->dport));
+
+    *b_value_rev_ = b_value_rev;
+    return TC_ACT_OK;
+#undef BPF_LOG_TOPIC
+}
+
+int __always_inline egress_fib_lookup_src(struct __sk_buff *skb, bool is_ipv4,
+                                          const union u_inet_addr *saddr,
+                                          const union u_inet_addr *daddr,
+                                          union u_inet_addr *to_addr) {
+#define BPF_LOG_TOPIC "egress_fib_lookup_src"
+    struct bpf_fib_lookup params = {
+        .family = is_ipv4 ? AF_INET : AF_INET6,
+        .ifindex = skb->ifindex,
+    };
+
+    if (is_ipv4) {
+        params.ipv4_src = saddr->ip;
+        params.ipv4_dst = daddr->ip;
+    } else {
+#ifdef FEAT_IPV6
+        COPY_ADDR6(params.ipv6_src, saddr->ip6);
+        COPY_ADDR6(params.ipv6_dst, daddr->ip6);
+#else
+        __bpf_unreachable();
+#endif
+    }
+    int ret = bpf_fib_lookup(skb, &params, sizeof(params),
+                             BPF_FIB_LOOKUP_OUTPUT | BPF_FIB_LOOKUP_SKIP_NEIGH |
+                                 BPF_FIB_LOOKUP_SRC);
+    if (ret) {
+        // The lookup would return -EINVAL if BPF_FIB_LOOKUP_SRC is not
+        // supported on current kernel, we then can fallback to use defined
+        // external address
+        if (ret > 0) {
+            bpf_log_error("FIB lookup failed, ret: %d", ret);
+        }
+        return TC_ACT_SHOT;
+    }
+
+    if (is_ipv4) {
+        inet_addr_set_ip(to_addr, params.ipv4_src);
+    } else {
+#ifdef FEAT_IPV6
+        inet_addr_set_ip6(to_addr, params.ipv6_src);
+#else
+        __bpf_unreachable();
+#endif
+    }
+
+    if (is_ipv4) {
+        bpf_log_trace("orig_src:%pI4, orig_dst:%pI4, src:%pI4, dst:%pI4",
+                      &saddr->ip, &daddr->ip, &params.ipv4_src,
+                      &params.ipv4_dst);
+    } else {
+#ifdef FEAT_IPV6
+        bpf_log_trace("orig_src:%pI6, orig_dst:%pI6, src:%pI6, dst:%pI6",
+                      saddr->ip6, daddr->ip6, params.ipv6_src, params.ipv6_dst);
+#endif
+    }
+
+    return TC_ACT_OK;
+#undef BPF_LOG_TOPIC
+}
+
+static __always_inline int
+egress_lookup_or_new_binding(struct __sk_buff *skb, bool is_ipv4, u8 l4proto,
+                             bool do_new, const struct inet_tuple *origin,
+                             struct map_binding_value **b_value_orig_,
+                             struct map_binding_value **b_value_rev_) {
+#define BPF_LOG_TOPIC "egress_lookup_or_new_binding"
+    struct map_binding_key b_key = {
+        .ifindex = skb->ifindex,
+        .flags =
+            BINDING_ORIG_DIR_FLAG | (is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG),
+        .l4proto = l4proto,
+        .from_port = origin->sport,
+        .from_addr = origin->saddr,
+    };
+
+    struct map_binding_value *b_value_rev = NULL;
+    struct map_binding_value *b_value_orig =
+        bpf_map_lookup_elem(&map_binding, &b_key);
+    if (!b_value_orig) {
+        if (!do_new) {
+            return TC_ACT_SHOT;
+        }
+
+        // XXX: do NAT64 for origin->daddr if it has NAT64 prefix
+        bool nat_x_4 = is_ipv4;
+        struct map_binding_value b_value_new;
+        partial_init_binding_value(nat_x_4, b_key.from_port, &b_value_new);
+
+        // XXX: use 0 as source address in the case of NAT64
+        if (!ENABLE_FIB_LOOKUP_SRC ||
+            egress_fib_lookup_src(skb, nat_x_4, &origin->saddr, &origin->daddr,
+                                  &b_value_new.to_addr)) {
+            select_external_addr(nat_x_4, l4proto, &origin->saddr,
+                                 &b_value_new.to_addr);
+        }
+        // XXX: source policy is not applicable to NAT64 for now
+        bool by_policy = source_policy_external_addr(is_ipv4, &origin->saddr,
+                                                     &b_value_new.to_addr);
+        if (PAIRED_POOLING && !by_policy) {
+            paired_external_addr(b_key.ifindex, is_ipv4, nat_x_4, l4proto,
+                                 &origin->saddr, &b_value_new.to_addr);
+        }
+
+        int ret;
+        struct external_config *ext_config =
+            lookup_external_config(nat_x_4, &b_value_new.to_addr);
+        if ((ret = nat_check_external_config(ext_config)) != TC_ACT_OK) {
+            return ret;
+        }
+        // source policy or FIB lookup could pick an address not serving the
+        // protocol
+        if (!external_serves(ext_config, l4proto)) {
+            bpf_log_debug("external address not serving the protocol");
+            return TC_ACT_SHOT;
+        }
+
+        struct port_range *proto_range;
+        u8 range_len = select_port_range(ext_config, l4proto, RANGE_OUTBOUND,
+                                         &proto_range);
+        barrier_var(range_len);
+        if (range_len == 0) {
+            // range_len=0 is defined as the semantic of passthrough NAT for
+            // respective IP protocol
+            return TC_ACT_UNSPEC;
+        }
+
+        if (!host_ports_check(b_key.ifindex,
+                              is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG,
+                              l4proto, &origin->saddr)) {
+            return TC_ACT_SHOT;
+        }
+        if (!host_binding_rate_check(b_key.ifindex,
+                                     is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG,
+                                     &origin->saddr)) {
+            return TC_ACT_SHOT;
+        }
+        if (l4proto == IPPROTO_UDP &&
+            !udp_binding_rate_check(b_key.ifindex,
+                                    is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG,
+                                    &origin->saddr)) {
+            return TC_ACT_SHOT;
+        }
+
+        struct port_range det_ranges[MAX_PORT_RANGES];
+        bool in_det_block = false;
+        // ICMP query IDs are allocated dynamically as usual
+        if (nat_x_4 && !is_icmpx(l4proto)) {
+            ret = det_nat_port_block(&origin->saddr, proto_range, det_ranges);
+            if (ret < 0) {
+                return TC_ACT_SHOT;
+            } else if (ret > 0) {
+                proto_range = det_ranges;
+                range_len = 1;
+                in_det_block = true;
+            } else if (DET_NAT_BLOCK_SIZE) {
+                range_len =
+                    det_nat_dynamic_ranges(proto_range, range_len, det_ranges);
+                proto_range = det_ranges;
+            }
+        }
+
+        // no range is left if deterministic port blocks take all ports
+        ret = range_len ? fill_unique_binding_port(ext_config, proto_range,
+                                                   range_len, &b_key,
+                                                   &b_value_new)
+                        : TC_ACT_SHOT;
+        if (ret != TC_ACT_OK) {
+            // spilling over would break pairing, source policy or
+            // deterministic port block
+            if (!EXTERNAL_SPILLOVER || PAIRED_POOLING || by_policy ||
+                in_det_block) {
+                return TC_ACT_SHOT;
+            }
+            ret = spill_over_external(nat_x_4, l4proto, &b_key, &b_value_new);
+            if (ret != TC_ACT_OK) {
+                return TC_ACT_SHOT;
+            }
+        } else if (g_external_spillover) {
+            g_external_spillover = false;
+        }
+
+        b_value_orig = insert_new_binding(&b_key, &b_value_new, &b_value_rev);
+        if (!b_value_orig) {
+            return TC_ACT_SHOT;
+        }
+    }
+
+    *b_value_orig_ = b_value_orig;
+    *b_value_rev_ = b_value_rev;
+    return TC_ACT_OK;
+#undef BPF_LOG_TOPIC
+}
+
+enum {
+    LK_CT_ERROR_NEW,
+    // new CT not allowed by MAX_EMBRYONIC_PER_HOST
+    LK_CT_ERROR_EMBRYONIC,
+    LK_CT_NONE,
+    LK_CT_EXIST,
+    LK_CT_NEW,
+};
+
+static __always_inline int
+ingress_lookup_or_new_ct(u32 ifindex, bool is_ipv4, u8 l4proto, bool do_new,
+                         const struct inet_tuple *reply,
+                         struct map_binding_value *b_value_rev,
+                         struct map_ct_value **ct_value_) {
+#define BPF_LOG_TOPIC "ingress_lookup_or_new_ct"
+    struct map_ct_key ct_key;
+    ct_key.ifindex = ifindex;
+    ct_key.flags = is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG;
+    ct_key.l4proto = l4proto;
+    ct_key._pad = 0;
+    inet_tuple_rev_copy(&ct_key.external, reply);
+
+    struct map_ct_value *ct_value = bpf_map_lookup_elem(&map_ct, &ct_key);
+    if (ct_value) {
+        if (ct_value->seq == b_value_rev->seq) {
+            *ct_value_ = ct_value;
+            return LK_CT_EXIST;
+        }
+        // This CT could become dangling and not refcounted if the binding
+        // was deleted in delete_ct() at this point, due to the deleted
+        // binding is still available here because of RCU but we might add a
+        // new CT referencing the binding(through refcount). Though the CT
+        // would fade out eventually due to timeout. Thus we assign a unique
+        // sequence number for binding and related CTs to distinguish
+        // binding generations. And if the sequence number does not matches
         // between binding and CT, then the CT must be dangling, so we just
         // delete that CT and recreate a CT with new sequence number from
         // binding.
-        bpf_map_delete_elem(&map_ct, &ct_key);
+        delete_ct_entry(&ct_key, ct_value);
         ct_value = NULL;
     }
     if (!ct_value && !do_new) {
@@ -1471,18 +3439,27 @@ ingress_lookup_or_new_ct(u32 ifindex, bool is_ipv4, u8 l4proto, bool do_new,
     ct_value_new.origin.dport =
         is_icmpx(l4proto) ? b_value_rev->to_port : reply->sport;
     ct_value_new.seq = b_value_rev->seq;
+    ct_value_new.pkts = 0;
     ct_value_new._pad[0] = 0;
     ct_value_new._pad[1] = 0;
-    ct_value_new._pad[2] = 0;
+    ct_value_new.expires = 0;
     ct_value_new.timer.__opaque[0] = 0;
     ct_value_new.timer.__opaque[1] = 0;
 
-    ct_value = insert_new_ct(l4proto, &ct_key, &ct_value_new);
+    if (!host_sessions_inc(ifindex, ct_value_new.flags,
+                           &ct_value_new.origin.saddr)) {
+        return LK_CT_ERROR_NEW;
+    }
+    ct_value = insert_new_ct(l4proto, &ct_key, &ct_value_new, b_value_rev);
     if (!ct_value) {
+        host_sessions_dec(ifindex, ct_value_new.flags,
+                          &ct_value_new.origin.saddr);
         return LK_CT_ERROR_NEW;
     }
 
-    b_value_orig->ref = BINDING_ORIG_REF_COUNTED;
+    filter_addr_inc(&ct_key);
+    binding_orig_set_ref_counted(ifindex, ct_value_new.flags, l4proto,
+                                 &b_value_rev->to_addr, b_value_orig);
     __sync_fetch_and_add(&b_value_rev->ref, 1);
 
     bpf_log_debug("insert new CT");
@@ -1517,7 +3494,7 @@ static __always_inline int egress_lookup_or_new_ct(
             *ct_value_ = ct_value;
             return LK_CT_EXIST;
         }
-        bpf_map_delete_elem(&map_ct, &ct_key);
+        delete_ct_entry(&ct_key, ct_value);
         ct_value = NULL;
     }
     if (!ct_value && !do_new) {
@@ -1542,14 +3519,30 @@ static __always_inline int egress_lookup_or_new_ct(
                                         .origin = *origin,
                                         .state = CT_INIT_OUT,
                                         .seq = b_value_rev->seq};
-    ct_value = insert_new_ct(l4proto, &ct_key, &ct_value_new);
+    if (!host_sessions_inc(ifindex, ct_value_new.flags, &origin->saddr)) {
+        return LK_CT_ERROR_NEW;
+    }
+    // new TCP CT is only initiated by SYN
+    bool is_embryonic = l4proto == IPPROTO_TCP;
+    if (is_embryonic &&
+        !host_embryonic_inc(ifindex, ct_value_new.flags, &origin->saddr)) {
+        host_sessions_dec(ifindex, ct_value_new.flags, &origin->saddr);
+        return LK_CT_ERROR_EMBRYONIC;
+    }
+    ct_value = insert_new_ct(l4proto, &ct_key, &ct_value_new, b_value_rev);
     if (!ct_value) {
+        host_sessions_dec(ifindex, ct_value_new.flags, &origin->saddr);
+        if (is_embryonic) {
+            host_embryonic_dec(ifindex, ct_value_new.flags, &origin->saddr);
+        }
         return LK_CT_ERROR_NEW;
     }
 
+    filter_addr_inc(&ct_key);
     __sync_fetch_and_add(&b_value_rev->ref, 1);
     __sync_fetch_and_add(&b_value_rev->use, 1);
-    b_value_orig->ref = BINDING_ORIG_REF_COUNTED;
+    binding_orig_set_ref_counted(ifindex, ct_value_new.flags, l4proto,
+                                 &origin->saddr, b_value_orig);
 
     bpf_log_debug("insert new CT");
 
@@ -1564,23 +3557,79 @@ static __always_inline bool ct_change_state(struct map_ct_value *ct_value,
                                         next_state);
 }
 
+// IPsec NAT-T flows carry long-lived SAs which could be kept alive by sparse
+// keepalives from client only, see
+// https://datatracker.ietf.org/doc/html/rfc3948#section-2.3
+static __always_inline bool ct_is_nat_t(u8 l4proto,
+                                        const struct map_ct_value *ct_value) {
+    return ESP_PASSTHROUGH && l4proto == IPPROTO_UDP &&
+           ct_value->origin.dport == bpf_htons(IPSEC_NAT_T_PORT);
+}
+
+// Timeout of connectionless CT once established
+static __always_inline u64
+ct_timeout_connless(u8 l4proto, const struct map_ct_value *ct_value) {
+    if (l4proto == IPPROTO_UDP) {
+        return ct_value->pkts >= UDP_STREAM_PACKETS ? TIMEOUT_UDP_STREAM
+                                                    : TIMEOUT_UDP_SINGLE;
+    }
+    return ct_timeout_pkt(l4proto, TIMEOUT_PKT_DEFAULT);
+}
+
+// Returns timeout override of the remote destination of CT if any, otherwise
+// the specified timeout. Only UDP and TCP established timeouts can be
+// overridden.
+static __always_inline u64 ct_timeout_dest(const struct map_ct_value *ct_value,
+                                           u8 l4proto, u64 timeout) {
+    if (!DEST_TIMEOUTS ||
+        (l4proto != IPPROTO_UDP && l4proto != IPPROTO_TCP)) {
+        return timeout;
+    }
+    struct dest_config *config = lookup_dest_config(
+        FLAGS_IS_IPV4(ct_value->flags), &ct_value->origin.daddr);
+    if (!config) {
+        return timeout;
+    }
+    u64 override =
+        l4proto == IPPROTO_UDP ? config->timeout_udp : config->timeout_tcp_est;
+    return override ? override : timeout;
+}
+
 static __always_inline int ct_reset_timer(struct map_ct_value *ct_value,
                                           u64 timeout) {
+    ct_value->expires = bpf_ktime_get_ns() + timeout;
     return bpf_timer_start(&ct_value->timer, timeout, 0);
 }
 
+static __always_inline u64 tcp_teardown_timeout(u8 teardown) {
+    if (teardown == TCP_TEARDOWN_CLOSE) {
+        return 0;
+    }
+    return teardown == TCP_TEARDOWN_TIME_WAIT ? TIMEOUT_TCP_TIME_WAIT
+                                              : TIMEOUT_TCP_TRANS;
+}
+
 static __always_inline int
 ct_state_transition(u32 ifindex, u8 l4proto, u8 pkt_type, bool is_outbound,
                     struct map_binding_value *b_value,
                     struct map_ct_value *ct_value) {
 #define BPF_LOG_TOPIC "ct_state_transition"
     u32 curr_state = ct_value->state;
+    bool refresh = is_outbound || INBOUND_REFRESH;
+
+    if ((pkt_type == PKT_TCP_FIN &&
+         TCP_FIN_TEARDOWN == TCP_TEARDOWN_IGNORE) ||
+        (pkt_type == PKT_TCP_RST &&
+         TCP_RST_TEARDOWN == TCP_TEARDOWN_IGNORE)) {
+        pkt_type = PKT_TCP_DATA;
+    }
 
 #define NEW_STATE(__state)                                                     \
     if (!ct_change_state(ct_value, curr_state, (__state))) {                   \
         return TC_ACT_SHOT;                                                    \
     }
-#define RESET_TIMER(__timeout) ct_reset_timer(ct_value, (__timeout))
+#define RESET_TIMER(__timeout)                                                 \
+    ct_reset_timer(ct_value, ct_clamp_timeout(l4proto, b_value, (__timeout)))
 
     switch (curr_state) {
     case CT_INIT_IN:
@@ -1604,51 +3653,88 @@ ct_state_transition(u32 ifindex, u8 l4proto, u8 pkt_type, bool is_outbound,
 
             NEW_STATE(CT_ESTABLISHED);
             __sync_fetch_and_add(&b_value_rev->use, 1);
-            RESET_TIMER(pkt_type == PKT_CONNLESS ? TIMEOUT_PKT_DEFAULT
-                                                 : TIMEOUT_TCP_TRANS);
+            RESET_TIMER(pkt_type == PKT_CONNLESS
+                            ? ct_timeout_dest(
+                                  ct_value, l4proto,
+                                  ct_timeout_connless(l4proto, ct_value))
+                            : TIMEOUT_TCP_TRANS);
             bpf_log_debug("INIT_IN -> ESTABLISHED");
-        } else if (b_value->use != 0) {
+        } else if (b_value->use != 0 && refresh) {
             // XXX: or just don't refresh timer and wait recreating CT instead
-            RESET_TIMER(pkt_type == PKT_CONNLESS ? TIMEOUT_PKT_MIN
-                                                 : TIMEOUT_TCP_TRANS);
+            RESET_TIMER(pkt_type == PKT_CONNLESS
+                            ? ct_timeout_pkt(l4proto, TIMEOUT_PKT_MIN)
+                            : TIMEOUT_TCP_TRANS);
             bpf_log_trace("INIT_IN refresh timer");
         }
         break;
     case CT_INIT_OUT:
+        if (pkt_type == PKT_TCP_RST && !is_outbound &&
+            TCP_RST_TEARDOWN != TCP_TEARDOWN_TRANS) {
+            // connection refused
+            RESET_TIMER(tcp_teardown_timeout(TCP_RST_TEARDOWN));
+            break;
+        }
         if (pkt_type != PKT_CONNLESS && pkt_type != PKT_TCP_SYN) {
             break;
         }
         if (is_outbound) {
-            RESET_TIMER(pkt_type == PKT_CONNLESS ? TIMEOUT_PKT_MIN
-                                                 : TIMEOUT_TCP_TRANS);
+            RESET_TIMER(pkt_type == PKT_CONNLESS
+                            ? ct_timeout_pkt(l4proto, TIMEOUT_PKT_MIN)
+                            : TIMEOUT_TCP_TRANS);
         } else {
             NEW_STATE(CT_ESTABLISHED);
-            RESET_TIMER(pkt_type == PKT_CONNLESS ? TIMEOUT_PKT_DEFAULT
-                                                 : TIMEOUT_TCP_EST);
+            if (l4proto == IPPROTO_TCP) {
+                host_embryonic_dec(ifindex, ct_value->flags,
+                                   &ct_value->origin.saddr);
+            }
+            if (ct_is_nat_t(l4proto, ct_value)) {
+                // promote to stream at once
+                ct_value->pkts = UDP_STREAM_PACKETS;
+            }
+            RESET_TIMER(ct_timeout_dest(ct_value, l4proto,
+                                        pkt_type == PKT_CONNLESS
+                                            ? ct_timeout_connless(l4proto,
+                                                                  ct_value)
+                                            : TIMEOUT_TCP_EST));
             bpf_log_debug("INIT_OUT -> ESTABLISHED");
         }
         break;
     case CT_ESTABLISHED:
-        if (pkt_type == PKT_CONNLESS) {
+        if (pkt_type == PKT_CONNLESS && l4proto == IPPROTO_UDP) {
+            bool promote = false;
+            if (ct_value->pkts < UDP_STREAM_PACKETS) {
+                // racing increments could only delay the promotion
+                ct_value->pkts += 1;
+                promote = ct_value->pkts >= UDP_STREAM_PACKETS;
+            }
+            if (is_outbound || promote) {
+                RESET_TIMER(ct_timeout_dest(ct_value, l4proto,
+                                            ct_value->pkts >= UDP_STREAM_PACKETS
+                                                ? TIMEOUT_UDP_STREAM
+                                                : TIMEOUT_UDP_SINGLE));
+            }
+        } else if (pkt_type == PKT_CONNLESS) {
             if (is_outbound) {
-                RESET_TIMER(TIMEOUT_TCP_EST);
+                RESET_TIMER(ct_timeout_pkt(l4proto, TIMEOUT_TCP_EST));
             }
         } else if (pkt_type == PKT_TCP_DATA) {
-            // XXX: should we allow refreshing from inbound?
-            RESET_TIMER(TIMEOUT_TCP_EST);
+            if (refresh) {
+                RESET_TIMER(
+                    ct_timeout_dest(ct_value, l4proto, TIMEOUT_TCP_EST));
+            }
         } else if (pkt_type == PKT_TCP_FIN) {
             NEW_STATE(is_outbound ? CT_FIN_OUT : CT_FIN_IN);
             bpf_log_debug("ESTABLISHED -> FIN_IN/FIN_OUT");
         } else if (pkt_type == PKT_TCP_RST) {
             NEW_STATE(CT_TRANS);
-            RESET_TIMER(TIMEOUT_TCP_TRANS);
+            RESET_TIMER(tcp_teardown_timeout(TCP_RST_TEARDOWN));
             bpf_log_debug("ESTABLISHED -> TRANS");
         }
         break;
     case CT_TRANS:
         if (pkt_type != PKT_TCP_RST) {
             NEW_STATE(CT_ESTABLISHED);
-            RESET_TIMER(TIMEOUT_TCP_EST);
+            RESET_TIMER(ct_timeout_dest(ct_value, l4proto, TIMEOUT_TCP_EST));
             bpf_log_debug("TRANS -> ESTABLISHED");
         }
         break;
@@ -1656,102 +3742,849 @@ ct_state_transition(u32 ifindex, u8 l4proto, u8 pkt_type, bool is_outbound,
         if (pkt_type == PKT_TCP_FIN) {
             if (is_outbound) {
                 NEW_STATE(CT_FIN_IN_OUT);
-                RESET_TIMER(TIMEOUT_TCP_TRANS);
+                RESET_TIMER(tcp_teardown_timeout(TCP_FIN_TEARDOWN));
                 bpf_log_debug("FIN_IN -> FIN_IN_OUT");
             }
-        } else {
-            RESET_TIMER(TIMEOUT_TCP_EST);
+        } else if (pkt_type == PKT_TCP_RST) {
+            NEW_STATE(CT_TRANS);
+            RESET_TIMER(tcp_teardown_timeout(TCP_RST_TEARDOWN));
+            bpf_log_debug("FIN_IN -> TRANS");
+        } else if (refresh) {
+            RESET_TIMER(ct_timeout_dest(ct_value, l4proto, TIMEOUT_TCP_EST));
         }
         break;
     case CT_FIN_OUT:
         if (pkt_type == PKT_TCP_FIN) {
             if (!is_outbound) {
                 NEW_STATE(CT_FIN_IN_OUT);
-                RESET_TIMER(TIMEOUT_TCP_TRANS);
+                RESET_TIMER(tcp_teardown_timeout(TCP_FIN_TEARDOWN));
                 bpf_log_debug("FIN_OUT -> FIN_IN_OUT");
             }
+        } else if (pkt_type == PKT_TCP_RST) {
+            NEW_STATE(CT_TRANS);
+            RESET_TIMER(tcp_teardown_timeout(TCP_RST_TEARDOWN));
+            bpf_log_debug("FIN_OUT -> TRANS");
+        } else if (refresh) {
+            RESET_TIMER(ct_timeout_dest(ct_value, l4proto, TIMEOUT_TCP_EST));
+        }
+        break;
+    case CT_FIN_IN_OUT:
+        if (pkt_type == PKT_TCP_RST &&
+            TCP_RST_TEARDOWN != TCP_TEARDOWN_TRANS) {
+            RESET_TIMER(tcp_teardown_timeout(TCP_RST_TEARDOWN));
+        }
+        break;
+    default:
+        return TC_ACT_SHOT;
+    }
+    return TC_ACT_OK;
+#undef BPF_LOG_TOPIC
+}
+
+static __always_inline int get_is_ipv4(struct __sk_buff *skb, bool *is_ipv4_) {
+    void *data_end = ctx_data_end(skb);
+    void *data = ctx_data(skb);
+    bool is_ipv4;
+    if (HAS_ETH_ENCAP && HAS_PPPOE_ENCAP) {
+        struct ethhdr *eth = data;
+        struct pppoe_ses_hdr *ppp = (void *)(eth + 1);
+        if ((void *)(ppp + 1) > data_end) {
+            return TC_ACT_UNSPEC;
+        }
+        // PPPoE discovery and other non-session frames are not for us
+        if (eth->h_proto != bpf_htons(ETH_P_PPP_SES) ||
+            ppp->ver_type != 0x11 || ppp->code != 0) {
+            return TC_ACT_UNSPEC;
+        }
+
+        if (ppp->ppp_proto == bpf_htons(PPP_IP)) {
+            is_ipv4 = true;
+#ifdef FEAT_IPV6
+        } else if (ppp->ppp_proto == bpf_htons(PPP_IPV6)) {
+            is_ipv4 = false;
+#endif
+        } else {
+            return TC_ACT_UNSPEC;
+        }
+    } else if (HAS_ETH_ENCAP) {
+        struct ethhdr *eth = data;
+        if ((void *)(eth + 1) > data_end) {
+            return TC_ACT_SHOT;
+        }
+
+        if (eth->h_proto == bpf_htons(ETH_P_IP)) {
+            is_ipv4 = true;
+#ifdef FEAT_IPV6
+        } else if (eth->h_proto == bpf_htons(ETH_P_IPV6)) {
+            is_ipv4 = false;
+#endif
+        } else {
+            return TC_ACT_UNSPEC;
+        }
+    } else {
+        u8 *p_version = data;
+        if ((void *)(p_version + 1) > data_end) {
+            return TC_ACT_SHOT;
+        }
+        u8 version = (*p_version) >> 4;
+        if (version == 4) {
+            is_ipv4 = true;
+#ifdef FEAT_IPV6
+        } else if (version == 6) {
+            is_ipv4 = false;
+#endif
         } else {
-            RESET_TIMER(TIMEOUT_TCP_EST);
+            return TC_ACT_UNSPEC;
+        }
+    }
+    *is_ipv4_ = is_ipv4;
+    return TC_ACT_OK;
+}
+
+// Ensure we are using PKT_IS_IPV4()
+#undef IS_IPV4
+#ifdef FEAT_IPV6
+#define PKT_IS_IPV4() (is_ipv4)
+#else
+#define PKT_IS_IPV4() (true)
+#endif
+
+static __always_inline bool l4proto_match(u8 l4proto, u8 filter) {
+    // IPPROTO_ICMP matches both ICMP and ICMPv6
+    return filter == IPPROTO_ICMP ? is_icmpx(l4proto)
+                                  : !filter || filter == l4proto;
+}
+
+static __always_inline bool
+prefix_match(bool is_ipv4, const union u_inet_addr *addr, bool net_is_ipv4,
+             const volatile __be32 *net, u8 prefix_len) {
+    if (prefix_len == 0) {
+        return true;
+    }
+    if (net_is_ipv4 != is_ipv4) {
+        return false;
+    }
+#pragma unroll
+    for (int i = 0; i < sizeof(addr->all) / sizeof(addr->all[0]); i++) {
+        int bits = prefix_len - i * 32;
+        if (bits <= 0) {
+            break;
+        }
+        u32 mask = bits >= 32 ? 0xffffffff : bpf_htonl(~0U << (32 - bits));
+        if ((addr->all[i] ^ net[i]) & mask) {
+            return false;
+        }
+    }
+    return true;
+}
+
+static __always_inline bool
+capture_match(bool is_ipv4, u8 l4proto, const union u_inet_addr *internal) {
+    return CAPTURE && l4proto_match(l4proto, CAPTURE_L4PROTO) &&
+           prefix_match(is_ipv4, internal, CAPTURE_ADDR_IPV4, CAPTURE_ADDR,
+                        CAPTURE_PREFIX_LEN);
+}
+
+static __always_inline void capture_packet(struct __sk_buff *skb, u8 flags) {
+    u32 cap_len = skb->len;
+    if (cap_len > CAPTURE_SNAPLEN) {
+        cap_len = CAPTURE_SNAPLEN;
+    }
+    struct capture_meta meta = {
+        .ifindex = skb->ifindex,
+        .len = skb->len,
+        .cap_len = cap_len,
+        .flags = flags,
+    };
+    // packet data of `cap_len` is appended to `meta` in perf sample
+    bpf_perf_event_output(skb, &map_capture,
+                          BPF_F_CURRENT_CPU | ((u64)cap_len << 32), &meta,
+                          sizeof(meta));
+}
+
+static __always_inline bool trace_addr_match(bool is_ipv4,
+                                             const union u_inet_addr *addr) {
+    return prefix_match(is_ipv4, addr, TRACE_ADDR_IPV4, TRACE_ADDR,
+                        TRACE_PREFIX_LEN);
+}
+
+static __always_inline bool trace_port_match(__be16 port) {
+    return !TRACE_PORT || bpf_ntohs(port) == TRACE_PORT;
+}
+
+// Matches trace filter against addresses and ports of `tuple`, and
+// additionally `alt_addr` and `alt_port` if not NULL
+static __always_inline bool
+trace_match(bool is_ipv4, u8 l4proto, const struct inet_tuple *tuple,
+            const union u_inet_addr *alt_addr, __be16 alt_port) {
+    if (!TRACE || !l4proto_match(l4proto, TRACE_L4PROTO)) {
+        return false;
+    }
+    if (!trace_port_match(tuple->sport) && !trace_port_match(tuple->dport) &&
+        !(alt_addr && trace_port_match(alt_port))) {
+        return false;
+    }
+    return trace_addr_match(is_ipv4, &tuple->saddr) ||
+           trace_addr_match(is_ipv4, &tuple->daddr) ||
+           (alt_addr && trace_addr_match(is_ipv4, alt_addr));
+}
+
+static __always_inline void
+trace_pkt_event(struct __sk_buff *skb, bool is_ingress, bool is_ipv4, u8 stage,
+                const struct packet_info *pkt, int value, u8 reason,
+                const union u_inet_addr *addr, __be16 port) {
+    struct trace_pkt_event *event =
+        bpf_ringbuf_reserve(&map_events, sizeof(struct trace_pkt_event), 0);
+    if (!event) {
+        return;
+    }
+    __builtin_memset(event, 0, sizeof(*event));
+    event->type = EVENT_TRACE;
+    event->ifindex = skb->ifindex;
+    event->stage = stage;
+    event->flags =
+        (is_ingress ? TRACE_F_INGRESS : 0) | (is_ipv4 ? TRACE_F_IPV4 : 0);
+    event->l4proto = pkt->nexthdr;
+    event->pkt_type = pkt->pkt_type;
+    event->value = value;
+    event->tuple = pkt->tuple;
+    if (addr) {
+        COPY_ADDR6(event->addr.all, addr->all);
+    }
+    event->port = port;
+    event->reason = reason;
+    bpf_ringbuf_submit(event, 0);
+}
+
+#define TRACE_EVENT(stage, value, reason, addr, port)                          \
+    ({                                                                         \
+        if (do_trace) {                                                        \
+            trace_pkt_event(skb, TRACE_IS_INGRESS, PKT_IS_IPV4(), stage,       \
+                            &pkt, value, reason, addr, port);                  \
+        }                                                                      \
+    })
+
+#define TRACE_RETURN(verdict, reason)                                          \
+    ({                                                                         \
+        int __verdict = (verdict);                                             \
+        TRACE_EVENT(TRACE_VERDICT, __verdict, reason, NULL, 0);                \
+        return __verdict;                                                      \
+    })
+
+// Translates embedded address of ICMP error message, which is of the packet
+// in reverse direction.
+static __always_inline void netmap_translate_icmp_err(struct __sk_buff *skb,
+                                                      bool is_ingress,
+                                                      u32 l4_off,
+                                                      __be32 from_addr,
+                                                      __be32 to_addr) {
+    struct icmphdr icmph;
+    if (bpf_skb_load_bytes(skb, l4_off, &icmph, sizeof(icmph)) ||
+        icmpx_msg_type(true, IPPROTO_ICMP, &icmph) != ICMP_ERROR_MSG) {
+        return;
+    }
+
+    u32 err_l3_off = icmpx_err_l3_offset(l4_off);
+    struct iphdr err_iph;
+    if (bpf_skb_load_bytes(skb, err_l3_off, &err_iph, sizeof(err_iph))) {
+        return;
+    }
+    if ((is_ingress ? err_iph.saddr : err_iph.daddr) != from_addr) {
+        return;
+    }
+
+    u32 err_l4_csum_off = err_l3_off + err_iph.ihl * 4;
+    bool err_l4_pseudo = true;
+    switch (err_iph.protocol) {
+    case IPPROTO_TCP:
+        err_l4_csum_off += offsetof(struct tcphdr, check);
+        break;
+    case IPPROTO_UDP:
+        err_l4_csum_off += offsetof(struct udphdr, check);
+        break;
+    case IPPROTO_ICMP:
+        err_l4_csum_off += offsetof(struct icmphdr, checksum);
+        err_l4_pseudo = false;
+        break;
+    default:
+        return;
+    }
+
+    if (bpf_skb_store_bytes(skb,
+                            err_l3_off + get_l3_to_addr_off(true, is_ingress),
+                            &to_addr, sizeof(to_addr), 0)) {
+        return;
+    }
+    ipv4_update_csum_icmp_err(
+        skb, l4_off + offsetof(struct icmphdr, checksum),
+        err_l3_off + offsetof(struct iphdr, check), err_l4_csum_off, from_addr,
+        0, to_addr, 0, err_l4_pseudo, err_iph.protocol == IPPROTO_UDP);
+}
+
+// Returns TC_ACT_OK if packet is not subject to NETMAP, TC_ACT_UNSPEC if
+// translated, or TC_ACT_SHOT if address can not be rewritten.
+static __always_inline int netmap_translate(struct __sk_buff *skb,
+                                            bool is_ingress) {
+#define BPF_LOG_TOPIC "netmap"
+    u32 l3_off = TC_SKB_L3_OFF();
+    struct iphdr *iph;
+    if (VALIDATE_PULL(skb, &iph, l3_off, sizeof(*iph))) {
+        return TC_ACT_OK;
+    }
+    __be32 from_addr = is_ingress ? iph->daddr : iph->saddr;
+    u8 protocol = iph->protocol;
+    bool is_first_frag = !(iph->frag_off & bpf_htons(IP_OFFSET));
+    u32 l4_off = l3_off + iph->ihl * 4;
+
+    struct ipv4_lpm_key key = {.prefixlen = 32, .ip = from_addr};
+    struct netmap_value *value = bpf_map_lookup_elem(&map_ipv4_netmap, &key);
+    if (!value || !(value->flags & NETMAP_ORIG_DIR_FLAG) != is_ingress) {
+        return TC_ACT_OK;
+    }
+    __be32 to_addr =
+        (from_addr & ~value->mask) | (value->to_prefix & value->mask);
+
+    if (bpf_skb_store_bytes(skb,
+                            l3_off + get_l3_to_addr_off(true, !is_ingress),
+                            &to_addr, sizeof(to_addr), 0) ||
+        bpf_l3_csum_replace(skb, l3_off + offsetof(struct iphdr, check),
+                            from_addr, to_addr, 4)) {
+        bpf_log_error("failed to rewrite address");
+        return TC_ACT_SHOT;
+    }
+    if (!is_first_frag) {
+        return TC_ACT_UNSPEC;
+    }
+
+    switch (protocol) {
+    case IPPROTO_TCP:
+        ipv4_update_csum(skb, l4_off + offsetof(struct tcphdr, check),
+                         from_addr, 0, to_addr, 0, true, false);
+        break;
+    case IPPROTO_UDP:
+        ipv4_update_csum(skb, l4_off + offsetof(struct udphdr, check),
+                         from_addr, 0, to_addr, 0, true, true);
+        break;
+    case IPPROTO_ICMP:
+        netmap_translate_icmp_err(skb, is_ingress, l4_off, from_addr, to_addr);
+        break;
+    }
+    return TC_ACT_UNSPEC;
+#undef BPF_LOG_TOPIC
+}
+
+#ifdef FEAT_IPV6
+static __always_inline u16 npt_csum_add(u16 a, u16 b) {
+    u32 sum = (u32)a + b;
+    return (sum & 0xffff) + (sum >> 16);
+}
+
+// Returns TC_ACT_OK if packet is not subject to NPTv6, TC_ACT_UNSPEC if
+// translated, or TC_ACT_SHOT if address can not be translated.
+static __always_inline int npt_translate(struct __sk_buff *skb,
+                                         bool is_ingress) {
+#define BPF_LOG_TOPIC "nptv6"
+    u8 prefix_len = g_npt_prefix_len;
+    if (prefix_len == 0 || prefix_len > 64) {
+        return TC_ACT_OK;
+    }
+
+    u32 addr_off = TC_SKB_L3_OFF() + (is_ingress
+                                          ? offsetof(struct ipv6hdr, daddr)
+                                          : offsetof(struct ipv6hdr, saddr));
+    __be16 addr[8];
+    if (bpf_skb_load_bytes(skb, addr_off, addr, sizeof(addr))) {
+        return TC_ACT_OK;
+    }
+    __be32 *addr32 = (__be32 *)addr;
+
+    const volatile __be32 *from_prefix =
+        is_ingress ? g_npt_external_prefix : g_npt_internal_prefix;
+    const volatile __be32 *to_prefix =
+        is_ingress ? g_npt_internal_prefix : g_npt_external_prefix;
+    __be32 mask[2];
+#pragma unroll
+    for (int i = 0; i < 2; i++) {
+        u32 bits = prefix_len > 32 * i ? prefix_len - 32 * i : 0;
+        mask[i] = bits >= 32 ? 0xffffffff
+                  : bits     ? bpf_htonl(0xffffffff << (32 - bits))
+                             : 0;
+        if ((addr32[i] ^ from_prefix[i]) & mask[i]) {
+            return TC_ACT_OK;
+        }
+    }
+
+    if (is_ingress) {
+#pragma unroll
+        for (int i = 0; i < MAX_NPT_LOCAL_ADDRS; i++) {
+            if (i >= g_npt_local_addrs_len) {
+                break;
+            }
+            if (addr32[0] == g_npt_local_addrs[i][0] &&
+                addr32[1] == g_npt_local_addrs[i][1] &&
+                addr32[2] == g_npt_local_addrs[i][2] &&
+                addr32[3] == g_npt_local_addrs[i][3]) {
+                return TC_ACT_OK;
+            }
+        }
+    }
+
+#pragma unroll
+    for (int i = 0; i < 2; i++) {
+        addr32[i] = (addr32[i] & ~mask[i]) | (to_prefix[i] & mask[i]);
+    }
+
+    // Adjust subnet ID for prefixes of /48 or shorter, otherwise the first
+    // word of interface identifier that is not 0xffff, see section 3.2 and
+    // 3.5 of RFC 6296.
+    u16 adjustment = is_ingress ? ~g_npt_adjustment : g_npt_adjustment;
+    bool adjusted = false;
+#pragma unroll
+    for (int i = 3; i < 8; i++) {
+        if (adjusted || (i == 3) != (prefix_len <= 48)) {
+            continue;
+        }
+        if (addr[i] == 0xffff) {
+            continue;
+        }
+        u16 word = npt_csum_add(bpf_ntohs(addr[i]), adjustment);
+        addr[i] = word == 0xffff ? 0 : bpf_htons(word);
+        adjusted = true;
+    }
+    if (!adjusted) {
+        bpf_log_debug("untranslatable address");
+        return TC_ACT_SHOT;
+    }
+
+    if (bpf_skb_store_bytes(skb, addr_off, addr, sizeof(addr), 0)) {
+        return TC_ACT_SHOT;
+    }
+    return TC_ACT_UNSPEC;
+#undef BPF_LOG_TOPIC
+}
+#endif
+
+static __always_inline __sum16 csum_fold(s64 csum) {
+    u32 sum = csum;
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    return ~sum;
+}
+
+// One's complement sum of TCP or UDP pseudo header, which is what checksum
+// field of packets with checksum offloaded holds
+static __always_inline __sum16 l4_pseudo_sum(bool is_ipv4, u8 l4proto,
+                                             u32 len,
+                                             const union u_inet_addr *saddr,
+                                             const union u_inet_addr *daddr) {
+    u64 sum;
+#ifdef FEAT_IPV6
+    if (!is_ipv4) {
+        sum = (u64)bpf_htonl(len) + bpf_htonl(l4proto);
+        for (int i = 0; i < 4; i++) {
+            sum += (u64)saddr->ip6[i] + daddr->ip6[i];
+        }
+    } else {
+        sum = (u64)saddr->ip + daddr->ip +
+              bpf_htonl(((u32)l4proto << 16) | len);
+    }
+#else
+    sum = (u64)saddr->ip + daddr->ip + bpf_htonl(((u32)l4proto << 16) | len);
+#endif
+    sum = (sum & 0xffffffff) + (sum >> 32);
+    sum = (sum & 0xffffffff) + (sum >> 32);
+    return ~csum_fold(sum);
+}
+
+// Offset of checksum field of TCP or UDP packet, or -1 for other packets
+static __always_inline int l4_csum_off(const struct packet_info *pkt) {
+    if (pkt->frag_type != FRAG_NONE || pkt->l4_off < 0) {
+        return -1;
+    }
+    switch (pkt->nexthdr) {
+    case IPPROTO_TCP:
+        return pkt->l4_off + offsetof(struct tcphdr, check);
+    case IPPROTO_UDP:
+        return pkt->l4_off + offsetof(struct udphdr, check);
+    default:
+        return -1;
+    }
+}
+
+// Loads checksum field of TCP or UDP packet before translation, for
+// csum_offloaded_fix(). Like csum_offloaded_fix(), it's a global function
+// verified once on its own, as egress_snat is close to verifier complexity
+// limit.
+__noinline int csum_offloaded_load(struct __sk_buff *skb,
+                                   const struct packet_info *pkt) {
+    __sum16 csum = 0;
+    if (!pkt) {
+        return 0;
+    }
+    int off = l4_csum_off(pkt);
+    if (off >= 0) {
+        bpf_skb_load_bytes(skb, off, &csum, sizeof(csum));
+    }
+    return csum;
+}
+
+// Sets checksum field of translated TCP or UDP packet to pseudo header sum if
+// checksum is offloaded, given checksum field `orig_csum` before translation.
+// There is no way to read ip_summed of skb, so checksum offload is assumed for
+// TCP GSO packets, and for UDP GSO packets or packets of local sockets if
+// checksum field held nothing but pseudo header sum.
+__noinline int csum_offloaded_fix(struct __sk_buff *skb,
+                                  const struct packet_info *pkt,
+                                  __sum16 orig_csum,
+                                  const union u_inet_addr *saddr,
+                                  const union u_inet_addr *daddr) {
+    if (!pkt || !saddr || !daddr) {
+        return TC_ACT_OK;
+    }
+    int off = l4_csum_off(pkt);
+    if (off < 0) {
+        return TC_ACT_OK;
+    }
+#ifdef FEAT_IPV6
+    bool is_ipv4 = pkt->is_ipv4;
+#else
+    bool is_ipv4 = true;
+#endif
+    u32 len = skb->len - pkt->l4_off;
+    if (!(skb->gso_size && pkt->nexthdr == IPPROTO_TCP)) {
+        if (!skb->gso_size && !skb->sk) {
+            return TC_ACT_OK;
+        }
+        if (orig_csum != l4_pseudo_sum(is_ipv4, pkt->nexthdr, len,
+                                       &pkt->tuple.saddr, &pkt->tuple.daddr)) {
+            return TC_ACT_OK;
+        }
+    }
+    __sum16 csum = l4_pseudo_sum(is_ipv4, pkt->nexthdr, len, saddr, daddr);
+    if (bpf_skb_store_bytes(skb, off, &csum, sizeof(csum), 0)) {
+        return TC_ACT_SHOT;
+    }
+    __sync_fetch_and_add(&g_csum_fix_skipped, 1);
+    return TC_ACT_OK;
+}
+#ifdef FEAT_IPV6
+// Pseudo header of ICMPv6 and TCP checksum over IPv6
+struct ipv6_pseudo_hdr {
+    __be32 saddr[4];
+    __be32 daddr[4];
+    __be32 len;
+    __be32 nexthdr;
+};
+
+// Offset of checksum field in L4 header, or -1 if protocol is not translated
+static __always_inline int clat_l4_csum_off(u8 l4proto) {
+    switch (l4proto) {
+    case IPPROTO_TCP:
+        return offsetof(struct tcphdr, check);
+    case IPPROTO_UDP:
+        return offsetof(struct udphdr, check);
+    case IPPROTO_ICMP:
+    case NEXTHDR_ICMP:
+        return offsetof(struct icmphdr, checksum);
+    default:
+        return -1;
+    }
+}
+
+// Stateless IPv4 to IPv6 translation (RFC 7915) of bare IP packets on egress
+// of CLAT interface, only unfragmented TCP, UDP and ICMP echo packets are
+// translated.
+static __always_inline int clat_egress(struct __sk_buff *skb) {
+#define BPF_LOG_TOPIC "clat ==>"
+    struct iphdr iph;
+    if (bpf_skb_load_bytes(skb, 0, &iph, sizeof(iph))) {
+        return TC_ACT_SHOT;
+    }
+    if (iph.version != 4 || iph.ihl != 5 ||
+        iph.frag_off & bpf_htons(IP_MF | IP_OFFSET)) {
+        bpf_log_debug("IPv4 options or fragments are not translated");
+        return TC_ACT_SHOT;
+    }
+    int csum_off = clat_l4_csum_off(iph.protocol);
+    if (csum_off < 0) {
+        bpf_log_debug("L4 protocol %d is not translated", iph.protocol);
+        return TC_ACT_SHOT;
+    }
+    csum_off += sizeof(struct ethhdr) + sizeof(struct ipv6hdr);
+    u16 payload_len = bpf_ntohs(iph.tot_len) - sizeof(iph);
+
+    struct ipv6hdr ip6h = {
+        .version = 6,
+        .priority = iph.tos >> 4,
+        .flow_lbl = {(iph.tos & 0xf) << 4},
+        .payload_len = bpf_htons(payload_len),
+        .nexthdr =
+            iph.protocol == IPPROTO_ICMP ? NEXTHDR_ICMP : iph.protocol,
+        .hop_limit = iph.ttl,
+    };
+    __builtin_memcpy(&ip6h.saddr, (void *)g_clat_ipv6_addr, 16);
+    ip6h.daddr.in6_u.u6_addr32[0] = g_clat_pref64[0];
+    ip6h.daddr.in6_u.u6_addr32[1] = g_clat_pref64[1];
+    ip6h.daddr.in6_u.u6_addr32[2] = g_clat_pref64[2];
+    ip6h.daddr.in6_u.u6_addr32[3] = iph.daddr;
+
+    u8 icmp_type = 0;
+    if (iph.protocol == IPPROTO_ICMP) {
+        if (bpf_skb_load_bytes(skb, sizeof(iph), &icmp_type, 1)) {
+            return TC_ACT_SHOT;
+        }
+        if (icmp_type != ICMP_ECHO && icmp_type != ICMP_ECHOREPLY) {
+            bpf_log_debug("ICMP type %d is not translated", icmp_type);
+            return TC_ACT_SHOT;
+        }
+    } else if (iph.protocol == IPPROTO_UDP) {
+        __sum16 check;
+        if (bpf_skb_load_bytes(skb, sizeof(iph) + offsetof(struct udphdr, check),
+                               &check, sizeof(check))) {
+            return TC_ACT_SHOT;
         }
-        break;
-    case CT_FIN_IN_OUT:
-        break;
-    default:
+        if (check == 0) {
+            bpf_log_debug("UDP packet without checksum is not translated");
+            return TC_ACT_SHOT;
+        }
+    }
+
+    if (bpf_skb_change_proto(skb, bpf_htons(ETH_P_IPV6), 0)) {
         return TC_ACT_SHOT;
     }
-    return TC_ACT_OK;
+    // bpf_redirect_neigh() requires an Ethernet header to strip, which our
+    // bare IP interface does not have
+    if (bpf_skb_change_head(skb, sizeof(struct ethhdr), 0)) {
+        return TC_ACT_SHOT;
+    }
+    struct ethhdr eth = {.h_proto = bpf_htons(ETH_P_IPV6)};
+    if (bpf_skb_store_bytes(skb, 0, &eth, sizeof(eth), 0)) {
+        return TC_ACT_SHOT;
+    }
+    if (bpf_skb_store_bytes(skb, sizeof(eth), &ip6h, sizeof(ip6h), 0)) {
+        return TC_ACT_SHOT;
+    }
+
+    if (iph.protocol == IPPROTO_ICMP) {
+        u8 type = icmp_type == ICMP_ECHO ? ICMPV6_ECHO_REQUEST
+                                         : ICMPV6_ECHO_REPLY;
+        struct ipv6_pseudo_hdr ph = {
+            .saddr = {ip6h.saddr.in6_u.u6_addr32[0],
+                      ip6h.saddr.in6_u.u6_addr32[1],
+                      ip6h.saddr.in6_u.u6_addr32[2],
+                      ip6h.saddr.in6_u.u6_addr32[3]},
+            .daddr = {ip6h.daddr.in6_u.u6_addr32[0],
+                      ip6h.daddr.in6_u.u6_addr32[1],
+                      ip6h.daddr.in6_u.u6_addr32[2],
+                      ip6h.daddr.in6_u.u6_addr32[3]},
+            .len = bpf_htonl(payload_len),
+            .nexthdr = bpf_htonl(NEXTHDR_ICMP),
+        };
+        s64 diff = bpf_csum_diff(NULL, 0, (__be32 *)&ph, sizeof(ph), 0);
+        bpf_l4_csum_replace(skb, csum_off, 0, diff, 0);
+        bpf_l4_csum_replace(skb, csum_off, bpf_htons(icmp_type << 8),
+                            bpf_htons(type << 8), 2);
+        bpf_skb_store_bytes(skb, sizeof(eth) + sizeof(ip6h), &type, 1, 0);
+    } else {
+        __be32 from[2] = {iph.saddr, iph.daddr};
+        s64 diff =
+            bpf_csum_diff(from, sizeof(from), (__be32 *)&ip6h.saddr, 32, 0);
+        bpf_l4_csum_replace(skb, csum_off, 0, diff,
+                            BPF_F_PSEUDO_HDR |
+                                (iph.protocol == IPPROTO_UDP
+                                     ? BPF_F_MARK_MANGLED_0
+                                     : 0));
+    }
+
+    return bpf_redirect_neigh(CLAT_UPLINK_IFINDEX, NULL, 0, 0);
 #undef BPF_LOG_TOPIC
 }
+#endif
 
-static __always_inline int get_is_ipv4(struct __sk_buff *skb, bool *is_ipv4_) {
-    void *data_end = ctx_data_end(skb);
-    void *data = ctx_data(skb);
-    bool is_ipv4;
+// Max length of packet quoted in ICMP error replied by us, covering IPv4
+// header with options or IPv6 header with short extension headers, and first 8
+// bytes of L4 header
+#define ICMP_ERR_QUOTE_MAX 68
+
+// Turns inbound packet into ICMP administratively prohibited error quoting it
+// and sends it back to remote host
+static __always_inline int
+reply_icmp_prohibited(struct __sk_buff *skb, bool is_ipv4,
+                      const struct packet_info *pkt) {
+#define BPF_LOG_TOPIC "reply_icmp_prohibited"
+    // PPPoE session header would have to be rebuilt
+    if (HAS_PPPOE_ENCAP) {
+        return TC_ACT_SHOT;
+    }
+    if (REJECT_RATE_INTERVAL &&
+        !host_rate_check(&map_host_reject_rate, skb->ifindex,
+                         is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG,
+                         &pkt->tuple.saddr, REJECT_RATE_INTERVAL,
+                         REJECT_RATE_BURST)) {
+        bpf_log_debug("remote host exceeded ICMP error rate");
+        __sync_fetch_and_add(&g_icmp_err_rate_limited, 1);
+        return TC_ACT_SHOT;
+    }
+
+    u32 l3_off = TC_SKB_L3_OFF();
+    u32 quote_len = pkt->l4_off - l3_off + 8;
+    if (quote_len < 8 || quote_len > ICMP_ERR_QUOTE_MAX) {
+        return TC_ACT_SHOT;
+    }
+    // ICMP header followed by quoted packet, zero padded for checksum
+    u8 icmp[8 + ICMP_ERR_QUOTE_MAX] = {0};
+    if (bpf_skb_load_bytes(skb, l3_off, icmp + 8, quote_len)) {
+        return TC_ACT_SHOT;
+    }
+
+    struct ethhdr eth;
     if (HAS_ETH_ENCAP) {
-        struct ethhdr *eth = data;
-        if ((void *)(eth + 1) > data_end) {
+        if (bpf_skb_load_bytes(skb, 0, &eth, sizeof(eth))) {
             return TC_ACT_SHOT;
         }
+        u8 smac[6];
+        __builtin_memcpy(smac, eth.h_source, sizeof(smac));
+        __builtin_memcpy(eth.h_source, eth.h_dest, sizeof(smac));
+        __builtin_memcpy(eth.h_dest, smac, sizeof(smac));
+    }
 
-        if (eth->h_proto == bpf_htons(ETH_P_IP)) {
-            is_ipv4 = true;
-#ifdef FEAT_IPV6
-        } else if (eth->h_proto == bpf_htons(ETH_P_IPV6)) {
-            is_ipv4 = false;
-#endif
-        } else {
-            return TC_ACT_UNSPEC;
+    u32 l3_len = is_ipv4 ? sizeof(struct iphdr) : sizeof(struct ipv6hdr);
+    // quoted packet stays in place, with new headers pushed before
+    if (bpf_skb_change_tail(skb, l3_off + quote_len, 0) ||
+        bpf_skb_change_head(skb, l3_len + 8, 0)) {
+        return TC_ACT_SHOT;
+    }
+    if (HAS_ETH_ENCAP &&
+        bpf_skb_store_bytes(skb, 0, &eth, sizeof(eth), BPF_F_RECOMPUTE_CSUM)) {
+        return TC_ACT_SHOT;
+    }
+
+    if (is_ipv4) {
+        struct iphdr iph = {
+            .version = 4,
+            .ihl = 5,
+            .tot_len = bpf_htons(sizeof(struct iphdr) + 8 + quote_len),
+            .ttl = 64,
+            .protocol = IPPROTO_ICMP,
+            .saddr = pkt->tuple.daddr.ip,
+            .daddr = pkt->tuple.saddr.ip,
+        };
+        iph.check =
+            csum_fold(bpf_csum_diff(NULL, 0, (__be32 *)&iph, sizeof(iph), 0));
+        if (bpf_skb_store_bytes(skb, l3_off, &iph, sizeof(iph),
+                                BPF_F_RECOMPUTE_CSUM)) {
+            return TC_ACT_SHOT;
         }
+        icmp[0] = ICMP_DEST_UNREACH;
+        icmp[1] = ICMP_PKT_FILTERED;
+        *(__sum16 *)&icmp[2] = csum_fold(
+            bpf_csum_diff(NULL, 0, (__be32 *)icmp, sizeof(icmp), 0));
     } else {
-        u8 *p_version = data;
-        if ((void *)(p_version + 1) > data_end) {
+#ifdef FEAT_IPV6
+        struct ipv6hdr ip6h = {
+            .version = 6,
+            .payload_len = bpf_htons(8 + quote_len),
+            .nexthdr = NEXTHDR_ICMP,
+            .hop_limit = 64,
+        };
+        COPY_ADDR6(ip6h.saddr.in6_u.u6_addr32, pkt->tuple.daddr.ip6);
+        COPY_ADDR6(ip6h.daddr.in6_u.u6_addr32, pkt->tuple.saddr.ip6);
+        if (bpf_skb_store_bytes(skb, l3_off, &ip6h, sizeof(ip6h),
+                                BPF_F_RECOMPUTE_CSUM)) {
             return TC_ACT_SHOT;
         }
-        u8 version = (*p_version) >> 4;
-        if (version == 4) {
-            is_ipv4 = true;
-#ifdef FEAT_IPV6
-        } else if (version == 6) {
-            is_ipv4 = false;
+        struct ipv6_pseudo_hdr ph = {
+            .len = bpf_htonl(8 + quote_len),
+            .nexthdr = bpf_htonl(NEXTHDR_ICMP),
+        };
+        COPY_ADDR6(ph.saddr, pkt->tuple.daddr.ip6);
+        COPY_ADDR6(ph.daddr, pkt->tuple.saddr.ip6);
+        icmp[0] = ICMPV6_DEST_UNREACH;
+        icmp[1] = ICMPV6_ADM_PROHIBITED;
+        s64 csum = bpf_csum_diff(NULL, 0, (__be32 *)&ph, sizeof(ph), 0);
+        *(__sum16 *)&icmp[2] = csum_fold(
+            bpf_csum_diff(NULL, 0, (__be32 *)icmp, sizeof(icmp), csum));
+#else
+        return TC_ACT_SHOT;
 #endif
-        } else {
-            return TC_ACT_UNSPEC;
-        }
     }
-    *is_ipv4_ = is_ipv4;
-    return TC_ACT_OK;
+    if (bpf_skb_store_bytes(skb, l3_off + l3_len, icmp, 8,
+                            BPF_F_RECOMPUTE_CSUM)) {
+        return TC_ACT_SHOT;
+    }
+
+    __sync_fetch_and_add(&g_icmp_err_generated, 1);
+    return bpf_redirect(skb->ifindex, 0);
+#undef BPF_LOG_TOPIC
 }
 
-// Ensure we are using PKT_IS_IPV4()
-#undef IS_IPV4
+// Whether inbound TCP or UDP packet belongs to a socket of this host, either
+// listening or of flows initiated by this host, e.g. replies to ephemeral ports
+static __always_inline bool is_local_socket_pkt(struct __sk_buff *skb,
+                                                const struct packet_info *pkt) {
+    struct bpf_sock_tuple tuple = {0};
+    u32 tuple_len;
+    if (IS_IPV4(pkt)) {
+        tuple.ipv4.saddr = pkt->tuple.saddr.ip;
+        tuple.ipv4.daddr = pkt->tuple.daddr.ip;
+        tuple.ipv4.sport = pkt->tuple.sport;
+        tuple.ipv4.dport = pkt->tuple.dport;
+        tuple_len = sizeof(tuple.ipv4);
+    } else {
 #ifdef FEAT_IPV6
-#define PKT_IS_IPV4() (is_ipv4)
+        COPY_ADDR6(tuple.ipv6.saddr, pkt->tuple.saddr.ip6);
+        COPY_ADDR6(tuple.ipv6.daddr, pkt->tuple.daddr.ip6);
+        tuple.ipv6.sport = pkt->tuple.sport;
+        tuple.ipv6.dport = pkt->tuple.dport;
+        tuple_len = sizeof(tuple.ipv6);
 #else
-#define PKT_IS_IPV4() (true)
+        return false;
 #endif
+    }
 
-SEC("tc") int ingress_rev_snat(struct __sk_buff *skb) {
-#define BPF_LOG_TOPIC "ingress<=="
-    int ret;
-    // XXX: separate out IPV4 and IPV6 outer branches and dispatch with tail
-    // call to further reduce complexity
-    // Also somehow use a separate is_ipv4 variable reduce complexity greatly..
-    bool is_ipv4;
-    ret = get_is_ipv4(skb, &is_ipv4);
-    if (ret != TC_ACT_OK) {
-        return ret;
+    struct bpf_sock *sk;
+    if (pkt->nexthdr == IPPROTO_TCP) {
+        sk = bpf_skc_lookup_tcp(skb, &tuple, tuple_len, BPF_F_CURRENT_NETNS,
+                                0);
+    } else {
+        sk = bpf_sk_lookup_udp(skb, &tuple, tuple_len, BPF_F_CURRENT_NETNS,
+                               0);
     }
+    if (!sk) {
+        return false;
+    }
+    bpf_sk_release(sk);
+    return true;
+}
 
-#ifdef FEAT_IPV6
-    barrier_var(is_ipv4);
-    if (is_ipv4 && !INGRESS_IPV4 || !is_ipv4 && !INGRESS_IPV6) {
+// Verdict of inbound packet to external address that is not for NAT. Only TCP
+// and UDP packets not belonging to any local socket are dropped, ICMP is passed
+// for queries and errors of this host.
+static __always_inline int
+ingress_unsolicited(struct __sk_buff *skb,
+                    const struct external_config *ext_config,
+                    const struct packet_info *pkt) {
+    if (!DROP_UNSOLICITED || is_icmpx_error_pkt(pkt) ||
+        (pkt->nexthdr != IPPROTO_TCP && pkt->nexthdr != IPPROTO_UDP) ||
+        (exclude_local_ports(ext_config, pkt->nexthdr) &&
+         is_local_port(pkt->nexthdr, pkt->tuple.dport)) ||
+        is_local_socket_pkt(skb, pkt)) {
         return TC_ACT_UNSPEC;
     }
-#else
-    if (!INGRESS_IPV4) {
+    __sync_fetch_and_add(&g_unsolicited_dropped, 1);
+    return TC_ACT_SHOT;
+}
+
+static __always_inline int ingress_rev_snat_family(struct __sk_buff *skb,
+                                                   bool is_ipv4) {
+#define BPF_LOG_TOPIC "ingress<=="
+#define TRACE_IS_INGRESS true
+    int ret;
+
+    // rejected by egress, to be replied by kernel
+    if (REJECT_FWMARK && skb->mark == REJECT_FWMARK) {
         return TC_ACT_UNSPEC;
     }
-#endif
 
     // XXX: just use local variables instead
     struct packet_info pkt;
@@ -1759,23 +4592,45 @@ SEC("tc") int ingress_rev_snat(struct __sk_buff *skb) {
     if (ret != TC_ACT_OK) {
         if (ret == TC_ACT_SHOT) {
             bpf_log_trace("invalid packet");
+            if (DROP_MALFORMED_ICMP_ERR &&
+                pkt.err_l4_off == ICMP_ERR_MALFORMED) {
+                return TC_ACT_SHOT;
+            }
+        } else if (PPTP && PKT_IS_IPV4() &&
+                   (ret = pptp_gre_translate(skb, TRACE_IS_INGRESS)) !=
+                       TC_ACT_OK) {
+            return ret;
+        } else if (ESP_PASSTHROUGH && PKT_IS_IPV4() &&
+                   (ret = esp_translate(skb, TRACE_IS_INGRESS)) != TC_ACT_OK) {
+            return ret;
+        } else if (ADDR_BINDINGS) {
+            return addr_binding_translate(skb, PKT_IS_IPV4(), TRACE_IS_INGRESS);
         }
         return TC_ACT_UNSPEC;
     }
 
+    bool do_trace =
+        trace_match(PKT_IS_IPV4(), pkt.nexthdr, &pkt.tuple, NULL, 0);
+    TRACE_EVENT(TRACE_PARSE, 0, TRACE_R_NONE, NULL, 0);
+
     struct external_config *ext_config =
         lookup_external_config(PKT_IS_IPV4(), &pkt.tuple.daddr);
-    if ((ret = nat_check_external_config(ext_config)) != TC_ACT_OK) {
-        return ret;
-    }
+    // ports are kept by static 1:1 NAT so no fragment tracking is needed
+    bool is_one_to_one = external_one_to_one(ext_config);
+    if (!is_one_to_one) {
+        if ((ret = nat_check_external_config(ext_config)) != TC_ACT_OK) {
+            TRACE_RETURN(ret, TRACE_R_NOT_EXTERNAL);
+        }
 
-    if ((ret = fragment_track(skb, &pkt, 0)) != TC_ACT_OK) {
-        return ret;
-    }
+        if ((ret = fragment_track(skb, &pkt, 0)) != TC_ACT_OK) {
+            TRACE_RETURN(ret, TRACE_R_FRAGMENT);
+        }
 
-    if (!nat_in_binding_range(ext_config, pkt.nexthdr,
-                              bpf_ntohs(pkt.tuple.dport))) {
-        return TC_ACT_UNSPEC;
+        if (!nat_in_binding_range(ext_config, pkt.nexthdr,
+                                  bpf_ntohs(pkt.tuple.dport))) {
+            TRACE_RETURN(ingress_unsolicited(skb, ext_config, &pkt),
+                         TRACE_R_OUT_OF_RANGE);
+        }
     }
 
     bool is_icmpx_error = is_icmpx_error_pkt(&pkt);
@@ -1783,29 +4638,79 @@ SEC("tc") int ingress_rev_snat(struct __sk_buff *skb) {
                               !is_icmpx_error && is_icmpx(pkt.nexthdr);
 
     struct map_binding_value *b_value_rev;
-    ret = ingress_lookup_or_new_binding(skb->ifindex, PKT_IS_IPV4(), ext_config,
-                                        pkt.nexthdr, do_inbound_binding,
-                                        &pkt.tuple, &b_value_rev);
+    if (is_one_to_one) {
+        b_value_rev = lookup_addr_binding(skb->ifindex, PKT_IS_IPV4(), false,
+                                          &pkt.tuple.daddr);
+        ret = b_value_rev ? TC_ACT_OK : TC_ACT_UNSPEC;
+    } else {
+        ret = ingress_lookup_or_new_binding(
+            skb->ifindex, PKT_IS_IPV4(), ext_config, pkt.nexthdr,
+            do_inbound_binding, &pkt.tuple, &b_value_rev);
+    }
+    if (ret == TC_ACT_SHOT && !is_one_to_one &&
+        exclude_local_ports(ext_config, pkt.nexthdr) &&
+        is_local_port(pkt.nexthdr, pkt.tuple.dport)) {
+        // not shadowing local service
+        ret = TC_ACT_UNSPEC;
+    }
+    bool is_dmz = false;
+    if (ret == TC_ACT_SHOT && DMZ_HOST && DMZ_HOST_IPV4 == PKT_IS_IPV4() &&
+        !is_one_to_one && !g_deleting_map_entries && !is_icmpx(pkt.nexthdr) &&
+        pkt_allow_initiating_ct(pkt.pkt_type)) {
+        ret = ingress_new_dmz_binding(skb->ifindex, PKT_IS_IPV4(),
+                                      pkt.nexthdr, &pkt.tuple, &b_value_rev);
+        is_dmz = ret == TC_ACT_OK;
+    }
     if (ret == TC_ACT_UNSPEC) {
-        return TC_ACT_UNSPEC;
+        TRACE_EVENT(TRACE_BINDING, ret, TRACE_R_NONE, NULL, 0);
+        TRACE_RETURN(is_one_to_one
+                         ? ingress_unsolicited(skb, ext_config, &pkt)
+                         : TC_ACT_UNSPEC,
+                     TRACE_R_NO_BINDING);
     } else if (ret != TC_ACT_OK) {
+        // binding lookup only fails for no binding if not initiating one
+        TRACE_EVENT(TRACE_BINDING, do_inbound_binding ? ret : TC_ACT_UNSPEC,
+                    TRACE_R_NONE, NULL, 0);
         // XXX: no free port, send back ICMP network unreachable
-        return TC_ACT_SHOT;
+        TRACE_RETURN(TC_ACT_SHOT, do_inbound_binding ? TRACE_R_BINDING_FAILED
+                                                     : TRACE_R_NO_BINDING);
+    }
+
+    __be16 to_port = is_one_to_one ? pkt.tuple.dport : b_value_rev->to_port;
+    // match translated destination, i.e. internal endpoint, as well
+    if (!do_trace && trace_match(PKT_IS_IPV4(), pkt.nexthdr, &pkt.tuple,
+                                 &b_value_rev->to_addr, to_port)) {
+        do_trace = true;
+        TRACE_EVENT(TRACE_PARSE, 0, TRACE_R_NONE, NULL, 0);
     }
+    TRACE_EVENT(TRACE_BINDING, ret, TRACE_R_NONE, &b_value_rev->to_addr,
+                to_port);
 
     if (!b_value_rev->is_static) {
         bool do_inbound_ct =
             !g_deleting_map_entries && !is_icmpx_error &&
-            ((b_value_rev->use != 0 && pkt_allow_initiating_ct(pkt.pkt_type)) ||
+            ((b_value_rev->use != 0 && pkt_allow_initiating_ct(pkt.pkt_type) &&
+              nat_filter_allow_inbound(skb->ifindex, PKT_IS_IPV4(),
+                                       pkt.nexthdr, ext_config, &pkt.tuple)) ||
              (do_inbound_binding &&
-              inet_addr_equal(&b_value_rev->to_addr, &pkt.tuple.daddr)));
+              inet_addr_equal(&b_value_rev->to_addr, &pkt.tuple.daddr)) ||
+             is_dmz);
 
         struct map_ct_value *ct_value;
         ret = ingress_lookup_or_new_ct(skb->ifindex, PKT_IS_IPV4(), pkt.nexthdr,
                                        do_inbound_ct, &pkt.tuple, b_value_rev,
                                        &ct_value);
-        if (ret == LK_CT_NONE || ret == LK_CT_ERROR_NEW) {
-            return TC_ACT_SHOT;
+        TRACE_EVENT(TRACE_CT, ret, TRACE_R_NONE, NULL, 0);
+        if (ret == LK_CT_NONE && !is_icmpx_error &&
+            pkt.pkt_type == PKT_TCP_RST &&
+            UNMATCHED_TCP_RST != UNMATCHED_RST_DROP) {
+            if (UNMATCHED_TCP_RST == UNMATCHED_RST_REPLY) {
+                TRACE_RETURN(reply_icmp_prohibited(skb, PKT_IS_IPV4(), &pkt),
+                             TRACE_R_NO_CT);
+            }
+            // otherwise translated without CT
+        } else if (ret == LK_CT_NONE || ret == LK_CT_ERROR_NEW) {
+            TRACE_RETURN(TC_ACT_SHOT, TRACE_R_NO_CT);
         }
         if (!is_icmpx_error && ret == LK_CT_EXIST) {
             ct_state_transition(skb->ifindex, pkt.nexthdr, pkt.pkt_type, false,
@@ -1813,24 +4718,60 @@ SEC("tc") int ingress_rev_snat(struct __sk_buff *skb) {
         }
     }
 
+    bool do_capture =
+        capture_match(PKT_IS_IPV4(), pkt.nexthdr, &b_value_rev->to_addr);
+    if (do_capture) {
+        capture_packet(skb, CAPTURE_F_INGRESS);
+    }
+
+    __sum16 orig_csum = 0;
+    if (CSUM_OFFLOAD_COMPAT && !is_icmpx_error) {
+        orig_csum = csum_offloaded_load(skb, &pkt);
+    }
+
     // modify dest
     ret = modify_headers(skb, PKT_IS_IPV4(), is_icmpx_error, pkt.nexthdr,
                          TC_SKB_L3_OFF(), pkt.l4_off, pkt.err_l4_off, false,
                          &pkt.tuple.daddr, pkt.tuple.dport,
-                         &b_value_rev->to_addr, b_value_rev->to_port);
+                         &b_value_rev->to_addr, to_port);
     if (ret) {
         bpf_log_error("failed to update csum, err:%d", ret);
-        return TC_ACT_SHOT;
+        TRACE_RETURN(TC_ACT_SHOT, TRACE_R_REWRITE_FAILED);
+    }
+    if (PPTP && PKT_IS_IPV4() && !is_icmpx_error &&
+        pkt.nexthdr == IPPROTO_TCP &&
+        pkt.tuple.sport == bpf_htons(PPTP_CTRL_PORT) &&
+        pptp_ctrl_translate(skb, pkt.l4_off, false, b_value_rev->to_addr.ip,
+                            pkt.tuple.daddr.ip, pkt.tuple.saddr.ip)) {
+        TRACE_RETURN(TC_ACT_SHOT, TRACE_R_REWRITE_FAILED);
+    }
+    if (CSUM_OFFLOAD_COMPAT && !is_icmpx_error &&
+        csum_offloaded_fix(skb, &pkt, orig_csum, &pkt.tuple.saddr,
+                           &b_value_rev->to_addr)) {
+        TRACE_RETURN(TC_ACT_SHOT, TRACE_R_REWRITE_FAILED);
+    }
+    if (INGRESS_DSCP != DSCP_KEEP &&
+        rewrite_dscp(skb, PKT_IS_IPV4(), INGRESS_DSCP)) {
+        TRACE_RETURN(TC_ACT_SHOT, TRACE_R_REWRITE_FAILED);
     }
 
-    return TC_ACT_UNSPEC;
+    if (do_capture) {
+        capture_packet(skb, CAPTURE_F_INGRESS | CAPTURE_F_TRANSLATED);
+    }
+
+    TRACE_RETURN(TC_ACT_UNSPEC, TRACE_R_TRANSLATED);
+#undef TRACE_IS_INGRESS
 #undef BPF_LOG_TOPIC
 }
 
-SEC("tc")
-int egress_snat(struct __sk_buff *skb) {
-#define BPF_LOG_TOPIC "egress ==>"
+SEC("tc") int ingress_rev_snat(struct __sk_buff *skb) {
+    if (is_bypassed(skb)) {
+        return TC_ACT_UNSPEC;
+    }
     int ret;
+    // XXX: separate out IPV4 and IPV6 outer branches and dispatch with tail
+    // call to further reduce complexity
+    // Also somehow use a separate is_ipv4 variable reduce complexity greatly..
     bool is_ipv4;
     ret = get_is_ipv4(skb, &is_ipv4);
     if (ret != TC_ACT_OK) {
@@ -1839,14 +4780,227 @@ int egress_snat(struct __sk_buff *skb) {
 
 #ifdef FEAT_IPV6
     barrier_var(is_ipv4);
-    if (is_ipv4 && !EGRESS_IPV4 || !is_ipv4 && !EGRESS_IPV6) {
+    if (NPTV6 && !is_ipv4 && (ret = npt_translate(skb, true)) != TC_ACT_OK) {
+        return ret;
+    }
+    if (NETMAP && is_ipv4 &&
+        (ret = netmap_translate(skb, true)) != TC_ACT_OK) {
+        return ret;
+    }
+    if (is_ipv4 && !INGRESS_IPV4 || !is_ipv4 && !INGRESS_IPV6) {
+        return TC_ACT_UNSPEC;
+    }
+#else
+    if (NETMAP && (ret = netmap_translate(skb, true)) != TC_ACT_OK) {
+        return ret;
+    }
+    if (!INGRESS_IPV4) {
+        return TC_ACT_UNSPEC;
+    }
+#endif
+
+    return ingress_rev_snat_family(skb, is_ipv4);
+}
+
+#ifdef FEAT_IPV6
+// Single address family variants of ingress_rev_snat, loaded instead if only
+// one address family is enabled, with code paths of the other address family
+// compiled out to cut verification time
+SEC("tc")
+int ingress_rev_snat_v4(struct __sk_buff *skb) {
+    if (is_bypassed(skb)) {
+        return TC_ACT_UNSPEC;
+    }
+    bool is_ipv4;
+    int ret = get_is_ipv4(skb, &is_ipv4);
+    if (ret != TC_ACT_OK) {
+        return ret;
+    }
+    if (!is_ipv4) {
+        return TC_ACT_UNSPEC;
+    }
+    if (NETMAP && (ret = netmap_translate(skb, true)) != TC_ACT_OK) {
+        return ret;
+    }
+    if (!INGRESS_IPV4) {
+        return TC_ACT_UNSPEC;
+    }
+    return ingress_rev_snat_family(skb, true);
+}
+
+SEC("tc")
+int ingress_rev_snat_v6(struct __sk_buff *skb) {
+    if (is_bypassed(skb)) {
+        return TC_ACT_UNSPEC;
+    }
+    bool is_ipv4;
+    int ret = get_is_ipv4(skb, &is_ipv4);
+    if (ret != TC_ACT_OK) {
+        return ret;
+    }
+    if (is_ipv4) {
+        return TC_ACT_UNSPEC;
+    }
+    if (NPTV6 && (ret = npt_translate(skb, true)) != TC_ACT_OK) {
+        return ret;
+    }
+    if (!INGRESS_IPV6) {
         return TC_ACT_UNSPEC;
     }
+    return ingress_rev_snat_family(skb, false);
+}
+#endif
+
+static __always_inline int reject_new_flow(struct __sk_buff *skb,
+                                           bool is_ipv4,
+                                           const union u_inet_addr *saddr) {
+#define BPF_LOG_TOPIC "reject_new_flow"
+    // PPPoE session header would have to be stripped, and packets already
+    // rejected are back if the IP rule is missing
+    if (!REJECT_FWMARK || HAS_PPPOE_ENCAP || skb->mark == REJECT_FWMARK) {
+        return TC_ACT_SHOT;
+    }
+    if (REJECT_RATE_INTERVAL &&
+        !host_rate_check(&map_host_reject_rate, skb->ifindex,
+                         is_ipv4 ? ADDR_IPV4_FLAG : ADDR_IPV6_FLAG, saddr,
+                         REJECT_RATE_INTERVAL, REJECT_RATE_BURST)) {
+        bpf_log_debug("host exceeded ICMP error rate");
+        __sync_fetch_and_add(&g_icmp_err_rate_limited, 1);
+        return TC_ACT_SHOT;
+    }
+    if (HAS_ETH_ENCAP) {
+        void *data_end = ctx_data_end(skb);
+        struct ethhdr *eth = ctx_data(skb);
+        if ((void *)(eth + 1) > data_end) {
+            return TC_ACT_SHOT;
+        }
+        u8 smac[6];
+        __builtin_memcpy(smac, eth->h_source, sizeof(smac));
+        __builtin_memcpy(eth->h_source, eth->h_dest, sizeof(smac));
+        __builtin_memcpy(eth->h_dest, smac, sizeof(smac));
+    }
+    // keep compiler from merging stores of inlined copies into a store to
+    // modified ctx pointer, which is rejected by verifier
+    barrier_var(skb);
+    skb->mark = REJECT_FWMARK;
+    __sync_fetch_and_add(&g_icmp_err_generated, 1);
+    return bpf_redirect(skb->ifindex, BPF_F_INGRESS);
+#undef BPF_LOG_TOPIC
+}
+
+// Replies SYN of internal host with SYN-ACK of zero window, which is not
+// tracked, so the host holds the connection in persist state instead of
+// retrying SYNs, like TARPIT target of xtables-addons
+static __always_inline int tarpit_syn(struct __sk_buff *skb, bool is_ipv4,
+                                      const struct packet_info *pkt) {
+#define BPF_LOG_TOPIC "tarpit_syn"
+    // PPPoE session header would have to be rebuilt
+    if (HAS_PPPOE_ENCAP) {
+        return TC_ACT_SHOT;
+    }
+    u32 l3_off = TC_SKB_L3_OFF();
+    u32 l3_len = is_ipv4 ? sizeof(struct iphdr) : sizeof(struct ipv6hdr);
+    // IP options or IPv6 extension headers are not worth handling
+    if (pkt->l4_off != l3_off + l3_len) {
+        return TC_ACT_SHOT;
+    }
+
+    struct tcphdr syn;
+    if (bpf_skb_load_bytes(skb, pkt->l4_off, &syn, sizeof(syn))) {
+        return TC_ACT_SHOT;
+    }
+    struct tcphdr synack = {
+        .source = syn.dest,
+        .dest = syn.source,
+        .seq = bpf_get_prandom_u32(),
+        .ack_seq = bpf_htonl(bpf_ntohl(syn.seq) + 1),
+        .doff = sizeof(synack) / 4,
+        .syn = 1,
+        .ack = 1,
+        .window = 0,
+    };
+
+    struct ethhdr eth;
+    if (HAS_ETH_ENCAP) {
+        if (bpf_skb_load_bytes(skb, 0, &eth, sizeof(eth))) {
+            return TC_ACT_SHOT;
+        }
+        u8 smac[6];
+        __builtin_memcpy(smac, eth.h_source, sizeof(smac));
+        __builtin_memcpy(eth.h_source, eth.h_dest, sizeof(smac));
+        __builtin_memcpy(eth.h_dest, smac, sizeof(smac));
+    }
+    if (bpf_skb_change_tail(skb, pkt->l4_off + sizeof(synack), 0)) {
+        return TC_ACT_SHOT;
+    }
+    if (HAS_ETH_ENCAP &&
+        bpf_skb_store_bytes(skb, 0, &eth, sizeof(eth), BPF_F_RECOMPUTE_CSUM)) {
+        return TC_ACT_SHOT;
+    }
+
+    s64 csum;
+    if (is_ipv4) {
+        struct iphdr iph = {
+            .version = 4,
+            .ihl = 5,
+            .tot_len = bpf_htons(sizeof(struct iphdr) + sizeof(synack)),
+            .ttl = 64,
+            .protocol = IPPROTO_TCP,
+            .saddr = pkt->tuple.daddr.ip,
+            .daddr = pkt->tuple.saddr.ip,
+        };
+        iph.check =
+            csum_fold(bpf_csum_diff(NULL, 0, (__be32 *)&iph, sizeof(iph), 0));
+        if (bpf_skb_store_bytes(skb, l3_off, &iph, sizeof(iph),
+                                BPF_F_RECOMPUTE_CSUM)) {
+            return TC_ACT_SHOT;
+        }
+        __be32 ph[3] = {iph.saddr, iph.daddr,
+                        bpf_htonl(IPPROTO_TCP << 16 | sizeof(synack))};
+        csum = bpf_csum_diff(NULL, 0, ph, sizeof(ph), 0);
+    } else {
+#ifdef FEAT_IPV6
+        struct ipv6hdr ip6h = {
+            .version = 6,
+            .payload_len = bpf_htons(sizeof(synack)),
+            .nexthdr = IPPROTO_TCP,
+            .hop_limit = 64,
+        };
+        COPY_ADDR6(ip6h.saddr.in6_u.u6_addr32, pkt->tuple.daddr.ip6);
+        COPY_ADDR6(ip6h.daddr.in6_u.u6_addr32, pkt->tuple.saddr.ip6);
+        if (bpf_skb_store_bytes(skb, l3_off, &ip6h, sizeof(ip6h),
+                                BPF_F_RECOMPUTE_CSUM)) {
+            return TC_ACT_SHOT;
+        }
+        struct ipv6_pseudo_hdr ph = {
+            .len = bpf_htonl(sizeof(synack)),
+            .nexthdr = bpf_htonl(IPPROTO_TCP),
+        };
+        COPY_ADDR6(ph.saddr, pkt->tuple.daddr.ip6);
+        COPY_ADDR6(ph.daddr, pkt->tuple.saddr.ip6);
+        csum = bpf_csum_diff(NULL, 0, (__be32 *)&ph, sizeof(ph), 0);
 #else
-    if (!EGRESS_IPV4) {
-        return TC_ACT_UNSPEC;
-    }
+        return TC_ACT_SHOT;
 #endif
+    }
+    synack.check = csum_fold(
+        bpf_csum_diff(NULL, 0, (__be32 *)&synack, sizeof(synack), csum));
+    if (bpf_skb_store_bytes(skb, pkt->l4_off, &synack, sizeof(synack),
+                            BPF_F_RECOMPUTE_CSUM)) {
+        return TC_ACT_SHOT;
+    }
+
+    bpf_log_debug("tarpit SYN");
+    // routed back to internal host by kernel
+    return bpf_redirect(skb->ifindex, BPF_F_INGRESS);
+#undef BPF_LOG_TOPIC
+}
+
+static __always_inline int egress_snat_family(struct __sk_buff *skb,
+                                              bool is_ipv4) {
+#define BPF_LOG_TOPIC "egress ==>"
+#define TRACE_IS_INGRESS false
+    int ret;
 
     // XXX: just use local variables instead
     struct packet_info pkt;
@@ -1854,15 +5008,38 @@ int egress_snat(struct __sk_buff *skb) {
     if (ret != TC_ACT_OK) {
         if (ret == TC_ACT_SHOT) {
             bpf_log_trace("invalid packet");
+            if (DROP_MALFORMED_ICMP_ERR &&
+                pkt.err_l4_off == ICMP_ERR_MALFORMED) {
+                return TC_ACT_SHOT;
+            }
+        } else if (PPTP && PKT_IS_IPV4() &&
+                   (ret = pptp_gre_translate(skb, TRACE_IS_INGRESS)) !=
+                       TC_ACT_OK) {
+            return ret;
+        } else if (ESP_PASSTHROUGH && PKT_IS_IPV4() &&
+                   (ret = esp_translate(skb, TRACE_IS_INGRESS)) != TC_ACT_OK) {
+            return ret;
+        } else if (ADDR_BINDINGS) {
+            return addr_binding_translate(skb, PKT_IS_IPV4(), TRACE_IS_INGRESS);
         }
         return TC_ACT_UNSPEC;
     }
 
+    bool do_trace =
+        trace_match(PKT_IS_IPV4(), pkt.nexthdr, &pkt.tuple, NULL, 0);
+    TRACE_EVENT(TRACE_PARSE, 0, TRACE_R_NONE, NULL, 0);
+    // reason of passing the packet on if not translated
+    u8 reason = TRACE_R_NONE;
+
     bool do_hairpin = false;
     bool pass_nat = false;
     struct dest_config *dest_config =
         lookup_dest_config(PKT_IS_IPV4(), &pkt.tuple.daddr);
     if (dest_config) {
+        if (dest_blocked(dest_config)) {
+            __sync_fetch_and_add(&g_dest_blocked, 1);
+            TRACE_RETURN(TC_ACT_SHOT, TRACE_R_DEST_BLOCKED);
+        }
         do_hairpin = dest_hairpin(dest_config);
         pass_nat = dest_pass_nat(dest_config);
     }
@@ -1871,56 +5048,93 @@ int egress_snat(struct __sk_buff *skb) {
         lookup_external_config(PKT_IS_IPV4(), &pkt.tuple.saddr);
     if (ext_config) { // this packet was send from local NAT host
         if (external_pass_nat(ext_config)) {
+            reason = TRACE_R_NOT_EXTERNAL;
             goto check_hairpin;
         }
-    } else if (pass_nat) {
+    } else if (pass_nat ||
+               is_no_snat_source(PKT_IS_IPV4(), &pkt.tuple.saddr) ||
+               is_no_snat_dport(pkt.nexthdr, pkt.tuple.dport)) {
+        reason = TRACE_R_PASS_NAT;
         goto check_hairpin;
     }
 
-    if ((ret = fragment_track(skb, &pkt, FRAG_TRACK_EGRESS_FLAG)) !=
-        TC_ACT_OK) {
-        if (ret == TC_ACT_UNSPEC) {
-            goto check_hairpin;
-        }
-        return TC_ACT_SHOT;
-    }
+    bool is_icmpx_error = is_icmpx_error_pkt(&pkt);
+    bool do_new = !g_deleting_map_entries && !is_icmpx_error &&
+                  pkt_allow_initiating_ct(pkt.pkt_type);
 
-    if (ext_config) {
-        if (!nat_in_binding_range(ext_config, pkt.nexthdr,
-                                  bpf_ntohs(pkt.tuple.sport))) {
-            goto check_hairpin;
+    struct map_binding_value *b_value_orig = NULL, *b_value_rev = NULL;
+    if (ADDR_BINDINGS) {
+        b_value_orig = lookup_addr_binding(skb->ifindex, PKT_IS_IPV4(), true,
+                                           &pkt.tuple.saddr);
+    }
+    // ports are kept by static 1:1 NAT so no fragment tracking is needed
+    bool is_one_to_one = b_value_orig != NULL;
+    if (!is_one_to_one) {
+        if ((ret = fragment_track(skb, &pkt, FRAG_TRACK_EGRESS_FLAG)) !=
+            TC_ACT_OK) {
+            if (ret == TC_ACT_UNSPEC) {
+                reason = TRACE_R_FRAGMENT;
+                goto check_hairpin;
+            }
+            TRACE_RETURN(TC_ACT_SHOT, TRACE_R_FRAGMENT);
         }
 
-        // SNAT from external IP to itself, i.e. do
-        // binding of
-        // <external IP>:<host port> -> <external IP>:<external port>.
-        //
-        // Note ICMP query ID remapping for external IP is always needed as
-        // Linux allows setting arbitrary ICMP ID which could causing
-        // collision with ICMP ID binding of other internal source.
-    }
+        if (ext_config) {
+            if (!nat_in_binding_range(ext_config, pkt.nexthdr,
+                                      bpf_ntohs(pkt.tuple.sport))) {
+                reason = TRACE_R_OUT_OF_RANGE;
+                goto check_hairpin;
+            }
 
-    bool is_icmpx_error = is_icmpx_error_pkt(&pkt);
-    bool do_new = !g_deleting_map_entries && !is_icmpx_error &&
-                  pkt_allow_initiating_ct(pkt.pkt_type);
+            // SNAT from external IP to itself, i.e. do
+            // binding of
+            // <external IP>:<host port> -> <external IP>:<external port>.
+            //
+            // Note ICMP query ID remapping for external IP is always needed
+            // as Linux allows setting arbitrary ICMP ID which could causing
+            // collision with ICMP ID binding of other internal source.
+        }
 
-    struct map_binding_value *b_value_orig, *b_value_rev;
-    ret = egress_lookup_or_new_binding(skb, PKT_IS_IPV4(), pkt.nexthdr, do_new,
-                                       &pkt.tuple, &b_value_orig, &b_value_rev);
-    if (ret == TC_ACT_UNSPEC) {
-        goto check_hairpin;
-    } else if (ret != TC_ACT_OK) {
-        // XXX: no free port, send back ICMP network unreachable
-        return TC_ACT_SHOT;
+        ret = egress_lookup_or_new_binding(skb, PKT_IS_IPV4(), pkt.nexthdr,
+                                           do_new, &pkt.tuple, &b_value_orig,
+                                           &b_value_rev);
+        // or ICMP error of NAT host itself, e.g. replied by
+        // reply_icmp_prohibited(), about packet sent to external address
+        if (ret == TC_ACT_UNSPEC ||
+            (ret == TC_ACT_SHOT && is_icmpx_error && ext_config)) {
+            TRACE_EVENT(TRACE_BINDING, ret, TRACE_R_NONE, NULL, 0);
+            reason = TRACE_R_NO_BINDING;
+            goto check_hairpin;
+        } else if (ret != TC_ACT_OK) {
+            TRACE_EVENT(TRACE_BINDING, ret, TRACE_R_NONE, NULL, 0);
+            TRACE_RETURN(do_new ? reject_new_flow(skb, PKT_IS_IPV4(),
+                                                  &pkt.tuple.saddr)
+                                : TC_ACT_SHOT,
+                         TRACE_R_BINDING_FAILED);
+        }
     }
+    __be16 to_port = is_one_to_one ? pkt.tuple.sport : b_value_orig->to_port;
+    TRACE_EVENT(TRACE_BINDING, ret, TRACE_R_NONE, &b_value_orig->to_addr,
+                to_port);
 
     if (!b_value_orig->is_static) {
         struct map_ct_value *ct_value;
         ret = egress_lookup_or_new_ct(skb->ifindex, PKT_IS_IPV4(), pkt.nexthdr,
                                       do_new, &pkt.tuple, b_value_orig,
                                       b_value_rev, &ct_value);
-        if (ret == LK_CT_NONE || ret == LK_CT_ERROR_NEW) {
-            return TC_ACT_SHOT;
+        TRACE_EVENT(TRACE_CT, ret, TRACE_R_NONE, NULL, 0);
+        if (ret == LK_CT_ERROR_NEW) {
+            TRACE_RETURN(
+                reject_new_flow(skb, PKT_IS_IPV4(), &pkt.tuple.saddr),
+                TRACE_R_NO_CT);
+        } else if (ret == LK_CT_ERROR_EMBRYONIC) {
+            __sync_fetch_and_add(&g_embryonic_limited, 1);
+            TRACE_RETURN(EMBRYONIC_TARPIT
+                             ? tarpit_syn(skb, PKT_IS_IPV4(), &pkt)
+                             : TC_ACT_SHOT,
+                         TRACE_R_NO_CT);
+        } else if (ret == LK_CT_NONE) {
+            TRACE_RETURN(TC_ACT_SHOT, TRACE_R_NO_CT);
         }
         if (!is_icmpx_error && ret == LK_CT_EXIST) {
             ct_state_transition(skb->ifindex, pkt.nexthdr, pkt.pkt_type, true,
@@ -1928,26 +5142,58 @@ int egress_snat(struct __sk_buff *skb) {
         }
     }
 
+    bool do_capture =
+        capture_match(PKT_IS_IPV4(), pkt.nexthdr, &pkt.tuple.saddr);
+    if (do_capture) {
+        capture_packet(skb, 0);
+    }
+
+    __sum16 orig_csum = 0;
+    if (CSUM_OFFLOAD_COMPAT && !is_icmpx_error) {
+        orig_csum = csum_offloaded_load(skb, &pkt);
+    }
+
     // modify source
     ret = modify_headers(skb, PKT_IS_IPV4(), is_icmpx_error, pkt.nexthdr,
                          TC_SKB_L3_OFF(), pkt.l4_off, pkt.err_l4_off, true,
                          &pkt.tuple.saddr, pkt.tuple.sport,
-                         &b_value_orig->to_addr, b_value_orig->to_port);
+                         &b_value_orig->to_addr, to_port);
     if (ret) {
         bpf_log_error("failed to update csum, err:%d", ret);
-        return TC_ACT_SHOT;
+        TRACE_RETURN(TC_ACT_SHOT, TRACE_R_REWRITE_FAILED);
+    }
+    if (PPTP && PKT_IS_IPV4() && !is_icmpx_error &&
+        pkt.nexthdr == IPPROTO_TCP &&
+        pkt.tuple.dport == bpf_htons(PPTP_CTRL_PORT) &&
+        pptp_ctrl_translate(skb, pkt.l4_off, true, pkt.tuple.saddr.ip,
+                            b_value_orig->to_addr.ip, pkt.tuple.daddr.ip)) {
+        TRACE_RETURN(TC_ACT_SHOT, TRACE_R_REWRITE_FAILED);
+    }
+    if (CSUM_OFFLOAD_COMPAT && !is_icmpx_error &&
+        csum_offloaded_fix(skb, &pkt, orig_csum, &b_value_orig->to_addr,
+                           &pkt.tuple.daddr)) {
+        TRACE_RETURN(TC_ACT_SHOT, TRACE_R_REWRITE_FAILED);
     }
+    if (EGRESS_DSCP != DSCP_KEEP &&
+        rewrite_dscp(skb, PKT_IS_IPV4(), EGRESS_DSCP)) {
+        TRACE_RETURN(TC_ACT_SHOT, TRACE_R_REWRITE_FAILED);
+    }
+
+    if (do_capture) {
+        capture_packet(skb, CAPTURE_F_TRANSLATED);
+    }
+    reason = TRACE_R_TRANSLATED;
 
 check_hairpin:
     if (!do_hairpin) {
-        return TC_ACT_UNSPEC;
+        TRACE_RETURN(TC_ACT_UNSPEC, reason);
     }
 
     if (HAS_ETH_ENCAP) {
         void *data_end = ctx_data_end(skb);
         struct ethhdr *eth = ctx_data(skb);
         if ((void *)(eth + 1) > data_end) {
-            return TC_ACT_SHOT;
+            TRACE_RETURN(TC_ACT_SHOT, TRACE_R_HAIRPIN);
         }
         // somehow printk MAC format token "%pM" does not work in BPF
         bpf_log_trace("hairpin smac: %x:%x:%x:%x:%x:%x", eth->h_source[0],
@@ -1965,8 +5211,556 @@ check_hairpin:
         bpf_log_trace("IP hairpin");
     }
 
-    return bpf_redirect(skb->ifindex, BPF_F_INGRESS);
+    // otherwise packets destined to ourselves would be steered back to
+    // external interface again by the fwmark IP rule
+    if ((HAIRPIN_FWMARK_IPV4 || HAIRPIN_FWMARK_IPV6) &&
+        skb->mark == HAIRPIN_FWMARK) {
+        skb->mark = 0;
+    }
+
+    TRACE_RETURN(bpf_redirect(skb->ifindex, BPF_F_INGRESS), TRACE_R_HAIRPIN);
+#undef TRACE_IS_INGRESS
+#undef BPF_LOG_TOPIC
+}
+
+SEC("tc")
+int egress_snat(struct __sk_buff *skb) {
+    if (is_bypassed(skb)) {
+        return TC_ACT_UNSPEC;
+    }
+    int ret;
+    bool is_ipv4;
+    ret = get_is_ipv4(skb, &is_ipv4);
+    if (ret != TC_ACT_OK) {
+        return ret;
+    }
+
+#ifdef FEAT_IPV6
+    barrier_var(is_ipv4);
+    if (NPTV6 && !is_ipv4 && (ret = npt_translate(skb, false)) != TC_ACT_OK) {
+        return ret;
+    }
+    if (NETMAP && is_ipv4 &&
+        (ret = netmap_translate(skb, false)) != TC_ACT_OK) {
+        return ret;
+    }
+    if (is_ipv4 && !EGRESS_IPV4 || !is_ipv4 && !EGRESS_IPV6) {
+        return TC_ACT_UNSPEC;
+    }
+#else
+    if (NETMAP && (ret = netmap_translate(skb, false)) != TC_ACT_OK) {
+        return ret;
+    }
+    if (!EGRESS_IPV4) {
+        return TC_ACT_UNSPEC;
+    }
+#endif
+
+    ret = egress_snat_family(skb, is_ipv4);
+#ifdef FEAT_IPV6
+    if (CLAT && is_ipv4 && ret == TC_ACT_UNSPEC) {
+        return clat_egress(skb);
+    }
+#endif
+    return ret;
+}
+
+#ifdef FEAT_IPV6
+// Single address family variants of egress_snat, loaded instead if only one
+// address family is enabled, with code paths of the other address family
+// compiled out to cut verification time
+SEC("tc")
+int egress_snat_v4(struct __sk_buff *skb) {
+    if (is_bypassed(skb)) {
+        return TC_ACT_UNSPEC;
+    }
+    bool is_ipv4;
+    int ret = get_is_ipv4(skb, &is_ipv4);
+    if (ret != TC_ACT_OK) {
+        return ret;
+    }
+    if (!is_ipv4) {
+        return TC_ACT_UNSPEC;
+    }
+    if (NETMAP && (ret = netmap_translate(skb, false)) != TC_ACT_OK) {
+        return ret;
+    }
+    if (!EGRESS_IPV4) {
+        return TC_ACT_UNSPEC;
+    }
+    ret = egress_snat_family(skb, true);
+    if (CLAT && ret == TC_ACT_UNSPEC) {
+        return clat_egress(skb);
+    }
+    return ret;
+}
+
+SEC("tc")
+int egress_snat_v6(struct __sk_buff *skb) {
+    if (is_bypassed(skb)) {
+        return TC_ACT_UNSPEC;
+    }
+    bool is_ipv4;
+    int ret = get_is_ipv4(skb, &is_ipv4);
+    if (ret != TC_ACT_OK) {
+        return ret;
+    }
+    if (is_ipv4) {
+        return TC_ACT_UNSPEC;
+    }
+    if (NPTV6 && (ret = npt_translate(skb, false)) != TC_ACT_OK) {
+        return ret;
+    }
+    if (!EGRESS_IPV6) {
+        return TC_ACT_UNSPEC;
+    }
+    return egress_snat_family(skb, false);
+}
+#endif
+
+// Attached on ingress of internal interfaces, which are assumed to be
+// Ethernet, see HAIRPIN_REDIRECT_IFINDEX and HAIRPIN_FWMARK
+SEC("tc")
+int ingress_hairpin(struct __sk_buff *skb) {
+#define BPF_LOG_TOPIC "ingress_hairpin"
+    if (is_bypassed(skb)) {
+        return TC_ACT_UNSPEC;
+    }
+    void *data_end = ctx_data_end(skb);
+    struct ethhdr *eth = ctx_data(skb);
+    if ((void *)(eth + 1) > data_end) {
+        return TC_ACT_UNSPEC;
+    }
+
+    bool is_ipv4;
+    union u_inet_addr daddr = {};
+    if (eth->h_proto == bpf_htons(ETH_P_IP)) {
+        struct iphdr *iph = (void *)(eth + 1);
+        if (!(HAIRPIN_REDIRECT_IPV4 || HAIRPIN_FWMARK_IPV4) ||
+            (void *)(iph + 1) > data_end) {
+            return TC_ACT_UNSPEC;
+        }
+        is_ipv4 = true;
+        daddr.ip = iph->daddr;
+    } else if (eth->h_proto == bpf_htons(ETH_P_IPV6)) {
+#ifdef FEAT_IPV6
+        struct ipv6hdr *ip6h = (void *)(eth + 1);
+        if (!(HAIRPIN_REDIRECT_IPV6 || HAIRPIN_FWMARK_IPV6) ||
+            (void *)(ip6h + 1) > data_end) {
+            return TC_ACT_UNSPEC;
+        }
+        is_ipv4 = false;
+        COPY_ADDR6(daddr.ip6, ip6h->daddr.in6_u.u6_addr32);
+#else
+        return TC_ACT_UNSPEC;
+#endif
+    } else {
+        return TC_ACT_UNSPEC;
+    }
+
+    struct dest_config *dest_config = lookup_dest_config(is_ipv4, &daddr);
+    if (!dest_config || !dest_hairpin(dest_config)) {
+        return TC_ACT_UNSPEC;
+    }
+
+    if (is_ipv4 ? HAIRPIN_FWMARK_IPV4 : HAIRPIN_FWMARK_IPV6) {
+        bpf_log_trace("set fwmark 0x%x", HAIRPIN_FWMARK);
+        skb->mark = HAIRPIN_FWMARK;
+        return TC_ACT_UNSPEC;
+    }
+
+#pragma unroll
+    for (int i = 0; i < 6; i++) {
+        eth->h_source[i] = HAIRPIN_REDIRECT_MAC[i];
+    }
+    bpf_log_trace("redirect to if %d", HAIRPIN_REDIRECT_IFINDEX);
+    return bpf_redirect(HAIRPIN_REDIRECT_IFINDEX, 0);
+#undef BPF_LOG_TOPIC
+}
+
+#ifdef FEAT_IPV6
+// IPv4 dummy address (RFC 7600), source of ICMP errors translated from IPv6
+// routers outside NAT64 prefix
+#define CLAT_DUMMY_IPV4_ADDR bpf_htonl(0xc0000008)
+
+// Maps type and code of ICMPv6 error to ICMPv4 (RFC 7915 Section 5.2),
+// returns false if the error is not translated
+static __always_inline bool clat_icmp6_err_to_icmp4(const struct icmp6hdr *icmp6,
+                                                    struct icmphdr *icmp) {
+    u32 param = bpf_ntohl(icmp6->icmp6_dataun.un_data32[0]);
+    switch (icmp6->icmp6_type) {
+    case ICMPV6_DEST_UNREACH:
+        icmp->type = ICMP_DEST_UNREACH;
+        switch (icmp6->icmp6_code) {
+        case ICMPV6_NOROUTE:
+        case ICMPV6_NOT_NEIGHBOUR:
+        case ICMPV6_ADDR_UNREACH:
+            icmp->code = ICMP_HOST_UNREACH;
+            return true;
+        case ICMPV6_ADM_PROHIBITED:
+            icmp->code = ICMP_HOST_ANO;
+            return true;
+        case ICMPV6_PORT_UNREACH:
+            icmp->code = ICMP_PORT_UNREACH;
+            return true;
+        default:
+            return false;
+        }
+    case ICMPV6_PKT_TOOBIG:
+        if (param <= sizeof(struct ipv6hdr) - sizeof(struct iphdr)) {
+            return false;
+        }
+        param -= sizeof(struct ipv6hdr) - sizeof(struct iphdr);
+        icmp->type = ICMP_DEST_UNREACH;
+        icmp->code = ICMP_FRAG_NEEDED;
+        icmp->un.frag.mtu = bpf_htons(param > 0xffff ? 0xffff : param);
+        return true;
+    case ICMPV6_TIME_EXCEED:
+        icmp->type = ICMP_TIME_EXCEEDED;
+        icmp->code = icmp6->icmp6_code;
+        return true;
+    case ICMPV6_PARAMPROB:
+        if (icmp6->icmp6_code == ICMPV6_UNK_NEXTHDR) {
+            icmp->type = ICMP_DEST_UNREACH;
+            icmp->code = ICMP_PROT_UNREACH;
+            return true;
+        }
+        if (icmp6->icmp6_code != ICMPV6_HDR_FIELD) {
+            return false;
+        }
+        icmp->type = ICMP_PARAMETERPROB;
+        icmp->code = 0;
+        // pointer to IPv6 header field mapped to the IPv4 one
+        if (param <= 1) {
+            icmp->un.reserved[0] = param;
+        } else if (param == 4 || param == 5) {
+            icmp->un.reserved[0] = offsetof(struct iphdr, tot_len);
+        } else if (param == 6) {
+            icmp->un.reserved[0] = offsetof(struct iphdr, protocol);
+        } else if (param == 7) {
+            icmp->un.reserved[0] = offsetof(struct iphdr, ttl);
+        } else if (param >= 8 && param < 24) {
+            icmp->un.reserved[0] = offsetof(struct iphdr, saddr);
+        } else if (param >= 24 && param < 40) {
+            icmp->un.reserved[0] = offsetof(struct iphdr, daddr);
+        } else {
+            return false;
+        }
+        return true;
+    default:
+        return false;
+    }
+}
+
+// Translates ICMPv6 error quoting packet sent by clat_egress to ICMPv4 error,
+// along with the quoted packet, and redirects it to ingress of CLAT interface.
+// ICMPv6 errors quoting other packets are passed.
+static __always_inline int clat_ingress_icmp_err(struct __sk_buff *skb,
+                                                 u32 l3_off,
+                                                 const struct ipv6hdr *ip6h,
+                                                 const struct icmp6hdr *icmp6) {
+#define BPF_LOG_TOPIC "clat <=="
+    u32 inner_off = l3_off + sizeof(*ip6h) + sizeof(*icmp6);
+    struct ipv6hdr inner6;
+    if (bpf_skb_load_bytes(skb, inner_off, &inner6, sizeof(inner6))) {
+        return TC_ACT_UNSPEC;
+    }
+    if (inner6.saddr.in6_u.u6_addr32[0] != g_clat_ipv6_addr[0] ||
+        inner6.saddr.in6_u.u6_addr32[1] != g_clat_ipv6_addr[1] ||
+        inner6.saddr.in6_u.u6_addr32[2] != g_clat_ipv6_addr[2] ||
+        inner6.saddr.in6_u.u6_addr32[3] != g_clat_ipv6_addr[3] ||
+        inner6.daddr.in6_u.u6_addr32[0] != g_clat_pref64[0] ||
+        inner6.daddr.in6_u.u6_addr32[1] != g_clat_pref64[1] ||
+        inner6.daddr.in6_u.u6_addr32[2] != g_clat_pref64[2]) {
+        return TC_ACT_UNSPEC;
+    }
+
+    struct icmphdr icmp = {0};
+    if (!clat_icmp6_err_to_icmp4(icmp6, &icmp)) {
+        bpf_log_debug("ICMPv6 error type %d code %d is not translated",
+                      icmp6->icmp6_type, icmp6->icmp6_code);
+        return TC_ACT_SHOT;
+    }
+    int csum_off = clat_l4_csum_off(inner6.nexthdr);
+    if (csum_off < 0) {
+        return TC_ACT_SHOT;
+    }
+    u16 payload_len = bpf_ntohs(ip6h->payload_len);
+    u16 inner_len = bpf_ntohs(inner6.payload_len);
+    if (payload_len < sizeof(*icmp6) + sizeof(inner6) ||
+        inner_len > 0xffff - sizeof(struct iphdr)) {
+        return TC_ACT_SHOT;
+    }
+
+    // ICMPv4 message is shorter by the difference of quoted IP headers, so the
+    // total length equals ICMPv6 payload length
+    struct iphdr iph = {
+        .version = 4,
+        .ihl = 5,
+        .tos = ip6h->priority << 4 | ip6h->flow_lbl[0] >> 4,
+        .tot_len = ip6h->payload_len,
+        .frag_off = bpf_htons(IP_DF),
+        .ttl = ip6h->hop_limit,
+        .protocol = IPPROTO_ICMP,
+        .saddr = ip6h->saddr.in6_u.u6_addr32[0] == g_clat_pref64[0] &&
+                         ip6h->saddr.in6_u.u6_addr32[1] == g_clat_pref64[1] &&
+                         ip6h->saddr.in6_u.u6_addr32[2] == g_clat_pref64[2]
+                     ? ip6h->saddr.in6_u.u6_addr32[3]
+                     : CLAT_DUMMY_IPV4_ADDR,
+        .daddr = CLAT_IPV4_ADDR,
+    };
+    iph.check =
+        csum_fold(bpf_csum_diff(NULL, 0, (__be32 *)&iph, sizeof(iph), 0));
+    struct iphdr inner = {
+        .version = 4,
+        .ihl = 5,
+        .tos = inner6.priority << 4 | inner6.flow_lbl[0] >> 4,
+        .tot_len = bpf_htons(inner_len + sizeof(struct iphdr)),
+        .frag_off = bpf_htons(IP_DF),
+        .ttl = inner6.hop_limit,
+        .protocol =
+            inner6.nexthdr == NEXTHDR_ICMP ? IPPROTO_ICMP : inner6.nexthdr,
+        .saddr = CLAT_IPV4_ADDR,
+        .daddr = inner6.daddr.in6_u.u6_addr32[3],
+    };
+    inner.check =
+        csum_fold(bpf_csum_diff(NULL, 0, (__be32 *)&inner, sizeof(inner), 0));
+
+    // ICMPv4 checksum is updated from ICMPv6 one, removing IPv6 pseudo header
+    // and replacing changed headers and quoted L4 header fields
+    struct ipv6_pseudo_hdr ph = {
+        .len = bpf_htonl(payload_len),
+        .nexthdr = bpf_htonl(NEXTHDR_ICMP),
+    };
+    COPY_ADDR6(ph.saddr, ip6h->saddr.in6_u.u6_addr32);
+    COPY_ADDR6(ph.daddr, ip6h->daddr.in6_u.u6_addr32);
+    struct icmp6hdr icmp6_hdr = *icmp6;
+    icmp6_hdr.icmp6_cksum = 0;
+    s64 csum = bpf_csum_diff((__be32 *)&ph, sizeof(ph), NULL, 0,
+                             (__u16)~icmp6->icmp6_cksum);
+    csum = bpf_csum_diff((__be32 *)&icmp6_hdr, sizeof(icmp6_hdr),
+                         (__be32 *)&icmp, sizeof(icmp), csum);
+    csum = bpf_csum_diff((__be32 *)&inner6, sizeof(inner6), (__be32 *)&inner,
+                         sizeof(inner), csum);
+
+    // Quoted L4 checksum, preceded by ICMP type and code for ICMP, in 16-bit
+    // words aligned in both ICMP messages
+    u32 l4_off = inner_off + sizeof(inner6);
+    u32 l4_word_off = inner6.nexthdr == NEXTHDR_ICMP ? 0 : csum_off;
+    __sum16 old_l4[2] = {0}, new_l4[2] = {0};
+    bool has_l4 = !bpf_skb_load_bytes(skb, l4_off + l4_word_off, old_l4,
+                                      inner6.nexthdr == NEXTHDR_ICMP ? 4 : 2);
+    if (has_l4) {
+        if (inner6.nexthdr == NEXTHDR_ICMP) {
+            u8 *type6 = (u8 *)&old_l4[0], *type = (u8 *)&new_l4[0];
+            if (*type6 != ICMPV6_ECHO_REQUEST && *type6 != ICMPV6_ECHO_REPLY) {
+                return TC_ACT_SHOT;
+            }
+            new_l4[0] = old_l4[0];
+            *type = *type6 == ICMPV6_ECHO_REQUEST ? ICMP_ECHO : ICMP_ECHOREPLY;
+            struct ipv6_pseudo_hdr inner_ph = {
+                .len = bpf_htonl(inner_len),
+                .nexthdr = bpf_htonl(NEXTHDR_ICMP),
+            };
+            COPY_ADDR6(inner_ph.saddr, inner6.saddr.in6_u.u6_addr32);
+            COPY_ADDR6(inner_ph.daddr, inner6.daddr.in6_u.u6_addr32);
+            s64 l4_csum = bpf_csum_diff((__be32 *)&inner_ph, sizeof(inner_ph),
+                                        NULL, 0, (__u16)~old_l4[1]);
+            __sum16 type_from[2] = {old_l4[0]}, type_to[2] = {new_l4[0]};
+            new_l4[1] = csum_fold(bpf_csum_diff((__be32 *)type_from, 4,
+                                                (__be32 *)type_to, 4, l4_csum));
+        } else if (old_l4[0] || inner6.nexthdr != IPPROTO_UDP) {
+            __be32 to[2] = {inner.saddr, inner.daddr};
+            new_l4[0] = csum_fold(bpf_csum_diff(
+                (__be32 *)&inner6.saddr, 32, to, sizeof(to),
+                (__u16)~old_l4[0]));
+            if (!new_l4[0] && inner6.nexthdr == IPPROTO_UDP) {
+                new_l4[0] = 0xffff;
+            }
+        }
+        csum = bpf_csum_diff((__be32 *)old_l4, sizeof(old_l4),
+                             (__be32 *)new_l4, sizeof(new_l4), csum);
+    }
+    icmp.checksum = csum_fold(csum);
+
+    if (bpf_skb_change_proto(skb, bpf_htons(ETH_P_IP), 0)) {
+        return TC_ACT_SHOT;
+    }
+    // change_proto shrinks the outer IP header, this shrinks the quoted one
+    if (bpf_skb_adjust_room(skb, -(s32)(sizeof(inner6) - sizeof(inner)),
+                            BPF_ADJ_ROOM_NET, 0)) {
+        return TC_ACT_SHOT;
+    }
+    if (bpf_skb_store_bytes(skb, l3_off, &iph, sizeof(iph), 0) ||
+        bpf_skb_store_bytes(skb, l3_off + sizeof(iph), &icmp, sizeof(icmp),
+                            0) ||
+        bpf_skb_store_bytes(skb, l3_off + sizeof(iph) + sizeof(icmp), &inner,
+                            sizeof(inner), 0)) {
+        return TC_ACT_SHOT;
+    }
+    if (has_l4 &&
+        bpf_skb_store_bytes(skb,
+                            l3_off + sizeof(iph) + sizeof(icmp) +
+                                sizeof(inner) + l4_word_off,
+                            new_l4, inner6.nexthdr == NEXTHDR_ICMP ? 4 : 2,
+                            0)) {
+        return TC_ACT_SHOT;
+    }
+    if (CLAT_UPLINK_HAS_ETH) {
+        __be16 h_proto = bpf_htons(ETH_P_IP);
+        bpf_skb_store_bytes(skb, offsetof(struct ethhdr, h_proto), &h_proto,
+                            sizeof(h_proto), 0);
+    }
+
+    return bpf_redirect(CLAT_IFINDEX, BPF_F_INGRESS);
+#undef BPF_LOG_TOPIC
+}
+
+// Attached on ingress of CLAT uplink interface, translates IPv6 packets from
+// NAT64 prefix to CLAT IPv6 address back to IPv4 (RFC 7915), and redirects
+// them to ingress of CLAT interface for reverse NAT44. ICMPv6 errors are
+// translated regardless of their source.
+SEC("tc")
+int ingress_clat(struct __sk_buff *skb) {
+#define BPF_LOG_TOPIC "clat <=="
+    if (!CLAT || skb->protocol != bpf_htons(ETH_P_IPV6)) {
+        return TC_ACT_UNSPEC;
+    }
+    u32 l3_off = CLAT_UPLINK_HAS_ETH ? sizeof(struct ethhdr) : 0;
+    struct ipv6hdr ip6h;
+    if (bpf_skb_load_bytes(skb, l3_off, &ip6h, sizeof(ip6h))) {
+        return TC_ACT_UNSPEC;
+    }
+    if (ip6h.daddr.in6_u.u6_addr32[0] != g_clat_ipv6_addr[0] ||
+        ip6h.daddr.in6_u.u6_addr32[1] != g_clat_ipv6_addr[1] ||
+        ip6h.daddr.in6_u.u6_addr32[2] != g_clat_ipv6_addr[2] ||
+        ip6h.daddr.in6_u.u6_addr32[3] != g_clat_ipv6_addr[3]) {
+        return TC_ACT_UNSPEC;
+    }
+    struct icmp6hdr icmp6 = {0};
+    if (ip6h.nexthdr == NEXTHDR_ICMP) {
+        if (bpf_skb_load_bytes(skb, l3_off + sizeof(ip6h), &icmp6,
+                               sizeof(icmp6))) {
+            return TC_ACT_UNSPEC;
+        }
+        // error messages have the highest bit of type cleared
+        if (!(icmp6.icmp6_type & 0x80)) {
+            return clat_ingress_icmp_err(skb, l3_off, &ip6h, &icmp6);
+        }
+    }
+    if (ip6h.saddr.in6_u.u6_addr32[0] != g_clat_pref64[0] ||
+        ip6h.saddr.in6_u.u6_addr32[1] != g_clat_pref64[1] ||
+        ip6h.saddr.in6_u.u6_addr32[2] != g_clat_pref64[2]) {
+        return TC_ACT_UNSPEC;
+    }
+
+    int csum_off = clat_l4_csum_off(ip6h.nexthdr);
+    if (csum_off < 0) {
+        bpf_log_debug("IPv6 extension headers or L4 protocol %d are not "
+                      "translated",
+                      ip6h.nexthdr);
+        return TC_ACT_SHOT;
+    }
+    csum_off += l3_off + sizeof(struct iphdr);
+    u16 payload_len = bpf_ntohs(ip6h.payload_len);
+    if (payload_len > 0xffff - sizeof(struct iphdr)) {
+        return TC_ACT_SHOT;
+    }
+
+    struct iphdr iph = {
+        .version = 4,
+        .ihl = 5,
+        .tos = ip6h.priority << 4 | ip6h.flow_lbl[0] >> 4,
+        .tot_len = bpf_htons(payload_len + sizeof(struct iphdr)),
+        .frag_off = bpf_htons(IP_DF),
+        .ttl = ip6h.hop_limit,
+        .protocol =
+            ip6h.nexthdr == NEXTHDR_ICMP ? IPPROTO_ICMP : ip6h.nexthdr,
+        .saddr = ip6h.saddr.in6_u.u6_addr32[3],
+        .daddr = CLAT_IPV4_ADDR,
+    };
+    iph.check =
+        csum_fold(bpf_csum_diff(NULL, 0, (__be32 *)&iph, sizeof(iph), 0));
+
+    u8 icmp6_type = icmp6.icmp6_type;
+    if (ip6h.nexthdr == NEXTHDR_ICMP && icmp6_type != ICMPV6_ECHO_REQUEST &&
+        icmp6_type != ICMPV6_ECHO_REPLY) {
+        bpf_log_debug("ICMPv6 type %d is not translated", icmp6_type);
+        return TC_ACT_SHOT;
+    }
+
+    if (bpf_skb_change_proto(skb, bpf_htons(ETH_P_IP), 0)) {
+        return TC_ACT_SHOT;
+    }
+    if (bpf_skb_store_bytes(skb, l3_off, &iph, sizeof(iph), 0)) {
+        return TC_ACT_SHOT;
+    }
+    if (CLAT_UPLINK_HAS_ETH) {
+        __be16 h_proto = bpf_htons(ETH_P_IP);
+        bpf_skb_store_bytes(skb, offsetof(struct ethhdr, h_proto), &h_proto,
+                            sizeof(h_proto), 0);
+    }
+
+    if (ip6h.nexthdr == NEXTHDR_ICMP) {
+        u8 type = icmp6_type == ICMPV6_ECHO_REQUEST ? ICMP_ECHO
+                                                     : ICMP_ECHOREPLY;
+        struct ipv6_pseudo_hdr ph = {
+            .saddr = {ip6h.saddr.in6_u.u6_addr32[0],
+                      ip6h.saddr.in6_u.u6_addr32[1],
+                      ip6h.saddr.in6_u.u6_addr32[2],
+                      ip6h.saddr.in6_u.u6_addr32[3]},
+            .daddr = {ip6h.daddr.in6_u.u6_addr32[0],
+                      ip6h.daddr.in6_u.u6_addr32[1],
+                      ip6h.daddr.in6_u.u6_addr32[2],
+                      ip6h.daddr.in6_u.u6_addr32[3]},
+            .len = bpf_htonl(payload_len),
+            .nexthdr = bpf_htonl(NEXTHDR_ICMP),
+        };
+        s64 diff = bpf_csum_diff((__be32 *)&ph, sizeof(ph), NULL, 0, 0);
+        bpf_l4_csum_replace(skb, csum_off, 0, diff, 0);
+        bpf_l4_csum_replace(skb, csum_off, bpf_htons(icmp6_type << 8),
+                            bpf_htons(type << 8), 2);
+        bpf_skb_store_bytes(skb, l3_off + sizeof(iph), &type, 1, 0);
+    } else {
+        __be32 to[2] = {iph.saddr, iph.daddr};
+        s64 diff =
+            bpf_csum_diff((__be32 *)&ip6h.saddr, 32, to, sizeof(to), 0);
+        bpf_l4_csum_replace(skb, csum_off, 0, diff,
+                            BPF_F_PSEUDO_HDR |
+                                (iph.protocol == IPPROTO_UDP
+                                     ? BPF_F_MARK_MANGLED_0
+                                     : 0));
+    }
+
+    return bpf_redirect(CLAT_IFINDEX, BPF_F_INGRESS);
 #undef BPF_LOG_TOPIC
 }
+#endif
+
+// Dump binding or CT map entries as concatenated raw key and value, so
+// userspace could read large maps in bulk instead of walking keys
+SEC("iter/bpf_map_elem")
+int dump_binding(struct bpf_iter__bpf_map_elem *ctx) {
+    struct map_binding_key *key = ctx->key;
+    struct map_binding_value *value = ctx->value;
+    if (!key || !value) {
+        return 0;
+    }
+    bpf_seq_write(ctx->meta->seq, key, sizeof(*key));
+    bpf_seq_write(ctx->meta->seq, value, sizeof(*value));
+    return 0;
+}
+
+SEC("iter/bpf_map_elem")
+int dump_ct(struct bpf_iter__bpf_map_elem *ctx) {
+    struct map_ct_key *key = ctx->key;
+    struct map_ct_value *value = ctx->value;
+    if (!key || !value) {
+        return 0;
+    }
+    bpf_seq_write(ctx->meta->seq, key, sizeof(*key));
+    bpf_seq_write(ctx->meta->seq, value, sizeof(*value));
+    return 0;
+}
 
 char _license[] SEC("license") = "GPL";
@@ -11,6 +11,8 @@
 #include <bpf/bpf_endian.h>
 #include <bpf/bpf_helpers.h>
 
+// log records share ring buffer with other events
+#define BPF_LOG_EVENT EVENT_LOG
 #include "bpf_log.h"
 
 #ifndef FEAT_IPV6
@@ -20,6 +22,20 @@
 // #include <linux/if_ether.h>
 #define ETH_P_IP 0x0800
 #define ETH_P_IPV6 0x86DD
+#define ETH_P_PPP_SES 0x8864
+
+// #include <linux/ppp_defs.h>
+#define PPP_IP 0x21
+#define PPP_IPV6 0x57
+
+// PPPoE session header followed by PPP protocol field, see RFC 2516
+struct pppoe_ses_hdr {
+    u8 ver_type;
+    u8 code;
+    __be16 sid;
+    __be16 length;
+    __be16 ppp_proto;
+};
 
 #define IP_CE 0x8000     /* Flag: "Congestion"		*/
 #define IP_DF 0x4000     /* Flag: "Don't Fragment"	*/
@@ -53,6 +69,13 @@
 #define ICMP_TIME_EXCEEDED 11 /* Time Exceeded		*/
 #define ICMP_PARAMETERPROB 12 /* Parameter Problem		*/
 
+#define ICMP_HOST_UNREACH 1  /* Host Unreachable		*/
+#define ICMP_PROT_UNREACH 2  /* Protocol Unreachable		*/
+#define ICMP_PORT_UNREACH 3  /* Port Unreachable		*/
+#define ICMP_FRAG_NEEDED 4   /* Fragmentation Needed/DF set	*/
+#define ICMP_HOST_ANO 10
+#define ICMP_PKT_FILTERED 13 /* Packet filtered */
+
 #define ICMP_ECHOREPLY 0       /* Echo Reply			*/
 #define ICMP_ECHO 8            /* Echo Request			*/
 #define ICMP_TIMESTAMP 13      /* Timestamp Request		*/
@@ -64,6 +87,15 @@
 #define ICMPV6_TIME_EXCEED 3
 #define ICMPV6_PARAMPROB 4
 
+#define ICMPV6_NOROUTE 0
+#define ICMPV6_ADM_PROHIBITED 1
+#define ICMPV6_NOT_NEIGHBOUR 2
+#define ICMPV6_ADDR_UNREACH 3
+#define ICMPV6_PORT_UNREACH 4
+
+#define ICMPV6_HDR_FIELD 0
+#define ICMPV6_UNK_NEXTHDR 1
+
 #define ICMPV6_ECHO_REQUEST 128
 #define ICMPV6_ECHO_REPLY 129
 
@@ -71,6 +103,7 @@
 #define AF_INET6 10
 
 #define CLOCK_MONOTONIC 1
+#define NSEC_PER_SEC 1000000000ULL
 
 // #include <linux/pkt_cls.h>
 #define TC_ACT_UNSPEC (-1)
@@ -128,19 +161,108 @@ struct external_config {
     // included by icmp_range
     struct port_range icmp_in_range[MAX_PORT_RANGES];
     struct port_range icmp_out_range[MAX_PORT_RANGES];
+    // TCP and UDP ports within tcp_range and udp_range that are never
+    // allocated for dynamic bindings
+    struct port_range excluded_range[MAX_PORT_RANGES];
     u8 tcp_range_len;
     u8 udp_range_len;
     u8 icmp_range_len;
     u8 icmp_in_range_len;
     u8 icmp_out_range_len;
+    u8 excluded_range_len;
 #define EXTERNAL_NO_SNAT_FLAG (1 << 1)
+// Address-dependent filtering, see
+// https://datatracker.ietf.org/doc/html/rfc4787#section-5
+#define EXTERNAL_FILTER_ADF_FLAG (1 << 2)
+// Address and port-dependent filtering
+#define EXTERNAL_FILTER_APDF_FLAG (1 << 3)
+// Static 1:1 NAT to an internal address by address bindings, always set along
+// with EXTERNAL_NO_SNAT_FLAG
+#define EXTERNAL_ONE_TO_ONE_FLAG (1 << 4)
+// External address is also an address of this host, see LOCAL_PORTS
+#define EXTERNAL_LOCAL_FLAG (1 << 5)
     u8 flags;
+// IP protocols the address is used for by new dynamic bindings, zero value
+// serves all protocols
+#define EXTERNAL_PROTO_TCP_FLAG (1 << 0)
+#define EXTERNAL_PROTO_UDP_FLAG (1 << 1)
+#define EXTERNAL_PROTO_ICMP_FLAG (1 << 2)
+    u8 protocols;
+};
+
+struct map_local_port_key {
+    u8 l4proto;
+    u8 _pad;
+    __be16 port;
+};
+
+struct netmap_value {
+    // Prefix of the other side, host bits are kept from the original address
+    __be32 to_prefix;
+    __be32 mask;
+// Entry of internal prefix, i.e. to translate source of outbound packets,
+// otherwise of external prefix to translate destination of inbound packets
+#define NETMAP_ORIG_DIR_FLAG (1 << 0)
+    u8 flags;
+    u8 _pad[3];
+};
+
+// PPTP call of internal client, keyed by call ID carried in GRE packets
+struct map_pptp_call_key {
+    u32 ifindex;
+// Keyed by client address and call ID of server, i.e. peer call ID carried in
+// outbound GRE packets, to translate their source address. Otherwise keyed by
+// external address and call ID allocated to the client, which is carried in
+// inbound GRE packets.
+#define PPTP_CALL_ORIG_DIR_FLAG (1 << 0)
+// Keyed by client address and original call ID of client, to translate call
+// ID in control messages from client
+#define PPTP_CALL_CTRL_FLAG (1 << 1)
+    u8 flags;
+    u8 _pad;
+    __be16 call_id;
+    __be32 from_addr;
+    __be32 server_addr;
+};
+
+struct map_pptp_call_value {
+    __be32 to_addr;
+    __be16 to_call_id;
+    u8 _pad[2];
+};
+
+// IPsec ESP pseudo-binding of internal client, keyed by SPI
+struct map_esp_key {
+    u32 ifindex;
+// Keyed by client address and SPI of outbound ESP packets, to translate their
+// source address. Otherwise keyed by external address and SPI of inbound ESP
+// packets.
+#define ESP_ORIG_DIR_FLAG (1 << 0)
+// Keyed by external address with SPI 0, the client most recently started an
+// outbound SA with the gateway, which the next inbound SA of unknown SPI from
+// the gateway is assigned to
+#define ESP_PENDING_FLAG (1 << 1)
+    u8 flags;
+    u8 _pad[3];
+    __be32 spi;
+    __be32 from_addr;
+    __be32 gateway_addr;
 };
 
 struct dest_config {
 #define DEST_HAIRPIN_FLAG (1 << 0)
 #define DEST_NO_SNAT_FLAG (1 << 1)
+// Use filtering behavior of destination instead of the external
+#define DEST_FILTER_OVERRIDE_FLAG (1 << 2)
+#define DEST_FILTER_ADF_FLAG (1 << 3)
+#define DEST_FILTER_APDF_FLAG (1 << 4)
+// Drop outbound packets to destination
+#define DEST_DROP_FLAG (1 << 5)
     u8 flags;
+    u8 _pad[7];
+    // Overrides of respective timeouts, 0 for not overridden
+    u64 timeout_udp;
+    u64 timeout_tcp_est;
 };
 
 #define BINDING_ORIG_DIR_FLAG (1 << 0)
@@ -172,6 +294,8 @@ struct map_frag_track_value {
 // is mapped external source address, otherwise the relations are reversed.
 // We duplicate binding entries for both direction for looking up from both
 // ingress and egress.
+// Static 1:1 NAT address bindings have l4proto and from_port of 0 and map all
+// ports of all protocols, with to_port of 0 as ports are kept.
 struct map_binding_key {
     u32 ifindex;
     u8 flags;
@@ -191,6 +315,12 @@ struct map_binding_value {
     u32 use;
     u32 ref;
     u32 seq;
+    // creation time in seconds of bpf_ktime_get_ns()
+    u32 created;
+    // Binding restored from snapshot or taken over without CT referencing it
+    // yet is not reused or deleted until this time in seconds of
+    // bpf_ktime_get_ns(), 0 if not held
+    u32 held_until;
 };
 
 // Set ref of orig dir binding to this to indicate the binding was ref counted
@@ -206,6 +336,166 @@ struct map_ct_key {
     struct inet_tuple external;
 };
 
+// Remote address that an external endpoint has CTs with
+struct map_filter_addr_key {
+    u32 ifindex;
+    u8 flags;
+    u8 l4proto;
+    __be16 port;
+    union u_inet_addr external_addr;
+    union u_inet_addr remote_addr;
+};
+
+struct map_host_key {
+    u32 ifindex;
+    u8 flags;
+    u8 _pad[3];
+    union u_inet_addr addr;
+};
+
+// Number of external ports held by internal host
+struct host_ports {
+    u32 tcp;
+    u32 udp;
+    u32 icmp;
+    u32 _pad;
+    // Time of last overflow event, for rate limiting events
+    u64 last_overflow;
+};
+
+enum {
+    EVENT_PORT_QUOTA_EXCEEDED = 1,
+    EVENT_EXTERNAL_SPILLOVER = 2,
+    EVENT_TRACE = 3,
+    EVENT_LOG = 4,
+};
+
+#define MAX_EXTERNAL_POOL 16
+// External address pool is indexed separately for TCP, UDP and ICMP
+#define EXTERNAL_POOL_PROTOS 3
+#define MAX_NPT_LOCAL_ADDRS 8
+// Value of EGRESS_DSCP and INGRESS_DSCP leaving DSCP untouched, out of 6-bit
+// DSCP range
+#define DSCP_KEEP 0xff
+
+// Strategy of selecting external address from pool for new binding
+enum {
+    EXTERNAL_SELECT_FIRST = 0,
+    // hashed by internal address
+    EXTERNAL_SELECT_HASH = 1,
+    EXTERNAL_SELECT_ROUND_ROBIN = 2,
+};
+
+// Strategy of picking external port to start searching free port from
+enum {
+    PORT_ALLOC_RANDOM = 0,
+    PORT_ALLOC_SEQUENTIAL = 1,
+    // hashed by internal address, so ports of a host are close to each other
+    PORT_ALLOC_HASHED = 2,
+};
+
+// Handling of inbound TCP RST to binding without matching CT
+enum {
+    UNMATCHED_RST_DROP = 0,
+    // translated to internal endpoint of binding
+    UNMATCHED_RST_PASS = 1,
+    // replied with ICMP administratively prohibited error
+    UNMATCHED_RST_REPLY = 2,
+};
+
+// Handling of TCP record once closed by FIN from both sides or by RST
+enum {
+    // expire after TIMEOUT_TCP_TRANS
+    TCP_TEARDOWN_TRANS = 0,
+    // expire after TIMEOUT_TCP_TIME_WAIT
+    TCP_TEARDOWN_TIME_WAIT = 1,
+    // remove immediately
+    TCP_TEARDOWN_CLOSE = 2,
+    // stay established
+    TCP_TEARDOWN_IGNORE = 3,
+};
+
+// Event reported to userspace through ring buffer
+struct event {
+    u32 type;
+    u32 ifindex;
+    u8 flags;
+    u8 l4proto;
+    u16 _pad;
+    union u_inet_addr addr;
+};
+
+// Stages of packet processing reported in trace event
+enum {
+    TRACE_PARSE = 0,
+    // `value` is result of binding lookup in TC action, with `addr` and
+    // `port` of translated endpoint if TC_ACT_OK
+    TRACE_BINDING = 1,
+    // `value` is result of CT lookup, LK_CT_*
+    TRACE_CT = 2,
+    // `value` is returned TC action
+    TRACE_VERDICT = 3,
+};
+
+enum {
+    TRACE_F_INGRESS = 1 << 0,
+    TRACE_F_IPV4 = 1 << 1,
+};
+
+// Reason of verdict in trace event
+enum {
+    TRACE_R_NONE = 0,
+    TRACE_R_TRANSLATED,
+    TRACE_R_HAIRPIN,
+    // not destined to or sent from external address, or external is excluded
+    // from NAT
+    TRACE_R_NOT_EXTERNAL,
+    TRACE_R_PASS_NAT,
+    TRACE_R_FRAGMENT,
+    TRACE_R_OUT_OF_RANGE,
+    TRACE_R_NO_BINDING,
+    TRACE_R_BINDING_FAILED,
+    TRACE_R_NO_CT,
+    TRACE_R_REWRITE_FAILED,
+    TRACE_R_DEST_BLOCKED,
+};
+
+// Per-packet event of trace mode, reported to userspace through ring buffer
+// as well, with the same leading `type` field as `struct event`
+struct trace_pkt_event {
+    u32 type;
+    u32 ifindex;
+    u8 stage;
+    u8 flags;
+    u8 l4proto;
+    u8 pkt_type;
+    s32 value;
+    struct inet_tuple tuple;
+    union u_inet_addr addr;
+    __be16 port;
+    u8 reason;
+    u8 _pad;
+};
+
+enum {
+    CAPTURE_F_INGRESS = 1 << 0,
+    // copy of packet after translation, otherwise before translation
+    CAPTURE_F_TRANSLATED = 1 << 1,
+};
+
+// Max bytes of packet data copied in capture
+#define CAPTURE_SNAPLEN 16384
+
+// Metadata of captured packet sent to userspace through perf buffer, followed
+// by `cap_len` bytes of packet data
+struct capture_meta {
+    u32 ifindex;
+    u32 len;
+    u32 cap_len;
+    u8 flags;
+    u8 _pad[3];
+};
+
 // Adapted from NAT64 TCP state machine per RFC6146
 enum ct_state {
     // CT_CLOSED,
@@ -221,9 +511,15 @@ enum ct_state {
 struct map_ct_value {
     struct inet_tuple origin;
     u8 flags;
-    u8 _pad[3];
+    // number of packets seen after established, saturated at
+    // UDP_STREAM_PACKETS
+    u8 pkts;
+    u8 _pad[2];
     u32 state;
     u32 seq;
+    // expiration time of timer in bpf_ktime_get_ns(), for userspace to find
+    // CTs with timer failed to fire
+    u64 expires;
     struct bpf_timer timer;
 };
 
@@ -350,6 +646,14 @@ static __always_inline int get_l3_to_addr_off(bool is_ipv4, bool is_source) {
                                 : offsetof(struct ipv6hdr, daddr));
 }
 
+static __always_inline int bpf_read_inet_addr(struct __sk_buff *skb,
+                                              bool is_ipv4, int addr_off,
+                                              union u_inet_addr *addr) {
+    return bpf_skb_load_bytes(
+        skb, addr_off, is_ipv4 ? &addr->ip : addr->all,
+        is_ipv4 ? sizeof(addr->ip) : sizeof(addr->all));
+}
+
 static __always_inline int bpf_write_inet_addr(struct __sk_buff *skb,
                                                bool is_ipv4, int addr_off,
                                                union u_inet_addr *to_addr) {
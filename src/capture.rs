@@ -0,0 +1,167 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! Capture of packets before and after translation into pcap file, for
+//! debugging checksum or header rewrite issues.
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use libbpf_rs::{Map, PerfBufferBuilder};
+use tracing::{debug, trace, warn};
+
+use crate::skel::{self, CaptureMeta};
+
+const POLL_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Same as `CAPTURE_SNAPLEN` of BPF programs
+const SNAPLEN: u32 = 16384;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+
+/// Writes pcap file in the classic format with microsecond timestamps.
+struct PcapWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    fn new(mut inner: W, has_eth_encap: bool) -> std::io::Result<Self> {
+        let link_type = if has_eth_encap {
+            LINKTYPE_ETHERNET
+        } else {
+            LINKTYPE_RAW
+        };
+        inner.write_all(&0xa1b2c3d4u32.to_ne_bytes())?;
+        inner.write_all(&2u16.to_ne_bytes())?;
+        inner.write_all(&4u16.to_ne_bytes())?;
+        // time zone offset and timestamp accuracy
+        inner.write_all(&[0; 8])?;
+        inner.write_all(&SNAPLEN.to_ne_bytes())?;
+        inner.write_all(&link_type.to_ne_bytes())?;
+        Ok(Self { inner })
+    }
+
+    fn write_packet(&mut self, time: SystemTime, len: u32, data: &[u8]) -> std::io::Result<()> {
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.inner
+            .write_all(&(time.as_secs() as u32).to_ne_bytes())?;
+        self.inner.write_all(&time.subsec_micros().to_ne_bytes())?;
+        self.inner.write_all(&(data.len() as u32).to_ne_bytes())?;
+        self.inner.write_all(&len.to_ne_bytes())?;
+        self.inner.write_all(data)
+    }
+}
+
+/// Writes packets captured by BPF programs to pcap file on a dedicated
+/// thread, the thread is stopped on drop.
+///
+/// Each translated packet is written twice, the original packet followed by
+/// the translated one.
+#[derive(Debug)]
+pub struct CaptureWriter {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CaptureWriter {
+    pub fn start(map_capture: &Map, path: &Path, has_eth_encap: bool) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create capture file {}", path.display()))?;
+        let mut writer = PcapWriter::new(BufWriter::new(file), has_eth_encap)?;
+
+        let display_path = path.display().to_string();
+        let perf_buf = PerfBufferBuilder::new(map_capture)
+            .sample_cb(move |_cpu, data: &[u8]| {
+                if let Err(e) = write_sample(&mut writer, data) {
+                    warn!("failed to write capture file {}: {}", display_path, e);
+                }
+            })
+            .lost_cb(|cpu, count| warn!("lost {} captured packets on CPU {}", count, cpu))
+            .build()?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::Builder::new()
+            .name("einat-capture".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        if let Err(e) = perf_buf.poll(POLL_TIMEOUT) {
+                            warn!("failed to poll captured packets: {}", e);
+                            break;
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for CaptureWriter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write_sample<W: Write>(writer: &mut PcapWriter<W>, data: &[u8]) -> std::io::Result<()> {
+    let meta_len = std::mem::size_of::<CaptureMeta>();
+    if data.len() < meta_len {
+        debug!("ignoring truncated capture sample of {} bytes", data.len());
+        return Ok(());
+    }
+    let meta: CaptureMeta = bytemuck::pod_read_unaligned(&data[..meta_len]);
+    // perf sample is padded, so take length from metadata
+    let Some(pkt) = data[meta_len..].get(..meta.cap_len as usize) else {
+        debug!("ignoring truncated capture sample of {} bytes", data.len());
+        return Ok(());
+    };
+    trace!(
+        "captured {} {} packet of {} bytes on if {}",
+        if meta.flags & skel::CAPTURE_F_INGRESS != 0 {
+            "ingress"
+        } else {
+            "egress"
+        },
+        if meta.flags & skel::CAPTURE_F_TRANSLATED != 0 {
+            "translated"
+        } else {
+            "original"
+        },
+        meta.len,
+        meta.if_index
+    );
+    writer.write_packet(SystemTime::now(), meta.len, pkt)?;
+    writer.inner.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcap_format() {
+        let mut writer = PcapWriter::new(Vec::new(), true).unwrap();
+        let time = UNIX_EPOCH + Duration::from_micros(1_000_002);
+        writer.write_packet(time, 100, &[0xaa; 60]).unwrap();
+        let buf = writer.inner;
+
+        assert_eq!(buf.len(), 24 + 16 + 60);
+        let word = |off: usize| u32::from_ne_bytes(buf[off..off + 4].try_into().unwrap());
+        assert_eq!(word(0), 0xa1b2c3d4);
+        assert_eq!(word(16), SNAPLEN);
+        assert_eq!(word(20), LINKTYPE_ETHERNET);
+        assert_eq!([word(24), word(28), word(32), word(36)], [1, 2, 60, 100]);
+        assert!(buf[40..].iter().all(|&b| b == 0xaa));
+    }
+}
@@ -0,0 +1,507 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! 464XLAT customer-side translator(CLAT, RFC 6877) on IPv6-only uplinks.
+//!
+//! IPv4 traffic is routed into a TUN interface created by us, where it's
+//! translated to IPv6 by BPF programs after NAT44 and sent out of the uplink,
+//! translated replies are redirected back to the TUN interface. This module
+//! creates the TUN interface and discovers the NAT64 prefix and CLAT IPv6
+//! address to translate with.
+use std::fs::OpenOptions;
+use std::io;
+use std::mem::size_of;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use ipnet::Ipv6Net;
+use netlink_packet_route::address::AddressMessage;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tracing::{debug, info, warn};
+
+use crate::config::{ConfigClat, NetIfId};
+use crate::route::{PacketEncap, RouteHelper};
+use crate::utils::{is_global_unicast, set_sysctl, with_netns, NetNs};
+
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc64"
+)))]
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+#[cfg(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc64"
+))]
+const TUNSETIFF: libc::c_ulong = 0x800454ca;
+
+const ND_ROUTER_SOLICIT: u8 = 133;
+const ND_ROUTER_ADVERT: u8 = 134;
+const ND_OPT_PREF64: u8 = 38;
+const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+const RA_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Well-known IPv4 addresses of `ipv4only.arpa`, see RFC 7050
+const IPV4ONLY_ADDRS: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+/// Interface identifier of CLAT IPv6 address derived from uplink prefix
+const CLAT_IID: u128 = 0xc1a7_0464;
+
+/// IPv4 default route via CLAT interface, with high metric so native IPv4
+/// routes are preferred if any
+const DEFAULT_ROUTE_METRIC: u32 = 2048;
+
+/// MTU of CLAT interface is reduced by the size difference of IPv4 and IPv6
+/// headers from that of uplink.
+const HEADER_GROWTH: u32 = 20;
+
+/// CLAT interface and its addresses. The interface is removed on drop, while
+/// the CLAT IPv6 address on uplink is only removed by [`Clat::deconfigure`].
+pub struct Clat {
+    _tun: OwnedFd,
+    rt_helper: RouteHelper,
+    /// IPv6 address is derived from uplink addresses if not configured
+    derive_address: bool,
+    pub uplink_if_index: u32,
+    pub uplink_has_eth: bool,
+    pub ipv4_address: Ipv4Addr,
+    pub pref64: Ipv6Net,
+    pub ipv6_address: Ipv6Addr,
+    /// CLAT IPv6 address added to uplink, so neighbor solicitations of it are
+    /// answered
+    uplink_address: Option<AddressMessage>,
+}
+
+impl Clat {
+    /// Creates CLAT interface `if_name` with IPv4 address and default route,
+    /// and assigns CLAT IPv6 address on uplink.
+    pub async fn setup(
+        netns: Option<&NetNs>,
+        rt_helper: &RouteHelper,
+        if_name: &str,
+        config: &ConfigClat,
+    ) -> Result<Self> {
+        if let Some(pref64) = config.pref64 {
+            check_pref64(pref64)?;
+        }
+        let uplink_if_index = with_netns(netns, || {
+            NetIfId::Name {
+                if_name: config.uplink_if_name.clone(),
+            }
+            .resolve_index()
+        })?;
+        let uplink_link_info = rt_helper.query_link_info(uplink_if_index).await?;
+        let uplink_has_eth = match uplink_link_info.encap() {
+            PacketEncap::Ethernet => true,
+            PacketEncap::BareIp => false,
+            encap => {
+                return Err(anyhow!(
+                    "unsupported packet encapsulation {:?} of CLAT uplink {}",
+                    encap,
+                    config.uplink_if_name
+                ))
+            }
+        };
+
+        let ipv6_address = match config.ipv6_address {
+            Some(address) => address,
+            None => {
+                let addresses = rt_helper.query_all_addresses(uplink_if_index).await?;
+                derive_ipv6_address(&addresses.ipv6).ok_or_else(|| {
+                    anyhow!(
+                        "no global IPv6 address on CLAT uplink {} to derive CLAT address from, set `ipv6_address` explicitly",
+                        config.uplink_if_name
+                    )
+                })?
+            }
+        };
+        let pref64 = match config.pref64 {
+            Some(pref64) => pref64.trunc(),
+            None => {
+                let pref64 = discover_pref64(netns, uplink_if_index).await?;
+                check_pref64(pref64)?;
+                pref64
+            }
+        };
+
+        let tun = with_netns(netns, || create_tun(if_name))?;
+        let tun_if_index = with_netns(netns, || {
+            NetIfId::Name {
+                if_name: if_name.to_string(),
+            }
+            .resolve_index()
+        })?;
+        with_netns(netns, || {
+            set_sysctl(&format!("net/ipv6/conf/{}/disable_ipv6", if_name), "1")
+        })?;
+        let mtu = uplink_link_info
+            .mtu()
+            .map(|mtu| mtu.saturating_sub(HEADER_GROWTH));
+        rt_helper.set_link_up(tun_if_index, mtu).await?;
+        rt_helper
+            .add_address(tun_if_index, config.ipv4_address.into(), 32)
+            .await?;
+        rt_helper
+            .add_v4_default_route(tun_if_index, DEFAULT_ROUTE_METRIC)
+            .await?;
+
+        let mut this = Self {
+            _tun: tun,
+            rt_helper: rt_helper.clone(),
+            derive_address: config.ipv6_address.is_none(),
+            uplink_if_index,
+            uplink_has_eth,
+            ipv4_address: config.ipv4_address,
+            pref64,
+            ipv6_address,
+            uplink_address: None,
+        };
+        this.add_uplink_address().await?;
+        info!(
+            "CLAT interface {} translating IPv4 to IPv6 from {} with NAT64 prefix {}",
+            if_name, ipv6_address, pref64
+        );
+        Ok(this)
+    }
+
+    async fn add_uplink_address(&mut self) -> Result<()> {
+        let msg = self
+            .rt_helper
+            .add_address(self.uplink_if_index, self.ipv6_address.into(), 128)
+            .await?;
+        self.uplink_address = Some(msg);
+        Ok(())
+    }
+
+    async fn del_uplink_address(&mut self) -> Result<()> {
+        if let Some(msg) = self.uplink_address.take() {
+            self.rt_helper.del_address(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Derives CLAT IPv6 address again on address changes of uplink, returns
+    /// whether it's changed. The previous address is kept if no global address
+    /// is left on uplink.
+    pub async fn reconfigure_ipv6_address(
+        &mut self,
+        uplink_addresses: &[Ipv6Addr],
+    ) -> Result<bool> {
+        if !self.derive_address {
+            return Ok(false);
+        }
+        let Some(address) = derive_ipv6_address(uplink_addresses) else {
+            return Ok(false);
+        };
+        if address == self.ipv6_address {
+            return Ok(false);
+        }
+        info!("CLAT IPv6 address {} -> {}", self.ipv6_address, address);
+        if let Err(e) = self.del_uplink_address().await {
+            warn!("failed to delete previous CLAT address from uplink: {}", e);
+        }
+        self.ipv6_address = address;
+        self.add_uplink_address().await?;
+        Ok(true)
+    }
+
+    pub async fn deconfigure(&mut self) -> Result<()> {
+        self.del_uplink_address().await
+    }
+}
+
+fn check_pref64(pref64: Ipv6Net) -> Result<()> {
+    if pref64.prefix_len() != 96 {
+        return Err(anyhow!(
+            "NAT64 prefix {} is not supported, only /96 prefixes are",
+            pref64
+        ));
+    }
+    Ok(())
+}
+
+/// Creates TUN interface of bare IP packets, which is removed once the
+/// returned file descriptor is closed.
+pub fn create_tun(if_name: &str) -> Result<OwnedFd> {
+    if if_name.len() >= libc::IFNAMSIZ {
+        return Err(anyhow!("interface name {} is too long", if_name));
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")
+        .context("failed to open /dev/net/tun")?;
+
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(if_name.as_bytes()) {
+        *dst = *src as _;
+    }
+    ifr.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as _;
+    if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &ifr) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("failed to create TUN interface {}", if_name));
+    }
+    Ok(file.into())
+}
+
+/// Derives CLAT IPv6 address within /64 prefix of the first global IPv6
+/// address of uplink.
+pub fn derive_ipv6_address(uplink_addresses: &[Ipv6Addr]) -> Option<Ipv6Addr> {
+    let iid_mask = (1u128 << 64) - 1;
+    let addr = uplink_addresses
+        .iter()
+        .filter(|addr| u128::from(**addr) & iid_mask != CLAT_IID)
+        .find(|addr| is_global_unicast(addr))?;
+    Some(Ipv6Addr::from((u128::from(*addr) & !iid_mask) | CLAT_IID))
+}
+
+/// Discovers NAT64 prefix from PREF64 option of router advertisements on
+/// uplink, or from AAAA records of `ipv4only.arpa` synthesized by DNS64.
+pub async fn discover_pref64(netns: Option<&NetNs>, uplink_if_index: u32) -> Result<Ipv6Net> {
+    match solicit_pref64(netns, uplink_if_index).await {
+        Ok(Some(pref64)) => {
+            info!(
+                "discovered NAT64 prefix {} from router advertisement",
+                pref64
+            );
+            return Ok(pref64);
+        }
+        Ok(None) => debug!("no PREF64 option in router advertisements"),
+        Err(e) => warn!("failed to solicit router advertisement: {}", e),
+    }
+
+    let addrs = tokio::net::lookup_host(("ipv4only.arpa", 0))
+        .await
+        .context("failed to resolve ipv4only.arpa")?;
+    for addr in addrs {
+        if let SocketAddr::V6(addr) = addr {
+            if let Some(pref64) = pref64_from_synthesized(*addr.ip()) {
+                info!("discovered NAT64 prefix {} from DNS64", pref64);
+                return Ok(pref64);
+            }
+        }
+    }
+
+    Err(anyhow!(
+        "failed to discover NAT64 prefix, set `pref64` explicitly"
+    ))
+}
+
+/// Sends a router solicitation on uplink, and waits for PREF64 option in
+/// router advertisements.
+async fn solicit_pref64(netns: Option<&NetNs>, if_index: u32) -> Result<Option<Ipv6Net>> {
+    let fd = with_netns(netns, || {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_INET6,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::IPPROTO_ICMPV6,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    })?;
+    let set_opt = |level, name, value: libc::c_int| {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                level,
+                name,
+                &value as *const _ as _,
+                size_of::<libc::c_int>() as _,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    // required by RFC 4861
+    set_opt(libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_HOPS, 255)?;
+    set_opt(libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_IF, if_index as _)?;
+
+    let fd = AsyncFd::with_interest(fd, Interest::READABLE)?;
+
+    let mut dest: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    dest.sin6_family = libc::AF_INET6 as _;
+    dest.sin6_addr.s6_addr = ALL_ROUTERS.octets();
+    dest.sin6_scope_id = if_index;
+    // type, code, checksum filled by kernel and reserved
+    let rs = [ND_ROUTER_SOLICIT, 0, 0, 0, 0, 0, 0, 0];
+    let ret = unsafe {
+        libc::sendto(
+            fd.as_raw_fd(),
+            rs.as_ptr() as _,
+            rs.len(),
+            0,
+            &dest as *const _ as _,
+            size_of::<libc::sockaddr_in6>() as _,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let recv = async {
+        let mut buf = [0u8; 1500];
+        loop {
+            let mut guard = fd.readable().await?;
+            let mut src: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+            let mut src_len = size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            let res = guard.try_io(|fd| {
+                let ret = unsafe {
+                    libc::recvfrom(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as _,
+                        buf.len(),
+                        0,
+                        &mut src as *mut _ as _,
+                        &mut src_len,
+                    )
+                };
+                if ret < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(ret as usize)
+                }
+            });
+            let Ok(len) = res else {
+                continue;
+            };
+            if src.sin6_scope_id != if_index {
+                continue;
+            }
+            if let Some(pref64) = parse_ra_pref64(&buf[..len?]) {
+                return Ok::<_, anyhow::Error>(pref64);
+            }
+        }
+    };
+    match tokio::time::timeout(RA_TIMEOUT, recv).await {
+        Ok(res) => res.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parses NAT64 prefix from PREF64 option of router advertisement ICMPv6
+/// message, see RFC 8781.
+fn parse_ra_pref64(msg: &[u8]) -> Option<Ipv6Net> {
+    if msg.len() < 16 || msg[0] != ND_ROUTER_ADVERT {
+        return None;
+    }
+    let mut options = &msg[16..];
+    while options.len() >= 8 {
+        let len = options[1] as usize * 8;
+        if len == 0 || len > options.len() {
+            return None;
+        }
+        let option = &options[..len];
+        options = &options[len..];
+        if option[0] != ND_OPT_PREF64 || len != 16 {
+            continue;
+        }
+        let scaled_lifetime = u16::from_be_bytes([option[2], option[3]]) >> 3;
+        if scaled_lifetime == 0 {
+            // prefix is withdrawn
+            continue;
+        }
+        let prefix_len = match option[3] & 0x7 {
+            0 => 96,
+            1 => 64,
+            2 => 56,
+            3 => 48,
+            4 => 40,
+            5 => 32,
+            _ => continue,
+        };
+        let mut octets = [0u8; 16];
+        octets[..12].copy_from_slice(&option[4..16]);
+        return Ipv6Net::new(Ipv6Addr::from(octets), prefix_len)
+            .ok()
+            .map(|prefix| prefix.trunc());
+    }
+    None
+}
+
+/// Extracts /96 NAT64 prefix from address synthesized by DNS64 for
+/// `ipv4only.arpa`, other prefix lengths of RFC 6052 are not supported by
+/// our translator.
+fn pref64_from_synthesized(addr: Ipv6Addr) -> Option<Ipv6Net> {
+    let octets = addr.octets();
+    let ipv4 = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+    if IPV4ONLY_ADDRS.contains(&ipv4) {
+        Ipv6Net::new(addr, 96).ok().map(|prefix| prefix.trunc())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pref64() {
+        let mut ra = vec![
+            ND_ROUTER_ADVERT,
+            0,
+            0,
+            0,
+            64,
+            0,
+            7,
+            8,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        // MTU option
+        ra.extend([5, 1, 0, 0, 0, 0, 5, 220]);
+        // PREF64 option of 64:ff9b::/96 with lifetime of 600s
+        ra.extend([ND_OPT_PREF64, 2, 0x04, 0xb0]);
+        ra.extend([0, 0x64, 0xff, 0x9b, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(parse_ra_pref64(&ra), Some("64:ff9b::/96".parse().unwrap()));
+
+        // withdrawn
+        ra[26] = 0;
+        ra[27] = 0;
+        assert_eq!(parse_ra_pref64(&ra), None);
+
+        assert_eq!(
+            pref64_from_synthesized("64:ff9b::c000:aa".parse().unwrap()),
+            Some("64:ff9b::/96".parse().unwrap())
+        );
+        assert_eq!(
+            pref64_from_synthesized("2001:db8::1".parse().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn clat_address() {
+        let addresses = [
+            "fe80::1".parse().unwrap(),
+            "2001:db8:1:2::c1a7:464".parse().unwrap(),
+            "2001:db8:1:3::1".parse().unwrap(),
+        ];
+        assert_eq!(
+            derive_ipv6_address(&addresses),
+            Some("2001:db8:1:3::c1a7:464".parse().unwrap())
+        );
+        assert_eq!(derive_ipv6_address(&addresses[..2]), None);
+    }
+}
@@ -3,9 +3,10 @@
 //! User-facing configuration types
 
 use std::fmt::Display;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::num::NonZeroU32;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::{NonZeroU16, NonZeroU32};
 use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use anyhow::Result;
@@ -14,12 +15,21 @@ use nix::net::if_::if_nametoindex;
 use serde::de::Error as DeError;
 use serde::{de::Visitor, Deserialize};
 
+use crate::utils::{glob_match, is_glob};
+
 #[derive(Debug, Clone)]
 pub struct ProtoRange {
     pub inner: RangeInclusive<u16>,
 }
 type ProtoRanges = Vec<ProtoRange>;
 
+/// TCP or UDP port range, e.g. "udp/500" or "tcp/8000-8080"
+#[derive(Debug, Clone)]
+pub struct ProtoPortRange {
+    pub proto: IpProtocol,
+    pub ports: ProtoRange,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct ConfigDefaults {
@@ -29,11 +39,19 @@ pub struct ConfigDefaults {
     pub ipv6_hairpin_rule_pref: u32,
     pub ipv4_hairpin_table_id: NonZeroU32,
     pub ipv6_hairpin_table_id: NonZeroU32,
+    pub hairpin_fwmark: NonZeroU32,
+    pub reject_fwmark: NonZeroU32,
+    pub hairpin_route_protocol: u8,
     pub tcp_ranges: ProtoRanges,
     pub udp_ranges: ProtoRanges,
     pub icmp_ranges: ProtoRanges,
     pub icmp_in_ranges: ProtoRanges,
     pub icmp_out_ranges: ProtoRanges,
+    pub excluded_ports: ProtoRanges,
+    pub btf_path: Option<PathBuf>,
+    pub bpf_object_path: Option<PathBuf>,
+    pub control_socket: Option<PathBuf>,
+    pub state_sync: Option<ConfigStateSync>,
 }
 
 #[derive(Debug, Clone, Copy, Deserialize)]
@@ -42,19 +60,68 @@ pub enum AddressMatcher {
     Range4 { start: Ipv4Addr, end: Ipv4Addr },
     Range6 { start: Ipv6Addr, end: Ipv6Addr },
     Network(IpNet),
+    Address(IpAddr),
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum AddressOrMatcher {
-    Static { address: IpAddr },
-    Matcher { match_address: AddressMatcher },
+    Static {
+        address: IpAddr,
+    },
+    Matcher {
+        match_address: AddressMatcher,
+    },
+    /// Address printed by a shell command, e.g. querying a provider API
+    Command {
+        address_command: String,
+    },
+    /// Source address of route towards destination, if routed via interface
+    Route {
+        match_route: IpAddr,
+    },
+}
+
+/// Scope of address, as shown by `ip address`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressScope {
+    Global,
+    Site,
+    Link,
+    Host,
+}
+
+/// Address flag name as shown by `ip address`, prefixed with "!" to match
+/// addresses without the flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressFlagMatch {
+    /// `IFA_F_*` bit
+    pub flag: u32,
+    pub negated: bool,
+}
+
+/// Matches interface addresses by attributes other than the address itself
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AddressAttrsMatcher {
+    /// Glob pattern of address label, IPv6 addresses have no labels
+    #[serde(default)]
+    pub match_label: Option<String>,
+    #[serde(default)]
+    pub match_scope: Option<AddressScope>,
+    #[serde(default)]
+    pub match_flags: Vec<AddressFlagMatch>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ConfigExternal {
     #[serde(flatten)]
     pub address: AddressOrMatcher,
+    #[serde(flatten)]
+    pub attrs: AddressAttrsMatcher,
+    /// Addresses not matched even if matching `match_address`
+    #[serde(default)]
+    pub exclude_address: Vec<AddressMatcher>,
     #[serde(default)]
     pub no_snat: bool,
     #[serde(default)]
@@ -69,6 +136,21 @@ pub struct ConfigExternal {
     pub icmp_in_ranges: Option<ProtoRanges>,
     #[serde(default)]
     pub icmp_out_ranges: Option<ProtoRanges>,
+    /// TCP and UDP ports within port ranges never allocated dynamically
+    #[serde(default)]
+    pub excluded_ports: Option<ProtoRanges>,
+    #[serde(default)]
+    pub sources: Vec<IpNet>,
+    #[serde(default = "default_external_weight")]
+    pub weight: u32,
+    /// Protocols the address is used for by new bindings, all if not set
+    #[serde(default)]
+    pub protocols: Option<Vec<IpProtocol>>,
+    #[serde(default)]
+    pub filtering: Option<Filtering>,
+    /// Internal address mapped 1:1 to the static external address
+    #[serde(default)]
+    pub internal_address: Option<IpAddr>,
 }
 
 impl ConfigExternal {
@@ -82,6 +164,8 @@ impl ConfigExternal {
             address: AddressOrMatcher::Matcher {
                 match_address: AddressMatcher::Network(network_any),
             },
+            attrs: Default::default(),
+            exclude_address: Vec::new(),
             no_snat: false,
             no_hairpin: false,
             tcp_ranges: None,
@@ -89,6 +173,12 @@ impl ConfigExternal {
             icmp_ranges: None,
             icmp_in_ranges: None,
             icmp_out_ranges: None,
+            excluded_ports: None,
+            sources: Vec::new(),
+            weight: default_external_weight(),
+            protocols: None,
+            filtering: None,
+            internal_address: None,
         }
     }
 
@@ -104,8 +194,23 @@ impl ConfigExternal {
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 pub enum NetIfId {
-    Index { if_index: u32 },
-    Name { if_name: String },
+    Index {
+        if_index: u32,
+    },
+    Name {
+        if_name: String,
+    },
+    /// Output interface of default route, followed on changes
+    Auto {
+        interface: AutoNetIf,
+    },
+}
+
+/// Value of `interface = "auto"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoNetIf {
+    Auto,
 }
 
 impl Default for NetIfId {
@@ -114,10 +219,127 @@ impl Default for NetIfId {
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortAllocation {
+    #[default]
+    Random,
+    Sequential,
+    Hashed,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExternalSelection {
+    #[default]
+    First,
+    Hash,
+    RoundRobin,
+}
+
+/// How a TCP record is torn down once the connection is closed by FIN from
+/// both sides or by RST
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TcpTeardown {
+    /// Expire after `timeout_tcp_trans`
+    #[default]
+    Transitory,
+    /// Expire after `timeout_tcp_time_wait`
+    TimeWait,
+    /// Remove immediately
+    Close,
+    /// Keep the established state and timeout
+    Ignore,
+}
+
+/// How an inbound TCP RST to a mapping without matching record is handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnmatchedTcpRst {
+    #[default]
+    Drop,
+    /// Translate to the internal endpoint of mapping
+    Pass,
+    /// Reply ICMP administratively prohibited error
+    Reply,
+}
+
+/// Filtering behavior of inbound packets from remote endpoints other than
+/// those an internal endpoint has sent packets to, see RFC 4787 section 5
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filtering {
+    #[default]
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+}
+
+/// nftables set of addresses, e.g. `nft add set inet nat no_snat { type
+/// ipv4_addr; flags interval; }`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigNftSet {
+    #[serde(default = "default_nft_family")]
+    pub family: String,
+    pub table: String,
+    pub name: String,
+}
+
+fn default_nft_family() -> String {
+    "inet".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ConfigFilteringDest {
+    pub network: IpNet,
+    pub filtering: Filtering,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ConfigTimeoutDest {
+    pub network: IpNet,
+    #[serde(default)]
+    pub timeout_udp: Option<Timeout>,
+    #[serde(default)]
+    pub timeout_tcp_est: Option<Timeout>,
+}
+
+/// How packets from internal interfaces towards external addresses are
+/// steered to external interface for hairpinning
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HairpinMode {
+    /// Policy routing with IP rules of internal interfaces and a route table
+    #[default]
+    Route,
+    /// BPF program on internal interfaces redirecting packets to external
+    /// interface
+    Redirect,
+    /// BPF program on internal interfaces marking packets, and a single fwmark
+    /// IP rule per IP protocol pointing to the route table
+    Fwmark,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressPooling {
+    #[default]
+    Arbitrary,
+    Paired,
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct Timeout(pub u64);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapSize {
+    /// Sized from system memory and `expected_hosts`
+    Auto,
+    Entries(NonZeroU32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum IpProtocol {
     Tcp,
     Udp,
@@ -129,8 +351,19 @@ pub struct ConfigHairpinRoute {
     #[serde(default)]
     pub enable: Option<bool>,
     #[serde(default)]
+    pub mode: HairpinMode,
+    #[serde(default)]
     pub internal_if_names: Vec<String>,
     #[serde(default)]
+    pub container_bridges: bool,
+    /// Also hairpin traffic generated by the router itself
+    #[serde(default)]
+    pub local: bool,
+    /// Source networks of internal traffic to hairpin, regardless of input
+    /// interfaces
+    #[serde(default)]
+    pub internal_networks: Vec<IpNet>,
+    #[serde(default)]
     pub ip_rule_pref: Option<u32>,
     #[serde(default)]
     pub table_id: Option<NonZeroU32>,
@@ -138,36 +371,339 @@ pub struct ConfigHairpinRoute {
     pub ip_protocols: Vec<IpProtocol>,
 }
 
+impl ConfigHairpinRoute {
+    /// Whether internal interfaces change with links, i.e. container bridges
+    /// or glob patterns in `internal_if_names`.
+    pub fn has_dynamic_if_names(&self) -> bool {
+        self.container_bridges || self.internal_if_names.iter().any(|name| is_glob(name))
+    }
+}
+
+/// Stateless NETMAP-style mapping between IPv4 prefixes of the same length,
+/// keeping host bits
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ConfigNetmap {
+    pub internal_prefix: Ipv4Net,
+    pub external_prefix: Ipv4Net,
+}
+
+/// Persistent binding of an external port to an internal endpoint, never
+/// expired or evicted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ConfigStaticBinding {
+    pub internal: SocketAddr,
+    pub external_port: u16,
+    pub proto: IpProtocol,
+    /// Defaults to the first external address of the same family
+    #[serde(default)]
+    pub external_address: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigDeterministicNat {
+    pub internal_network: Ipv4Net,
+    pub block_size: NonZeroU16,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigNptv6 {
+    #[cfg(feature = "ipv6")]
+    pub internal_prefix: Ipv6Net,
+    /// Discovered from global addresses of interface if not set
+    #[serde(default)]
+    pub external_prefix: Option<Ipv6Net>,
+    /// Interface to discover external prefix from instead of the external
+    /// interface, e.g. an internal interface numbered from delegated prefix
+    #[cfg(feature = "ipv6")]
+    #[serde(default)]
+    pub prefix_if_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigClat {
+    /// IPv6-only interface to send translated packets out of
+    #[cfg(feature = "ipv6")]
+    pub uplink_if_name: String,
+    /// IPv4 address assigned to the CLAT interface, which packets are
+    /// SNATed to before translation
+    #[cfg(feature = "ipv6")]
+    #[serde(default = "default_clat_ipv4_address")]
+    pub ipv4_address: Ipv4Addr,
+    /// Derived from global address prefix of uplink if not set
+    #[cfg(feature = "ipv6")]
+    #[serde(default)]
+    pub ipv6_address: Option<Ipv6Addr>,
+    /// Discovered from router advertisements or DNS64 if not set
+    #[cfg(feature = "ipv6")]
+    #[serde(default)]
+    pub pref64: Option<Ipv6Net>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigDsLite {
+    /// Interface to send IPv4-in-IPv6 packets out of
+    pub uplink_if_name: String,
+    /// IPv6 address or domain name of AFTR
+    pub aftr: String,
+    /// IPv4 address assigned to the B4 tunnel interface
+    #[serde(default = "default_dslite_ipv4_address")]
+    pub ipv4_address: Ipv4Addr,
+    /// Tunnel source address, first global address of uplink if not set
+    #[serde(default)]
+    pub ipv6_address: Option<Ipv6Addr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigStateSync {
+    /// Address to receive bindings pushed by peer on
+    #[serde(default)]
+    pub listen: Option<SocketAddr>,
+    /// Address of peer to push bindings to, required with `listen` as
+    /// connections to it are only accepted from IP address of peer
+    #[serde(default)]
+    pub peer: Option<SocketAddr>,
+    #[serde(default)]
+    pub interval: Option<Timeout>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigFailover {
+    /// Interface to perform NAT on instead if the configured one is not live
+    pub backup_if_name: String,
+    /// Keep external ports of bindings when switching between interfaces
+    #[serde(default)]
+    pub preserve_ports: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ConfigPortQuota {
+    #[serde(default)]
+    pub tcp: Option<u32>,
+    #[serde(default)]
+    pub udp: Option<u32>,
+    #[serde(default)]
+    pub icmp: Option<u32>,
+}
+
+/// Filter of packets to trace, parsed from expression like
+/// "udp and host 192.168.1.100", matches any packet if empty
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub protocol: Option<IpProtocol>,
+    /// Matches either source or destination address
+    pub network: Option<IpNet>,
+    /// Matches either source or destination port
+    pub port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigCapture {
+    /// pcap file to write captured packets to
+    pub path: PathBuf,
+    /// Only capture packets of internal hosts within this network
+    #[serde(default)]
+    pub internal: Option<IpNet>,
+    #[serde(default)]
+    pub protocol: Option<IpProtocol>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigRateLimit {
+    /// Events per second, e.g. new bindings
+    pub rate: NonZeroU32,
+    /// Defaults to `rate`
+    #[serde(default)]
+    pub burst: Option<NonZeroU32>,
+}
+
+impl ConfigRateLimit {
+    /// Nanoseconds between two events at sustained rate
+    pub fn interval(&self) -> u64 {
+        (1_000_000_000 / self.rate.get() as u64).max(1)
+    }
+
+    pub fn max_burst(&self) -> u32 {
+        self.burst.unwrap_or(self.rate).get()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigFwmark {
+    pub mark: NonZeroU32,
+    /// Defaults to all bits
+    #[serde(default)]
+    pub mask: Option<NonZeroU32>,
+}
+
+impl ConfigFwmark {
+    pub fn mask(&self) -> u32 {
+        self.mask.map_or(u32::MAX, NonZeroU32::get)
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Default, Deserialize)]
 pub struct ConfigNetIf {
     #[serde(flatten)]
     pub interface: NetIfId,
     #[serde(default)]
+    pub netns: Option<String>,
+    #[serde(default)]
+    pub pppoe_lower_if_name: Option<String>,
+    #[serde(default)]
     pub nat44: bool,
     #[serde(default)]
     pub nat66: bool,
     #[serde(default)]
+    pub nptv6: Option<ConfigNptv6>,
+    #[serde(default)]
+    pub netmap: Vec<ConfigNetmap>,
+    #[serde(default)]
+    pub static_bindings: Vec<ConfigStaticBinding>,
+    #[serde(default)]
+    pub clat: Option<ConfigClat>,
+    #[serde(default)]
+    pub dslite: Option<ConfigDsLite>,
+    #[serde(default)]
+    pub failover: Option<ConfigFailover>,
+    #[serde(default)]
+    pub vrrp_address: Option<IpAddr>,
+    #[serde(default)]
     pub bpf_log_level: Option<u8>,
     #[serde(default)]
+    pub bpf_log_max_level: Option<u8>,
+    #[serde(default)]
     pub bpf_fib_lookup_external: Option<bool>,
     #[serde(default)]
     pub allow_inbound_icmpx: Option<bool>,
     #[serde(default)]
+    pub dmz_host: Option<IpAddr>,
+    #[serde(default)]
+    pub exclude_local_ports: bool,
+    #[serde(default)]
+    pub pptp: bool,
+    #[serde(default)]
+    pub esp_passthrough: bool,
+    #[serde(default)]
+    pub drop_malformed_icmp_errors: bool,
+    #[serde(default)]
+    pub icmp_on_alloc_failure: bool,
+    #[serde(default)]
+    pub port_parity: Option<bool>,
+    #[serde(default)]
+    pub port_preservation: Option<bool>,
+    #[serde(default)]
+    pub port_allocation: Option<PortAllocation>,
+    #[serde(default)]
+    pub address_pooling: Option<AddressPooling>,
+    #[serde(default)]
+    pub external_selection: Option<ExternalSelection>,
+    #[serde(default)]
+    pub external_spillover: Option<bool>,
+    #[serde(default)]
+    pub filtering: Option<Filtering>,
+    #[serde(default)]
+    pub inbound_refresh: Option<bool>,
+    #[serde(default)]
     pub timeout_fragment: Option<Timeout>,
     #[serde(default)]
     pub timeout_pkt_min: Option<Timeout>,
     #[serde(default)]
     pub timeout_pkt_default: Option<Timeout>,
     #[serde(default)]
+    pub timeout_udp_single: Option<Timeout>,
+    #[serde(default)]
+    pub timeout_udp_stream: Option<Timeout>,
+    #[serde(default)]
+    pub udp_stream_packets: Option<u8>,
+    #[serde(default)]
+    pub timeout_icmp: Option<Timeout>,
+    #[serde(default)]
     pub timeout_tcp_trans: Option<Timeout>,
     #[serde(default)]
     pub timeout_tcp_est: Option<Timeout>,
+    #[serde(default)]
+    pub timeout_tcp_time_wait: Option<Timeout>,
+    #[serde(default)]
+    pub tcp_fin_teardown: Option<TcpTeardown>,
+    #[serde(default)]
+    pub tcp_rst_teardown: Option<TcpTeardown>,
+    #[serde(default)]
+    pub unmatched_tcp_rst: Option<UnmatchedTcpRst>,
+    #[serde(default)]
+    pub drop_unsolicited: bool,
+    #[serde(default)]
+    pub bypass_fwmark: Option<ConfigFwmark>,
+    #[serde(default)]
+    pub egress_dscp: Option<u8>,
+    #[serde(default)]
+    pub ingress_dscp: Option<u8>,
+    #[serde(default)]
+    pub checksum_offload_compat: bool,
+    #[serde(default)]
+    pub max_binding_lifetime: Option<Timeout>,
+    #[serde(default)]
+    pub deterministic_nat: Option<ConfigDeterministicNat>,
+    #[serde(default)]
+    pub max_sessions_per_host: Option<u32>,
+    #[serde(default)]
+    pub max_embryonic_per_host: Option<u32>,
+    #[serde(default)]
+    pub embryonic_tarpit: bool,
+    #[serde(default)]
+    pub port_quota: Option<ConfigPortQuota>,
+    #[serde(default)]
+    pub binding_rate_limit: Option<ConfigRateLimit>,
+    #[serde(default)]
+    pub icmp_error_rate_limit: Option<ConfigRateLimit>,
+    #[serde(default)]
+    pub udp_binding_rate_limit: Option<ConfigRateLimit>,
+    #[serde(default)]
+    pub udp_host_binding_rate_limit: Option<ConfigRateLimit>,
+    #[serde(default)]
+    pub udp_high_water: Option<u32>,
+    #[serde(default)]
+    pub udp_emergency_timeout: Option<Timeout>,
+    #[serde(default)]
+    pub gc_interval: Option<Timeout>,
+    #[serde(default)]
+    pub address_expiry_margin: Option<Timeout>,
+    #[serde(default)]
+    pub address_command_interval: Option<Timeout>,
+    #[serde(default)]
+    pub map_size: Option<MapSize>,
+    #[serde(default)]
+    pub expected_hosts: Option<NonZeroU32>,
+    #[serde(default)]
+    pub ct_lru: Option<bool>,
+    #[serde(default)]
+    pub pin_path: Option<PathBuf>,
+    #[serde(default)]
+    pub binding_snapshot: Option<PathBuf>,
+    #[serde(default)]
+    pub capture: Option<ConfigCapture>,
+    #[serde(default)]
+    pub trace: Option<TraceFilter>,
     #[serde(default = "default_true")]
     pub default_externals: bool,
     #[serde(default)]
     pub no_snat_dests: Vec<IpNet>,
     #[serde(default)]
+    pub blocked_dests: Vec<IpNet>,
+    #[serde(default)]
+    pub no_snat_sources: Vec<IpNet>,
+    #[serde(default)]
+    pub no_snat_dports: Vec<ProtoPortRange>,
+    #[serde(default)]
+    pub no_snat_nft_set: Option<ConfigNftSet>,
+    #[serde(default)]
+    pub nft_set_interval: Option<Timeout>,
+    #[serde(default)]
+    pub filtering_dests: Vec<ConfigFilteringDest>,
+    #[serde(default)]
+    pub timeout_dests: Vec<ConfigTimeoutDest>,
+    #[serde(default)]
     pub externals: Vec<ConfigExternal>,
     #[serde(default)]
     pub ipv4_hairpin_route: ConfigHairpinRoute,
@@ -187,13 +723,34 @@ pub struct Config {
     pub interfaces: Vec<ConfigNetIf>,
 }
 
+impl Display for NetIfId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetIfId::Index { if_index } => write!(f, "#{}", if_index),
+            NetIfId::Name { if_name } => f.write_str(if_name),
+            NetIfId::Auto {
+                interface: AutoNetIf::Auto,
+            } => f.write_str("auto"),
+        }
+    }
+}
+
 impl NetIfId {
+    /// Resolves index of interface, which is not supported for
+    /// `interface = "auto"` resolved by route lookup instead.
     pub fn resolve_index(&self) -> Result<u32> {
         match self {
             NetIfId::Index { if_index } => Ok(*if_index),
             NetIfId::Name { if_name } => Ok(if_nametoindex(if_name.as_str())?),
+            NetIfId::Auto { .. } => Err(anyhow::anyhow!(
+                "interface \"auto\" is resolved from default route"
+            )),
         }
     }
+
+    pub fn is_auto(&self) -> bool {
+        matches!(self, NetIfId::Auto { .. })
+    }
 }
 
 impl From<Timeout> for u64 {
@@ -235,6 +792,65 @@ impl<'de> Deserialize<'de> for Timeout {
     }
 }
 
+impl<'de> Deserialize<'de> for MapSize {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct MapSizeVisitor;
+        impl<'de> Visitor<'de> for MapSizeVisitor {
+            type Value = MapSize;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("number of map entries or \"auto\"")
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                u32::try_from(v)
+                    .ok()
+                    .and_then(NonZeroU32::new)
+                    .map(MapSize::Entries)
+                    .ok_or_else(|| DeError::custom("map size out of range"))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                self.visit_i64(v.try_into().unwrap_or(-1))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                if v.eq_ignore_ascii_case("auto") {
+                    Ok(MapSize::Auto)
+                } else {
+                    Err(DeError::custom(
+                        "Invalid map size, expecting number of entries or \"auto\".",
+                    ))
+                }
+            }
+        }
+
+        deserializer.deserialize_any(MapSizeVisitor)
+    }
+}
+
+impl Display for IpProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            IpProtocol::Tcp => "tcp",
+            IpProtocol::Udp => "udp",
+            IpProtocol::Icmp => "icmp",
+        })
+    }
+}
+
 impl<'de> Deserialize<'de> for IpProtocol {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -279,9 +895,8 @@ impl Display for ProtoRange {
 impl FromStr for ProtoRange {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> std::prelude::v1::Result<Self, Self::Err> {
-        let Some((start, end)) = s.split_once('-') else {
-            return Err(anyhow::anyhow!("missing '-' in port range"));
-        };
+        // single port
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
         let start: u16 = start.parse()?;
         let end: u16 = end.parse()?;
 
@@ -322,6 +937,126 @@ impl<'de> Deserialize<'de> for ProtoRange {
     }
 }
 
+impl Display for ProtoPortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}/{}", self.proto, self.ports))
+    }
+}
+
+impl FromStr for ProtoPortRange {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> std::prelude::v1::Result<Self, Self::Err> {
+        let (proto, ports) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("expecting <protocol>/<port range>"))?;
+        let proto = if proto.eq_ignore_ascii_case("tcp") {
+            IpProtocol::Tcp
+        } else if proto.eq_ignore_ascii_case("udp") {
+            IpProtocol::Udp
+        } else {
+            return Err(anyhow::anyhow!(
+                "invalid protocol {:?}, expecting \"tcp\" or \"udp\"",
+                proto
+            ));
+        };
+        Ok(ProtoPortRange {
+            proto,
+            ports: ports.parse()?,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ProtoPortRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct ProtoPortRangeVisitor;
+        impl<'de> Visitor<'de> for ProtoPortRangeVisitor {
+            type Value = ProtoPortRange;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("TCP or UDP port range, e.g. \"udp/500\"")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                v.parse().map_err(DeError::custom)
+            }
+        }
+
+        deserializer.deserialize_str(ProtoPortRangeVisitor)
+    }
+}
+
+impl FromStr for TraceFilter {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> std::prelude::v1::Result<Self, Self::Err> {
+        fn set<T>(field: &mut Option<T>, value: T, name: &str) -> Result<()> {
+            if field.replace(value).is_some() {
+                return Err(anyhow::anyhow!("duplicated {} in trace filter", name));
+            }
+            Ok(())
+        }
+
+        let mut filter = TraceFilter::default();
+        let mut tokens = s.split_whitespace();
+        while let Some(token) = tokens.next() {
+            let mut operand = || {
+                tokens
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("missing operand of {} in trace filter", token))
+            };
+            match token.to_ascii_lowercase().as_str() {
+                "and" | "any" => {}
+                "tcp" => set(&mut filter.protocol, IpProtocol::Tcp, "protocol")?,
+                "udp" => set(&mut filter.protocol, IpProtocol::Udp, "protocol")?,
+                "icmp" => set(&mut filter.protocol, IpProtocol::Icmp, "protocol")?,
+                "host" => {
+                    let addr: IpAddr = operand()?.parse()?;
+                    set(&mut filter.network, addr.into(), "host or net")?
+                }
+                "net" => set(&mut filter.network, operand()?.parse()?, "host or net")?,
+                "port" => set(&mut filter.port, operand()?.parse()?, "port")?,
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "unexpected {:?} in trace filter, expecting tcp, udp, icmp, host <address>, net <network> or port <port>",
+                        token
+                    ))
+                }
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl<'de> Deserialize<'de> for TraceFilter {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct TraceFilterVisitor;
+        impl<'de> Visitor<'de> for TraceFilterVisitor {
+            type Value = TraceFilter;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("trace filter expression")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                v.parse().map_err(DeError::custom)
+            }
+        }
+
+        deserializer.deserialize_str(TraceFilterVisitor)
+    }
+}
+
 impl Default for ConfigDefaults {
     fn default() -> Self {
         fn range(inner: RangeInclusive<u16>) -> ProtoRanges {
@@ -335,16 +1070,185 @@ impl Default for ConfigDefaults {
             ipv6_hairpin_rule_pref: 100,
             ipv4_hairpin_table_id: NonZeroU32::new(4787).unwrap(),
             ipv6_hairpin_table_id: NonZeroU32::new(4787).unwrap(),
+            hairpin_fwmark: NonZeroU32::new(0x4787).unwrap(),
+            reject_fwmark: NonZeroU32::new(0x4788).unwrap(),
+            hairpin_route_protocol: 47,
             tcp_ranges: range(20000..=29999),
             udp_ranges: range(20000..=29999),
             icmp_ranges: range(0..=u16::MAX),
             icmp_in_ranges: range(0..=9999),
             icmp_out_ranges: range(1000..=u16::MAX),
+            excluded_ports: Vec::new(),
+            btf_path: None,
+            bpf_object_path: None,
+            control_socket: None,
+            state_sync: None,
+        }
+    }
+}
+
+impl AddressOrMatcher {
+    pub fn is_ipv4(&self) -> bool {
+        match self {
+            AddressOrMatcher::Static { address } => address.is_ipv4(),
+            AddressOrMatcher::Matcher { match_address } => match match_address {
+                AddressMatcher::Range4 { .. } => true,
+                AddressMatcher::Range6 { .. } => false,
+                AddressMatcher::Network(network) => matches!(network, IpNet::V4(_)),
+                AddressMatcher::Address(address) => address.is_ipv4(),
+            },
+            // might print an IPv4 address
+            AddressOrMatcher::Command { .. } => true,
+            AddressOrMatcher::Route { match_route } => match_route.is_ipv4(),
+        }
+    }
+}
+
+impl ConfigNetIf {
+    /// Commands of externals with `address_command`
+    pub fn address_commands(&self) -> impl Iterator<Item = &str> {
+        self.externals
+            .iter()
+            .filter_map(|external| match &external.address {
+                AddressOrMatcher::Command { address_command } => Some(address_command.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Destinations of externals with `match_route`
+    pub fn route_probes(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.externals
+            .iter()
+            .filter_map(|external| match external.address {
+                AddressOrMatcher::Route { match_route } => Some(match_route),
+                _ => None,
+            })
+    }
+}
+
+impl AddressScope {
+    /// `RT_SCOPE_*` value
+    pub fn raw(self) -> u8 {
+        match self {
+            AddressScope::Global => 0,
+            AddressScope::Site => 200,
+            AddressScope::Link => 253,
+            AddressScope::Host => 254,
+        }
+    }
+}
+
+impl FromStr for AddressFlagMatch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, negated) = match s.strip_prefix('!') {
+            Some(name) => (name, true),
+            None => (s, false),
+        };
+        let flag = match name {
+            "secondary" | "temporary" => 0x01,
+            "nodad" => 0x02,
+            "optimistic" => 0x04,
+            "dadfailed" => 0x08,
+            "deprecated" => 0x20,
+            "tentative" => 0x40,
+            "permanent" => 0x80,
+            "mngtmpaddr" => 0x100,
+            "noprefixroute" => 0x200,
+            "stable-privacy" => 0x800,
+            _ => return Err(anyhow::anyhow!("unknown address flag {}", name)),
+        };
+        Ok(Self { flag, negated })
+    }
+}
+
+impl<'de> Deserialize<'de> for AddressFlagMatch {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(DeError::custom)
+    }
+}
+
+impl AddressAttrsMatcher {
+    pub fn is_empty(&self) -> bool {
+        self.match_label.is_none() && self.match_scope.is_none() && self.match_flags.is_empty()
+    }
+
+    /// Matches address with its label, `RT_SCOPE_*` scope and `IFA_F_*`
+    /// flags. Global scope excludes IPv6 unique local addresses, which are
+    /// of global scope for kernel.
+    pub fn matches(&self, address: &IpAddr, label: Option<&str>, scope: u8, flags: u32) -> bool {
+        if let Some(pattern) = &self.match_label {
+            if !label.is_some_and(|label| glob_match(pattern, label)) {
+                return false;
+            }
+        }
+        if let Some(match_scope) = self.match_scope {
+            if scope != match_scope.raw() {
+                return false;
+            }
+            let is_ula = matches!(address, IpAddr::V6(v6) if v6.segments()[0] & 0xfe00 == 0xfc00);
+            if match_scope == AddressScope::Global && is_ula {
+                return false;
+            }
         }
+        self.match_flags
+            .iter()
+            .all(|m| (flags & m.flag != 0) != m.negated)
     }
 }
 
+/// Special-purpose networks, addresses within them are useless as external
+/// addresses in general and only matched by matchers narrowed down to them
+fn special_purpose_networks() -> [IpNet; 9] {
+    let v4 = |a, b, c, len| IpNet::V4(Ipv4Net::new(Ipv4Addr::new(a, b, c, 0), len).unwrap());
+    let v6 = |addr: Ipv6Addr, len| IpNet::V6(Ipv6Net::new(addr, len).unwrap());
+    [
+        // loopback
+        v4(127, 0, 0, 8),
+        v6(Ipv6Addr::LOCALHOST, 128),
+        // link-local
+        v4(169, 254, 0, 16),
+        v6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10),
+        // shared address space of carrier-grade NAT, RFC 6598
+        v4(100, 64, 0, 10),
+        // documentation, RFC 5737 and RFC 3849
+        v4(192, 0, 2, 24),
+        v4(198, 51, 100, 24),
+        v4(203, 0, 113, 24),
+        v6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0), 32),
+    ]
+}
+
 impl AddressMatcher {
+    /// Like [`Self::contains`], but excludes special-purpose addresses unless
+    /// the matcher is within the special-purpose network, e.g.
+    /// "0.0.0.0/0" does not match 100.64.0.1 while "100.64.0.0/10" does.
+    pub fn matches(&self, address: &IpAddr) -> bool {
+        self.contains(address)
+            && special_purpose_networks()
+                .iter()
+                .filter(|network| network.contains(address))
+                .all(|network| self.is_within(network))
+    }
+
+    fn is_within(&self, network: &IpNet) -> bool {
+        match self {
+            AddressMatcher::Network(this) => network.contains(this),
+            AddressMatcher::Range4 { start, end } => {
+                network.contains(&IpAddr::V4(*start)) && network.contains(&IpAddr::V4(*end))
+            }
+            AddressMatcher::Range6 { start, end } => {
+                network.contains(&IpAddr::V6(*start)) && network.contains(&IpAddr::V6(*end))
+            }
+            AddressMatcher::Address(address) => network.contains(address),
+        }
+    }
+
     pub fn contains(&self, address: &IpAddr) -> bool {
         match self {
             AddressMatcher::Network(network) => match network {
@@ -365,6 +1269,7 @@ impl AddressMatcher {
                 IpAddr::V6(v6) => v6 >= start && v6 <= end,
                 _ => false,
             },
+            AddressMatcher::Address(this) => this == address,
         }
     }
 }
@@ -373,6 +1278,20 @@ const fn default_true() -> bool {
     true
 }
 
+const fn default_external_weight() -> u32 {
+    1
+}
+
+#[cfg(feature = "ipv6")]
+const fn default_clat_ipv4_address() -> Ipv4Addr {
+    Ipv4Addr::new(192, 0, 0, 1)
+}
+
+/// Well-known B4 address of RFC 6333
+const fn default_dslite_ipv4_address() -> Ipv4Addr {
+    Ipv4Addr::new(192, 0, 0, 2)
+}
+
 fn default_ip_protocols() -> Vec<IpProtocol> {
     vec![IpProtocol::Tcp, IpProtocol::Udp]
 }
@@ -385,24 +1304,67 @@ mod tests {
     fn test_parse() {
         let config_str = r#"
 [defaults]
+hairpin_fwmark = 0x4787
 tcp_ranges = ["10000-65535"]
 udp_ranges = ["10000-65535"]
 icmp_ranges = ["0-65535"]
 icmp_in_ranges = ["0-9999"]
 icmp_out_ranges = ["1000-65535"]
+excluded_ports = ["25565", "27000-27015"]
+state_sync = { listen = "0.0.0.0:4787", peer = "10.0.0.2:4787", interval = "5s" }
 
 [[interfaces]]
 if_index = 3
+map_size = "auto"
+expected_hosts = 200
+clat = { uplink_if_name = "wwan0" }
 
 [[interfaces]]
 if_name = "eth0"
 nat44 = true
+map_size = 262144
 nat66 = false
+nptv6 = { internal_prefix = "fd00:1234:5678::/48" }
+netmap = [{ internal_prefix = "10.0.0.0/24", external_prefix = "203.0.113.0/24" }]
+static_bindings = [{ internal = "192.168.1.5:25565", external_port = 25565, proto = "tcp" }]
+dslite = { uplink_if_name = "eth1", aftr = "aftr.example.net" }
 bpf_fib_lookup_external = false
+dmz_host = "192.168.1.100"
+exclude_local_ports = true
+pptp = true
+esp_passthrough = true
+drop_malformed_icmp_errors = true
+icmp_on_alloc_failure = true
+icmp_error_rate_limit = { rate = 10 }
+tcp_fin_teardown = "time-wait"
+tcp_rst_teardown = "close"
+unmatched_tcp_rst = "reply"
+drop_unsolicited = true
+bypass_fwmark = { mark = 0x100, mask = 0xff00 }
+egress_dscp = 0
+checksum_offload_compat = true
+max_embryonic_per_host = 64
+embryonic_tarpit = true
+udp_binding_rate_limit = { rate = 2000, burst = 4000 }
+udp_high_water = 100000
+udp_emergency_timeout = "30s"
 default_externals = true
 no_snat_dests = ["192.168.0.0/16"]
+blocked_dests = ["198.51.100.0/24", "2001:db8:bad::/48"]
+no_snat_sources = ["192.0.2.0/28"]
+no_snat_dports = ["udp/500", "TCP/8000-8080"]
+no_snat_nft_set = { table = "nat", name = "no_snat" }
+nft_set_interval = "30s"
 hairpin_dests = ["192.168.2.0/24"]
 
+[interfaces.ipv4_hairpin_route]
+mode = "redirect"
+internal_if_names = ["eth1"]
+
+[interfaces.ipv6_hairpin_route]
+mode = "fwmark"
+internal_if_names = ["eth1"]
+
 [[interfaces.externals]]
 address = "192.168.1.1"
 no_snat = false
@@ -415,10 +1377,237 @@ icmp_out_ranges = ["1000-65535"]
 
 [[interfaces.externals]]
 match_address = "192.168.1.1/24"
+exclude_address = ["192.168.1.1", { start = "192.168.1.200", end = "192.168.1.255" }]
 
 [[interfaces.externals]]
 match_address = { start = "192.168.1.1", end = "192.168.1.255" }
+protocols = ["udp", "icmp"]
+
+[[interfaces.externals]]
+match_route = "1.1.1.1"
+
+[[interfaces.externals]]
+address = "192.168.1.2"
+internal_address = "10.0.0.2"
+
+[[interfaces]]
+interface = "auto"
+
+[[interfaces]]
+if_name = "eth1"
+failover = { backup_if_name = "wwan0", preserve_ports = true }
+vrrp_address = "192.168.1.254"
         "#;
-        let _config: Config = toml::from_str(config_str).unwrap();
+        let config: Config = toml::from_str(config_str).unwrap();
+        assert_eq!(
+            config.interfaces[1].ipv4_hairpin_route.mode,
+            HairpinMode::Redirect
+        );
+        assert_eq!(
+            config.interfaces[0].ipv4_hairpin_route.mode,
+            HairpinMode::Route
+        );
+        assert_eq!(
+            config.interfaces[1].ipv6_hairpin_route.mode,
+            HairpinMode::Fwmark
+        );
+        assert_eq!(config.defaults.hairpin_fwmark.get(), 0x4787);
+        assert_eq!(config.defaults.excluded_ports[0].inner, 25565..=25565);
+        assert_eq!(config.defaults.excluded_ports[1].inner, 27000..=27015);
+        let nptv6 = config.interfaces[1].nptv6.as_ref().unwrap();
+        #[cfg(feature = "ipv6")]
+        assert_eq!(nptv6.internal_prefix.prefix_len(), 48);
+        assert!(nptv6.external_prefix.is_none());
+        assert_eq!(
+            config.interfaces[1].dmz_host,
+            Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)))
+        );
+        assert!(config.interfaces[1].exclude_local_ports);
+        assert!(config.interfaces[1].pptp);
+        assert!(config.interfaces[1].esp_passthrough);
+        assert!(config.interfaces[1].drop_malformed_icmp_errors);
+        assert!(config.interfaces[1].icmp_on_alloc_failure);
+        let limit = config.interfaces[1].icmp_error_rate_limit.as_ref().unwrap();
+        assert_eq!((limit.rate.get(), limit.burst), (10, None));
+        assert_eq!(
+            config.interfaces[1].tcp_fin_teardown,
+            Some(TcpTeardown::TimeWait)
+        );
+        assert_eq!(
+            config.interfaces[1].tcp_rst_teardown,
+            Some(TcpTeardown::Close)
+        );
+        assert_eq!(
+            config.interfaces[1].unmatched_tcp_rst,
+            Some(UnmatchedTcpRst::Reply)
+        );
+        assert_eq!(config.interfaces[1].max_embryonic_per_host, Some(64));
+        assert!(config.interfaces[1].embryonic_tarpit);
+        assert!(config.interfaces[1].drop_unsolicited);
+        assert_eq!(
+            config.interfaces[1].no_snat_sources,
+            vec!["192.0.2.0/28".parse::<IpNet>().unwrap()]
+        );
+        assert_eq!(
+            config.interfaces[1]
+                .no_snat_dports
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            ["udp/500-500", "tcp/8000-8080"]
+        );
+        assert!("icmp/1".parse::<ProtoPortRange>().is_err());
+        assert!("udp/600-500".parse::<ProtoPortRange>().is_err());
+        let nft_set = config.interfaces[1].no_snat_nft_set.as_ref().unwrap();
+        assert_eq!(
+            (
+                nft_set.family.as_str(),
+                nft_set.table.as_str(),
+                nft_set.name.as_str()
+            ),
+            ("inet", "nat", "no_snat")
+        );
+        assert_eq!(config.interfaces[1].egress_dscp, Some(0));
+        assert!(config.interfaces[1].ingress_dscp.is_none());
+        assert!(config.interfaces[1].checksum_offload_compat);
+        let bypass_fwmark = config.interfaces[1].bypass_fwmark.as_ref().unwrap();
+        assert_eq!(
+            (bypass_fwmark.mark.get(), bypass_fwmark.mask()),
+            (0x100, 0xff00)
+        );
+        assert_eq!(
+            config.interfaces[1].blocked_dests,
+            vec![
+                "198.51.100.0/24".parse::<IpNet>().unwrap(),
+                "2001:db8:bad::/48".parse().unwrap()
+            ]
+        );
+        let limit = config.interfaces[1]
+            .udp_binding_rate_limit
+            .as_ref()
+            .unwrap();
+        assert_eq!((limit.interval(), limit.max_burst()), (500_000, 4000));
+        assert!(config.interfaces[1].udp_host_binding_rate_limit.is_none());
+        assert_eq!(config.interfaces[1].udp_high_water, Some(100000));
+        assert_eq!(
+            config.interfaces[1].udp_emergency_timeout.map(u64::from),
+            Some(30_000_000_000)
+        );
+        let netmap = &config.interfaces[1].netmap;
+        assert_eq!(netmap[0].external_prefix.prefix_len(), 24);
+        let static_binding = &config.interfaces[1].static_bindings[0];
+        assert_eq!(static_binding.internal.port(), 25565);
+        assert_eq!(static_binding.proto, IpProtocol::Tcp);
+        assert!(static_binding.external_address.is_none());
+        #[cfg(feature = "ipv6")]
+        {
+            let clat = config.interfaces[0].clat.as_ref().unwrap();
+            assert_eq!(clat.ipv4_address, Ipv4Addr::new(192, 0, 0, 1));
+            assert!(clat.pref64.is_none());
+        }
+        assert!(config.interfaces[0].clat.is_some());
+        let dslite = config.interfaces[1].dslite.as_ref().unwrap();
+        assert_eq!(dslite.ipv4_address, Ipv4Addr::new(192, 0, 0, 2));
+        assert!(dslite.ipv6_address.is_none());
+        let excludes = &config.interfaces[1].externals[1].exclude_address;
+        assert!(excludes[0].contains(&"192.168.1.1".parse().unwrap()));
+        assert!(!excludes[0].contains(&"192.168.1.2".parse().unwrap()));
+        assert!(excludes[1].contains(&"192.168.1.233".parse().unwrap()));
+        assert_eq!(
+            config.interfaces[1].externals[2].protocols,
+            Some(vec![IpProtocol::Udp, IpProtocol::Icmp])
+        );
+        assert!(config.interfaces[1].externals[0].protocols.is_none());
+        assert_eq!(
+            config.interfaces[1].route_probes().collect::<Vec<_>>(),
+            ["1.1.1.1".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(
+            config.interfaces[1].externals[4].internal_address,
+            Some("10.0.0.2".parse().unwrap())
+        );
+        assert!(config.interfaces[2].interface.is_auto());
+        let failover = config.interfaces[3].failover.as_ref().unwrap();
+        assert_eq!(failover.backup_if_name, "wwan0");
+        assert!(failover.preserve_ports);
+        let state_sync = config.defaults.state_sync.as_ref().unwrap();
+        assert_eq!(state_sync.peer, Some("10.0.0.2:4787".parse().unwrap()));
+        assert_eq!(
+            config.interfaces[3].vrrp_address,
+            Some("192.168.1.254".parse().unwrap())
+        );
+        assert!(toml::from_str::<ConfigNetIf>(r#"interface = "eth0""#).is_err());
+    }
+
+    #[test]
+    fn test_address_attrs_matcher() {
+        let external: ConfigExternal = toml::from_str(
+            r#"
+match_address = "0.0.0.0/0"
+match_label = "eth0:*"
+match_scope = "global"
+match_flags = ["permanent", "!deprecated"]
+            "#,
+        )
+        .unwrap();
+        let matcher = &external.attrs;
+        let addr: IpAddr = "192.168.1.2".parse().unwrap();
+        assert!(matcher.matches(&addr, Some("eth0:wan"), 0, 0x80));
+        assert!(!matcher.matches(&addr, Some("eth0"), 0, 0x80));
+        assert!(!matcher.matches(&addr, Some("eth0:wan"), 253, 0x80));
+        assert!(!matcher.matches(&addr, Some("eth0:wan"), 0, 0x80 | 0x20));
+
+        let matcher = AddressAttrsMatcher {
+            match_scope: Some(AddressScope::Global),
+            ..Default::default()
+        };
+        assert!(matcher.matches(&"2001:db8::1".parse().unwrap(), None, 0, 0));
+        assert!(!matcher.matches(&"fd00::1".parse().unwrap(), None, 0, 0));
+        assert!("!bogus".parse::<AddressFlagMatch>().is_err());
+    }
+
+    #[test]
+    fn test_address_matcher_special_purpose() {
+        let any4 = ConfigExternal::match_any_ipv4();
+        let AddressOrMatcher::Matcher { match_address } = any4.address else {
+            unreachable!()
+        };
+        assert!(match_address.matches(&"192.168.1.2".parse().unwrap()));
+        assert!(!match_address.matches(&"169.254.1.2".parse().unwrap()));
+        assert!(!match_address.matches(&"100.64.1.2".parse().unwrap()));
+        assert!(!match_address.matches(&"192.0.2.1".parse().unwrap()));
+
+        let cgn = AddressMatcher::Network("100.64.0.0/10".parse().unwrap());
+        assert!(cgn.matches(&"100.64.1.2".parse().unwrap()));
+        let doc = AddressMatcher::Range6 {
+            start: "2001:db8::1".parse().unwrap(),
+            end: "2001:db8::ff".parse().unwrap(),
+        };
+        assert!(doc.matches(&"2001:db8::2".parse().unwrap()));
+
+        let any6 = AddressMatcher::Network("::/0".parse().unwrap());
+        assert!(any6.matches(&"2400::1".parse().unwrap()));
+        assert!(!any6.matches(&"fe80::1".parse().unwrap()));
+        assert!(!any6.matches(&"2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_trace_filter() {
+        let filter: TraceFilter = "udp and host 192.168.1.100 and port 53".parse().unwrap();
+        assert_eq!(
+            filter,
+            TraceFilter {
+                protocol: Some(IpProtocol::Udp),
+                network: Some("192.168.1.100/32".parse().unwrap()),
+                port: Some(53),
+            }
+        );
+        let filter: TraceFilter = "net 10.0.0.0/8".parse().unwrap();
+        assert_eq!(filter.network, Some("10.0.0.0/8".parse().unwrap()));
+        assert_eq!("".parse::<TraceFilter>().unwrap(), TraceFilter::default());
+
+        assert!("tcp udp".parse::<TraceFilter>().is_err());
+        assert!("host".parse::<TraceFilter>().is_err());
+        assert!("src 10.0.0.1".parse::<TraceFilter>().is_err());
     }
 }
@@ -0,0 +1,391 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! Control API of running daemon served on Unix stream socket.
+//!
+//! A client sends a single request line and reads the response until the
+//! socket is closed, failed requests are responded with a line prefixed with
+//! `error: `.
+use std::io::{Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config::{ConfigStaticBinding, IpProtocol};
+
+const ERROR_PREFIX: &str = "error: ";
+const MAX_REQUEST_LEN: u64 = 4096;
+const MAX_BPF_LOG_LEVEL: u8 = 5;
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Sets max BPF log level of interface, or all interfaces if `interface`
+    /// is `None`, and responds with log levels of these interfaces.
+    BpfLogLevel {
+        level: Option<u8>,
+        interface: Option<String>,
+    },
+    /// Adds static binding on interface, which can be omitted if only one
+    /// interface is managed, and responds with the binding added.
+    BindingAdd {
+        binding: ConfigStaticBinding,
+        interface: Option<String>,
+    },
+    /// Removes static binding of external port on interface, either added
+    /// at runtime or configured, and responds with bindings removed.
+    BindingDel {
+        proto: IpProtocol,
+        external_port: u16,
+        interface: Option<String>,
+    },
+    /// Reserves external port on interface for `lease`, so it's not allocated
+    /// for dynamic bindings until the lease expires or is renewed by another
+    /// reservation, and responds with the reservation.
+    PortReserve {
+        proto: IpProtocol,
+        port: u16,
+        lease: Duration,
+        interface: Option<String>,
+    },
+    /// Releases port reservation on interface.
+    PortRelease {
+        proto: IpProtocol,
+        port: u16,
+        interface: Option<String>,
+    },
+    /// Responds with counters of interface, or all interfaces if `interface`
+    /// is `None`.
+    Counters { interface: Option<String> },
+}
+
+fn parse_proto(word: Option<&str>) -> Result<IpProtocol> {
+    match word {
+        Some("tcp") => Ok(IpProtocol::Tcp),
+        Some("udp") => Ok(IpProtocol::Udp),
+        Some(proto) => Err(anyhow!(
+            "unsupported protocol {}, expected tcp or udp",
+            proto
+        )),
+        None => Err(anyhow!("missing protocol")),
+    }
+}
+
+fn parse_port(word: Option<&str>) -> Result<u16> {
+    let port = word.ok_or_else(|| anyhow!("missing external port"))?;
+    port.parse()
+        .map_err(|_| anyhow!("invalid external port {}", port))
+}
+
+impl FromStr for Request {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace();
+        let request = match words.next() {
+            Some("bpf-log") => {
+                let level = words
+                    .next()
+                    .map(|level| -> Result<u8> {
+                        let level = level.parse()?;
+                        if level > MAX_BPF_LOG_LEVEL {
+                            return Err(anyhow!(
+                                "BPF log level {} is out of range 0-{}",
+                                level,
+                                MAX_BPF_LOG_LEVEL
+                            ));
+                        }
+                        Ok(level)
+                    })
+                    .transpose()?;
+                Request::BpfLogLevel {
+                    level,
+                    interface: words.next().map(str::to_string),
+                }
+            }
+            Some("binding") => match words.next() {
+                Some("add") => {
+                    let proto = parse_proto(words.next())?;
+                    let external_port = parse_port(words.next())?;
+                    let internal = words
+                        .next()
+                        .ok_or_else(|| anyhow!("missing internal endpoint"))?;
+                    let internal = internal
+                        .parse()
+                        .map_err(|_| anyhow!("invalid internal endpoint {}", internal))?;
+                    Request::BindingAdd {
+                        binding: ConfigStaticBinding {
+                            internal,
+                            external_port,
+                            proto,
+                            external_address: None,
+                        },
+                        interface: words.next().map(str::to_string),
+                    }
+                }
+                Some("del") => Request::BindingDel {
+                    proto: parse_proto(words.next())?,
+                    external_port: parse_port(words.next())?,
+                    interface: words.next().map(str::to_string),
+                },
+                Some(command) => return Err(anyhow!("unknown binding command {}", command)),
+                None => return Err(anyhow!("missing binding command, expected add or del")),
+            },
+            Some("port") => match words.next() {
+                Some("reserve") => {
+                    let proto = parse_proto(words.next())?;
+                    let port = parse_port(words.next())?;
+                    let lease = words.next().ok_or_else(|| anyhow!("missing lease"))?;
+                    let lease = fundu::parse_duration(lease)
+                        .map_err(|e| anyhow!("invalid lease {}: {}", lease, e))?;
+                    Request::PortReserve {
+                        proto,
+                        port,
+                        lease,
+                        interface: words.next().map(str::to_string),
+                    }
+                }
+                Some("release") => Request::PortRelease {
+                    proto: parse_proto(words.next())?,
+                    port: parse_port(words.next())?,
+                    interface: words.next().map(str::to_string),
+                },
+                Some(command) => return Err(anyhow!("unknown port command {}", command)),
+                None => return Err(anyhow!("missing port command, expected reserve or release")),
+            },
+            Some("counters") => Request::Counters {
+                interface: words.next().map(str::to_string),
+            },
+            Some(command) => return Err(anyhow!("unknown command {}", command)),
+            None => return Err(anyhow!("empty request")),
+        };
+        if let Some(word) = words.next() {
+            return Err(anyhow!("unexpected argument {}", word));
+        }
+        Ok(request)
+    }
+}
+
+/// Request waiting to be handled by daemon.
+#[derive(Debug)]
+pub struct Pending {
+    pub request: Request,
+    reply: oneshot::Sender<Result<String>>,
+}
+
+impl Pending {
+    pub fn reply(self, response: Result<String>) {
+        let _ = self.reply.send(response);
+    }
+}
+
+/// Accepts control connections on Unix socket, the socket file is removed on
+/// drop.
+#[derive(Debug)]
+pub struct ControlServer {
+    path: PathBuf,
+    ino: u64,
+    task: JoinHandle<()>,
+}
+
+impl ControlServer {
+    /// Binds control socket at `path`, replacing socket left by crashed
+    /// instance, or socket of running instance only if `handover`.
+    pub fn bind(path: &Path, handover: bool) -> Result<(Self, mpsc::Receiver<Pending>)> {
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) => {
+                if !meta.file_type().is_socket() {
+                    return Err(anyhow!(
+                        "control socket path {} exists and is not a socket",
+                        path.display()
+                    ));
+                }
+                if !handover && std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(anyhow!(
+                        "control socket {} is in use by running instance",
+                        path.display()
+                    ));
+                }
+                std::fs::remove_file(path).with_context(|| {
+                    format!("failed to remove control socket {}", path.display())
+                })?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => {
+                return Err(anyhow!(
+                    "failed to stat control socket {}: {}",
+                    path.display(),
+                    e
+                ))
+            }
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("failed to bind control socket {}", path.display()))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        let ino = std::fs::metadata(path)?.ino();
+        info!("control socket listening on {}", path.display());
+
+        let (tx, rx) = mpsc::channel(8);
+        let task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve(stream, tx.clone()));
+                    }
+                    Err(e) => {
+                        // e.g. running out of file descriptors, which might
+                        // be transient
+                        warn!("failed to accept control connection: {}", e);
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    }
+                }
+            }
+        });
+
+        Ok((
+            Self {
+                path: path.to_owned(),
+                ino,
+                task,
+            },
+            rx,
+        ))
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        // the socket is replaced by new instance on handover
+        if std::fs::metadata(&self.path).is_ok_and(|meta| meta.ino() == self.ino) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+async fn serve(stream: UnixStream, tx: mpsc::Sender<Pending>) {
+    let (read, mut write) = stream.into_split();
+    let response = async {
+        let mut line = String::new();
+        // idle clients would hold the connection open forever otherwise
+        tokio::time::timeout(
+            READ_TIMEOUT,
+            BufReader::new(read.take(MAX_REQUEST_LEN)).read_line(&mut line),
+        )
+        .await
+        .map_err(|_| anyhow!("timed out reading request"))??;
+        let request = line.parse()?;
+        let (reply, rx) = oneshot::channel();
+        let exiting = || anyhow!("daemon is exiting");
+        tx.send(Pending { request, reply })
+            .await
+            .map_err(|_| exiting())?;
+        rx.await.map_err(|_| exiting())?
+    }
+    .await
+    .unwrap_or_else(|e| format!("{}{}\n", ERROR_PREFIX, e));
+    let _ = write.write_all(response.as_bytes()).await;
+}
+
+/// Sends request to daemon listening on control socket `path` and returns the
+/// response.
+pub fn request(path: &Path, request: &str) -> Result<String> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)
+        .with_context(|| format!("failed to connect control socket {}", path.display()))?;
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    if let Some(e) = response.strip_prefix(ERROR_PREFIX) {
+        return Err(anyhow!("{}", e.trim_end()));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(
+            "bpf-log".parse::<Request>().unwrap(),
+            Request::BpfLogLevel {
+                level: None,
+                interface: None
+            }
+        );
+        assert_eq!(
+            "bpf-log 4 eth0\n".parse::<Request>().unwrap(),
+            Request::BpfLogLevel {
+                level: Some(4),
+                interface: Some("eth0".to_string())
+            }
+        );
+        assert!("bpf-log 6".parse::<Request>().is_err());
+        assert!("bpf-log 4 eth0 eth1".parse::<Request>().is_err());
+        assert_eq!(
+            "binding del udp 27015".parse::<Request>().unwrap(),
+            Request::BindingDel {
+                proto: IpProtocol::Udp,
+                external_port: 27015,
+                interface: None
+            }
+        );
+        assert_eq!(
+            "binding add tcp 25565 192.168.1.5:25565 eth0"
+                .parse::<Request>()
+                .unwrap(),
+            Request::BindingAdd {
+                binding: ConfigStaticBinding {
+                    internal: "192.168.1.5:25565".parse().unwrap(),
+                    external_port: 25565,
+                    proto: IpProtocol::Tcp,
+                    external_address: None,
+                },
+                interface: Some("eth0".to_string())
+            }
+        );
+        assert!("binding add icmp 1 192.168.1.5:1"
+            .parse::<Request>()
+            .is_err());
+        assert!("binding add tcp 25565 192.168.1.5"
+            .parse::<Request>()
+            .is_err());
+        assert_eq!(
+            "port reserve udp 51820 60s".parse::<Request>().unwrap(),
+            Request::PortReserve {
+                proto: IpProtocol::Udp,
+                port: 51820,
+                lease: Duration::from_secs(60),
+                interface: None
+            }
+        );
+        assert_eq!(
+            "port release udp 51820 eth0".parse::<Request>().unwrap(),
+            Request::PortRelease {
+                proto: IpProtocol::Udp,
+                port: 51820,
+                interface: Some("eth0".to_string())
+            }
+        );
+        assert!("port reserve udp 51820".parse::<Request>().is_err());
+        assert_eq!(
+            "counters eth0".parse::<Request>().unwrap(),
+            Request::Counters {
+                interface: Some("eth0".to_string())
+            }
+        );
+        assert!("foo".parse::<Request>().is_err());
+        assert!("".parse::<Request>().is_err());
+    }
+}
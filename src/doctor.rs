@@ -0,0 +1,530 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! Diagnostics of environment einat runs in, reporting common misconfigurations
+//! of kernel and network stack
+use std::fmt::Display;
+use std::process::Command;
+
+use anyhow::{anyhow, Result};
+
+use crate::config::{Config, ConfigNetIf, NetIfId};
+use crate::probe::{self, KernelFeatures};
+use crate::route::{self, PacketEncap};
+use crate::utils::{with_netns, NetNs};
+
+const MIN_KERNEL_VERSION: (u32, u32) = (5, 15);
+
+/// Helpers called by BPF programs, and whether these are optional.
+const HELPERS: &[(&str, libbpf_sys::bpf_func_id, bool)] = &[
+    (
+        "map_lookup_elem",
+        libbpf_sys::BPF_FUNC_map_lookup_elem,
+        false,
+    ),
+    (
+        "map_update_elem",
+        libbpf_sys::BPF_FUNC_map_update_elem,
+        false,
+    ),
+    (
+        "map_delete_elem",
+        libbpf_sys::BPF_FUNC_map_delete_elem,
+        false,
+    ),
+    ("ktime_get_ns", libbpf_sys::BPF_FUNC_ktime_get_ns, false),
+    (
+        "get_prandom_u32",
+        libbpf_sys::BPF_FUNC_get_prandom_u32,
+        false,
+    ),
+    ("skb_load_bytes", libbpf_sys::BPF_FUNC_skb_load_bytes, false),
+    (
+        "skb_store_bytes",
+        libbpf_sys::BPF_FUNC_skb_store_bytes,
+        false,
+    ),
+    ("skb_pull_data", libbpf_sys::BPF_FUNC_skb_pull_data, false),
+    (
+        "l3_csum_replace",
+        libbpf_sys::BPF_FUNC_l3_csum_replace,
+        false,
+    ),
+    (
+        "l4_csum_replace",
+        libbpf_sys::BPF_FUNC_l4_csum_replace,
+        false,
+    ),
+    ("redirect", libbpf_sys::BPF_FUNC_redirect, false),
+    ("fib_lookup", libbpf_sys::BPF_FUNC_fib_lookup, false),
+    (
+        "ringbuf_reserve",
+        libbpf_sys::BPF_FUNC_ringbuf_reserve,
+        false,
+    ),
+    ("ringbuf_submit", libbpf_sys::BPF_FUNC_ringbuf_submit, false),
+    ("timer_init", libbpf_sys::BPF_FUNC_timer_init, false),
+    (
+        "timer_set_callback",
+        libbpf_sys::BPF_FUNC_timer_set_callback,
+        false,
+    ),
+    ("timer_start", libbpf_sys::BPF_FUNC_timer_start, false),
+    // falls back to bounded loop for port searching
+    ("loop", libbpf_sys::BPF_FUNC_loop, true),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+            Status::Skip => "SKIP",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    checks: Vec<(Status, String, String)>,
+}
+
+impl Report {
+    fn add(&mut self, status: Status, name: impl Into<String>, detail: impl Into<String>) {
+        self.checks.push((status, name.into(), detail.into()));
+    }
+
+    fn count(&self, status: Status) -> usize {
+        self.checks.iter().filter(|(s, ..)| *s == status).count()
+    }
+
+    pub fn failed(&self) -> bool {
+        self.count(Status::Fail) > 0
+    }
+
+    pub fn print(&self) {
+        for (status, name, detail) in &self.checks {
+            println!("[{}] {}: {}", status, name, detail);
+        }
+        println!(
+            "\n{} passed, {} warnings, {} failed, {} skipped",
+            self.count(Status::Pass),
+            self.count(Status::Warn),
+            self.count(Status::Fail),
+            self.count(Status::Skip)
+        );
+    }
+}
+
+/// Runs all checks against running kernel and interfaces in `config`. This
+/// must be called from Tokio context.
+pub async fn run(config: &Config) -> Report {
+    let mut report = Report::default();
+
+    check_kernel_version(&mut report);
+    check_btf(&mut report, config);
+    check_bpf_support(&mut report);
+    check_clsact(&mut report);
+
+    for if_config in &config.interfaces {
+        if let Err(e) = check_interface(&mut report, if_config).await {
+            report.add(
+                Status::Fail,
+                format!("interface {}", if_config.interface),
+                format!("{:#}", e),
+            );
+        }
+    }
+
+    report
+}
+
+fn check_kernel_version(report: &mut Report) {
+    let name = "kernel version";
+    let release = match probe::kernel_release() {
+        Ok(release) => release,
+        Err(e) => return report.add(Status::Fail, name, format!("{:#}", e)),
+    };
+    let mut nums = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse::<u32>().ok());
+    let version = match (nums.next().flatten(), nums.next().flatten()) {
+        (Some(major), Some(minor)) => (major, minor),
+        _ => {
+            return report.add(
+                Status::Warn,
+                name,
+                format!("unable to parse kernel release {}", release),
+            )
+        }
+    };
+    let status = if version >= MIN_KERNEL_VERSION {
+        Status::Pass
+    } else {
+        Status::Fail
+    };
+    report.add(
+        status,
+        name,
+        format!(
+            "{}, requires >={}.{}",
+            release, MIN_KERNEL_VERSION.0, MIN_KERNEL_VERSION.1
+        ),
+    );
+}
+
+fn check_btf(report: &mut Report, config: &Config) {
+    let name = "kernel BTF";
+    if KernelFeatures::get().vmlinux_btf {
+        report.add(Status::Pass, name, "/sys/kernel/btf/vmlinux");
+    } else if let Some(path) = &config.defaults.btf_path {
+        match probe::resolve_btf_path(path) {
+            Ok(path) => report.add(Status::Pass, name, format!("using {}", path.display())),
+            Err(e) => report.add(Status::Fail, name, format!("{:#}", e)),
+        }
+    } else {
+        report.add(
+            Status::Fail,
+            name,
+            "/sys/kernel/btf/vmlinux not available, specify `btf_path` or enable CONFIG_DEBUG_INFO_BTF",
+        );
+    }
+}
+
+fn check_bpf_support(report: &mut Report) {
+    let prog_type = libbpf_sys::BPF_PROG_TYPE_SCHED_CLS;
+    let res = unsafe { libbpf_sys::libbpf_probe_bpf_prog_type(prog_type, std::ptr::null()) };
+    if res != 1 {
+        let detail = if res < 0 {
+            format!(
+                "failed to load TC BPF program: {}",
+                std::io::Error::from_raw_os_error(-res)
+            )
+        } else {
+            "TC BPF programs not supported, requires CONFIG_BPF_SYSCALL and CONFIG_NET_CLS_BPF"
+                .into()
+        };
+        report.add(Status::Fail, "BPF support", detail);
+        // helpers can't be probed either
+        return;
+    }
+    report.add(Status::Pass, "BPF support", "TC BPF programs supported");
+
+    let mut missing = Vec::new();
+    let mut missing_optional = Vec::new();
+    for &(name, id, optional) in HELPERS {
+        let res = unsafe { libbpf_sys::libbpf_probe_bpf_helper(prog_type, id, std::ptr::null()) };
+        if res != 1 {
+            if optional {
+                missing_optional.push(name);
+            } else {
+                missing.push(name);
+            }
+        }
+    }
+    let name = "BPF helpers";
+    if !missing.is_empty() {
+        report.add(
+            Status::Fail,
+            name,
+            format!("missing required bpf_{}()", missing.join("(), bpf_")),
+        );
+    } else if !missing_optional.is_empty() {
+        report.add(
+            Status::Warn,
+            name,
+            format!(
+                "missing optional bpf_{}()",
+                missing_optional.join("(), bpf_")
+            ),
+        );
+    } else {
+        report.add(Status::Pass, name, "all helpers available");
+    }
+}
+
+/// Tries creating clsact qdisc on loopback interface of a new network
+/// namespace, leaving existing interfaces untouched.
+fn check_clsact(report: &mut Report) {
+    let name = "clsact qdisc";
+    let res = std::thread::spawn(|| {
+        let res = unsafe { libc::unshare(libc::CLONE_NEWNET) };
+        if res < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut hook = libbpf_sys::bpf_tc_hook {
+            sz: core::mem::size_of::<libbpf_sys::bpf_tc_hook>() as _,
+            ifindex: 1,
+            attach_point: libbpf_sys::BPF_TC_INGRESS,
+            ..Default::default()
+        };
+        let res = unsafe { libbpf_sys::bpf_tc_hook_create(&mut hook) };
+        if res < 0 && res != -libc::EEXIST {
+            return Ok(Some(std::io::Error::from_raw_os_error(-res)));
+        }
+        Ok(None)
+    })
+    .join()
+    .expect("clsact probing thread panicked");
+
+    match res {
+        Ok(None) => report.add(Status::Pass, name, "supported"),
+        Ok(Some(e)) => report.add(
+            Status::Fail,
+            name,
+            format!("failed to create, requires CONFIG_NET_SCH_INGRESS: {}", e),
+        ),
+        Err(e) => report.add(
+            Status::Skip,
+            name,
+            format!("failed to create network namespace for probing: {}", e),
+        ),
+    }
+}
+
+async fn check_interface(report: &mut Report, if_config: &ConfigNetIf) -> Result<()> {
+    let mut netns = if_config.netns.as_deref().map(NetNs::open).transpose()?;
+    if let Some(ns) = &netns {
+        if ns.is_current()? {
+            netns = None;
+        }
+    }
+    let netns = netns.as_ref();
+
+    let (monitor_task, rt_helper, _) = with_netns(netns, || route::spawn_monitor(false, false))?;
+    let res = async {
+        let if_index = rt_helper
+            .resolve_if_index(netns, &if_config.interface)
+            .await?;
+        let link_info = rt_helper.query_link_info(if_index).await?;
+        let if_name = link_info
+            .name()
+            .ok_or_else(|| anyhow!("interface {} has no name", if_index))?
+            .to_string();
+
+        let encap = if let Some(lower) = &if_config.pppoe_lower_if_name {
+            let lower_id = NetIfId::Name {
+                if_name: lower.clone(),
+            };
+            let lower_if_index = with_netns(netns, || lower_id.resolve_index())?;
+            let lower_encap = rt_helper.query_link_info(lower_if_index).await?.encap();
+            if lower_encap == PacketEncap::Ethernet {
+                PacketEncap::Pppoe
+            } else {
+                PacketEncap::Unsupported
+            }
+        } else {
+            link_info.encap()
+        };
+        Ok::<_, anyhow::Error>((if_name, encap))
+    }
+    .await;
+    monitor_task.abort();
+    let (if_name, encap) = res?;
+
+    let name = format!("interface {}", if_name);
+    let status = match encap {
+        PacketEncap::BareIp | PacketEncap::Ethernet | PacketEncap::Pppoe => Status::Pass,
+        PacketEncap::Unsupported => Status::Fail,
+        PacketEncap::Unknown => Status::Warn,
+    };
+    report.add(status, &name, format!("{:?} encapsulation", encap));
+
+    with_netns(netns, || {
+        check_sysctls(report, &name, &if_name, if_config);
+        check_nat_rules(report, &name, &if_name);
+        Ok(())
+    })
+}
+
+fn read_sysctl(path: &str) -> Result<i64> {
+    let value = std::fs::read_to_string(format!("/proc/sys/{}", path))?;
+    Ok(value.trim().parse()?)
+}
+
+fn check_sysctls(report: &mut Report, name: &str, if_name: &str, if_config: &ConfigNetIf) {
+    let mut forwarding = vec![];
+    if if_config.nat44 {
+        forwarding.push("net/ipv4/ip_forward".to_string());
+    }
+    #[cfg(feature = "ipv6")]
+    if if_config.nat66 {
+        forwarding.push(format!("net/ipv6/conf/{}/forwarding", if_name));
+    }
+    for path in forwarding {
+        match read_sysctl(&path) {
+            Ok(1) => report.add(Status::Pass, name, format!("{} = 1", path)),
+            Ok(value) => report.add(
+                Status::Fail,
+                name,
+                format!("{} = {}, forwarding is required", path, value),
+            ),
+            Err(e) => report.add(
+                Status::Skip,
+                name,
+                format!("failed to read {}: {}", path, e),
+            ),
+        }
+    }
+
+    if if_config.nat44 {
+        // the maximum of "all" and interface's value takes effect
+        let all = read_sysctl("net/ipv4/conf/all/rp_filter");
+        let dev = read_sysctl(&format!("net/ipv4/conf/{}/rp_filter", if_name));
+        match (all, dev) {
+            (Ok(all), Ok(dev)) if all.max(dev) == 1 => report.add(
+                Status::Warn,
+                name,
+                "strict rp_filter is on, which could drop hairpinned or asymmetrically routed traffic",
+            ),
+            (Ok(all), Ok(dev)) => {
+                report.add(Status::Pass, name, format!("rp_filter = {}", all.max(dev)))
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                report.add(Status::Skip, name, format!("failed to read rp_filter: {}", e))
+            }
+        }
+    }
+}
+
+/// Looks for Netfilter SNAT or masquerading rules that could apply to
+/// traffic out of the interface, which conflicts with NAT of einat.
+fn check_nat_rules(report: &mut Report, name: &str, if_name: &str) {
+    let quoted_if_name = format!("\"{}\"", if_name);
+    let nft = run_command("nft", &["list", "ruleset"]).map(|output| {
+        nat_rules(&output, &["masquerade", "snat"], &["oifname", "oif"], |w| {
+            w == quoted_if_name || w == if_name
+        })
+    });
+    let mut available = nft.is_some();
+    let mut iptables = Vec::new();
+    for cmd in ["iptables-save", "ip6tables-save"] {
+        if let Some(output) = run_command(cmd, &["-t", "nat"]) {
+            available = true;
+            iptables.extend(nat_rules(
+                &output,
+                &["MASQUERADE", "SNAT"],
+                &["-o", "--out-interface"],
+                |w| w == if_name,
+            ));
+        }
+    }
+
+    if !available {
+        return report.add(
+            Status::Skip,
+            name,
+            "neither nft nor iptables-save available, unable to check NAT rules",
+        );
+    }
+
+    let conflicts: Vec<_> = nft.into_iter().flatten().chain(iptables).collect();
+    if conflicts.is_empty() {
+        report.add(Status::Pass, name, "no conflicting Netfilter NAT rules");
+    } else {
+        report.add(
+            Status::Warn,
+            name,
+            format!(
+                "Netfilter NAT rules could conflict with einat, consider disabling masquerading:\n  {}",
+                conflicts.join("\n  ")
+            ),
+        );
+    }
+}
+
+/// Returns rules containing any of `actions` that either match the
+/// interface or don't match any output interface.
+fn nat_rules<F: Fn(&str) -> bool>(
+    ruleset: &str,
+    actions: &[&str],
+    oif_keys: &[&str],
+    is_if: F,
+) -> Vec<String> {
+    let mut rules = Vec::new();
+    for line in ruleset.lines() {
+        let words: Vec<_> = line.split_whitespace().collect();
+        if !words.iter().any(|w| actions.contains(w)) {
+            continue;
+        }
+        let oifs: Vec<_> = words
+            .windows(2)
+            .filter(|w| oif_keys.contains(&w[0]))
+            .map(|w| w[1])
+            .collect();
+        if oifs.is_empty() || oifs.into_iter().any(&is_if) {
+            rules.push(line.trim().to_string());
+        }
+    }
+    rules
+}
+
+fn run_command(cmd: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(cmd).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_nat_rules() {
+        let nft = r#"
+table inet fw4 {
+	chain srcnat {
+		type nat hook postrouting priority srcnat; policy accept;
+		oifname "pppoe-wan" jump srcnat_wan comment "!fw4: Handle wan IPv4/IPv6 srcnat traffic"
+	}
+
+	chain srcnat_wan {
+		meta nfproto ipv4 masquerade comment "!fw4: Masquerade IPv4 wan traffic"
+	}
+}
+table ip nat {
+	chain post {
+		type nat hook postrouting priority srcnat; policy accept;
+		oifname "eth1" masquerade
+		oifname "pppoe-wan" snat to 192.0.2.1
+	}
+}
+"#;
+        let rules = nat_rules(nft, &["masquerade", "snat"], &["oifname", "oif"], |w| {
+            w == "\"pppoe-wan\""
+        });
+        assert_eq!(
+            rules,
+            [
+                r#"meta nfproto ipv4 masquerade comment "!fw4: Masquerade IPv4 wan traffic""#,
+                r#"oifname "pppoe-wan" snat to 192.0.2.1"#
+            ]
+        );
+
+        let iptables = "\
+*nat
+:POSTROUTING ACCEPT [0:0]
+-A POSTROUTING -o eth1 -j MASQUERADE
+-A POSTROUTING -o eth0 -j MASQUERADE
+COMMIT
+";
+        let rules = nat_rules(
+            iptables,
+            &["MASQUERADE", "SNAT"],
+            &["-o", "--out-interface"],
+            |w| w == "eth0",
+        );
+        assert_eq!(rules, ["-A POSTROUTING -o eth0 -j MASQUERADE"]);
+    }
+}
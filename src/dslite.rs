@@ -0,0 +1,173 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! DS-Lite Basic Bridging BroadBand element(B4, RFC 6333).
+//!
+//! IPv4 traffic is routed into an IPv4-in-IPv6 softwire towards AFTR, which
+//! is an ordinary ip6tnl interface created or managed by us, NAT44 is then
+//! performed on the tunnel interface like any other bare IP interface.
+use std::net::{Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, Context, Result};
+use tracing::{info, warn};
+
+use crate::config::{ConfigDsLite, NetIfId};
+use crate::route::RouteHelper;
+use crate::utils::{is_global_unicast, with_netns, NetNs};
+
+/// B4 and AFTR addresses are within 192.0.0.0/29, see RFC 6333
+const IPV4_PREFIX_LEN: u8 = 29;
+
+/// IPv4 default route via softwire, with high metric so native IPv4 routes
+/// are preferred if any
+const DEFAULT_ROUTE_METRIC: u32 = 2048;
+
+/// Softwire interface towards AFTR, which is removed on deconfiguration.
+pub struct DsLite {
+    rt_helper: RouteHelper,
+    if_name: String,
+    if_index: u32,
+    /// Tunnel source address follows uplink addresses if not configured
+    derive_address: bool,
+    pub uplink_if_index: u32,
+    pub aftr: Ipv6Addr,
+    pub ipv6_address: Ipv6Addr,
+}
+
+impl DsLite {
+    /// Creates softwire interface `if_name` with IPv4 address and default
+    /// route, an existing ip6tnl interface of the same name is reused.
+    pub async fn setup(
+        netns: Option<&NetNs>,
+        rt_helper: &RouteHelper,
+        if_name: &str,
+        config: &ConfigDsLite,
+    ) -> Result<Self> {
+        let uplink_if_index = with_netns(netns, || {
+            NetIfId::Name {
+                if_name: config.uplink_if_name.clone(),
+            }
+            .resolve_index()
+        })?;
+        let aftr = resolve_aftr(&config.aftr).await?;
+        let ipv6_address = match config.ipv6_address {
+            Some(address) => address,
+            None => {
+                let addresses = rt_helper.query_all_addresses(uplink_if_index).await?;
+                select_ipv6_address(&addresses.ipv6).ok_or_else(|| {
+                    anyhow!(
+                        "no global IPv6 address on DS-Lite uplink {}, set `ipv6_address` explicitly",
+                        config.uplink_if_name
+                    )
+                })?
+            }
+        };
+
+        let existing = with_netns(netns, || {
+            NetIfId::Name {
+                if_name: if_name.to_string(),
+            }
+            .resolve_index()
+        })
+        .ok();
+        if let Some(if_index) = existing {
+            if !rt_helper.query_link_info(if_index).await?.is_ip6tnl() {
+                return Err(anyhow!(
+                    "interface {} exists and is not an ip6tnl interface",
+                    if_name
+                ));
+            }
+        }
+        rt_helper
+            .set_ip4ip6_tunnel(if_name, existing, uplink_if_index, ipv6_address, aftr)
+            .await
+            .with_context(|| format!("failed to set up DS-Lite interface {}", if_name))?;
+        let if_index = with_netns(netns, || {
+            NetIfId::Name {
+                if_name: if_name.to_string(),
+            }
+            .resolve_index()
+        })?;
+
+        let this = Self {
+            rt_helper: rt_helper.clone(),
+            if_name: if_name.to_string(),
+            if_index,
+            derive_address: config.ipv6_address.is_none(),
+            uplink_if_index,
+            aftr,
+            ipv6_address,
+        };
+        let res = async {
+            rt_helper.set_link_up(if_index, None).await?;
+            rt_helper
+                .add_address(if_index, config.ipv4_address.into(), IPV4_PREFIX_LEN)
+                .await?;
+            rt_helper
+                .add_v4_default_route(if_index, DEFAULT_ROUTE_METRIC)
+                .await
+        }
+        .await;
+        if let Err(e) = res {
+            if let Err(e) = rt_helper.del_link(if_index).await {
+                warn!("failed to remove DS-Lite interface {}: {}", if_name, e);
+            }
+            return Err(e);
+        }
+        info!(
+            "DS-Lite interface {} tunneling IPv4 from {} to AFTR {}",
+            if_name, ipv6_address, aftr
+        );
+        Ok(this)
+    }
+
+    /// Updates tunnel source address on address changes of uplink, the
+    /// previous address is kept if no global address is left on uplink.
+    pub async fn reconfigure_ipv6_address(&mut self, uplink_addresses: &[Ipv6Addr]) -> Result<()> {
+        if !self.derive_address || uplink_addresses.contains(&self.ipv6_address) {
+            return Ok(());
+        }
+        let Some(address) = select_ipv6_address(uplink_addresses) else {
+            return Ok(());
+        };
+        info!(
+            "DS-Lite tunnel source address {} -> {}",
+            self.ipv6_address, address
+        );
+        self.rt_helper
+            .set_ip4ip6_tunnel(
+                &self.if_name,
+                Some(self.if_index),
+                self.uplink_if_index,
+                address,
+                self.aftr,
+            )
+            .await?;
+        self.ipv6_address = address;
+        Ok(())
+    }
+
+    pub async fn deconfigure(&mut self) -> Result<()> {
+        self.rt_helper.del_link(self.if_index).await
+    }
+}
+
+/// Resolves AFTR from IPv6 address or domain name.
+async fn resolve_aftr(aftr: &str) -> Result<Ipv6Addr> {
+    if let Ok(address) = aftr.parse() {
+        return Ok(address);
+    }
+    let addrs = tokio::net::lookup_host((aftr, 0))
+        .await
+        .with_context(|| format!("failed to resolve AFTR {}", aftr))?;
+    for addr in addrs {
+        if let SocketAddr::V6(addr) = addr {
+            info!("resolved AFTR {} to {}", aftr, addr.ip());
+            return Ok(*addr.ip());
+        }
+    }
+    Err(anyhow!("no IPv6 address of AFTR {}", aftr))
+}
+
+fn select_ipv6_address(uplink_addresses: &[Ipv6Addr]) -> Option<Ipv6Addr> {
+    uplink_addresses.iter().copied().find(is_global_unicast)
+}
@@ -0,0 +1,162 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::Result;
+use libbpf_rs::{MapHandle, RingBufferBuilder};
+use tracing::{debug, error, info, trace, warn};
+
+use crate::skel::{self, Event, LogEvent, TraceEvent};
+use crate::trace;
+
+const POLL_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Reads events emitted by BPF programs through ring buffer on a dedicated
+/// thread, the thread is stopped on drop.
+///
+/// Log records of BPF programs are read from the same ring buffer and emitted
+/// as tracing events of target `einat::bpf`.
+#[derive(Debug)]
+pub struct EventReader {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EventReader {
+    pub fn start(map_events: &MapHandle, if_index: u32) -> Result<Self> {
+        let map_events = MapHandle::try_clone(map_events)?;
+
+        let mut builder = RingBufferBuilder::new();
+        builder.add(&map_events, move |data| handle_event(if_index, data))?;
+        let ring_buf = builder.build()?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::Builder::new()
+            .name("einat-events".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || {
+                    // keep the map fd open as long as the ring buffer is polled
+                    let _map_events = map_events;
+                    while !stop.load(Ordering::Relaxed) {
+                        let ret = ring_buf.poll_raw(POLL_TIMEOUT);
+                        if ret < 0 && ret != -libc::EINTR {
+                            warn!("failed to poll events: {}", ret);
+                            break;
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for EventReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn handle_event(if_index: u32, data: &[u8]) -> i32 {
+    let type_ = data
+        .get(..4)
+        .map(|type_| u32::from_ne_bytes(type_.try_into().unwrap()));
+    if type_ == Some(skel::EVENT_LOG) {
+        if data.len() < std::mem::size_of::<LogEvent>() {
+            debug!("ignoring truncated log event of {} bytes", data.len());
+            return 0;
+        }
+        let event: LogEvent =
+            bytemuck::pod_read_unaligned(&data[..std::mem::size_of::<LogEvent>()]);
+        handle_log(if_index, &event);
+        return 0;
+    }
+    if type_ == Some(skel::EVENT_TRACE) {
+        if data.len() < std::mem::size_of::<TraceEvent>() {
+            debug!("ignoring truncated trace event of {} bytes", data.len());
+            return 0;
+        }
+        let event: TraceEvent =
+            bytemuck::pod_read_unaligned(&data[..std::mem::size_of::<TraceEvent>()]);
+        trace::print_event(&event);
+        return 0;
+    }
+
+    if data.len() < std::mem::size_of::<Event>() {
+        debug!("ignoring truncated event of {} bytes", data.len());
+        return 0;
+    }
+    let event: Event = bytemuck::pod_read_unaligned(&data[..std::mem::size_of::<Event>()]);
+
+    let protocol = match event.l4proto as i32 {
+        libc::IPPROTO_TCP => "TCP",
+        libc::IPPROTO_UDP => "UDP",
+        _ => "ICMP",
+    };
+    match event.type_ {
+        skel::EVENT_PORT_QUOTA_EXCEEDED => {
+            warn!(
+                "internal host {} exceeded {} port quota on if {}",
+                event.addr.to_ip(event.flags),
+                protocol,
+                event.if_index
+            );
+        }
+        skel::EVENT_EXTERNAL_SPILLOVER => {
+            warn!(
+                "external address {} ran out of {} ports on if {}, spilling over to next external address",
+                event.addr.to_ip(event.flags),
+                protocol,
+                event.if_index
+            );
+        }
+        type_ => debug!("ignoring unknown event type {}", type_),
+    }
+    0
+}
+
+/// Text of NUL terminated or NUL padded string of BPF programs
+fn c_str(bytes: &[u8]) -> std::borrow::Cow<'_, str> {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len])
+}
+
+fn handle_log(if_index: u32, event: &LogEvent) {
+    let topic = c_str(&event.topic);
+    let msg = c_str(&event.msg);
+    match event.level {
+        skel::BPF_LOG_LEVEL_ERROR => {
+            error!(target: "einat::bpf", if_index, %topic, "{}", msg)
+        }
+        skel::BPF_LOG_LEVEL_WARN => warn!(target: "einat::bpf", if_index, %topic, "{}", msg),
+        skel::BPF_LOG_LEVEL_INFO => info!(target: "einat::bpf", if_index, %topic, "{}", msg),
+        skel::BPF_LOG_LEVEL_DEBUG => {
+            debug!(target: "einat::bpf", if_index, %topic, "{}", msg)
+        }
+        _ => trace!(target: "einat::bpf", if_index, %topic, "{}", msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_text() {
+        let mut topic = [0u8; 32];
+        topic[..6].copy_from_slice(b"ct_new");
+        assert_eq!(c_str(&topic), "ct_new");
+        // truncated topic is not NUL terminated
+        assert_eq!(c_str(&[b'a'; 32]), "a".repeat(32));
+    }
+}
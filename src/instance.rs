@@ -1,35 +1,67 @@
 // SPDX-FileCopyrightText: 2023 Huang-Huang Bao
 // SPDX-License-Identifier: GPL-2.0-or-later
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
 use std::fmt::Debug;
+use std::fs::File;
+use std::io::Read;
 #[cfg(feature = "ipv6")]
 use std::net::Ipv6Addr;
 use std::net::{IpAddr, Ipv4Addr};
+use std::num::NonZeroU32;
 use std::ops::RangeInclusive;
-use std::os::fd::AsFd;
-use std::time::Instant;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use bytemuck::Pod;
 #[cfg(feature = "ipv6")]
 use ipnet::Ipv6Net;
 use ipnet::{IpNet, Ipv4Net};
 use libbpf_rs::skel::{OpenSkel, SkelBuilder};
-use libbpf_rs::{MapFlags, TcHook, TcHookBuilder, TC_EGRESS, TC_INGRESS};
+use libbpf_rs::{
+    AsRawLibbpf, MapFlags, MapHandle, MapType, ObjectBuilder, TcHook, TcHookBuilder, TC_EGRESS,
+    TC_INGRESS,
+};
+use nix::net::if_::if_nametoindex;
 use prefix_trie::{Prefix, PrefixMap, PrefixSet};
 use tracing::{debug, info, warn};
 
-use crate::config::{AddressOrMatcher, ConfigDefaults, ConfigExternal, ConfigNetIf, ProtoRange};
+use crate::capture::CaptureWriter;
+#[cfg(feature = "ipv6")]
+use crate::config::ConfigNptv6;
+use crate::config::{
+    AddressAttrsMatcher, AddressMatcher, AddressOrMatcher, AddressPooling, ConfigDefaults,
+    ConfigDeterministicNat, ConfigExternal, ConfigFwmark, ConfigNetIf, ConfigNetmap,
+    ConfigRateLimit, ConfigStaticBinding, ConfigTimeoutDest, ExternalSelection, Filtering,
+    HairpinMode, IpProtocol, MapSize, PortAllocation, ProtoPortRange, ProtoRange, TcpTeardown,
+    TraceFilter, UnmatchedTcpRst,
+};
+use crate::event::EventReader;
+use crate::probe::{self, KernelFeatures};
 use crate::route::{IfAddresses, PacketEncap};
 use crate::skel;
 use crate::skel::{
-    DestConfig as BpfDestConfig, DestFlags, EinatMaps, EinatSkel, EinatSkelBuilder,
-    ExternalConfig as BpfExternalConfig, ExternalFlags, OpenEinatSkel,
+    DestConfig as BpfDestConfig, DestFlags, EinatMaps, EinatProgs, EinatSkel, EinatSkelBuilder,
+    ExternalConfig as BpfExternalConfig, ExternalFlags, ExternalProtocols, OpenEinatSkel,
+};
+use crate::snapshot::BindingSnapshot;
+#[cfg(feature = "ipv6")]
+use crate::utils::is_global_unicast;
+use crate::utils::{
+    local_listening_ports, local_port_range, monotonic_now_ns, total_memory, with_netns, IpNetwork,
+    MapChange, NetNs, PrefixMapDiff,
 };
-use crate::utils::{IpNetwork, MapChange, PrefixMapDiff};
 
 #[derive(Debug, Default)]
 struct ConstConfig {
     log_level: Option<u8>,
+    max_log_level: Option<u8>,
     has_eth_encap: Option<bool>,
+    has_pppoe_encap: Option<bool>,
     ingress_ipv4: Option<bool>,
     egress_ipv4: Option<bool>,
     #[cfg(feature = "ipv6")]
@@ -38,16 +70,103 @@ struct ConstConfig {
     egress_ipv6: Option<bool>,
     enable_fib_lookup_src: Option<bool>,
     allow_inbound_icmpx: Option<bool>,
+    port_parity: Option<bool>,
+    port_preservation: Option<bool>,
+    port_allocation: Option<u8>,
+    paired_pooling: Option<bool>,
+    external_selection: Option<u8>,
+    external_spillover: Option<bool>,
+    filter_addr_tracking: Option<bool>,
+    inbound_refresh: Option<bool>,
+    dest_timeouts: Option<bool>,
+    addr_bindings: Option<bool>,
+    dmz_host: Option<IpAddr>,
+    local_ports: Option<bool>,
+    pptp: Option<bool>,
+    esp_passthrough: Option<bool>,
+    drop_malformed_icmp_err: Option<bool>,
     timeout_fragment: Option<u64>,
     timeout_pkt_min: Option<u64>,
     timeout_pkt_default: Option<u64>,
+    timeout_udp_single: Option<u64>,
+    timeout_udp_stream: Option<u64>,
+    udp_stream_packets: Option<u8>,
+    timeout_icmp: Option<u64>,
+    max_binding_lifetime: Option<u64>,
     timeout_tcp_trans: Option<u64>,
     timeout_tcp_est: Option<u64>,
+    timeout_tcp_time_wait: Option<u64>,
+    tcp_fin_teardown: Option<u8>,
+    tcp_rst_teardown: Option<u8>,
+    unmatched_tcp_rst: Option<u8>,
+    drop_unsolicited: Option<bool>,
+    det_nat_network: Option<Ipv4Net>,
+    det_nat_block_size: Option<u16>,
+    max_sessions_per_host: Option<u32>,
+    max_embryonic_per_host: Option<u32>,
+    embryonic_tarpit: Option<bool>,
+    port_quota_tcp: Option<u32>,
+    port_quota_udp: Option<u32>,
+    port_quota_icmp: Option<u32>,
+    binding_rate_interval: Option<u64>,
+    binding_rate_burst: Option<u32>,
+    reject_rate_interval: Option<u64>,
+    reject_rate_burst: Option<u32>,
+    udp_binding_rate_interval: Option<u64>,
+    udp_binding_rate_burst: Option<u32>,
+    udp_host_binding_rate_interval: Option<u64>,
+    udp_host_binding_rate_burst: Option<u32>,
+    udp_high_water: Option<u32>,
+    udp_emergency_timeout: Option<u64>,
+    capture: Option<bool>,
+    capture_network: Option<IpNet>,
+    capture_l4proto: Option<u8>,
+    trace: Option<TraceFilter>,
+    hairpin_redirect_ipv4: Option<bool>,
+    hairpin_redirect_ipv6: Option<bool>,
+    /// Interface index and MAC address to redirect hairpin packets to
+    hairpin_redirect_target: Option<(u32, [u8; 6])>,
+    hairpin_fwmark_ipv4: Option<bool>,
+    hairpin_fwmark_ipv6: Option<bool>,
+    hairpin_fwmark: Option<u32>,
+    /// Packet mark of new flows to reject, 0 disables rejection
+    reject_fwmark: Option<u32>,
+    bypass_fwmark: Option<u32>,
+    bypass_fwmark_mask: Option<u32>,
+    netmap: Option<bool>,
+    no_snat_sources: Option<bool>,
+    no_snat_dports: Option<bool>,
+    egress_dscp: Option<u8>,
+    ingress_dscp: Option<u8>,
+    csum_offload_compat: Option<bool>,
+    #[cfg(feature = "ipv6")]
+    nptv6: Option<bool>,
+    #[cfg(feature = "ipv6")]
+    clat: Option<ClatConstConfig>,
+}
+
+/// TC filter handle of CLAT program on uplink, limiting CLAT interfaces to one
+/// per uplink
+#[cfg(feature = "ipv6")]
+const CLAT_TC_HANDLE: u32 = 0x464;
+
+#[cfg(feature = "ipv6")]
+#[derive(Debug, Clone, Copy)]
+struct ClatConstConfig {
+    /// CLAT interface, i.e. the TUN interface we are attached to
+    if_index: u32,
+    uplink_if_index: u32,
+    uplink_has_eth: bool,
+    ipv4_address: Ipv4Addr,
 }
+
 #[derive(Debug)]
 struct RuntimeV4Config {
     external_addr: Ipv4Net,
+    /// External addresses with their weights and served protocols
+    external_pool: Vec<(Ipv4Net, u32, ExternalProtocols)>,
     dest_config: PrefixMap<Ipv4Net, BpfDestConfig>,
+    source_policy: PrefixMap<Ipv4Net, skel::InetAddr>,
     external_config: PrefixMap<Ipv4Net, BpfExternalConfig>,
 }
 
@@ -55,7 +174,9 @@ struct RuntimeV4Config {
 #[derive(Debug)]
 struct RuntimeV6Config {
     external_addr: Ipv6Net,
+    external_pool: Vec<(Ipv6Net, u32, ExternalProtocols)>,
     dest_config: PrefixMap<Ipv6Net, BpfDestConfig>,
+    source_policy: PrefixMap<Ipv6Net, skel::InetAddr>,
     external_config: PrefixMap<Ipv6Net, BpfExternalConfig>,
 }
 
@@ -65,6 +186,8 @@ struct ExternalRanges(Vec<RangeInclusive<u16>>);
 #[derive(Debug)]
 struct External {
     address: AddressOrMatcher,
+    attrs: AddressAttrsMatcher,
+    exclude_address: Vec<AddressMatcher>,
     no_snat: bool,
     no_hairpin: bool,
     tcp_ranges: ExternalRanges,
@@ -72,37 +195,243 @@ struct External {
     icmp_ranges: ExternalRanges,
     icmp_in_ranges: ExternalRanges,
     icmp_out_ranges: ExternalRanges,
+    excluded_ports: ExternalRanges,
+    sources: Vec<IpNet>,
+    weight: u32,
+    protocols: ExternalProtocols,
+    filtering: Filtering,
+    internal_address: Option<IpAddr>,
+}
+
+/// Timeout overrides of destination network in nanoseconds, 0 for not
+/// overridden
+#[derive(Debug, Clone, Copy)]
+struct DestTimeouts {
+    udp: u64,
+    tcp_est: u64,
 }
 
 #[derive(Debug)]
 pub struct InstanceConfig {
     if_index: u32,
-    v4_no_snat_dests: Vec<Ipv4Net>,
+    netns: Option<Arc<NetNs>>,
+    pin_path: Option<PathBuf>,
+    binding_snapshot: Option<PathBuf>,
+    capture_path: Option<PathBuf>,
+    btf_path: Option<PathBuf>,
+    gc_interval: Option<Duration>,
+    ct_lru: bool,
+    map_size: Option<MapSize>,
+    expected_hosts: Option<NonZeroU32>,
+    v4_flagged_dests: Vec<(Ipv4Net, DestFlags)>,
     #[cfg(feature = "ipv6")]
-    v6_no_snat_dests: Vec<Ipv6Net>,
+    v6_flagged_dests: Vec<(Ipv6Net, DestFlags)>,
+    v4_filtering_dests: Vec<(Ipv4Net, Filtering)>,
+    #[cfg(feature = "ipv6")]
+    v6_filtering_dests: Vec<(Ipv6Net, Filtering)>,
+    v4_timeout_dests: Vec<(Ipv4Net, DestTimeouts)>,
+    #[cfg(feature = "ipv6")]
+    v6_timeout_dests: Vec<(Ipv6Net, DestTimeouts)>,
     externals: Vec<External>,
     const_config: ConstConfig,
     runtime_v4_config: RuntimeV4Config,
     #[cfg(feature = "ipv6")]
     runtime_v6_config: RuntimeV6Config,
+    #[cfg(feature = "ipv6")]
+    nptv6_config: Option<ConfigNptv6>,
+    /// `None` if NPTv6 is disabled or external prefix is not discovered
+    #[cfg(feature = "ipv6")]
+    nptv6: Option<Nptv6>,
+    /// IPv6 addresses of external interface
+    #[cfg(feature = "ipv6")]
+    v6_addresses: Vec<Ipv6Addr>,
+    /// Index and IPv6 addresses of interface to discover NPTv6 external prefix
+    /// from, if it's not the external interface
+    #[cfg(feature = "ipv6")]
+    nptv6_prefix_source: Option<(u32, Vec<Ipv6Addr>)>,
+    /// NAT64 prefix and CLAT IPv6 address
+    #[cfg(feature = "ipv6")]
+    clat_addresses: Option<(Ipv6Net, Ipv6Addr)>,
+    netmap: Vec<ConfigNetmap>,
+    no_snat_sources: Vec<IpNet>,
+    no_snat_dports: Vec<ProtoPortRange>,
+    static_bindings: Vec<ConfigStaticBinding>,
+    scan_local_ports: bool,
+    /// Bindings of previous interface to keep external ports of on failover
+    carried_bindings: Vec<(skel::MapBindingKey, skel::MapBindingValue)>,
+    /// Bindings of peer router to take over, see [`crate::sync`]
+    synced_bindings: Option<BindingSnapshot>,
 }
 
 pub struct Instance {
+    // stopped on drop
+    _event_reader: EventReader,
+    _capture_writer: Option<CaptureWriter>,
     config: InstanceConfig,
     skel: EinatSkel<'static>,
     attached_ingress_hook: Option<TcHook>,
     attached_egress_hook: Option<TcHook>,
+    /// Hooks on internal interfaces of hairpin redirect or fwmark mode
+    attached_hairpin_hooks: Vec<(u32, TcHook)>,
+    #[cfg(feature = "ipv6")]
+    attached_clat_hook: Option<TcHook>,
+    next_gc: Option<Instant>,
+    /// Ports in map_local_ports, see [`Instance::update_local_ports`]
+    local_ports: HashSet<(u8, u16)>,
+    /// Ports of local listening sockets found by last scan
+    listening_ports: HashSet<(u8, u16)>,
+    /// Ports reserved over control socket with lease expiry time
+    reserved_ports: HashMap<(u8, u16), Instant>,
+    next_local_ports_update: Option<Instant>,
+}
+
+/// Address families handled by TC program variant.
+#[cfg(feature = "ipv6")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProgFamily {
+    Dual,
+    V4,
+    V6,
+}
+
+#[cfg(feature = "ipv6")]
+impl ProgFamily {
+    fn from_enabled(ipv4: Option<bool>, ipv6: Option<bool>) -> Self {
+        match (ipv4.unwrap_or(true), ipv6.unwrap_or(true)) {
+            (true, false) => Self::V4,
+            (false, true) => Self::V6,
+            _ => Self::Dual,
+        }
+    }
 }
 
 impl ConstConfig {
+    #[cfg(feature = "ipv6")]
+    fn ingress_family(&self) -> ProgFamily {
+        ProgFamily::from_enabled(
+            self.with_netmap(self.ingress_ipv4),
+            self.with_nptv6(self.ingress_ipv6),
+        )
+    }
+
+    #[cfg(feature = "ipv6")]
+    fn egress_family(&self) -> ProgFamily {
+        ProgFamily::from_enabled(
+            self.with_netmap(self.egress_ipv4),
+            self.with_nptv6(self.egress_ipv6),
+        )
+    }
+
+    /// IPv6 packets are handled if NPTv6 is enabled even without NAT66.
+    #[cfg(feature = "ipv6")]
+    fn with_nptv6(&self, ipv6: Option<bool>) -> Option<bool> {
+        if self.nptv6 == Some(true) {
+            Some(true)
+        } else {
+            ipv6
+        }
+    }
+
+    /// IPv4 packets are handled if NETMAP is enabled even without NAT44.
+    #[cfg(feature = "ipv6")]
+    fn with_netmap(&self, ipv4: Option<bool>) -> Option<bool> {
+        if self.netmap == Some(true) {
+            Some(true)
+        } else {
+            ipv4
+        }
+    }
+
+    /// Whether IPv4 maps are referenced by loaded TC programs.
+    #[cfg(feature = "ipv6")]
+    fn has_ipv4_maps(&self) -> bool {
+        self.ingress_family() != ProgFamily::V6 || self.egress_family() != ProgFamily::V6
+    }
+
+    #[cfg(not(feature = "ipv6"))]
+    fn has_ipv4_maps(&self) -> bool {
+        true
+    }
+
+    /// Whether IPv6 maps are referenced by loaded TC programs.
+    #[cfg(feature = "ipv6")]
+    fn has_ipv6_maps(&self) -> bool {
+        self.ingress_family() != ProgFamily::V4 || self.egress_family() != ProgFamily::V4
+    }
+
+    /// Whether hairpin BPF program on internal interfaces is used, i.e. in
+    /// redirect or fwmark hairpin mode.
+    fn hairpin_bpf(&self) -> bool {
+        [
+            self.hairpin_redirect_ipv4,
+            self.hairpin_redirect_ipv6,
+            self.hairpin_fwmark_ipv4,
+            self.hairpin_fwmark_ipv6,
+        ]
+        .contains(&Some(true))
+    }
+
+    /// Only loads TC program variants of enabled address families, so code
+    /// paths of disabled address family are not verified, and only creates
+    /// maps of address families in use.
+    #[cfg(feature = "ipv6")]
+    fn select_family(&self, skel: &mut OpenEinatSkel) -> Result<()> {
+        let ingress = self.ingress_family();
+        let egress = self.egress_family();
+        debug!(
+            "ingress program for {:?}, egress program for {:?}",
+            ingress, egress
+        );
+
+        let mut progs = skel.progs_mut();
+        progs
+            .ingress_rev_snat()
+            .set_autoload(ingress == ProgFamily::Dual)?;
+        progs
+            .ingress_rev_snat_v4()
+            .set_autoload(ingress == ProgFamily::V4)?;
+        progs
+            .ingress_rev_snat_v6()
+            .set_autoload(ingress == ProgFamily::V6)?;
+        progs
+            .egress_snat()
+            .set_autoload(egress == ProgFamily::Dual)?;
+        progs
+            .egress_snat_v4()
+            .set_autoload(egress == ProgFamily::V4)?;
+        progs
+            .egress_snat_v6()
+            .set_autoload(egress == ProgFamily::V6)?;
+
+        let ipv4 = self.has_ipv4_maps();
+        let ipv6 = self.has_ipv6_maps();
+        let mut maps = skel.maps_mut();
+        maps.map_ipv4_external_config().set_autocreate(ipv4)?;
+        maps.map_ipv4_dest_config().set_autocreate(ipv4)?;
+        maps.map_ipv4_source_policy().set_autocreate(ipv4)?;
+        maps.map_ipv4_netmap().set_autocreate(ipv4)?;
+        maps.map_ipv4_no_snat_source().set_autocreate(ipv4)?;
+        maps.map_ipv6_external_config().set_autocreate(ipv6)?;
+        maps.map_ipv6_dest_config().set_autocreate(ipv6)?;
+        maps.map_ipv6_source_policy().set_autocreate(ipv6)?;
+        maps.map_ipv6_no_snat_source().set_autocreate(ipv6)?;
+        Ok(())
+    }
+
     fn apply(&self, skel: &mut OpenEinatSkel) {
-        let rodata = skel.rodata_mut();
         if let Some(log_level) = self.log_level {
-            rodata.LOG_LEVEL = log_level;
+            skel.data_mut().g_log_level = log_level;
+        }
+        let rodata = skel.rodata_mut();
+        if let Some(max_log_level) = self.max_log_level {
+            rodata.MAX_LOG_LEVEL = max_log_level;
         }
         if let Some(has_eth_encap) = self.has_eth_encap {
             rodata.HAS_ETH_ENCAP = has_eth_encap as _;
         }
+        if let Some(has_pppoe_encap) = self.has_pppoe_encap {
+            rodata.HAS_PPPOE_ENCAP = has_pppoe_encap as _;
+        }
         if let Some(ingress_ipv4) = self.ingress_ipv4 {
             rodata.INGRESS_IPV4 = ingress_ipv4 as _;
         }
@@ -117,12 +446,100 @@ impl ConstConfig {
         if let Some(egress_ipv6) = self.egress_ipv6 {
             rodata.EGRESS_IPV6 = egress_ipv6 as _;
         }
+        if let Some(hairpin_redirect_ipv4) = self.hairpin_redirect_ipv4 {
+            rodata.HAIRPIN_REDIRECT_IPV4 = hairpin_redirect_ipv4 as _;
+        }
+        if let Some(hairpin_redirect_ipv6) = self.hairpin_redirect_ipv6 {
+            rodata.HAIRPIN_REDIRECT_IPV6 = hairpin_redirect_ipv6 as _;
+        }
+        if let Some((if_index, mac)) = self.hairpin_redirect_target {
+            rodata.HAIRPIN_REDIRECT_IFINDEX = if_index;
+            rodata.HAIRPIN_REDIRECT_MAC = mac;
+        }
+        if let Some(hairpin_fwmark_ipv4) = self.hairpin_fwmark_ipv4 {
+            rodata.HAIRPIN_FWMARK_IPV4 = hairpin_fwmark_ipv4 as _;
+        }
+        if let Some(hairpin_fwmark_ipv6) = self.hairpin_fwmark_ipv6 {
+            rodata.HAIRPIN_FWMARK_IPV6 = hairpin_fwmark_ipv6 as _;
+        }
+        if let Some(netmap) = self.netmap {
+            rodata.NETMAP = netmap as _;
+        }
+        if let Some(no_snat_sources) = self.no_snat_sources {
+            rodata.NO_SNAT_SOURCES = no_snat_sources as _;
+        }
+        if let Some(no_snat_dports) = self.no_snat_dports {
+            rodata.NO_SNAT_DPORTS = no_snat_dports as _;
+        }
+        #[cfg(feature = "ipv6")]
+        if let Some(nptv6) = self.nptv6 {
+            rodata.NPTV6 = nptv6 as _;
+        }
+        #[cfg(feature = "ipv6")]
+        if let Some(clat) = self.clat {
+            rodata.CLAT = true as _;
+            rodata.CLAT_IFINDEX = clat.if_index;
+            rodata.CLAT_UPLINK_IFINDEX = clat.uplink_if_index;
+            rodata.CLAT_UPLINK_HAS_ETH = clat.uplink_has_eth as _;
+            rodata.CLAT_IPV4_ADDR = u32::from_ne_bytes(clat.ipv4_address.octets());
+        }
+        if let Some(hairpin_fwmark) = self.hairpin_fwmark {
+            rodata.HAIRPIN_FWMARK = hairpin_fwmark;
+        }
+        if let Some(reject_fwmark) = self.reject_fwmark {
+            rodata.REJECT_FWMARK = reject_fwmark;
+        }
+        if let Some(bypass_fwmark) = self.bypass_fwmark {
+            rodata.BYPASS_FWMARK = bypass_fwmark;
+        }
+        if let Some(bypass_fwmark_mask) = self.bypass_fwmark_mask {
+            rodata.BYPASS_FWMARK_MASK = bypass_fwmark_mask;
+        }
+        if let Some(egress_dscp) = self.egress_dscp {
+            rodata.EGRESS_DSCP = egress_dscp;
+        }
+        if let Some(ingress_dscp) = self.ingress_dscp {
+            rodata.INGRESS_DSCP = ingress_dscp;
+        }
+        if let Some(csum_offload_compat) = self.csum_offload_compat {
+            rodata.CSUM_OFFLOAD_COMPAT = csum_offload_compat as _;
+        }
         if let Some(enable_fib_lookup_src) = self.enable_fib_lookup_src {
             rodata.ENABLE_FIB_LOOKUP_SRC = enable_fib_lookup_src as _;
         }
         if let Some(allow_inbound_icmpx) = self.allow_inbound_icmpx {
             rodata.ALLOW_INBOUND_ICMPX = allow_inbound_icmpx as _;
         }
+        if let Some(port_parity) = self.port_parity {
+            rodata.PORT_PARITY = port_parity as _;
+        }
+        if let Some(port_preservation) = self.port_preservation {
+            rodata.PORT_PRESERVATION = port_preservation as _;
+        }
+        if let Some(port_allocation) = self.port_allocation {
+            rodata.PORT_ALLOCATION = port_allocation;
+        }
+        if let Some(paired_pooling) = self.paired_pooling {
+            rodata.PAIRED_POOLING = paired_pooling as _;
+        }
+        if let Some(external_selection) = self.external_selection {
+            rodata.EXTERNAL_SELECTION = external_selection;
+        }
+        if let Some(external_spillover) = self.external_spillover {
+            rodata.EXTERNAL_SPILLOVER = external_spillover as _;
+        }
+        if let Some(filter_addr_tracking) = self.filter_addr_tracking {
+            rodata.FILTER_ADDR_TRACKING = filter_addr_tracking as _;
+        }
+        if let Some(inbound_refresh) = self.inbound_refresh {
+            rodata.INBOUND_REFRESH = inbound_refresh as _;
+        }
+        if let Some(dest_timeouts) = self.dest_timeouts {
+            rodata.DEST_TIMEOUTS = dest_timeouts as _;
+        }
+        if let Some(addr_bindings) = self.addr_bindings {
+            rodata.ADDR_BINDINGS = addr_bindings as _;
+        }
         if let Some(timeout_fragment) = self.timeout_fragment {
             rodata.TIMEOUT_FRAGMENT = timeout_fragment;
         }
@@ -132,13 +549,418 @@ impl ConstConfig {
         if let Some(timeout_pkt_default) = self.timeout_pkt_default {
             rodata.TIMEOUT_PKT_MIN = timeout_pkt_default;
         }
+        if let Some(timeout_udp_single) = self.timeout_udp_single {
+            rodata.TIMEOUT_UDP_SINGLE = timeout_udp_single;
+        }
+        if let Some(timeout_udp_stream) = self.timeout_udp_stream {
+            rodata.TIMEOUT_UDP_STREAM = timeout_udp_stream;
+        }
+        if let Some(udp_stream_packets) = self.udp_stream_packets {
+            rodata.UDP_STREAM_PACKETS = udp_stream_packets;
+        }
+        if let Some(timeout_icmp) = self.timeout_icmp {
+            rodata.TIMEOUT_ICMP = timeout_icmp;
+        }
         if let Some(timeout_tcp_trans) = self.timeout_tcp_trans {
             rodata.TIMEOUT_TCP_TRANS = timeout_tcp_trans;
         }
         if let Some(timeout_tcp_est) = self.timeout_tcp_est {
             rodata.TIMEOUT_TCP_EST = timeout_tcp_est;
         }
+        if let Some(timeout_tcp_time_wait) = self.timeout_tcp_time_wait {
+            rodata.TIMEOUT_TCP_TIME_WAIT = timeout_tcp_time_wait;
+        }
+        if let Some(tcp_fin_teardown) = self.tcp_fin_teardown {
+            rodata.TCP_FIN_TEARDOWN = tcp_fin_teardown;
+        }
+        if let Some(tcp_rst_teardown) = self.tcp_rst_teardown {
+            rodata.TCP_RST_TEARDOWN = tcp_rst_teardown;
+        }
+        if let Some(unmatched_tcp_rst) = self.unmatched_tcp_rst {
+            rodata.UNMATCHED_TCP_RST = unmatched_tcp_rst;
+        }
+        if let Some(drop_unsolicited) = self.drop_unsolicited {
+            rodata.DROP_UNSOLICITED = drop_unsolicited as _;
+        }
+        if let Some(max_binding_lifetime) = self.max_binding_lifetime {
+            rodata.MAX_BINDING_LIFETIME = max_binding_lifetime;
+        }
+        if let Some(det_nat_network) = self.det_nat_network {
+            rodata.DET_NAT_NETWORK = det_nat_network.network().into();
+            rodata.DET_NAT_MASK = det_nat_network.netmask().into();
+        }
+        if let Some(det_nat_block_size) = self.det_nat_block_size {
+            rodata.DET_NAT_BLOCK_SIZE = det_nat_block_size;
+        }
+        if let Some(max_sessions_per_host) = self.max_sessions_per_host {
+            rodata.MAX_SESSIONS_PER_HOST = max_sessions_per_host;
+        }
+        if let Some(max_embryonic_per_host) = self.max_embryonic_per_host {
+            rodata.MAX_EMBRYONIC_PER_HOST = max_embryonic_per_host;
+        }
+        if let Some(embryonic_tarpit) = self.embryonic_tarpit {
+            rodata.EMBRYONIC_TARPIT = embryonic_tarpit as _;
+        }
+        if let Some(port_quota_tcp) = self.port_quota_tcp {
+            rodata.PORT_QUOTA_TCP = port_quota_tcp;
+        }
+        if let Some(port_quota_udp) = self.port_quota_udp {
+            rodata.PORT_QUOTA_UDP = port_quota_udp;
+        }
+        if let Some(port_quota_icmp) = self.port_quota_icmp {
+            rodata.PORT_QUOTA_ICMP = port_quota_icmp;
+        }
+        if let Some(binding_rate_interval) = self.binding_rate_interval {
+            rodata.BINDING_RATE_INTERVAL = binding_rate_interval;
+        }
+        if let Some(binding_rate_burst) = self.binding_rate_burst {
+            rodata.BINDING_RATE_BURST = binding_rate_burst;
+        }
+        if let Some(reject_rate_interval) = self.reject_rate_interval {
+            rodata.REJECT_RATE_INTERVAL = reject_rate_interval;
+        }
+        if let Some(reject_rate_burst) = self.reject_rate_burst {
+            rodata.REJECT_RATE_BURST = reject_rate_burst;
+        }
+        if let Some(udp_binding_rate_interval) = self.udp_binding_rate_interval {
+            rodata.UDP_BINDING_RATE_INTERVAL = udp_binding_rate_interval;
+        }
+        if let Some(udp_binding_rate_burst) = self.udp_binding_rate_burst {
+            rodata.UDP_BINDING_RATE_BURST = udp_binding_rate_burst;
+        }
+        if let Some(udp_host_binding_rate_interval) = self.udp_host_binding_rate_interval {
+            rodata.UDP_HOST_BINDING_RATE_INTERVAL = udp_host_binding_rate_interval;
+        }
+        if let Some(udp_host_binding_rate_burst) = self.udp_host_binding_rate_burst {
+            rodata.UDP_HOST_BINDING_RATE_BURST = udp_host_binding_rate_burst;
+        }
+        if let Some(udp_high_water) = self.udp_high_water {
+            rodata.UDP_HIGH_WATER = udp_high_water;
+        }
+        if let Some(udp_emergency_timeout) = self.udp_emergency_timeout {
+            rodata.UDP_EMERGENCY_TIMEOUT = udp_emergency_timeout;
+        }
+        if let Some(local_ports) = self.local_ports {
+            rodata.LOCAL_PORTS = local_ports as _;
+        }
+        if let Some(pptp) = self.pptp {
+            rodata.PPTP = pptp as _;
+        }
+        if let Some(esp_passthrough) = self.esp_passthrough {
+            rodata.ESP_PASSTHROUGH = esp_passthrough as _;
+        }
+        if let Some(drop_malformed_icmp_err) = self.drop_malformed_icmp_err {
+            rodata.DROP_MALFORMED_ICMP_ERR = drop_malformed_icmp_err as _;
+        }
+        if let Some(dmz_host) = self.dmz_host {
+            let (is_ipv4, addr) = prefix_words(dmz_host.into());
+            rodata.DMZ_HOST = true as _;
+            rodata.DMZ_HOST_IPV4 = is_ipv4 as _;
+            rodata.DMZ_HOST_ADDR = addr;
+        }
+        if let Some(capture) = self.capture {
+            rodata.CAPTURE = capture as _;
+        }
+        if let Some(capture_network) = self.capture_network {
+            let (is_ipv4, addr) = prefix_words(capture_network);
+            rodata.CAPTURE_ADDR_IPV4 = is_ipv4 as _;
+            rodata.CAPTURE_ADDR = addr;
+            rodata.CAPTURE_PREFIX_LEN = capture_network.prefix_len();
+        }
+        if let Some(capture_l4proto) = self.capture_l4proto {
+            rodata.CAPTURE_L4PROTO = capture_l4proto;
+        }
+        if let Some(trace) = &self.trace {
+            rodata.TRACE = true as _;
+            if let Some(network) = trace.network {
+                let (is_ipv4, addr) = prefix_words(network);
+                rodata.TRACE_ADDR_IPV4 = is_ipv4 as _;
+                rodata.TRACE_ADDR = addr;
+                rodata.TRACE_PREFIX_LEN = network.prefix_len();
+            }
+            rodata.TRACE_PORT = trace.port.unwrap_or(0);
+            rodata.TRACE_L4PROTO = trace.protocol.map_or(0, l4proto);
+        }
+    }
+}
+
+/// Returns address family and network address words in network byte order of
+/// `network`, as prefix filter of BPF programs. IPv4 address is placed at the
+/// start.
+fn prefix_words(network: IpNet) -> (bool, [u32; 4]) {
+    let mut octets = [0u8; 16];
+    match network.network() {
+        IpAddr::V4(addr) => octets[..4].copy_from_slice(&addr.octets()),
+        IpAddr::V6(addr) => octets = addr.octets(),
+    }
+    let mut words = [0; 4];
+    for (word, chunk) in words.iter_mut().zip(octets.chunks(4)) {
+        *word = u32::from_ne_bytes(chunk.try_into().unwrap());
+    }
+    (network.network().is_ipv4(), words)
+}
+
+fn l4proto(protocol: IpProtocol) -> u8 {
+    match protocol {
+        IpProtocol::Tcp => libc::IPPROTO_TCP as u8,
+        IpProtocol::Udp => libc::IPPROTO_UDP as u8,
+        IpProtocol::Icmp => libc::IPPROTO_ICMP as u8,
+    }
+}
+
+fn external_protocol(proto: IpProtocol) -> ExternalProtocols {
+    match proto {
+        IpProtocol::Tcp => ExternalProtocols::TCP,
+        IpProtocol::Udp => ExternalProtocols::UDP,
+        IpProtocol::Icmp => ExternalProtocols::ICMP,
+    }
+}
+
+fn external_serves(protocols: ExternalProtocols, proto: IpProtocol) -> bool {
+    protocols.is_empty() || protocols.contains(external_protocol(proto))
+}
+
+fn tcp_teardown(teardown: TcpTeardown) -> u8 {
+    match teardown {
+        TcpTeardown::Transitory => skel::TCP_TEARDOWN_TRANS,
+        TcpTeardown::TimeWait => skel::TCP_TEARDOWN_TIME_WAIT,
+        TcpTeardown::Close => skel::TCP_TEARDOWN_CLOSE,
+        TcpTeardown::Ignore => skel::TCP_TEARDOWN_IGNORE,
+    }
+}
+
+/// Checks that port blocks of all hosts fit in the first TCP and UDP port
+/// range of IPv4 externals.
+fn check_deterministic_nat(det_nat: &ConfigDeterministicNat, externals: &[External]) -> Result<()> {
+    let hosts = 1u64 << (32 - det_nat.internal_network.prefix_len());
+    let ports = hosts * det_nat.block_size.get() as u64;
+    for external in externals
+        .iter()
+        .filter(|external| external.address.is_ipv4() && external.internal_address.is_none())
+    {
+        for ranges in [&external.tcp_ranges, &external.udp_ranges] {
+            let Some(first) = ranges.0.first() else {
+                continue;
+            };
+            let range_len = (*first.end() - *first.start()) as u64 + 1;
+            if range_len < ports {
+                return Err(anyhow!(
+                    "deterministic NAT requires {} ports for {} hosts of {}, but port range {}-{} only has {}",
+                    ports,
+                    hosts,
+                    det_nat.internal_network,
+                    first.start(),
+                    first.end(),
+                    range_len
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that bits of fwmark are all covered by its mask, otherwise no packet
+/// would match.
+fn check_fwmark(fwmark: &ConfigFwmark) -> Result<()> {
+    if fwmark.mark.get() & !fwmark.mask() != 0 {
+        return Err(anyhow!(
+            "fwmark {:#x} is out of mask {:#x}",
+            fwmark.mark.get(),
+            fwmark.mask()
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that NETMAP prefixes are of the same length and none of them
+/// overlaps with another.
+fn check_netmap(netmap: &[ConfigNetmap]) -> Result<()> {
+    let mut prefixes = Vec::new();
+    for entry in netmap {
+        if entry.internal_prefix.prefix_len() != entry.external_prefix.prefix_len() {
+            return Err(anyhow!(
+                "NETMAP prefixes {} and {} are not of the same length",
+                entry.internal_prefix,
+                entry.external_prefix
+            ));
+        }
+        for prefix in [entry.internal_prefix.trunc(), entry.external_prefix.trunc()] {
+            if let Some(other) = prefixes
+                .iter()
+                .find(|other: &&Ipv4Net| other.contains(&prefix) || prefix.contains(*other))
+            {
+                return Err(anyhow!("NETMAP prefix {} overlaps with {}", prefix, other));
+            }
+            prefixes.push(prefix);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that static bindings are of TCP or UDP and not bound twice, with
+/// external ports within port ranges of any NAT external, as inbound packets
+/// to ports out of range are passed through to this host.
+fn check_static_bindings(bindings: &[ConfigStaticBinding], externals: &[External]) -> Result<()> {
+    for (i, binding) in bindings.iter().enumerate() {
+        let internal = binding.internal;
+        if internal.port() == 0 || binding.external_port == 0 {
+            return Err(anyhow!("static binding of {} has zero port", internal));
+        }
+        if binding
+            .external_address
+            .is_some_and(|address| address.is_ipv4() != internal.is_ipv4())
+        {
+            return Err(anyhow!(
+                "static binding of {} has external address of different family",
+                internal
+            ));
+        }
+        if binding.proto == IpProtocol::Icmp {
+            return Err(anyhow!(
+                "static binding of {} must be of TCP or UDP",
+                internal
+            ));
+        }
+        let in_range = |external: &External| {
+            let ranges = if binding.proto == IpProtocol::Tcp {
+                &external.tcp_ranges
+            } else {
+                &external.udp_ranges
+            };
+            !external.no_snat
+                && external.internal_address.is_none()
+                && external_serves(external.protocols, binding.proto)
+                && ranges
+                    .0
+                    .iter()
+                    .any(|range| range.contains(&binding.external_port))
+        };
+        if !externals.iter().any(in_range) {
+            return Err(anyhow!(
+                "external port {} of static binding of {} is not within port ranges of any external",
+                binding.external_port,
+                internal
+            ));
+        }
+        if bindings[..i].iter().any(|other| {
+            other.proto == binding.proto
+                && other.external_port == binding.external_port
+                && other.internal.is_ipv4() == internal.is_ipv4()
+                && other.external_address == binding.external_address
+        }) {
+            return Err(anyhow!(
+                "external port {} is bound by multiple static bindings",
+                binding.external_port
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Inserts NETMAP entries of internal prefixes and external prefixes.
+fn apply_netmap(netmap: &[ConfigNetmap], skel: &EinatSkel) -> Result<()> {
+    use skel::{Ipv4LpmKey, NetmapFlags, NetmapValue};
+
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    for entry in netmap {
+        let internal = entry.internal_prefix.trunc();
+        let external = entry.external_prefix.trunc();
+        for (from, to, flags) in [
+            (internal, external, NetmapFlags::ORIG_DIR),
+            (external, internal, NetmapFlags::empty()),
+        ] {
+            let value = NetmapValue {
+                to_prefix: to.addr().octets(),
+                mask: to.netmask().octets(),
+                flags,
+                _pad: [0; 3],
+            };
+            keys.extend_from_slice(bytemuck::bytes_of(&Ipv4LpmKey::from(from)));
+            values.extend_from_slice(bytemuck::bytes_of(&value));
+        }
+        info!(
+            "mapping IPv4 prefix {} to {} with NETMAP",
+            internal, external
+        );
+    }
+    update_batch_or_each(skel.maps().map_ipv4_netmap(), &keys, &values)
+}
+
+/// Inserts internal source prefixes exempted from SNAT, of address families
+/// with maps created.
+fn apply_no_snat_sources(
+    no_snat_sources: &[IpNet],
+    const_config: &ConstConfig,
+    skel: &EinatSkel,
+) -> Result<()> {
+    let maps = skel.maps();
+    for source in no_snat_sources {
+        match source.trunc() {
+            IpNet::V4(source) if const_config.has_ipv4_maps() => {
+                let key = skel::Ipv4LpmKey::from(source);
+                maps.map_ipv4_no_snat_source().update(
+                    bytemuck::bytes_of(&key),
+                    &[1],
+                    MapFlags::ANY,
+                )?;
+            }
+            #[cfg(feature = "ipv6")]
+            IpNet::V6(source) if const_config.has_ipv6_maps() => {
+                let key = skel::Ipv6LpmKey::from(source);
+                maps.map_ipv6_no_snat_source().update(
+                    bytemuck::bytes_of(&key),
+                    &[1],
+                    MapFlags::ANY,
+                )?;
+            }
+            _ => continue,
+        }
+        info!("not translating source prefix {}", source.trunc());
+    }
+    Ok(())
+}
+
+/// Inserts TCP and UDP destination ports exempted from SNAT.
+fn apply_no_snat_dports(no_snat_dports: &[ProtoPortRange], skel: &EinatSkel) -> Result<()> {
+    let maps = skel.maps();
+    for range in no_snat_dports {
+        let l4proto = match range.proto {
+            IpProtocol::Tcp => libc::IPPROTO_TCP,
+            IpProtocol::Udp => libc::IPPROTO_UDP,
+            IpProtocol::Icmp => unreachable!(),
+        } as u8;
+        for port in range.ports.inner.clone() {
+            let key = skel::MapLocalPortKey {
+                l4proto,
+                _pad: 0,
+                port: port.to_be(),
+            };
+            maps.map_no_snat_dport()
+                .update(bytemuck::bytes_of(&key), &[1], MapFlags::ANY)?;
+        }
+        info!("not translating packets to {}", range);
+    }
+    Ok(())
+}
+
+/// DSCP is the upper 6 bits of IPv4 TOS or IPv6 traffic class
+const MAX_DSCP: u8 = 63;
+
+/// Checks that `no_snat_dports` fit in map_no_snat_dport.
+fn check_no_snat_dports(no_snat_dports: &[ProtoPortRange]) -> Result<()> {
+    let ports: usize = no_snat_dports
+        .iter()
+        .map(|range| range.ports.inner.clone().count())
+        .sum();
+    if ports > MAX_NO_SNAT_DPORTS {
+        return Err(anyhow!(
+            "no_snat_dports has {} ports, more than the maximum of {}",
+            ports,
+            MAX_NO_SNAT_DPORTS
+        ));
     }
+    Ok(())
 }
 
 fn sort_and_merge_ranges(ranges: &[RangeInclusive<u16>]) -> Vec<RangeInclusive<u16>> {
@@ -230,8 +1052,69 @@ impl ExternalRanges {
     }
 }
 
+/// Warns if binding port ranges of external addresses that are also addresses
+/// of this host overlap the ephemeral port range of this host stack, as
+/// bindings could then collide with connections initiated by this host.
+fn check_ephemeral_ports<P: Prefix + IpNetwork>(
+    ephemeral: &RangeInclusive<u16>,
+    external_config: &PrefixMap<P, BpfExternalConfig>,
+) {
+    fn ranges(raw_ranges: &skel::PortRanges, raw_len: u8) -> Vec<RangeInclusive<u16>> {
+        raw_ranges[..raw_len as usize]
+            .iter()
+            .map(|range| range.start_port..=range.end_port)
+            .collect()
+    }
+
+    for (network, config) in external_config {
+        if !config.flags.contains(ExternalFlags::LOCAL)
+            || config
+                .flags
+                .intersects(ExternalFlags::NO_SNAT | ExternalFlags::ONE_TO_ONE)
+        {
+            continue;
+        }
+        let excluded = ExternalRanges(ranges(&config.excluded_range, config.excluded_range_len));
+        for (proto, raw_ranges, raw_len) in [
+            ("TCP", &config.tcp_range, config.tcp_range_len),
+            ("UDP", &config.udp_range, config.udp_range_len),
+        ] {
+            let overlaps: Vec<_> = ranges(raw_ranges, raw_len)
+                .into_iter()
+                .map(|range| {
+                    *range.start().max(ephemeral.start())..=*range.end().min(ephemeral.end())
+                })
+                .filter(|range| !range.is_empty())
+                .collect();
+            if !overlaps.is_empty() && !excluded.contains(&ExternalRanges(overlaps.clone())) {
+                warn!(
+                    "{} port ranges of external address {} overlap ephemeral port range {}-{} of this host in {:?}, add them to `excluded_ports` or change `net.ipv4.ip_local_port_range` to avoid collisions",
+                    proto,
+                    network.ip_addr(),
+                    ephemeral.start(),
+                    ephemeral.end(),
+                    overlaps
+                );
+            }
+        }
+    }
+}
+
+impl From<&ConfigTimeoutDest> for DestTimeouts {
+    fn from(dest: &ConfigTimeoutDest) -> Self {
+        Self {
+            udp: dest.timeout_udp.map(Into::into).unwrap_or(0),
+            tcp_est: dest.timeout_tcp_est.map(Into::into).unwrap_or(0),
+        }
+    }
+}
+
 impl External {
-    fn try_from(external: &ConfigExternal, defaults: &ConfigDefaults) -> Result<Self> {
+    fn try_from(
+        external: &ConfigExternal,
+        defaults: &ConfigDefaults,
+        filtering: Option<Filtering>,
+    ) -> Result<Self> {
         let tcp_ranges = ExternalRanges::try_from(
             external.tcp_ranges.as_ref().unwrap_or(&defaults.tcp_ranges),
             false,
@@ -289,65 +1172,215 @@ impl External {
             ));
         }
 
-        Ok(Self {
-            address: external.address,
-            no_snat: external.no_snat,
-            no_hairpin: external.no_hairpin,
-            tcp_ranges,
-            udp_ranges,
-            icmp_ranges,
-            icmp_in_ranges,
-            icmp_out_ranges,
-        })
-    }
-}
+        let excluded_ports = ExternalRanges::try_from(
+            external
+                .excluded_ports
+                .as_ref()
+                .unwrap_or(&defaults.excluded_ports),
+            false,
+        )?;
+        for range in excluded_ports.0.iter() {
+            let range = ExternalRanges(vec![range.clone()]);
+            if !tcp_ranges.contains(&range) && !udp_ranges.contains(&range) {
+                return Err(anyhow!(
+                    "excluded ports {:?} not within TCP or UDP ranges",
+                    range
+                ));
+            }
+        }
+        for (name, ranges) in [("TCP", &tcp_ranges), ("UDP", &udp_ranges)] {
+            if !ranges.0.is_empty() && excluded_ports.contains(ranges) {
+                return Err(anyhow!("excluded ports cover all {} ranges", name));
+            }
+        }
 
-trait RuntimeConfig {
+        if let Some(internal_address) = external.internal_address {
+            let AddressOrMatcher::Static { address } = external.address else {
+                return Err(anyhow!(
+                    "`internal_address` requires static external `address`"
+                ));
+            };
+            if address.is_ipv4() != internal_address.is_ipv4() {
+                return Err(anyhow!(
+                    "internal address {} is not of the same family as external address {}",
+                    internal_address,
+                    address
+                ));
+            }
+            if external.no_snat {
+                return Err(anyhow!("`internal_address` conflicts with `no_snat`"));
+            }
+        }
+
+        let mut protocols = ExternalProtocols::empty();
+        if let Some(protos) = &external.protocols {
+            if external.no_snat || external.internal_address.is_some() {
+                return Err(anyhow!(
+                    "`protocols` conflicts with `no_snat` and `internal_address`"
+                ));
+            }
+            if protos.is_empty() {
+                return Err(anyhow!("`protocols` is empty"));
+            }
+            for &proto in protos {
+                let flag = external_protocol(proto);
+                if protocols.contains(flag) {
+                    return Err(anyhow!("duplicated protocol {} in `protocols`", proto));
+                }
+                protocols.insert(flag);
+            }
+            for (proto, ranges) in [
+                (IpProtocol::Tcp, &external.tcp_ranges),
+                (IpProtocol::Udp, &external.udp_ranges),
+                (IpProtocol::Icmp, &external.icmp_ranges),
+            ] {
+                if !protocols.contains(external_protocol(proto))
+                    && ranges.as_ref().is_some_and(|ranges| !ranges.is_empty())
+                {
+                    return Err(anyhow!(
+                        "{} ranges set but {} is not in `protocols`",
+                        proto,
+                        proto
+                    ));
+                }
+            }
+        }
+
+        Ok(Self {
+            address: external.address.clone(),
+            attrs: external.attrs.clone(),
+            exclude_address: external.exclude_address.clone(),
+            no_snat: external.no_snat,
+            no_hairpin: external.no_hairpin,
+            tcp_ranges,
+            udp_ranges,
+            icmp_ranges,
+            icmp_in_ranges,
+            icmp_out_ranges,
+            excluded_ports,
+            sources: external.sources.clone(),
+            weight: external.weight,
+            protocols,
+            filtering: external.filtering.or(filtering).unwrap_or_default(),
+            internal_address: external.internal_address,
+        })
+    }
+}
+
+trait RuntimeConfig {
     type Prefix: IpNetwork + Copy + Prefix + PartialEq + Debug;
 
     fn external_addr(&self) -> &Self::Prefix;
     fn external_addr_mut(&mut self) -> &mut Self::Prefix;
+    fn external_pool(&self) -> &[(Self::Prefix, u32, ExternalProtocols)];
+    fn external_pool_mut(&mut self) -> &mut Vec<(Self::Prefix, u32, ExternalProtocols)>;
 
     fn dest_config(&self) -> &PrefixMap<Self::Prefix, BpfDestConfig>;
     fn dest_config_mut(&mut self) -> &mut PrefixMap<Self::Prefix, BpfDestConfig>;
 
+    fn source_policy(&self) -> &PrefixMap<Self::Prefix, skel::InetAddr>;
+    fn source_policy_mut(&mut self) -> &mut PrefixMap<Self::Prefix, skel::InetAddr>;
+
     fn external_config(&self) -> &PrefixMap<Self::Prefix, BpfExternalConfig>;
     fn external_config_mut(&mut self) -> &mut PrefixMap<Self::Prefix, BpfExternalConfig>;
 
     fn with_lpm_key_bytes<R, F: FnOnce(&[u8]) -> R>(prefix: Self::Prefix, f: F) -> R;
 
+    /// Concatenated raw keys and values of entries, for batch update.
+    fn lpm_entries_bytes<V: bytemuck::Pod>(map: &PrefixMap<Self::Prefix, V>) -> (Vec<u8>, Vec<u8>) {
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for (k, v) in map.iter() {
+            Self::with_lpm_key_bytes(*k, |k| keys.extend_from_slice(k));
+            values.extend_from_slice(bytemuck::bytes_of(v));
+        }
+        (keys, values)
+    }
+
     fn apply_external_addr(&self, skel: &mut EinatSkel);
     fn skel_map_dest_config<'a>(maps: &'a EinatMaps<'_>) -> &'a libbpf_rs::Map;
+    fn skel_map_source_policy<'a>(maps: &'a EinatMaps<'_>) -> &'a libbpf_rs::Map;
     fn skel_map_external_config<'a>(maps: &'a EinatMaps<'_>) -> &'a libbpf_rs::Map;
 
     fn init(
         &mut self,
-        no_snat_dests: &[Self::Prefix],
+        flagged_dests: &[(Self::Prefix, DestFlags)],
+        filtering_dests: &[(Self::Prefix, Filtering)],
+        timeout_dests: &[(Self::Prefix, DestTimeouts)],
         externals: &[External],
         addresses: &[Self::Prefix],
+        if_addresses: &IfAddresses,
     ) {
         let mut external_addr: Option<Self::Prefix> = None;
+        let mut external_pool = Vec::new();
 
-        for network in no_snat_dests {
+        for (network, flags) in flagged_dests {
             let dest_value = self.dest_config_mut().entry(*network).or_default();
-            dest_value.flags.insert(DestFlags::NO_SNAT);
+            dest_value.flags.insert(*flags);
+        }
+        for (network, _) in filtering_dests {
+            self.dest_config_mut().entry(*network).or_default();
+        }
+        for (network, _) in timeout_dests {
+            self.dest_config_mut().entry(*network).or_default();
         }
 
         let mut addresses_set = PrefixSet::from_iter(addresses.iter().copied());
 
         for external in externals {
             let mut matches = Vec::new();
-            match external.address {
+            match &external.address {
                 AddressOrMatcher::Static { address } => {
-                    if let Some(address) = Self::Prefix::from_ip_addr(address) {
+                    if let Some(address) = Self::Prefix::from_ip_addr(*address) {
                         if !address.is_unspecified() {
                             matches.push(address);
                         }
                     }
                 }
+                AddressOrMatcher::Command { address_command } => {
+                    if let Some(address) = if_addresses
+                        .command_outputs
+                        .get(address_command)
+                        .and_then(|&address| Self::Prefix::from_ip_addr(address))
+                    {
+                        if !address.is_unspecified() {
+                            matches.push(address);
+                        }
+                    }
+                }
+                AddressOrMatcher::Route { match_route } => {
+                    if let Some(address) = if_addresses
+                        .route_sources
+                        .get(match_route)
+                        .and_then(|&address| Self::Prefix::from_ip_addr(address))
+                    {
+                        matches.push(address);
+                    }
+                }
                 AddressOrMatcher::Matcher { match_address } => {
-                    for address in addresses_set.iter() {
-                        if match_address.contains(&address.ip_addr()) && !address.is_unspecified() {
+                    // in order of interface addresses, which are ranked by
+                    // preference, so the first match is the most preferred
+                    for address in addresses {
+                        let ip_addr = address.ip_addr();
+                        let attrs_matched = external.attrs.is_empty()
+                            || if_addresses.attrs.get(&ip_addr).is_some_and(|attrs| {
+                                external.attrs.matches(
+                                    &ip_addr,
+                                    attrs.label.as_deref(),
+                                    attrs.scope,
+                                    attrs.flags,
+                                )
+                            });
+                        if addresses_set.contains(address)
+                            && match_address.matches(&ip_addr)
+                            && !external
+                                .exclude_address
+                                .iter()
+                                .any(|exclude| exclude.contains(&ip_addr))
+                            && attrs_matched
+                            && !address.is_unspecified()
+                            && !matches.contains(address)
+                        {
                             matches.push(*address);
                         }
                     }
@@ -358,11 +1391,31 @@ trait RuntimeConfig {
                 addresses_set.remove(address);
             }
 
-            if external_addr.is_none() && !external.no_snat {
+            // addresses of static 1:1 NAT are not shared by other internal
+            // addresses
+            let no_snat = external.no_snat || external.internal_address.is_some();
+            if external_addr.is_none() && !no_snat {
                 if let Some(first) = matches.first() {
                     external_addr = Some(*first);
                 }
             }
+            if !no_snat {
+                external_pool.extend(
+                    matches
+                        .iter()
+                        .map(|&address| (address, external.weight, external.protocols)),
+                );
+
+                if let Some(first) = matches.first() {
+                    for source in external.sources.iter() {
+                        if let Some(source) = Self::Prefix::from_ip_net(*source) {
+                            self.source_policy_mut()
+                                .entry(source)
+                                .or_insert_with(|| first.ip_addr().into());
+                        }
+                    }
+                }
+            }
 
             for network in matches {
                 let dest_value = self.dest_config_mut().entry(network).or_default();
@@ -371,14 +1424,28 @@ trait RuntimeConfig {
                     .set(DestFlags::HAIRPIN, !external.no_hairpin);
 
                 let ext_value = self.external_config_mut().entry(network).or_default();
+                ext_value.flags.set(ExternalFlags::NO_SNAT, no_snat);
+                ext_value.flags.set(
+                    ExternalFlags::ONE_TO_ONE,
+                    external.internal_address.is_some(),
+                );
                 ext_value
                     .flags
-                    .set(ExternalFlags::NO_SNAT, external.no_snat);
+                    .set(ExternalFlags::LOCAL, addresses.contains(&network));
+                ext_value.flags.set(
+                    ExternalFlags::FILTER_ADF,
+                    external.filtering == Filtering::AddressDependent,
+                );
+                ext_value.flags.set(
+                    ExternalFlags::FILTER_APDF,
+                    external.filtering == Filtering::AddressAndPortDependent,
+                );
 
-                if external.no_snat {
+                if no_snat {
                     continue;
                 }
 
+                ext_value.protocols = external.protocols;
                 external
                     .tcp_ranges
                     .apply_raw(&mut ext_value.tcp_range, &mut ext_value.tcp_range_len);
@@ -396,10 +1463,48 @@ trait RuntimeConfig {
                     &mut ext_value.icmp_out_range,
                     &mut ext_value.icmp_out_range_len,
                 );
+                external.excluded_ports.apply_raw(
+                    &mut ext_value.excluded_range,
+                    &mut ext_value.excluded_range_len,
+                );
+            }
+        }
+
+        // more specific dest entries inherit blocking, filtering and timeout
+        // overrides of the enclosing network as BPF programs only see the
+        // longest match
+        let blocked_set: PrefixSet<Self::Prefix> = flagged_dests
+            .iter()
+            .filter(|(_, flags)| flags.contains(DestFlags::DROP))
+            .map(|(network, _)| *network)
+            .collect();
+        let filtering_map: PrefixMap<Self::Prefix, Filtering> =
+            filtering_dests.iter().copied().collect();
+        let timeout_map: PrefixMap<Self::Prefix, DestTimeouts> =
+            timeout_dests.iter().copied().collect();
+        for (network, dest_value) in self.dest_config_mut().iter_mut() {
+            if blocked_set.get_spm(network).is_some() {
+                dest_value.flags.insert(DestFlags::DROP);
+            }
+            if let Some((_, filtering)) = filtering_map.get_lpm(network) {
+                dest_value.flags.insert(DestFlags::FILTER_OVERRIDE);
+                dest_value.flags.set(
+                    DestFlags::FILTER_ADF,
+                    *filtering == Filtering::AddressDependent,
+                );
+                dest_value.flags.set(
+                    DestFlags::FILTER_APDF,
+                    *filtering == Filtering::AddressAndPortDependent,
+                );
+            }
+            if let Some((_, timeouts)) = timeout_map.get_lpm(network) {
+                dest_value.timeout_udp = timeouts.udp;
+                dest_value.timeout_tcp_est = timeouts.tcp_est;
             }
         }
 
         *self.external_addr_mut() = external_addr.unwrap_or(Self::Prefix::unspecified());
+        *self.external_pool_mut() = external_pool;
     }
 
     fn hairpin_dests(&self) -> Vec<Self::Prefix> {
@@ -491,7 +1596,28 @@ trait RuntimeConfig {
             Ok(())
         };
 
+        let handle_source_policy_change = |skel: &mut EinatSkel, change| -> Result<()> {
+            let maps = skel.maps();
+            let map_source_policy = Self::skel_map_source_policy(&maps);
+            match change {
+                MapChange::Insert(k, v) | MapChange::Update(k, v) => {
+                    debug!("update source policy of {:?}", k);
+                    Self::with_lpm_key_bytes(*k, |k| {
+                        map_source_policy.update(k, bytemuck::bytes_of(v), MapFlags::ANY)
+                    })?;
+                }
+                MapChange::Delete(k) => {
+                    debug!("delete source policy of {:?}", k);
+                    Self::with_lpm_key_bytes(*k, |k| map_source_policy.delete(k))?;
+                }
+            }
+            Ok(())
+        };
+
         if let Some(old) = old {
+            for change in PrefixMapDiff::new(old.source_policy(), self.source_policy()) {
+                handle_source_policy_change(skel, change)?;
+            }
             let dest_config_diff = PrefixMapDiff::new(old.dest_config(), self.dest_config());
             let external_config_diff =
                 PrefixMapDiff::new(old.external_config(), self.external_config());
@@ -501,25 +1627,22 @@ trait RuntimeConfig {
             for change in external_config_diff {
                 handle_external_change(skel, change)?;
             }
-            if old.external_addr() != self.external_addr() {
+            if old.external_addr() != self.external_addr()
+                || old.external_pool() != self.external_pool()
+            {
                 self.apply_external_addr(skel);
             }
         } else {
-            for change in self
-                .dest_config()
-                .iter()
-                .map(|(k, v)| MapChange::Insert(k, v))
-            {
-                handle_dest_change(skel, change)?;
-            }
+            let maps = skel.maps();
 
-            for change in self
-                .external_config()
-                .iter()
-                .map(|(k, v)| MapChange::Insert(k, v))
-            {
-                handle_external_change(skel, change)?;
-            }
+            let (keys, values) = Self::lpm_entries_bytes(self.dest_config());
+            update_batch_or_each(Self::skel_map_dest_config(&maps), &keys, &values)?;
+
+            let (keys, values) = Self::lpm_entries_bytes(self.external_config());
+            update_batch_or_each(Self::skel_map_external_config(&maps), &keys, &values)?;
+
+            let (keys, values) = Self::lpm_entries_bytes(self.source_policy());
+            update_batch_or_each(Self::skel_map_source_policy(&maps), &keys, &values)?;
 
             self.apply_external_addr(skel);
         }
@@ -537,6 +1660,12 @@ impl RuntimeConfig for RuntimeV4Config {
     fn external_addr_mut(&mut self) -> &mut Self::Prefix {
         &mut self.external_addr
     }
+    fn external_pool(&self) -> &[(Self::Prefix, u32, ExternalProtocols)] {
+        &self.external_pool
+    }
+    fn external_pool_mut(&mut self) -> &mut Vec<(Self::Prefix, u32, ExternalProtocols)> {
+        &mut self.external_pool
+    }
 
     fn dest_config(&self) -> &PrefixMap<Self::Prefix, BpfDestConfig> {
         &self.dest_config
@@ -545,6 +1674,13 @@ impl RuntimeConfig for RuntimeV4Config {
         &mut self.dest_config
     }
 
+    fn source_policy(&self) -> &PrefixMap<Self::Prefix, skel::InetAddr> {
+        &self.source_policy
+    }
+    fn source_policy_mut(&mut self) -> &mut PrefixMap<Self::Prefix, skel::InetAddr> {
+        &mut self.source_policy
+    }
+
     fn external_config(&self) -> &PrefixMap<Self::Prefix, BpfExternalConfig> {
         &self.external_config
     }
@@ -564,13 +1700,29 @@ impl RuntimeConfig for RuntimeV4Config {
         } else {
             info!("setting default external IPv4 address {}", addr);
         }
-        skel.data_mut().g_ipv4_external_addr = bytemuck::cast(addr.octets());
+        let data = skel.data_mut();
+        data.g_ipv4_external_addr = bytemuck::cast(addr.octets());
+
+        let pool = external_pool_slots(&self.external_pool);
+        data.g_ipv4_external_pool_len = 0;
+        for (i, (addr, _, _)) in pool.iter().enumerate() {
+            data.g_ipv4_external_pool[i] = bytemuck::cast(addr.addr().octets());
+        }
+        let tables = ExternalPoolTables::new(pool);
+        data.g_ipv4_external_pool_weight = tables.weight;
+        data.g_ipv4_external_pool_first = tables.first;
+        data.g_ipv4_external_pool_next = tables.next;
+        data.g_ipv4_external_pool_len = pool.len() as _;
     }
 
     fn skel_map_dest_config<'a>(maps: &'a EinatMaps<'_>) -> &'a libbpf_rs::Map {
         maps.map_ipv4_dest_config()
     }
 
+    fn skel_map_source_policy<'a>(maps: &'a EinatMaps<'_>) -> &'a libbpf_rs::Map {
+        maps.map_ipv4_source_policy()
+    }
+
     fn skel_map_external_config<'a>(maps: &'a EinatMaps<'_>) -> &'a libbpf_rs::Map {
         maps.map_ipv4_external_config()
     }
@@ -586,6 +1738,12 @@ impl RuntimeConfig for RuntimeV6Config {
     fn external_addr_mut(&mut self) -> &mut Self::Prefix {
         &mut self.external_addr
     }
+    fn external_pool(&self) -> &[(Self::Prefix, u32, ExternalProtocols)] {
+        &self.external_pool
+    }
+    fn external_pool_mut(&mut self) -> &mut Vec<(Self::Prefix, u32, ExternalProtocols)> {
+        &mut self.external_pool
+    }
 
     fn dest_config(&self) -> &PrefixMap<Self::Prefix, BpfDestConfig> {
         &self.dest_config
@@ -594,6 +1752,13 @@ impl RuntimeConfig for RuntimeV6Config {
         &mut self.dest_config
     }
 
+    fn source_policy(&self) -> &PrefixMap<Self::Prefix, skel::InetAddr> {
+        &self.source_policy
+    }
+    fn source_policy_mut(&mut self) -> &mut PrefixMap<Self::Prefix, skel::InetAddr> {
+        &mut self.source_policy
+    }
+
     fn external_config(&self) -> &PrefixMap<Self::Prefix, BpfExternalConfig> {
         &self.external_config
     }
@@ -613,47 +1778,281 @@ impl RuntimeConfig for RuntimeV6Config {
         } else {
             info!("setting default external IPv6 address {}", addr);
         }
-        skel.data_mut().g_ipv6_external_addr = bytemuck::cast(addr.octets());
+        let data = skel.data_mut();
+        data.g_ipv6_external_addr = bytemuck::cast(addr.octets());
+
+        let pool = external_pool_slots(&self.external_pool);
+        data.g_ipv6_external_pool_len = 0;
+        for (i, (addr, _, _)) in pool.iter().enumerate() {
+            data.g_ipv6_external_pool[i] = bytemuck::cast(addr.addr().octets());
+        }
+        let tables = ExternalPoolTables::new(pool);
+        data.g_ipv6_external_pool_weight = tables.weight;
+        data.g_ipv6_external_pool_first = tables.first;
+        data.g_ipv6_external_pool_next = tables.next;
+        data.g_ipv6_external_pool_len = pool.len() as _;
     }
 
     fn skel_map_dest_config<'a>(maps: &'a EinatMaps<'_>) -> &'a libbpf_rs::Map {
         maps.map_ipv6_dest_config()
     }
 
+    fn skel_map_source_policy<'a>(maps: &'a EinatMaps<'_>) -> &'a libbpf_rs::Map {
+        maps.map_ipv6_source_policy()
+    }
+
     fn skel_map_external_config<'a>(maps: &'a EinatMaps<'_>) -> &'a libbpf_rs::Map {
         maps.map_ipv6_external_config()
     }
 }
 
+/// Resolved NPTv6 prefixes.
+#[cfg(feature = "ipv6")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Nptv6 {
+    internal_prefix: Ipv6Net,
+    external_prefix: Ipv6Net,
+    /// Addresses of interface within external prefix, which are not translated
+    local_addrs: Vec<Ipv6Addr>,
+}
+
+#[cfg(feature = "ipv6")]
+impl Nptv6 {
+    /// Resolves prefixes with addresses of external interface, and those of
+    /// interface to discover external prefix from.
+    fn resolve(
+        config: &ConfigNptv6,
+        addresses: &[Ipv6Addr],
+        prefix_addresses: &[Ipv6Addr],
+    ) -> Option<Self> {
+        let internal_prefix = config.internal_prefix.trunc();
+        let external_prefix = if let Some(prefix) = config.external_prefix {
+            prefix.trunc()
+        } else {
+            let addr = prefix_addresses
+                .iter()
+                .find(|addr| is_global_unicast(addr))?;
+            Ipv6Net::new(*addr, internal_prefix.prefix_len())
+                .ok()?
+                .trunc()
+        };
+        let local_addrs = addresses
+            .iter()
+            .filter(|addr| external_prefix.contains(*addr))
+            .copied()
+            .collect();
+        Some(Self {
+            internal_prefix,
+            external_prefix,
+            local_addrs,
+        })
+    }
+
+    /// One's complement sum of internal prefix minus that of external prefix,
+    /// see section 3.1 of RFC 6296.
+    fn adjustment(&self) -> u16 {
+        fn prefix_sum(prefix: &Ipv6Net) -> u16 {
+            prefix
+                .addr()
+                .segments()
+                .iter()
+                .fold(0, |sum, &word| ones_complement_add(sum, word))
+        }
+        ones_complement_add(
+            prefix_sum(&self.internal_prefix),
+            !prefix_sum(&self.external_prefix),
+        )
+    }
+
+    fn apply(this: Option<&Self>, skel: &mut EinatSkel) {
+        let data = skel.data_mut();
+        let Some(this) = this else {
+            data.g_npt_prefix_len = 0;
+            return;
+        };
+        info!(
+            "translating IPv6 prefix {} to {} with NPTv6",
+            this.internal_prefix, this.external_prefix
+        );
+        // disable translation while updating
+        data.g_npt_prefix_len = 0;
+        let internal: [u32; 4] = bytemuck::cast(this.internal_prefix.addr().octets());
+        let external: [u32; 4] = bytemuck::cast(this.external_prefix.addr().octets());
+        data.g_npt_internal_prefix.copy_from_slice(&internal[..2]);
+        data.g_npt_external_prefix.copy_from_slice(&external[..2]);
+        data.g_npt_adjustment = this.adjustment();
+
+        let local_addrs = if this.local_addrs.len() > skel::MAX_NPT_LOCAL_ADDRS {
+            warn!(
+                "only first {} of local addresses within NPTv6 external prefix are excluded from translation, ignoring {:?}",
+                skel::MAX_NPT_LOCAL_ADDRS,
+                &this.local_addrs[skel::MAX_NPT_LOCAL_ADDRS..]
+            );
+            &this.local_addrs[..skel::MAX_NPT_LOCAL_ADDRS]
+        } else {
+            &this.local_addrs
+        };
+        for (i, addr) in local_addrs.iter().enumerate() {
+            data.g_npt_local_addrs[i] = bytemuck::cast(addr.octets());
+        }
+        data.g_npt_local_addrs_len = local_addrs.len() as _;
+        data.g_npt_prefix_len = this.internal_prefix.prefix_len();
+    }
+}
+
+#[cfg(feature = "ipv6")]
+fn ones_complement_add(a: u16, b: u16) -> u16 {
+    let sum = a as u32 + b as u32;
+    ((sum & 0xffff) + (sum >> 16)) as u16
+}
+
+/// Returns external addresses fitting in the pool of BPF programs.
+fn external_pool_slots<P: Debug>(pool: &[P]) -> &[P] {
+    if pool.len() > skel::MAX_EXTERNAL_POOL {
+        warn!(
+            "only first {} of external addresses are used, ignoring {:?}",
+            skel::MAX_EXTERNAL_POOL,
+            &pool[skel::MAX_EXTERNAL_POOL..]
+        );
+        &pool[..skel::MAX_EXTERNAL_POOL]
+    } else {
+        pool
+    }
+}
+
+/// Lookup tables of external address pool for each of TCP, UDP and ICMP, so
+/// BPF programs select only addresses serving the protocol
+#[derive(Debug, PartialEq, Eq)]
+struct ExternalPoolTables {
+    /// Cumulative weights, addresses not serving the protocol add no weight
+    weight: [[u32; skel::MAX_EXTERNAL_POOL]; skel::EXTERNAL_POOL_PROTOS],
+    /// Index of the first address serving the protocol
+    first: [u8; skel::EXTERNAL_POOL_PROTOS],
+    /// Index of the address serving the protocol next to each address,
+    /// wrapped around, or the address itself if there is no other
+    next: [[u8; skel::MAX_EXTERNAL_POOL]; skel::EXTERNAL_POOL_PROTOS],
+}
+
+impl ExternalPoolTables {
+    fn new<P>(pool: &[(P, u32, ExternalProtocols)]) -> Self {
+        let mut tables = Self {
+            weight: Default::default(),
+            first: Default::default(),
+            next: Default::default(),
+        };
+        for (p, proto) in [IpProtocol::Tcp, IpProtocol::Udp, IpProtocol::Icmp]
+            .into_iter()
+            .enumerate()
+        {
+            let serving: Vec<usize> = pool
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, _, protocols))| external_serves(*protocols, proto))
+                .map(|(i, _)| i)
+                .collect();
+            let mut total_weight = 0u32;
+            for (i, (_, weight, _)) in pool.iter().enumerate() {
+                if serving.contains(&i) {
+                    total_weight = total_weight.saturating_add(*weight);
+                }
+                tables.weight[p][i] = total_weight;
+                tables.next[p][i] = serving
+                    .iter()
+                    .find(|&&j| j > i)
+                    .or_else(|| serving.iter().find(|&&j| j != i))
+                    .map_or(i, |&j| j) as u8;
+            }
+            tables.first[p] = serving.first().map_or(0, |&i| i as u8);
+        }
+        tables
+    }
+}
+
 impl RuntimeV4Config {
-    fn from(no_snat_dests: &[Ipv4Net], externals: &[External], addresses: &[Ipv4Addr]) -> Self {
+    fn from(
+        flagged_dests: &[(Ipv4Net, DestFlags)],
+        filtering_dests: &[(Ipv4Net, Filtering)],
+        timeout_dests: &[(Ipv4Net, DestTimeouts)],
+        externals: &[External],
+        if_addresses: &IfAddresses,
+    ) -> Self {
         let mut this = Self {
             external_addr: Ipv4Net::from_addr(Ipv4Addr::UNSPECIFIED),
+            external_pool: Vec::new(),
             dest_config: Default::default(),
+            source_policy: Default::default(),
             external_config: Default::default(),
         };
-        let addresses: Vec<_> = addresses
+        let addresses: Vec<_> = if_addresses
+            .ipv4
             .iter()
             .map(|&addr| Ipv4Net::from_addr(addr))
             .collect();
-        Self::init(&mut this, no_snat_dests, externals, &addresses);
+        let flagged_dests: Vec<_> = flagged_dests
+            .iter()
+            .copied()
+            .chain(if_addresses.nft_set_dests.iter().filter_map(|network| {
+                if let IpNet::V4(network) = network {
+                    Some((*network, DestFlags::NO_SNAT))
+                } else {
+                    None
+                }
+            }))
+            .collect();
+        Self::init(
+            &mut this,
+            &flagged_dests,
+            filtering_dests,
+            timeout_dests,
+            externals,
+            &addresses,
+            if_addresses,
+        );
         this
     }
 }
 
 #[cfg(feature = "ipv6")]
 impl RuntimeV6Config {
-    fn from(no_snat_dests: &[Ipv6Net], externals: &[External], addresses: &[Ipv6Addr]) -> Self {
+    fn from(
+        flagged_dests: &[(Ipv6Net, DestFlags)],
+        filtering_dests: &[(Ipv6Net, Filtering)],
+        timeout_dests: &[(Ipv6Net, DestTimeouts)],
+        externals: &[External],
+        if_addresses: &IfAddresses,
+    ) -> Self {
         let mut this = Self {
             external_addr: Ipv6Net::from_addr(Ipv6Addr::UNSPECIFIED),
+            external_pool: Vec::new(),
             dest_config: Default::default(),
+            source_policy: Default::default(),
             external_config: Default::default(),
         };
-        let addresses: Vec<_> = addresses
+        let addresses: Vec<_> = if_addresses
+            .ipv6
             .iter()
             .map(|&addr| Ipv6Net::from_addr(addr))
             .collect();
-        Self::init(&mut this, no_snat_dests, externals, &addresses);
+        let flagged_dests: Vec<_> = flagged_dests
+            .iter()
+            .copied()
+            .chain(if_addresses.nft_set_dests.iter().filter_map(|network| {
+                if let IpNet::V6(network) = network {
+                    Some((*network, DestFlags::NO_SNAT))
+                } else {
+                    None
+                }
+            }))
+            .collect();
+        Self::init(
+            &mut this,
+            &flagged_dests,
+            filtering_dests,
+            timeout_dests,
+            externals,
+            &addresses,
+            if_addresses,
+        );
         this
     }
 }
@@ -661,13 +2060,14 @@ impl RuntimeV6Config {
 impl InstanceConfig {
     pub fn try_from(
         if_index: u32,
+        netns: Option<Arc<NetNs>>,
         if_encap: PacketEncap,
         if_config: &ConfigNetIf,
         defaults: &ConfigDefaults,
         addresses: &IfAddresses,
     ) -> Result<Self> {
         let has_eth_encap = match if_encap {
-            PacketEncap::Ethernet => true,
+            PacketEncap::Ethernet | PacketEncap::Pppoe => true,
             PacketEncap::BareIp => false,
             PacketEncap::Unsupported => {
                 return Err(anyhow::anyhow!(
@@ -687,10 +2087,17 @@ impl InstanceConfig {
         let nat66 = cfg!(feature = "ipv6") && if_config.nat66;
         let nat64 = false;
 
-        let const_config = ConstConfig {
-            // defaults to disable logging
-            log_level: Some(if_config.bpf_log_level.unwrap_or(0).min(5)),
+        // defaults to disable logging
+        let log_level = if_config.bpf_log_level.unwrap_or(0).min(5);
+        let mut const_config = ConstConfig {
+            log_level: Some(log_level),
+            max_log_level: Some(
+                if_config
+                    .bpf_log_max_level
+                    .map_or(log_level, |level| level.clamp(log_level, 5)),
+            ),
             has_eth_encap: Some(has_eth_encap),
+            has_pppoe_encap: Some(if_encap == PacketEncap::Pppoe),
             ingress_ipv4: Some(nat44 || nat64),
             egress_ipv4: Some(nat44),
             #[cfg(feature = "ipv6")]
@@ -699,13 +2106,180 @@ impl InstanceConfig {
             egress_ipv6: Some(nat66 || nat64),
             enable_fib_lookup_src: if_config.bpf_fib_lookup_external,
             allow_inbound_icmpx: if_config.allow_inbound_icmpx,
+            port_parity: if_config.port_parity,
+            port_preservation: if_config.port_preservation,
+            port_allocation: if_config.port_allocation.map(|alloc| match alloc {
+                PortAllocation::Random => skel::PORT_ALLOC_RANDOM,
+                PortAllocation::Sequential => skel::PORT_ALLOC_SEQUENTIAL,
+                PortAllocation::Hashed => skel::PORT_ALLOC_HASHED,
+            }),
+            paired_pooling: if_config
+                .address_pooling
+                .map(|pooling| pooling == AddressPooling::Paired),
+            external_selection: if_config
+                .external_selection
+                .map(|selection| match selection {
+                    ExternalSelection::First => skel::EXTERNAL_SELECT_FIRST,
+                    ExternalSelection::Hash => skel::EXTERNAL_SELECT_HASH,
+                    ExternalSelection::RoundRobin => skel::EXTERNAL_SELECT_ROUND_ROBIN,
+                }),
+            external_spillover: if_config.external_spillover,
+            filter_addr_tracking: None,
+            addr_bindings: None,
+            dmz_host: if_config.dmz_host,
+            pptp: Some(nat44 && if_config.pptp),
+            esp_passthrough: Some(nat44 && if_config.esp_passthrough),
+            drop_malformed_icmp_err: Some(if_config.drop_malformed_icmp_errors),
+            // ports can also be reserved over control socket
+            local_ports: Some(if_config.exclude_local_ports || defaults.control_socket.is_some()),
+            inbound_refresh: if_config.inbound_refresh,
+            dest_timeouts: Some(!if_config.timeout_dests.is_empty()),
             timeout_fragment: if_config.timeout_fragment.map(Into::into),
             timeout_pkt_min: if_config.timeout_pkt_min.map(Into::into),
             timeout_pkt_default: if_config.timeout_pkt_default.map(Into::into),
+            timeout_udp_single: if_config
+                .timeout_udp_single
+                .or(if_config.timeout_pkt_default)
+                .map(Into::into),
+            timeout_udp_stream: if_config
+                .timeout_udp_stream
+                .or(if_config.timeout_pkt_default)
+                .map(Into::into),
+            udp_stream_packets: if_config.udp_stream_packets,
+            timeout_icmp: if_config.timeout_icmp.map(Into::into),
             timeout_tcp_est: if_config.timeout_tcp_est.map(Into::into),
             timeout_tcp_trans: if_config.timeout_tcp_trans.map(Into::into),
+            timeout_tcp_time_wait: if_config.timeout_tcp_time_wait.map(Into::into),
+            tcp_fin_teardown: if_config.tcp_fin_teardown.map(tcp_teardown),
+            tcp_rst_teardown: if_config.tcp_rst_teardown.map(tcp_teardown),
+            unmatched_tcp_rst: if_config.unmatched_tcp_rst.map(|rst| match rst {
+                UnmatchedTcpRst::Drop => skel::UNMATCHED_RST_DROP,
+                UnmatchedTcpRst::Pass => skel::UNMATCHED_RST_PASS,
+                UnmatchedTcpRst::Reply => skel::UNMATCHED_RST_REPLY,
+            }),
+            max_binding_lifetime: if_config.max_binding_lifetime.map(Into::into),
+            det_nat_network: if_config
+                .deterministic_nat
+                .as_ref()
+                .map(|det_nat| det_nat.internal_network.trunc()),
+            det_nat_block_size: if_config
+                .deterministic_nat
+                .as_ref()
+                .map(|det_nat| det_nat.block_size.get()),
+            max_sessions_per_host: if_config.max_sessions_per_host,
+            max_embryonic_per_host: if_config.max_embryonic_per_host,
+            embryonic_tarpit: Some(if_config.embryonic_tarpit),
+            drop_unsolicited: Some(if_config.drop_unsolicited),
+            port_quota_tcp: if_config.port_quota.as_ref().and_then(|q| q.tcp),
+            port_quota_udp: if_config.port_quota.as_ref().and_then(|q| q.udp),
+            port_quota_icmp: if_config.port_quota.as_ref().and_then(|q| q.icmp),
+            binding_rate_interval: if_config
+                .binding_rate_limit
+                .as_ref()
+                .map(ConfigRateLimit::interval),
+            binding_rate_burst: if_config
+                .binding_rate_limit
+                .as_ref()
+                .map(ConfigRateLimit::max_burst),
+            reject_rate_interval: if_config
+                .icmp_error_rate_limit
+                .as_ref()
+                .map(ConfigRateLimit::interval),
+            reject_rate_burst: if_config
+                .icmp_error_rate_limit
+                .as_ref()
+                .map(ConfigRateLimit::max_burst),
+            udp_binding_rate_interval: if_config
+                .udp_binding_rate_limit
+                .as_ref()
+                .map(ConfigRateLimit::interval),
+            udp_binding_rate_burst: if_config
+                .udp_binding_rate_limit
+                .as_ref()
+                .map(ConfigRateLimit::max_burst),
+            udp_host_binding_rate_interval: if_config
+                .udp_host_binding_rate_limit
+                .as_ref()
+                .map(ConfigRateLimit::interval),
+            udp_host_binding_rate_burst: if_config
+                .udp_host_binding_rate_limit
+                .as_ref()
+                .map(ConfigRateLimit::max_burst),
+            udp_high_water: if_config.udp_high_water,
+            udp_emergency_timeout: if_config.udp_emergency_timeout.map(Into::into),
+            capture: if_config.capture.as_ref().map(|_| true),
+            capture_network: if_config
+                .capture
+                .as_ref()
+                .and_then(|capture| capture.internal)
+                .map(|network| network.trunc()),
+            capture_l4proto: if_config
+                .capture
+                .as_ref()
+                .and_then(|capture| capture.protocol)
+                .map(l4proto),
+            trace: if_config.trace.clone(),
+            hairpin_redirect_ipv4: Some(
+                nat44 && if_config.ipv4_hairpin_route.mode == HairpinMode::Redirect,
+            ),
+            hairpin_redirect_ipv6: Some(
+                nat66 && if_config.ipv6_hairpin_route.mode == HairpinMode::Redirect,
+            ),
+            hairpin_redirect_target: Some((if_index, [0; 6])),
+            hairpin_fwmark_ipv4: Some(
+                nat44 && if_config.ipv4_hairpin_route.mode == HairpinMode::Fwmark,
+            ),
+            hairpin_fwmark_ipv6: Some(
+                nat66 && if_config.ipv6_hairpin_route.mode == HairpinMode::Fwmark,
+            ),
+            hairpin_fwmark: Some(defaults.hairpin_fwmark.get()),
+            reject_fwmark: Some(if if_config.icmp_on_alloc_failure {
+                defaults.reject_fwmark.get()
+            } else {
+                0
+            }),
+            bypass_fwmark: Some(
+                if_config
+                    .bypass_fwmark
+                    .as_ref()
+                    .map_or(0, |fwmark| fwmark.mark.get()),
+            ),
+            bypass_fwmark_mask: if_config.bypass_fwmark.as_ref().map(ConfigFwmark::mask),
+            netmap: Some(!if_config.netmap.is_empty()),
+            no_snat_sources: Some(!if_config.no_snat_sources.is_empty()),
+            no_snat_dports: Some(!if_config.no_snat_dports.is_empty()),
+            egress_dscp: if_config.egress_dscp,
+            ingress_dscp: if_config.ingress_dscp,
+            csum_offload_compat: Some(if_config.checksum_offload_compat),
+            #[cfg(feature = "ipv6")]
+            nptv6: Some(if_config.nptv6.is_some()),
+            #[cfg(feature = "ipv6")]
+            clat: None,
         };
 
+        #[cfg(feature = "ipv6")]
+        if let Some(nptv6) = &if_config.nptv6 {
+            if nptv6.internal_prefix.prefix_len() > 64 {
+                return Err(anyhow!(
+                    "NPTv6 internal prefix {} is longer than /64",
+                    nptv6.internal_prefix
+                ));
+            }
+            if let Some(external_prefix) = nptv6.external_prefix {
+                if external_prefix.prefix_len() != nptv6.internal_prefix.prefix_len() {
+                    return Err(anyhow!(
+                        "NPTv6 external prefix {} and internal prefix {} are of different length",
+                        external_prefix,
+                        nptv6.internal_prefix
+                    ));
+                }
+            }
+        }
+        #[cfg(not(feature = "ipv6"))]
+        if if_config.nptv6.is_some() {
+            warn!("NPTv6 is ignored as IPv6 support is not enabled");
+        }
+
         let mut default_externals = Vec::new();
         if if_config.default_externals {
             if nat44 {
@@ -719,9 +2293,52 @@ impl InstanceConfig {
             .externals
             .iter()
             .chain(&default_externals)
-            .map(|external| External::try_from(external, defaults))
+            .map(|external| External::try_from(external, defaults, if_config.filtering))
             .collect::<Result<Vec<_>>>()?;
 
+        if let Some(det_nat) = &if_config.deterministic_nat {
+            check_deterministic_nat(det_nat, &externals)?;
+        }
+        check_netmap(&if_config.netmap)?;
+        if let Some(fwmark) = &if_config.bypass_fwmark {
+            check_fwmark(fwmark)?;
+        }
+        check_no_snat_dports(&if_config.no_snat_dports)?;
+        for (name, dscp) in [
+            ("egress_dscp", if_config.egress_dscp),
+            ("ingress_dscp", if_config.ingress_dscp),
+        ] {
+            if dscp.is_some_and(|dscp| dscp > MAX_DSCP) {
+                return Err(anyhow!("{} is larger than {}", name, MAX_DSCP));
+            }
+        }
+        check_static_bindings(&if_config.static_bindings, &externals)?;
+
+        const_config.addr_bindings = Some(
+            externals
+                .iter()
+                .any(|external| external.internal_address.is_some()),
+        );
+        const_config.filter_addr_tracking = Some(
+            externals
+                .iter()
+                .map(|external| external.filtering)
+                .chain(if_config.filtering_dests.iter().map(|dest| dest.filtering))
+                .any(|filtering| filtering == Filtering::AddressDependent),
+        );
+
+        fn flagged_dests<P>(
+            if_config: &ConfigNetIf,
+            unwrap: fn(&IpNet) -> Option<P>,
+        ) -> Vec<(P, DestFlags)> {
+            let no_snat = if_config.no_snat_dests.iter().filter_map(unwrap);
+            let blocked = if_config.blocked_dests.iter().filter_map(unwrap);
+            no_snat
+                .map(|network| (network, DestFlags::NO_SNAT))
+                .chain(blocked.map(|network| (network, DestFlags::DROP)))
+                .collect()
+        }
+
         fn unwrap_v4(network: &IpNet) -> Option<Ipv4Net> {
             if let IpNet::V4(network) = network {
                 Some(*network)
@@ -730,14 +2347,27 @@ impl InstanceConfig {
             }
         }
 
-        let v4_no_snat_dests = if_config
-            .no_snat_dests
+        let v4_flagged_dests = flagged_dests(if_config, unwrap_v4);
+
+        let v4_filtering_dests = if_config
+            .filtering_dests
+            .iter()
+            .filter_map(|dest| Some((unwrap_v4(&dest.network)?, dest.filtering)))
+            .collect::<Vec<_>>();
+
+        let v4_timeout_dests = if_config
+            .timeout_dests
             .iter()
-            .filter_map(unwrap_v4)
+            .filter_map(|dest| Some((unwrap_v4(&dest.network)?, DestTimeouts::from(dest))))
             .collect::<Vec<_>>();
 
-        let runtime_v4_config =
-            RuntimeV4Config::from(&v4_no_snat_dests, &externals, &addresses.ipv4);
+        let runtime_v4_config = RuntimeV4Config::from(
+            &v4_flagged_dests,
+            &v4_filtering_dests,
+            &v4_timeout_dests,
+            &externals,
+            addresses,
+        );
 
         #[cfg(feature = "ipv6")]
         fn unwrap_v6(network: &IpNet) -> Option<Ipv6Net> {
@@ -749,86 +2379,1000 @@ impl InstanceConfig {
         }
 
         #[cfg(feature = "ipv6")]
-        let v6_no_snat_dests = if_config
-            .no_snat_dests
+        let v6_flagged_dests = flagged_dests(if_config, unwrap_v6);
+        #[cfg(feature = "ipv6")]
+        let v6_filtering_dests = if_config
+            .filtering_dests
             .iter()
-            .filter_map(unwrap_v6)
+            .filter_map(|dest| Some((unwrap_v6(&dest.network)?, dest.filtering)))
             .collect::<Vec<_>>();
         #[cfg(feature = "ipv6")]
-        let runtime_v6_config =
-            RuntimeV6Config::from(&v6_no_snat_dests, &externals, &addresses.ipv6);
+        let v6_timeout_dests = if_config
+            .timeout_dests
+            .iter()
+            .filter_map(|dest| Some((unwrap_v6(&dest.network)?, DestTimeouts::from(dest))))
+            .collect::<Vec<_>>();
+        #[cfg(feature = "ipv6")]
+        let runtime_v6_config = RuntimeV6Config::from(
+            &v6_flagged_dests,
+            &v6_filtering_dests,
+            &v6_timeout_dests,
+            &externals,
+            addresses,
+        );
+        match with_netns(netns.as_deref(), local_port_range) {
+            Ok(ephemeral) => {
+                check_ephemeral_ports(&ephemeral, &runtime_v4_config.external_config);
+                #[cfg(feature = "ipv6")]
+                check_ephemeral_ports(&ephemeral, &runtime_v6_config.external_config);
+            }
+            Err(e) => debug!("failed to get ephemeral port range: {}", e),
+        }
+
+        // resolved later with addresses of prefix interface if specified
+        #[cfg(feature = "ipv6")]
+        let nptv6 = if_config
+            .nptv6
+            .as_ref()
+            .filter(|config| config.prefix_if_name.is_none())
+            .and_then(|config| Nptv6::resolve(config, &addresses.ipv6, &addresses.ipv6));
+        #[cfg(feature = "ipv6")]
+        if if_config
+            .nptv6
+            .as_ref()
+            .is_some_and(|config| config.prefix_if_name.is_none())
+            && nptv6.is_none()
+        {
+            warn!("no global IPv6 address to discover NPTv6 external prefix from");
+        }
+
+        let gc_interval = if_config
+            .gc_interval
+            .map(|interval| Duration::from_nanos(interval.into()))
+            .filter(|interval| !interval.is_zero());
+        // CTs evicted from LRU map are not deleted by BPF programs, so their
+        // bindings and counters are only released by garbage collection
+        if if_config.ct_lru == Some(true) && gc_interval.is_none() {
+            return Err(anyhow!("`ct_lru` requires `gc_interval` to be set"));
+        }
 
         Ok(Self {
             if_index,
-            v4_no_snat_dests,
+            netns,
+            pin_path: if_config.pin_path.clone(),
+            binding_snapshot: if_config.binding_snapshot.clone(),
+            capture_path: if_config
+                .capture
+                .as_ref()
+                .map(|capture| capture.path.clone()),
+            btf_path: defaults
+                .btf_path
+                .as_deref()
+                .map(probe::resolve_btf_path)
+                .transpose()?,
+            ct_lru: if_config.ct_lru.unwrap_or(false),
+            map_size: if_config.map_size,
+            expected_hosts: if_config.expected_hosts,
+            gc_interval,
+            v4_flagged_dests,
+            #[cfg(feature = "ipv6")]
+            v6_flagged_dests,
+            v4_filtering_dests,
+            #[cfg(feature = "ipv6")]
+            v6_filtering_dests,
+            v4_timeout_dests,
             #[cfg(feature = "ipv6")]
-            v6_no_snat_dests,
+            v6_timeout_dests,
             externals,
             const_config,
             runtime_v4_config,
             #[cfg(feature = "ipv6")]
             runtime_v6_config,
+            #[cfg(feature = "ipv6")]
+            nptv6_config: if_config.nptv6.clone(),
+            #[cfg(feature = "ipv6")]
+            nptv6,
+            #[cfg(feature = "ipv6")]
+            v6_addresses: addresses.ipv6.clone(),
+            #[cfg(feature = "ipv6")]
+            nptv6_prefix_source: None,
+            #[cfg(feature = "ipv6")]
+            clat_addresses: None,
+            netmap: if_config.netmap.clone(),
+            no_snat_sources: if_config.no_snat_sources.clone(),
+            no_snat_dports: if_config.no_snat_dports.clone(),
+            static_bindings: if_config.static_bindings.clone(),
+            scan_local_ports: if_config.exclude_local_ports,
+            carried_bindings: Vec::new(),
+            synced_bindings: None,
         })
     }
 
+    /// Carries bindings over from instance of previous interface on
+    /// failover, with external addresses replaced by default external
+    /// addresses of this interface, so external ports are kept.
+    pub fn set_carried_bindings(
+        &mut self,
+        entries: Vec<(skel::MapBindingKey, skel::MapBindingValue)>,
+    ) {
+        self.carried_bindings = entries;
+    }
+
+    /// Takes over bindings received from peer router, with external
+    /// addresses replaced like [`Self::set_carried_bindings`].
+    pub fn set_synced_bindings(&mut self, snapshot: BindingSnapshot) {
+        self.synced_bindings = Some(snapshot);
+    }
+
+    /// Discovers NPTv6 external prefix from addresses of interface
+    /// `if_index` instead of the external interface.
+    #[cfg(feature = "ipv6")]
+    pub fn set_nptv6_prefix_source(&mut self, if_index: u32, addresses: Vec<Ipv6Addr>) {
+        let Some(config) = &self.nptv6_config else {
+            return;
+        };
+        self.nptv6 = Nptv6::resolve(config, &self.v6_addresses, &addresses);
+        if self.nptv6.is_none() {
+            warn!(
+                "no global IPv6 address on interface {} to discover NPTv6 external prefix from",
+                if_index
+            );
+        }
+        self.nptv6_prefix_source = Some((if_index, addresses));
+    }
+
+    /// Sets interface hairpin packets are redirected to in hairpin redirect
+    /// mode, and its MAC address if it has Ethernet header.
+    pub fn set_hairpin_redirect_target(&mut self, if_index: u32, mac: Option<[u8; 6]>) {
+        self.const_config.hairpin_redirect_target = Some((if_index, mac.unwrap_or_default()));
+    }
+
+    /// Translates IPv4 packets leaving this interface to IPv6 with NAT64
+    /// prefix `pref64` after NAT44, and sends them out of uplink.
+    #[cfg(feature = "ipv6")]
+    pub fn set_clat(
+        &mut self,
+        uplink_if_index: u32,
+        uplink_has_eth: bool,
+        ipv4_address: Ipv4Addr,
+        pref64: Ipv6Net,
+        ipv6_address: Ipv6Addr,
+    ) {
+        self.const_config.clat = Some(ClatConstConfig {
+            if_index: self.if_index,
+            uplink_if_index,
+            uplink_has_eth,
+            ipv4_address,
+        });
+        self.clat_addresses = Some((pref64, ipv6_address));
+    }
+
     pub fn is_static(&self) -> bool {
         self.externals
             .iter()
             .all(|external| matches!(external.address, AddressOrMatcher::Static { .. }))
     }
 
-    pub fn load(self) -> Result<Instance> {
-        let skel_builder = EinatSkelBuilder::default();
+    /// Restores binding entries from snapshot file if binding map is empty,
+    /// skipping those that would have timed out since the snapshot was taken.
+    /// Returns true if any entry was restored.
+    fn restore_binding_snapshot(&self, skel: &EinatSkel, path: &Path) -> Result<bool> {
+        if !path.exists() {
+            return Ok(false);
+        }
 
-        let mut open_skel = skel_builder.open()?;
+        let maps = skel.maps();
+        let map_binding = maps.map_binding();
+        if map_binding.keys().next().is_some() {
+            info!("binding map is not empty, skip restoring binding snapshot");
+            return Ok(false);
+        }
 
-        self.const_config.apply(&mut open_skel);
+        let snapshot = BindingSnapshot::load(path)?;
 
-        let start = Instant::now();
-        let mut skel = open_skel.load()?;
-        info!("eBPF programs loaded in {:?}", start.elapsed());
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for (mut key, mut value) in unexpired_snapshot_entries(skel, snapshot) {
+            // Interface index might change across reboots. And there is no CT
+            // referencing restored bindings yet, they are held till the
+            // remaining timeout instead.
+            key.if_index = self.if_index;
+            value.use_ = 0;
+            value.ref_ = 0;
+            keys.extend_from_slice(bytemuck::bytes_of(&key));
+            values.extend_from_slice(bytemuck::bytes_of(&value));
+        }
+        // binding map is empty, so there is no conflicting entry
+        update_batch_or_each(map_binding, &keys, &values)?;
+        let count = keys.len() / core::mem::size_of::<skel::MapBindingKey>();
+
+        info!(
+            "restored {} binding entries from snapshot {}",
+            count,
+            path.display()
+        );
+        Ok(count != 0)
+    }
+
+    /// Inserts bindings carried over from previous interface or peer router,
+    /// see [`Self::set_carried_bindings`]. Returns true if any entry was
+    /// inserted.
+    fn restore_carried_bindings(
+        &self,
+        skel: &EinatSkel,
+        entries: &[(skel::MapBindingKey, skel::MapBindingValue)],
+        source: &str,
+    ) -> Result<bool> {
+        use skel::{BindingFlags, InetAddr};
 
-        self.runtime_v4_config.apply(None, &mut skel)?;
+        let v4_external = Some(self.runtime_v4_config.external_addr.addr())
+            .filter(|addr| !addr.is_unspecified())
+            .map(InetAddr::from);
         #[cfg(feature = "ipv6")]
-        self.runtime_v6_config.apply(None, &mut skel)?;
+        let v6_external = Some(self.runtime_v6_config.external_addr.addr())
+            .filter(|addr| !addr.is_unspecified())
+            .map(InetAddr::from);
+        #[cfg(not(feature = "ipv6"))]
+        let v6_external = None;
 
-        Ok(Instance {
+        let now_secs = monotonic_now_ns() / 1_000_000_000;
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for &(mut key, mut value) in entries {
+            let external = if key.flags.contains(BindingFlags::ADDR_IPV4) {
+                v4_external
+            } else {
+                v6_external
+            };
+            let Some(external) = external else {
+                continue;
+            };
+            if key.flags.contains(BindingFlags::ORIG_DIR) {
+                value.to_addr = external;
+            } else {
+                key.from_addr = external;
+            }
+            key.if_index = self.if_index;
+            // There is no CT referencing carried bindings yet, hold them for
+            // a full timeout unless held for the rest of timeout already, see
+            // `unexpired_snapshot_entries`.
+            value.use_ = 0;
+            value.ref_ = 0;
+            if (value.held_until as u64) <= now_secs {
+                let timeout = binding_timeout(skel, key.l4proto);
+                value.held_until = (now_secs + timeout.as_secs()) as u32;
+            }
+            keys.extend_from_slice(bytemuck::bytes_of(&key));
+            values.extend_from_slice(bytemuck::bytes_of(&value));
+        }
+        update_batch_or_each(skel.maps().map_binding(), &keys, &values)?;
+        let count = keys.len() / core::mem::size_of::<skel::MapBindingKey>();
+
+        info!("carried over {} binding entries from {}", count, source);
+        Ok(count != 0)
+    }
+
+    /// Inserts static 1:1 NAT address bindings of externals with internal
+    /// address, which are never evicted, replacing those left in pinned map.
+    fn update_addr_bindings(&self, skel: &EinatSkel) -> Result<()> {
+        use skel::{BindingFlags, MapBindingKey, MapBindingValue};
+
+        let maps = skel.maps();
+        let map_binding = maps.map_binding();
+        if self.pin_path.is_some() {
+            for (key, value) in dump_bindings(skel)? {
+                if key.if_index == self.if_index && key.l4proto == 0 && value.is_static != 0 {
+                    map_binding.delete(bytemuck::bytes_of(&key))?;
+                }
+            }
+        }
+
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for external in self.externals.iter() {
+            let (AddressOrMatcher::Static { address }, Some(internal_address)) =
+                (&external.address, external.internal_address)
+            else {
+                continue;
+            };
+            #[cfg(not(feature = "ipv6"))]
+            if address.is_ipv6() {
+                continue;
+            }
+            let addr_flag = if address.is_ipv4() {
+                BindingFlags::ADDR_IPV4
+            } else {
+                BindingFlags::ADDR_IPV6
+            };
+
+            for (flags, from_addr, to_addr) in [
+                (
+                    BindingFlags::ORIG_DIR | addr_flag,
+                    internal_address,
+                    *address,
+                ),
+                (addr_flag, *address, internal_address),
+            ] {
+                let key = MapBindingKey {
+                    if_index: self.if_index,
+                    flags,
+                    l4proto: 0,
+                    from_port: 0,
+                    from_addr: from_addr.into(),
+                };
+                let value = MapBindingValue {
+                    to_addr: to_addr.into(),
+                    flags: addr_flag,
+                    is_static: 1,
+                    ..Default::default()
+                };
+                keys.extend_from_slice(bytemuck::bytes_of(&key));
+                values.extend_from_slice(bytemuck::bytes_of(&value));
+            }
+            info!("mapping {} to {} 1:1", address, internal_address);
+        }
+        update_batch_or_each(map_binding, &keys, &values)
+    }
+
+    /// Inserts static bindings on their external addresses, which are never
+    /// expired or evicted, and removes static bindings of this interface no
+    /// longer configured. Only entries that changed are touched, so other
+    /// static bindings keep working meanwhile. Bindings without explicit
+    /// external address follow the first external address of the same family.
+    /// Returns bindings skipped as either of their entries are in use by
+    /// dynamic bindings.
+    fn update_static_bindings(&self, skel: &EinatSkel) -> Result<Vec<ConfigStaticBinding>> {
+        use skel::{BindingFlags, MapBindingKey, MapBindingValue};
+
+        let maps = skel.maps();
+        let map_binding = maps.map_binding();
+        let existing: HashMap<MapBindingKey, MapBindingValue> = dump_bindings(skel)?
+            .into_iter()
+            .filter(|(key, _)| key.if_index == self.if_index && key.l4proto != 0)
+            .collect();
+
+        // CT reference counts of existing entries are kept
+        let unchanged = |key: &MapBindingKey, value: &MapBindingValue| {
+            existing.get(key).is_some_and(|existing| {
+                MapBindingValue {
+                    use_: value.use_,
+                    ref_: value.ref_,
+                    ..*existing
+                } == *value
+            })
+        };
+        let mut wanted = HashMap::new();
+        let mut skipped = Vec::new();
+        for binding in self.static_bindings.iter() {
+            let internal = binding.internal;
+            #[cfg(not(feature = "ipv6"))]
+            if internal.is_ipv6() {
+                continue;
+            }
+            let external_address = match binding.external_address {
+                Some(address) => address,
+                None if internal.is_ipv4() => self.runtime_v4_config.external_addr.addr().into(),
+                #[cfg(feature = "ipv6")]
+                None => self.runtime_v6_config.external_addr.addr().into(),
+                #[cfg(not(feature = "ipv6"))]
+                None => continue,
+            };
+            if external_address.is_unspecified() {
+                debug!("no external address for static binding of {}", internal);
+                continue;
+            }
+            let addr_flag = if internal.is_ipv4() {
+                BindingFlags::ADDR_IPV4
+            } else {
+                BindingFlags::ADDR_IPV6
+            };
+            let l4proto = l4proto(binding.proto);
+            let external_port = binding.external_port.to_be();
+
+            let entries = [
+                (
+                    BindingFlags::ORIG_DIR | addr_flag,
+                    (internal.ip(), internal.port().to_be()),
+                    (external_address, external_port),
+                ),
+                (
+                    addr_flag,
+                    (external_address, external_port),
+                    (internal.ip(), internal.port().to_be()),
+                ),
+            ]
+            .map(|(flags, from, to)| {
+                let key = MapBindingKey {
+                    if_index: self.if_index,
+                    flags,
+                    l4proto,
+                    from_port: from.1,
+                    from_addr: from.0.into(),
+                };
+                let value = MapBindingValue {
+                    to_addr: to.0.into(),
+                    to_port: to.1,
+                    flags: addr_flag,
+                    is_static: 1,
+                    ..Default::default()
+                };
+                (key, value)
+            });
+
+            // Overwriting dynamic binding of either direction would orphan
+            // its pair
+            if entries
+                .iter()
+                .any(|(key, _)| existing.get(key).is_some_and(|value| value.is_static == 0))
+            {
+                warn!(
+                    "external port {} of {} or internal endpoint {} is in use, skipping static binding",
+                    binding.external_port, external_address, internal
+                );
+                skipped.push(*binding);
+                continue;
+            }
+
+            if !entries.iter().all(|(key, value)| unchanged(key, value)) {
+                info!(
+                    "binding {}:{} to {} statically",
+                    external_address, binding.external_port, internal
+                );
+            }
+            wanted.extend(entries);
+        }
+
+        for (key, value) in existing.iter() {
+            if value.is_static != 0 && !wanted.contains_key(key) {
+                map_binding.delete(bytemuck::bytes_of(key))?;
+            }
+        }
+
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for (key, value) in wanted.iter() {
+            if !unchanged(key, value) {
+                keys.extend_from_slice(bytemuck::bytes_of(key));
+                values.extend_from_slice(bytemuck::bytes_of(value));
+            }
+        }
+        update_batch_or_each(map_binding, &keys, &values)?;
+        Ok(skipped)
+    }
+
+    /// Removes binding and CT entries restored from pinned maps or snapshot
+    /// that no longer belong to this interface or any of current external
+    /// addresses.
+    fn remove_stale_entries(&self, skel: &mut EinatSkel) -> Result<()> {
+        use skel::{BindingFlags, InetAddr};
+
+        #[allow(unused_mut)]
+        let mut externals: Vec<(BindingFlags, InetAddr)> = self
+            .runtime_v4_config
+            .external_config
+            .iter()
+            .map(|(k, _)| (BindingFlags::ADDR_IPV4, k.ip_addr().into()))
+            .collect();
+        #[cfg(feature = "ipv6")]
+        externals.extend(
+            self.runtime_v6_config
+                .external_config
+                .iter()
+                .map(|(k, _)| (BindingFlags::ADDR_IPV6, k.ip_addr().into())),
+        );
+
+        let if_index = self.if_index;
+        let (bindings, cts) =
+            remove_binding_and_ct_entries_if(skel, |key_if_index, flags, addr| {
+                key_if_index != if_index
+                    || !externals
+                        .iter()
+                        .any(|(addr_flag, external)| flags.contains(*addr_flag) && addr == external)
+            })?;
+        if bindings != 0 || cts != 0 {
+            info!(
+                "removed {} stale binding and {} stale CT entries",
+                bindings, cts
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn load(mut self) -> Result<Instance> {
+        let skel_builder = EinatSkelBuilder::default();
+
+        let mut open_skel = if let Some(btf_path) = &self.btf_path {
+            debug!("using custom BTF {}", btf_path.display());
+            let btf_path = CString::new(btf_path.as_os_str().as_bytes())?;
+            let mut opts = *skel_builder.object_builder().opts();
+            opts.btf_custom_path = btf_path.as_ptr();
+            // libbpf copies the path on open
+            skel_builder.open_opts(opts)?
+        } else {
+            skel_builder.open()?
+        };
+
+        self.const_config.apply(&mut open_skel);
+        #[cfg(feature = "ipv6")]
+        self.const_config.select_family(&mut open_skel)?;
+        open_skel
+            .progs_mut()
+            .ingress_hairpin()
+            .set_autoload(self.const_config.hairpin_bpf())?;
+        #[cfg(feature = "ipv6")]
+        open_skel
+            .progs_mut()
+            .ingress_clat()
+            .set_autoload(self.const_config.clat.is_some())?;
+        // iterators attach to kernel BTF, custom BTF only serves CO-RE
+        // relocations, maps are walked key by key without them
+        let map_elem_iter = KernelFeatures::get().map_elem_iter;
+        open_skel
+            .progs_mut()
+            .dump_binding()
+            .set_autoload(map_elem_iter)?;
+        open_skel
+            .progs_mut()
+            .dump_ct()
+            .set_autoload(map_elem_iter)?;
+
+        let session_entries = match self.map_size {
+            Some(MapSize::Entries(entries)) => Some(entries.get()),
+            Some(MapSize::Auto) => Some(auto_map_size(self.expected_hosts)?),
+            None => None,
+        };
+        // leave headroom as LRU maps might evict before being full
+        let host_entries = self
+            .expected_hosts
+            .map(|hosts| hosts.get().saturating_mul(2).next_power_of_two());
+        {
+            let mut maps = open_skel.maps_mut();
+            if let Some(entries) = session_entries {
+                maps.map_binding().set_max_entries(entries)?;
+                maps.map_ct().set_max_entries(entries)?;
+                maps.map_filter_addr().set_max_entries(entries)?;
+                info!("binding and CT maps sized to {} entries", entries);
+            }
+            if let Some(entries) = host_entries {
+                maps.map_host_sessions().set_max_entries(entries)?;
+                maps.map_host_embryonic().set_max_entries(entries)?;
+                maps.map_host_ports().set_max_entries(entries)?;
+                maps.map_host_external().set_max_entries(entries)?;
+                maps.map_host_binding_rate().set_max_entries(entries)?;
+                maps.map_host_reject_rate().set_max_entries(entries)?;
+                maps.map_host_udp_binding_rate().set_max_entries(entries)?;
+                info!("internal host maps sized to {} entries", entries);
+            }
+        }
+
+        if self.ct_lru {
+            let mut maps = open_skel.maps_mut();
+            let map_ct = maps.map_ct();
+            map_ct.set_type(MapType::LruHash)?;
+            // LRU hash maps are always preallocated
+            map_ct.set_map_flags(0)?;
+        }
+
+        if let Some(pin_path) = &self.pin_path {
+            // libbpf reuses maps already pinned, or pins newly created maps
+            std::fs::create_dir_all(pin_path)
+                .map_err(|e| anyhow!("failed to create pin path {}: {}", pin_path.display(), e))?;
+            let mut maps = open_skel.maps_mut();
+            maps.map_binding()
+                .set_pin_path(pin_path.join("map_binding"))?;
+            maps.map_ct().set_pin_path(pin_path.join("map_ct"))?;
+        }
+
+        let start = Instant::now();
+        let mut skel = open_skel.load()?;
+        info!("eBPF programs loaded in {:?}", start.elapsed());
+
+        if self.const_config.has_ipv4_maps() {
+            self.runtime_v4_config.apply(None, &mut skel)?;
+        }
+        #[cfg(feature = "ipv6")]
+        if self.const_config.has_ipv6_maps() {
+            self.runtime_v6_config.apply(None, &mut skel)?;
+        }
+        if !self.netmap.is_empty() {
+            apply_netmap(&self.netmap, &skel)?;
+        }
+        if !self.no_snat_sources.is_empty() {
+            apply_no_snat_sources(&self.no_snat_sources, &self.const_config, &skel)?;
+        }
+        if !self.no_snat_dports.is_empty() {
+            apply_no_snat_dports(&self.no_snat_dports, &skel)?;
+        }
+        #[cfg(feature = "ipv6")]
+        if self.nptv6.is_some() {
+            Nptv6::apply(self.nptv6.as_ref(), &mut skel);
+        }
+        #[cfg(feature = "ipv6")]
+        if let Some((pref64, ipv6_address)) = self.clat_addresses {
+            let pref64: [u32; 4] = bytemuck::cast(pref64.addr().octets());
+            skel.data_mut().g_clat_pref64.copy_from_slice(&pref64[..3]);
+            skel.data_mut().g_clat_ipv6_addr = bytemuck::cast(ipv6_address.octets());
+        }
+
+        let mut restored = false;
+        if let Some(path) = &self.binding_snapshot {
+            match self.restore_binding_snapshot(&skel, path) {
+                Ok(res) => restored = res,
+                Err(e) => warn!(
+                    "failed to restore binding snapshot {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+
+        if !self.carried_bindings.is_empty() {
+            match self.restore_carried_bindings(&skel, &self.carried_bindings, "previous interface")
+            {
+                Ok(res) => restored |= res,
+                Err(e) => warn!("failed to carry over bindings: {}", e),
+            }
+        }
+
+        if let Some(snapshot) = self.synced_bindings.take() {
+            let entries = unexpired_snapshot_entries(&skel, snapshot);
+            match self.restore_carried_bindings(&skel, &entries, "peer") {
+                Ok(res) => restored |= res,
+                Err(e) => warn!("failed to take over bindings of peer: {}", e),
+            }
+        }
+
+        if self.pin_path.is_some() || restored {
+            self.remove_stale_entries(&mut skel)?;
+            continue_binding_seq(&mut skel);
+        }
+
+        if self.pin_path.is_some() || self.const_config.addr_bindings == Some(true) {
+            self.update_addr_bindings(&skel)?;
+        }
+        if self.pin_path.is_some() || !self.static_bindings.is_empty() {
+            self.update_static_bindings(&skel)?;
+        }
+
+        // always read as BPF log level can be raised at runtime
+        let event_reader = EventReader::start(skel.maps().map_events(), self.if_index)?;
+
+        let capture_writer = if let Some(path) = &self.capture_path {
+            info!("capturing translated packets to {}", path.display());
+            Some(CaptureWriter::start(
+                skel.maps().map_capture(),
+                path,
+                self.const_config.has_eth_encap.unwrap_or(true),
+            )?)
+        } else {
+            None
+        };
+
+        let next_gc = self.gc_interval.map(|interval| Instant::now() + interval);
+
+        let mut instance = Instance {
+            _event_reader: event_reader,
+            _capture_writer: capture_writer,
             config: self,
             skel,
             attached_egress_hook: None,
             attached_ingress_hook: None,
-        })
+            attached_hairpin_hooks: Vec::new(),
+            #[cfg(feature = "ipv6")]
+            attached_clat_hook: None,
+            next_gc,
+            local_ports: HashSet::new(),
+            listening_ports: HashSet::new(),
+            reserved_ports: HashMap::new(),
+            next_local_ports_update: None,
+        };
+        if instance.config.scan_local_ports {
+            instance.update_local_ports()?;
+        }
+        Ok(instance)
     }
 }
 
 impl Instance {
-    pub fn reconfigure_v4_addresses(&mut self, addresses: &[Ipv4Addr]) -> Result<()> {
+    pub fn reconfigure_v4_addresses(&mut self, addresses: &IfAddresses) -> Result<()> {
         let new = RuntimeV4Config::from(
-            &self.config.v4_no_snat_dests,
+            &self.config.v4_flagged_dests,
+            &self.config.v4_filtering_dests,
+            &self.config.v4_timeout_dests,
             &self.config.externals,
             addresses,
         );
 
-        new.apply(Some(&self.config.runtime_v4_config), &mut self.skel)?;
+        if self.config.const_config.has_ipv4_maps() {
+            new.apply(Some(&self.config.runtime_v4_config), &mut self.skel)?;
+        }
         self.config.runtime_v4_config = new;
+        if !self.config.static_bindings.is_empty() {
+            self.config.update_static_bindings(&self.skel)?;
+        }
 
         Ok(())
     }
 
     #[cfg(feature = "ipv6")]
-    pub fn reconfigure_v6_addresses(&mut self, addresses: &[Ipv6Addr]) -> Result<()> {
+    pub fn reconfigure_v6_addresses(&mut self, addresses: &IfAddresses) -> Result<()> {
         let new = RuntimeV6Config::from(
-            &self.config.v6_no_snat_dests,
+            &self.config.v6_flagged_dests,
+            &self.config.v6_filtering_dests,
+            &self.config.v6_timeout_dests,
             &self.config.externals,
             addresses,
         );
 
-        new.apply(Some(&self.config.runtime_v6_config), &mut self.skel)?;
+        if self.config.const_config.has_ipv6_maps() {
+            new.apply(Some(&self.config.runtime_v6_config), &mut self.skel)?;
+        }
         self.config.runtime_v6_config = new;
+        if !self.config.static_bindings.is_empty() {
+            self.config.update_static_bindings(&self.skel)?;
+        }
+        self.config.v6_addresses = addresses.ipv6.clone();
+        self.reconfigure_nptv6();
+
+        Ok(())
+    }
+
+    /// Index of interface NPTv6 external prefix is discovered from, if it's
+    /// not the external interface.
+    #[cfg(feature = "ipv6")]
+    pub fn nptv6_prefix_if_index(&self) -> Option<u32> {
+        self.config
+            .nptv6_prefix_source
+            .as_ref()
+            .map(|(if_index, _)| *if_index)
+    }
+
+    /// Renumbers NPTv6 external prefix on address changes of the interface
+    /// it's discovered from, e.g. after delegated prefix is changed.
+    #[cfg(feature = "ipv6")]
+    pub fn reconfigure_nptv6_prefix_addresses(&mut self, addresses: &[Ipv6Addr]) {
+        if let Some((_, prefix_addresses)) = &mut self.config.nptv6_prefix_source {
+            if prefix_addresses != addresses {
+                *prefix_addresses = addresses.to_vec();
+                self.reconfigure_nptv6();
+            }
+        }
+    }
+
+    #[cfg(feature = "ipv6")]
+    fn reconfigure_nptv6(&mut self) {
+        let Some(config) = &self.config.nptv6_config else {
+            return;
+        };
+        let prefix_addresses = match &self.config.nptv6_prefix_source {
+            Some((_, addresses)) => addresses,
+            None => &self.config.v6_addresses,
+        };
+        let nptv6 = Nptv6::resolve(config, &self.config.v6_addresses, prefix_addresses);
+        if nptv6 != self.config.nptv6 {
+            if nptv6.is_none() {
+                warn!("NPTv6 external prefix is gone, NPTv6 disabled");
+            }
+            Nptv6::apply(nptv6.as_ref(), &mut self.skel);
+            self.config.nptv6 = nptv6;
+        }
+    }
+
+    #[cfg(feature = "ipv6")]
+    pub fn set_clat_ipv6_address(&mut self, address: Ipv6Addr) {
+        if let Some((_, ipv6_address)) = &mut self.config.clat_addresses {
+            *ipv6_address = address;
+            self.skel.data_mut().g_clat_ipv6_addr = bytemuck::cast(address.octets());
+        }
+    }
+
+    /// Max BPF log level of running BPF programs.
+    pub fn bpf_log_level(&self) -> u8 {
+        self.skel.data().g_log_level
+    }
+
+    /// Sets max BPF log level of running BPF programs, capped at
+    /// `bpf_log_max_level` as logging beyond it is left out of programs on
+    /// load. Returns the level set.
+    pub fn set_bpf_log_level(&mut self, level: u8) -> u8 {
+        let level = level.min(self.config.const_config.max_log_level.unwrap_or(0));
+        self.skel.data_mut().g_log_level = level;
+        level
+    }
+
+    /// Names and values of counters of running BPF programs.
+    pub fn counters(&self) -> Vec<(&'static str, u64)> {
+        let data = self.skel.data();
+        vec![
+            ("icmp_err_malformed", data.g_icmp_err_malformed),
+            ("icmp_err_generated", data.g_icmp_err_generated),
+            ("icmp_err_rate_limited", data.g_icmp_err_rate_limited),
+            ("embryonic_limited", data.g_embryonic_limited),
+            ("udp_binding_rate_limited", data.g_udp_binding_rate_limited),
+            ("udp_cts", data.g_udp_cts as u64),
+            ("unsolicited_dropped", data.g_unsolicited_dropped),
+            ("dest_blocked", data.g_dest_blocked),
+            ("csum_fix_skipped", data.g_csum_fix_skipped),
+        ]
+    }
+
+    /// Adds static binding at runtime, which is not saved to configuration
+    /// and lost on restart.
+    pub fn add_static_binding(&mut self, binding: ConfigStaticBinding) -> Result<()> {
+        let mut bindings = self.config.static_bindings.clone();
+        bindings.push(binding);
+        check_static_bindings(&bindings, &self.config.externals)?;
+        self.config.static_bindings = bindings;
+
+        let skipped = self.config.update_static_bindings(&self.skel)?;
+        if skipped.contains(&binding) {
+            self.config.static_bindings.pop();
+            return Err(anyhow!("external port {} is in use", binding.external_port));
+        }
+        Ok(())
+    }
+
+    /// Removes static bindings of external port, returns bindings removed.
+    pub fn remove_static_binding(
+        &mut self,
+        proto: IpProtocol,
+        external_port: u16,
+    ) -> Result<Vec<ConfigStaticBinding>> {
+        let (removed, kept): (Vec<_>, Vec<_>) =
+            self.config.static_bindings.iter().partition(|binding| {
+                binding.proto == proto && binding.external_port == external_port
+            });
+        if removed.is_empty() {
+            return Err(anyhow!(
+                "no static binding of {} port {}",
+                proto,
+                external_port
+            ));
+        }
+        self.config.static_bindings = kept;
+        self.config.update_static_bindings(&self.skel)?;
+        Ok(removed)
+    }
+
+    /// Time of next scheduled garbage collection, `None` if disabled.
+    pub fn next_gc(&self) -> Option<Instant> {
+        self.next_gc
+    }
+
+    pub fn collect_garbage(&mut self) -> Result<()> {
+        let stats = collect_garbage(&mut self.skel)?;
+        if stats.cts != 0 || stats.bindings != 0 || stats.fixed_refs != 0 {
+            info!(
+                "garbage collection evicted {} CT and {} binding entries, fixed references of {} bindings",
+                stats.cts, stats.bindings, stats.fixed_refs
+            );
+        } else {
+            debug!("garbage collection found nothing to evict");
+        }
+        self.next_gc = self
+            .config
+            .gc_interval
+            .map(|interval| Instant::now() + interval);
+        Ok(())
+    }
+
+    /// Time of next scan of local listening ports or expiry of port
+    /// reservations, `None` if there is neither.
+    pub fn next_local_ports_update(&self) -> Option<Instant> {
+        self.next_local_ports_update
+    }
+
+    /// Rescans ports of local listening sockets if `exclude_local_ports` is
+    /// enabled and expires port reservations. These ports are skipped when
+    /// allocating binding ports on external addresses that are also local
+    /// addresses, and inbound packets to them are passed to this host if
+    /// there is no binding.
+    pub fn update_local_ports(&mut self) -> Result<()> {
+        use skel::MapLocalPortKey;
+
+        let now = Instant::now();
+        if self.config.scan_local_ports {
+            self.listening_ports = with_netns(self.config.netns.as_deref(), local_listening_ports)?;
+        }
+        self.reserved_ports.retain(|port, expiry| {
+            if *expiry <= now {
+                info!("reservation of port {}/{} expired", port.1, port.0);
+            }
+            *expiry > now
+        });
+
+        let ports: HashSet<_> = self
+            .listening_ports
+            .iter()
+            .chain(self.reserved_ports.keys())
+            .copied()
+            .collect();
+        let maps = self.skel.maps();
+        let map_local_ports = maps.map_local_ports();
+        let key = |&(l4proto, port): &(u8, u16)| MapLocalPortKey {
+            l4proto,
+            _pad: 0,
+            port: port.to_be(),
+        };
+        for port in self.local_ports.difference(&ports) {
+            debug!("local port {}/{} released", port.1, port.0);
+            map_local_ports.delete(bytemuck::bytes_of(&key(port)))?;
+        }
+        for port in ports.difference(&self.local_ports) {
+            debug!("excluding local port {}/{}", port.1, port.0);
+            map_local_ports.update(bytemuck::bytes_of(&key(port)), &[1], MapFlags::ANY)?;
+        }
+        self.local_ports = ports;
+
+        self.next_local_ports_update = self
+            .config
+            .scan_local_ports
+            .then(|| now + LOCAL_PORTS_SCAN_INTERVAL)
+            .into_iter()
+            .chain(self.reserved_ports.values().copied())
+            .min();
+        Ok(())
+    }
+
+    /// Reserves external port for local service until `lease` passes, so it's
+    /// not allocated for dynamic bindings. Reserving again renews the lease.
+    pub fn reserve_port(&mut self, proto: IpProtocol, port: u16, lease: Duration) -> Result<()> {
+        if self.config.const_config.local_ports != Some(true) {
+            return Err(anyhow!("port reservation is not enabled"));
+        }
+        if proto == IpProtocol::Icmp {
+            return Err(anyhow!("ICMP ports can not be reserved"));
+        }
+        if port == 0 {
+            return Err(anyhow!("port 0 can not be reserved"));
+        }
+        self.reserved_ports
+            .insert((l4proto(proto), port), Instant::now() + lease);
+        self.update_local_ports()
+    }
+
+    /// Releases port reservation before its lease expires.
+    pub fn release_port(&mut self, proto: IpProtocol, port: u16) -> Result<()> {
+        if self
+            .reserved_ports
+            .remove(&(l4proto(proto), port))
+            .is_none()
+        {
+            return Err(anyhow!("no reservation of {} port {}", proto, port));
+        }
+        self.update_local_ports()
+    }
+
+    /// Dumps bindings referenced by any CT, see [`BindingSnapshot::new`].
+    pub fn dump_bindings(&self) -> Result<Vec<(skel::MapBindingKey, skel::MapBindingValue)>> {
+        Ok(BindingSnapshot::new(dump_bindings(&self.skel)?).entries)
+    }
 
+    /// Handle of binding map, for dumping bindings off the async runtime with
+    /// [`BindingSnapshot::dump`].
+    pub fn binding_map(&self) -> Result<MapHandle> {
+        Ok(MapHandle::try_clone(self.skel.maps().map_binding())?)
+    }
+
+    pub fn save_binding_snapshot(&self) -> Result<()> {
+        let Some(path) = &self.config.binding_snapshot else {
+            return Ok(());
+        };
+        let snapshot = BindingSnapshot::new(dump_bindings(&self.skel)?);
+        snapshot.save(path)?;
+        info!(
+            "saved {} binding entries to snapshot {}",
+            snapshot.entries.len(),
+            path.display()
+        );
         Ok(())
     }
 
+    pub fn netns(&self) -> Option<&NetNs> {
+        self.config.netns.as_deref()
+    }
+
     pub fn v4_hairpin_dests(&self) -> Vec<Ipv4Net> {
         self.config.runtime_v4_config.hairpin_dests()
     }
@@ -838,9 +3382,68 @@ impl Instance {
         self.config.runtime_v6_config.hairpin_dests()
     }
 
+    /// Runs ingress or egress TC program with packet `pkt` for `repeat` times
+    /// with `BPF_PROG_TEST_RUN`, returns return value of the program, output
+    /// packet and average run time.
+    pub fn test_run(
+        &self,
+        ingress: bool,
+        pkt: &[u8],
+        repeat: u32,
+    ) -> Result<(i32, Vec<u8>, Duration)> {
+        let progs = self.skel.progs();
+        let prog = if ingress {
+            self.ingress_prog(&progs)
+        } else {
+            self.egress_prog(&progs)
+        };
+
+        // leave room for encapsulation growth
+        let mut out = vec![0u8; pkt.len() + 256];
+        let mut opts: libbpf_sys::bpf_test_run_opts = unsafe { std::mem::zeroed() };
+        opts.sz = core::mem::size_of::<libbpf_sys::bpf_test_run_opts>() as _;
+        opts.data_in = pkt.as_ptr().cast();
+        opts.data_size_in = pkt.len() as _;
+        opts.data_out = out.as_mut_ptr().cast();
+        opts.data_size_out = out.len() as _;
+        opts.repeat = repeat as _;
+        let res =
+            unsafe { libbpf_sys::bpf_prog_test_run_opts(prog.as_fd().as_raw_fd(), &mut opts) };
+        if res < 0 {
+            return Err(std::io::Error::from_raw_os_error(-res))
+                .context("failed to test run BPF program");
+        }
+        out.truncate(opts.data_size_out as _);
+        Ok((
+            opts.retval as i32,
+            out,
+            Duration::from_nanos(opts.duration as _),
+        ))
+    }
+
+    fn ingress_prog<'a>(&self, progs: &'a EinatProgs) -> &'a libbpf_rs::Program {
+        #[cfg(feature = "ipv6")]
+        match self.config.const_config.ingress_family() {
+            ProgFamily::V4 => return progs.ingress_rev_snat_v4(),
+            ProgFamily::V6 => return progs.ingress_rev_snat_v6(),
+            ProgFamily::Dual => (),
+        }
+        progs.ingress_rev_snat()
+    }
+
+    fn egress_prog<'a>(&self, progs: &'a EinatProgs) -> &'a libbpf_rs::Program {
+        #[cfg(feature = "ipv6")]
+        match self.config.const_config.egress_family() {
+            ProgFamily::V4 => return progs.egress_snat_v4(),
+            ProgFamily::V6 => return progs.egress_snat_v6(),
+            ProgFamily::Dual => (),
+        }
+        progs.egress_snat()
+    }
+
     fn ingress_tc_hook(&self) -> TcHook {
         let progs = self.skel.progs();
-        TcHookBuilder::new(progs.ingress_rev_snat().as_fd())
+        TcHookBuilder::new(self.ingress_prog(&progs).as_fd())
             .ifindex(self.config.if_index as _)
             .replace(true)
             .handle(1)
@@ -850,7 +3453,7 @@ impl Instance {
 
     fn egress_tc_hook(&self) -> TcHook {
         let progs = self.skel.progs();
-        TcHookBuilder::new(progs.egress_snat().as_fd())
+        TcHookBuilder::new(self.egress_prog(&progs).as_fd())
             .ifindex(self.config.if_index as _)
             .replace(true)
             .handle(1)
@@ -858,22 +3461,446 @@ impl Instance {
             .hook(TC_EGRESS)
     }
 
+    fn hairpin_tc_hook(&self, if_index: u32) -> TcHook {
+        let progs = self.skel.progs();
+        TcHookBuilder::new(progs.ingress_hairpin().as_fd())
+            .ifindex(if_index as _)
+            .replace(true)
+            // distinct for each external interface, so an internal interface
+            // can be shared by multiple external interfaces
+            .handle(self.config.if_index)
+            .priority(1)
+            .hook(TC_INGRESS)
+    }
+
+    #[cfg(feature = "ipv6")]
+    fn clat_tc_hook(&self, uplink_if_index: u32) -> TcHook {
+        let progs = self.skel.progs();
+        TcHookBuilder::new(progs.ingress_clat().as_fd())
+            .ifindex(uplink_if_index as _)
+            .replace(true)
+            // fixed as interface index of CLAT interface changes across runs,
+            // so a stale filter would be replaced
+            .handle(CLAT_TC_HANDLE)
+            .priority(1)
+            .hook(TC_INGRESS)
+    }
+
+    /// Attaches hairpin program on ingress of internal interfaces
+    /// `if_names`, and detaches it from previous ones no longer listed.
+    pub fn attach_hairpin_bpf(&mut self, if_names: &[String]) -> Result<()> {
+        let netns = self.config.netns.clone();
+        with_netns(netns.as_deref(), || {
+            let mut if_indexes = Vec::with_capacity(if_names.len());
+            for if_name in if_names {
+                match if_nametoindex(if_name.as_str()) {
+                    Ok(if_index) => if_indexes.push(if_index),
+                    Err(e) => warn!(
+                        "failed to find hairpin internal interface {}: {}",
+                        if_name, e
+                    ),
+                }
+            }
+
+            let (attached, stale): (Vec<_>, Vec<_>) =
+                std::mem::take(&mut self.attached_hairpin_hooks)
+                    .into_iter()
+                    .partition(|(if_index, _)| if_indexes.contains(if_index));
+            self.attached_hairpin_hooks = attached;
+            for (if_index, mut hook) in stale {
+                debug!("detaching hairpin program from if {}", if_index);
+                if let Err(e) = hook.detach() {
+                    warn!(
+                        "failed to detach hairpin program from if {}: {}",
+                        if_index, e
+                    );
+                }
+            }
+
+            for if_index in if_indexes {
+                if self
+                    .attached_hairpin_hooks
+                    .iter()
+                    .any(|(i, _)| *i == if_index)
+                {
+                    continue;
+                }
+                debug!("attaching hairpin program to if {}", if_index);
+                let hook = self.hairpin_tc_hook(if_index).create()?.attach()?;
+                self.attached_hairpin_hooks.push((if_index, hook));
+            }
+            Ok(())
+        })
+    }
+
+    /// Detaches TC filter left behind by a previous einat run that exited
+    /// without detaching, e.g. crashed. Filters are identified by our handle and
+    /// priority, and their program name, or name of its address family variant.
+    fn detach_stale_hook(&self, mut hook: TcHook, prog_name: &str) {
+        let Ok(prog_id) = hook.query() else {
+            return;
+        };
+        // kernel truncates program name to BPF_OBJ_NAME_LEN - 1
+        let prog_name = &prog_name.as_bytes()[..prog_name.len().min(15)];
+        let is_ours = libbpf_rs::query::ProgInfoIter::default()
+            .find(|info| info.id == prog_id)
+            .is_some_and(|info| info.name.as_bytes().starts_with(prog_name));
+        if !is_ours {
+            // not detached, but overwritten once ours is attached in place
+            warn!(
+                "TC filter of foreign BPF program {} on if {} has our handle and priority, it will be overwritten",
+                prog_id, self.config.if_index
+            );
+            return;
+        }
+
+        warn!(
+            "detaching stale TC filter of BPF program {} on if {}",
+            prog_id, self.config.if_index
+        );
+        if let Err(e) = hook.detach() {
+            warn!("failed to detach stale TC filter: {}", e);
+        }
+    }
+
+    pub fn detach_stale_hooks(&self) -> Result<()> {
+        // TC operations are performed in network namespace of current thread
+        with_netns(self.config.netns.as_deref(), || {
+            self.detach_stale_hook(self.ingress_tc_hook(), "ingress_rev_snat");
+            self.detach_stale_hook(self.egress_tc_hook(), "egress_snat");
+            #[cfg(feature = "ipv6")]
+            if let Some(clat) = self.config.const_config.clat {
+                self.detach_stale_hook(self.clat_tc_hook(clat.uplink_if_index), "ingress_clat");
+            }
+            Ok(())
+        })
+    }
+
     pub fn attach(&mut self) -> Result<()> {
-        self.attached_ingress_hook = Some(self.ingress_tc_hook().create()?.attach()?);
-        self.attached_egress_hook = Some(self.egress_tc_hook().attach()?);
-        Ok(())
+        let netns = self.config.netns.clone();
+        with_netns(netns.as_deref(), || {
+            self.attached_ingress_hook = Some(self.ingress_tc_hook().create()?.attach()?);
+            self.attached_egress_hook = Some(self.egress_tc_hook().attach()?);
+            #[cfg(feature = "ipv6")]
+            if let Some(clat) = self.config.const_config.clat {
+                self.attached_clat_hook =
+                    Some(self.clat_tc_hook(clat.uplink_if_index).create()?.attach()?);
+            }
+            Ok(())
+        })
     }
 
     pub fn detach(&mut self) -> Result<()> {
-        if let Some(mut hook) = self.attached_egress_hook.take() {
-            hook.detach()?;
+        let netns = self.config.netns.clone();
+        with_netns(netns.as_deref(), || {
+            if let Some(mut hook) = self.attached_egress_hook.take() {
+                hook.detach()?;
+            }
+            if let Some(mut hook) = self.attached_ingress_hook.take() {
+                hook.detach()?;
+            }
+            for (_, mut hook) in self.attached_hairpin_hooks.drain(..) {
+                hook.detach()?;
+            }
+            #[cfg(feature = "ipv6")]
+            if let Some(mut hook) = self.attached_clat_hook.take() {
+                hook.detach()?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Continues binding sequence number from existing binding entries, so new
+/// bindings won't be mistaken as the same generation of existing ones.
+fn continue_binding_seq(skel: &mut EinatSkel) {
+    let next_seq = dump_bindings(skel)
+        .unwrap_or_default()
+        .iter()
+        .map(|(_, value)| value.seq)
+        .max()
+        .map_or(0, |seq| seq.wrapping_add(1));
+
+    skel.bss_mut().g_next_binding_seq = next_seq;
+}
+
+/// Estimated memory taken by a NAT session, i.e. a CT, a pair of bindings and
+/// a filtering record, with 64 bytes of hash table overhead for each.
+const SESSION_MEM_ESTIMATE: u64 = (core::mem::size_of::<skel::MapCtKey>()
+    + core::mem::size_of::<skel::MapCtValue>()
+    + 2 * (core::mem::size_of::<skel::MapBindingKey>()
+        + core::mem::size_of::<skel::MapBindingValue>())
+    + core::mem::size_of::<skel::MapFilterAddrKey>()
+    + core::mem::size_of::<u32>()
+    + 4 * 64) as u64;
+/// Sessions expected per internal host for `expected_hosts`
+const SESSIONS_PER_HOST: u64 = 1024;
+const AUTO_MAP_SIZE_RANGE: RangeInclusive<u64> = (1 << 12)..=(1 << 24);
+
+/// Picks entries of binding and CT maps, so sessions take at most 1/16 of
+/// system memory, and no more than needed for `expected_hosts`.
+fn auto_map_size(expected_hosts: Option<NonZeroU32>) -> Result<u32> {
+    let total_memory = total_memory()?;
+    let mut entries = total_memory / 16 / SESSION_MEM_ESTIMATE;
+    if let Some(hosts) = expected_hosts {
+        entries = entries.min(hosts.get() as u64 * SESSIONS_PER_HOST);
+    }
+    // round down to power of 2
+    let entries = entries
+        .checked_ilog2()
+        .map_or(0, |exp| 1 << exp)
+        .clamp(*AUTO_MAP_SIZE_RANGE.start(), *AUTO_MAP_SIZE_RANGE.end());
+    debug!(
+        "auto map size: {} bytes of memory, {} bytes per session estimated",
+        total_memory, SESSION_MEM_ESTIMATE
+    );
+    Ok(entries as u32)
+}
+
+/// Makes skeleton load BPF object at `path` instead of the embedded one, after
+/// validating it's compatible with the skeleton.
+pub fn use_bpf_object(path: &Path) -> Result<()> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("failed to read BPF object {}", path.display()))?;
+    validate_bpf_object(&data)
+        .with_context(|| format!("incompatible BPF object {}", path.display()))?;
+    if !skel::set_object_data(Vec::leak(data)) {
+        return Err(anyhow!("BPF object already replaced"));
+    }
+    info!("using BPF object {}", path.display());
+    Ok(())
+}
+
+/// Checks maps and programs accessed through skeleton exist in BPF object with
+/// the same types and key/value sizes, while map sizes are allowed to differ.
+fn validate_bpf_object(data: &[u8]) -> Result<()> {
+    let open = |data: &[u8]| -> Result<libbpf_rs::OpenObject> {
+        // internal map names are prefixed with object name
+        Ok(ObjectBuilder::default()
+            .name(skel::OBJECT_NAME)?
+            .open_memory(data)?)
+    };
+    let embedded = open(skel::embedded_object_data())?;
+    let custom = open(data).context("failed to parse BPF object")?;
+
+    for map in embedded.maps_iter() {
+        let name = map.name()?;
+        let Some(custom_map) = custom.map(name) else {
+            return Err(anyhow!("map {} not found", name));
+        };
+        if custom_map.map_type() != map.map_type() {
+            return Err(anyhow!(
+                "map {} type mismatch, expecting {:?}, found {:?}",
+                name,
+                map.map_type(),
+                custom_map.map_type()
+            ));
+        }
+        let sizes = |map: &libbpf_rs::OpenMap| {
+            let ptr = map.as_libbpf_object().as_ptr();
+            unsafe {
+                (
+                    libbpf_sys::bpf_map__key_size(ptr),
+                    libbpf_sys::bpf_map__value_size(ptr),
+                )
+            }
+        };
+        if sizes(custom_map) != sizes(map) {
+            return Err(anyhow!(
+                "map {} key/value size mismatch, expecting {:?}, found {:?}",
+                name,
+                sizes(map),
+                sizes(custom_map)
+            ));
         }
-        if let Some(mut hook) = self.attached_ingress_hook.take() {
-            hook.detach()?;
+    }
+
+    for prog in embedded.progs_iter() {
+        let name = prog.name()?;
+        let Some(custom_prog) = custom.prog(name) else {
+            return Err(anyhow!("program {} not found", name));
+        };
+        if custom_prog.section() != prog.section() {
+            return Err(anyhow!(
+                "program {} section mismatch, expecting {}, found {}",
+                name,
+                prog.section(),
+                custom_prog.section()
+            ));
         }
+    }
 
-        Ok(())
+    Ok(())
+}
+
+/// Returns the longest timeout of CTs of binding with `l4proto`.
+fn binding_timeout(skel: &EinatSkel, l4proto: u8) -> Duration {
+    let rodata = skel.rodata();
+    if l4proto == libc::IPPROTO_TCP as u8 {
+        Duration::from_nanos(rodata.TIMEOUT_TCP_EST)
+    } else {
+        Duration::from_nanos(rodata.TIMEOUT_PKT_DEFAULT)
+    }
+}
+
+/// Returns snapshot entries of which the binding would not have timed out
+/// since the snapshot was taken. As monotonic clock differs across reboots
+/// and hosts, the lifetime of bindings is counted from the time snapshot was
+/// taken.
+fn unexpired_snapshot_entries(
+    skel: &EinatSkel,
+    snapshot: BindingSnapshot,
+) -> Vec<(skel::MapBindingKey, skel::MapBindingValue)> {
+    let elapsed = snapshot.time.elapsed().unwrap_or_default();
+    let now_secs = monotonic_now_ns() / 1_000_000_000;
+    let created = now_secs.saturating_sub(elapsed.as_secs()) as u32;
+
+    snapshot
+        .entries
+        .into_iter()
+        .filter_map(|(key, mut value)| {
+            let timeout = binding_timeout(skel, key.l4proto);
+            // Held from reuse and deletion for the rest of timeout, until
+            // referenced by CT again
+            let remaining = timeout.checked_sub(elapsed)?;
+            value.created = created;
+            value.held_until = (now_secs + remaining.as_secs()) as u32;
+            Some((key, value))
+        })
+        .collect()
+}
+
+/// Inserts or updates entries of concatenated raw `keys` and `values` in a
+/// single batch, falling back to updating them one by one if batch operation
+/// is not supported by the map type or kernel.
+fn update_batch_or_each(map: &libbpf_rs::Map, keys: &[u8], values: &[u8]) -> Result<()> {
+    let key_size = map.key_size() as usize;
+    let value_size = map.value_size() as usize;
+    let count = keys.len() / key_size;
+    if count == 0 {
+        return Ok(());
+    }
+    debug!("insert {} entries into map {}", count, map.name());
+
+    if KernelFeatures::get().batch_ops {
+        match map.update_batch(keys, values, count as _, MapFlags::ANY, MapFlags::ANY) {
+            Ok(()) => return Ok(()),
+            Err(e) => debug!(
+                "batch update of map {} failed, updating one by one: {}",
+                map.name(),
+                e
+            ),
+        }
+    }
+
+    for (k, v) in keys
+        .chunks_exact(key_size)
+        .zip(values.chunks_exact(value_size))
+    {
+        map.update(k, v, MapFlags::ANY)?;
+    }
+    Ok(())
+}
+
+const LOCAL_PORTS_SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// max_entries of map_no_snat_dport
+const MAX_NO_SNAT_DPORTS: usize = 4096;
+/// Grace period before removing entries that should have been removed by BPF
+/// programs, to avoid racing with timer callbacks and CT creation.
+const GC_GRACE: Duration = Duration::from_secs(10);
+
+/// Reads all entries of `map` through BPF map element iterator `prog`, which
+/// streams entries in bulk rather than walking keys and looking up each of
+/// them. Falls back to the latter if the iterator is not loaded on kernels
+/// without map iterators or kernel BTF, or fails.
+fn dump_map<K: Pod, V: Pod>(
+    prog: &libbpf_rs::Program,
+    map: &libbpf_rs::Map,
+) -> Result<Vec<(K, V)>> {
+    let key_size = core::mem::size_of::<K>();
+    let entry_size = key_size + core::mem::size_of::<V>();
+
+    if prog.autoload() {
+        match read_map_iter(prog, map) {
+            Ok(buf) => {
+                return Ok(buf
+                    .chunks_exact(entry_size)
+                    .map(|entry| {
+                        let (k, v) = entry.split_at(key_size);
+                        (
+                            bytemuck::pod_read_unaligned(k),
+                            bytemuck::pod_read_unaligned(v),
+                        )
+                    })
+                    .collect());
+            }
+            Err(e) => debug!(
+                "failed to iterate map {}, walking keys instead: {}",
+                map.name(),
+                e
+            ),
+        }
+    }
+
+    walk_map(map)
+}
+
+fn walk_map<K: Pod, V: Pod>(map: &libbpf_rs::Map) -> Result<Vec<(K, V)>> {
+    let mut entries = Vec::new();
+    for key_raw in map.keys() {
+        // entry might be deleted during iteration
+        let Some(value_raw) = map.lookup(&key_raw, MapFlags::ANY)? else {
+            continue;
+        };
+        entries.push((
+            bytemuck::pod_read_unaligned(&key_raw),
+            bytemuck::pod_read_unaligned(&value_raw),
+        ));
+    }
+    Ok(entries)
+}
+
+fn read_map_iter(prog: &libbpf_rs::Program, map: &libbpf_rs::Map) -> Result<Vec<u8>> {
+    let mut link_info = libbpf_sys::bpf_iter_link_info::default();
+    link_info.map.map_fd = map.as_fd().as_raw_fd() as _;
+    let opts = libbpf_sys::bpf_link_create_opts {
+        sz: core::mem::size_of::<libbpf_sys::bpf_link_create_opts>() as _,
+        iter_info: &mut link_info,
+        iter_info_len: core::mem::size_of_val(&link_info) as _,
+        ..Default::default()
+    };
+    // `Program::attach_iter` requires mutable reference to program
+    let link_fd = unsafe {
+        libbpf_sys::bpf_link_create(
+            prog.as_fd().as_raw_fd(),
+            0,
+            libbpf_sys::BPF_TRACE_ITER,
+            &opts,
+        )
+    };
+    if link_fd < 0 {
+        return Err(std::io::Error::from_raw_os_error(-link_fd).into());
     }
+    let link_fd = unsafe { OwnedFd::from_raw_fd(link_fd) };
+
+    let iter_fd = unsafe { libbpf_sys::bpf_iter_create(link_fd.as_raw_fd()) };
+    if iter_fd < 0 {
+        return Err(std::io::Error::from_raw_os_error(-iter_fd).into());
+    }
+    let mut iter = File::from(unsafe { OwnedFd::from_raw_fd(iter_fd) });
+
+    let mut buf = Vec::new();
+    iter.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn dump_bindings(skel: &EinatSkel) -> Result<Vec<(skel::MapBindingKey, skel::MapBindingValue)>> {
+    dump_map(skel.progs().dump_binding(), skel.maps().map_binding())
+}
+
+fn dump_cts(skel: &EinatSkel) -> Result<Vec<(skel::MapCtKey, skel::MapCtValue)>> {
+    dump_map(skel.progs().dump_ct(), skel.maps().map_ct())
 }
 
 fn with_skel_deleting<T, F: FnOnce(&mut EinatSkel) -> T>(skel: &mut EinatSkel, f: F) -> T {
@@ -891,12 +3918,8 @@ fn with_skel_deleting<T, F: FnOnce(&mut EinatSkel) -> T>(skel: &mut EinatSkel, f
     res
 }
 
-fn remove_binding_and_ct_entries(skel: &EinatSkel, external_addr: IpAddr) -> Result<()> {
-    use skel::{BindingFlags, InetAddr, MapBindingKey, MapBindingValue, MapCtKey};
-
-    let maps = skel.maps();
-    let map_binding = maps.map_binding();
-    let map_ct = maps.map_ct();
+fn remove_binding_and_ct_entries(skel: &mut EinatSkel, external_addr: IpAddr) -> Result<()> {
+    use skel::{BindingFlags, InetAddr};
 
     let addr_flag = if external_addr.is_ipv4() {
         BindingFlags::ADDR_IPV4
@@ -905,44 +3928,475 @@ fn remove_binding_and_ct_entries(skel: &EinatSkel, external_addr: IpAddr) -> Res
     };
     let external_addr: InetAddr = external_addr.into();
 
+    remove_binding_and_ct_entries_if(skel, |_, flags, addr| {
+        flags.contains(addr_flag) && *addr == external_addr
+    })?;
+
+    Ok(())
+}
+
+/// Removes binding and CT entries of which the predicate returns true on
+/// (interface index, address family flag, external address), returns numbers
+/// of removed binding and CT entries.
+fn remove_binding_and_ct_entries_if<F>(skel: &mut EinatSkel, pred: F) -> Result<(usize, usize)>
+where
+    F: Fn(u32, skel::BindingFlags, &skel::InetAddr) -> bool,
+{
+    use skel::{BindingFlags, MapBindingKey, MapCtKey};
+
+    let maps = skel.maps();
+    let map_binding = maps.map_binding();
+    let map_ct = maps.map_ct();
+
+    let mut bindings = Vec::new();
     let mut to_delete_binding_keys = Vec::new();
-    for binding_key_raw in map_binding.keys() {
-        let binding_key: &MapBindingKey = bytemuck::from_bytes(&binding_key_raw);
-        if binding_key.flags.contains(BindingFlags::ORIG_DIR) {
-            if let Some(binding_value_raw) = map_binding.lookup(&binding_key_raw, MapFlags::ANY)? {
-                let binding_value: &MapBindingValue = bytemuck::from_bytes(&binding_value_raw);
-                if binding_value.flags.contains(addr_flag) && binding_value.to_addr == external_addr
-                {
-                    to_delete_binding_keys.extend(binding_key_raw);
-                }
-            }
-        } else if binding_key.flags.contains(addr_flag) && binding_key.from_addr == external_addr {
-            to_delete_binding_keys.extend(binding_key_raw);
+    for (binding_key, binding_value) in dump_bindings(skel)? {
+        let matched = if binding_key.flags.contains(BindingFlags::ORIG_DIR) {
+            pred(
+                binding_key.if_index,
+                binding_value.flags,
+                &binding_value.to_addr,
+            )
+        } else {
+            pred(
+                binding_key.if_index,
+                binding_key.flags,
+                &binding_key.from_addr,
+            )
+        };
+        if matched {
+            to_delete_binding_keys.extend(bytemuck::bytes_of(&binding_key));
+        } else {
+            bindings.push((binding_key, binding_value));
         }
     }
 
+    let binding_count = to_delete_binding_keys.len() / core::mem::size_of::<MapBindingKey>();
     if !to_delete_binding_keys.is_empty() {
         map_binding.delete_batch(
             &to_delete_binding_keys,
-            (to_delete_binding_keys.len() / core::mem::size_of::<MapBindingKey>()) as _,
+            binding_count as _,
             MapFlags::ANY,
             MapFlags::ANY,
         )?;
     }
 
+    let mut cts = Vec::new();
     let mut to_delete_ct_keys = Vec::new();
-    for ct_key_raw in map_ct.keys() {
-        let ct_key: &MapCtKey = bytemuck::from_bytes(&ct_key_raw);
-        if ct_key.flags.contains(addr_flag) && ct_key.external.src_addr == external_addr {
-            to_delete_ct_keys.extend(ct_key_raw);
+    for (ct_key, ct_value) in dump_cts(skel)? {
+        if pred(ct_key.if_index, ct_key.flags, &ct_key.external.src_addr) {
+            to_delete_ct_keys.extend(bytemuck::bytes_of(&ct_key));
+        } else {
+            cts.push((ct_key, ct_value));
         }
     }
 
+    let ct_count = to_delete_ct_keys.len() / core::mem::size_of::<MapCtKey>();
     if !to_delete_ct_keys.is_empty() {
         map_ct.delete_batch(
             &to_delete_ct_keys,
-            (to_delete_ct_keys.len() / core::mem::size_of::<MapCtKey>()) as _,
+            ct_count as _,
+            MapFlags::ANY,
+            MapFlags::ANY,
+        )?;
+    }
+
+    rebuild_counters(skel, &cts, &bindings)?;
+
+    Ok((binding_count, ct_count))
+}
+
+#[derive(Debug, Default)]
+struct GcStats {
+    cts: usize,
+    bindings: usize,
+    fixed_refs: usize,
+}
+
+/// Removes CTs of which the timeout has lapsed but the timer failed to fire,
+/// e.g. carried over in pinned map, or of which the binding is gone, and
+/// removes bindings not referenced by any CT. Reference counts of remaining
+/// bindings are recounted from CTs.
+///
+/// Maps are dumped and examined while BPF programs keep running, which are only
+/// prevented from creating new entries while removing and fixing entries, and
+/// entries changed since dumped are left to next collection.
+fn collect_garbage(skel: &mut EinatSkel) -> Result<GcStats> {
+    use skel::{
+        BindingFlags, MapBindingKey, MapBindingValue, MapCtKey, MapCtValue,
+        BINDING_ORIG_REF_COUNTED, CT_INIT_IN,
+    };
+
+    let now = monotonic_now_ns();
+    let grace = GC_GRACE.as_nanos() as u64;
+    let mut stats = GcStats::default();
+
+    // CTs are dumped before bindings, so binding of a CT is always found in
+    // dump unless the CT is dangling.
+    let cts = dump_cts(skel)?;
+    let bindings: HashMap<MapBindingKey, MapBindingValue> =
+        dump_bindings(skel)?.into_iter().collect();
+
+    // (ref, use) of reverse direction bindings counted from live CTs
+    let mut refs: HashMap<MapBindingKey, (u32, u32)> = HashMap::new();
+    let mut stale_cts: Vec<[(MapCtKey, MapCtValue); 1]> = Vec::new();
+    for (ct_key, ct_value) in cts {
+        let b_key_rev = MapBindingKey {
+            if_index: ct_key.if_index,
+            flags: ct_key.flags,
+            l4proto: ct_key.l4proto,
+            from_port: ct_key.external.src_port,
+            from_addr: ct_key.external.src_addr,
+        };
+        let dangling = bindings
+            .get(&b_key_rev)
+            .map_or(true, |b_value_rev| b_value_rev.seq != ct_value.seq);
+        let expired = ct_value.expires != 0 && ct_value.expires.saturating_add(grace) < now;
+        if dangling || expired {
+            stale_cts.push([(ct_key, ct_value)]);
+            continue;
+        }
+        let (ref_, use_) = refs.entry(b_key_rev).or_default();
+        *ref_ += 1;
+        if ct_value.state != CT_INIT_IN {
+            *use_ += 1;
+        }
+    }
+
+    let now_secs = now / 1_000_000_000;
+    // Bindings of a pair are removed together or not at all
+    let mut stale_bindings: Vec<Vec<(MapBindingKey, MapBindingValue)>> = Vec::new();
+    let mut fixed_bindings = Vec::new();
+    for (key, value) in bindings.iter() {
+        if value.is_static != 0 {
+            continue;
+        }
+        let is_orig = key.flags.contains(BindingFlags::ORIG_DIR);
+        let key_pair = MapBindingKey {
+            if_index: key.if_index,
+            flags: if is_orig {
+                value.flags - BindingFlags::ORIG_DIR
+            } else {
+                value.flags | BindingFlags::ORIG_DIR
+            },
+            l4proto: key.l4proto,
+            from_port: value.to_port,
+            from_addr: value.to_addr,
+        };
+        let value_pair = bindings
+            .get(&key_pair)
+            .filter(|value_pair| value_pair.seq == value.seq);
+
+        if is_orig {
+            // the pair is handled along with reverse direction binding
+            if value_pair.is_none() {
+                stale_bindings.push(vec![(*key, *value)]);
+            }
+            continue;
+        }
+
+        if let Some(&(ref_, use_)) = refs.get(key) {
+            if value.ref_ != ref_ || value.use_ != use_ {
+                let fixed = MapBindingValue {
+                    ref_,
+                    use_,
+                    ..*value
+                };
+                fixed_bindings.push((*key, *value, fixed));
+            }
+            continue;
+        }
+
+        // Binding not yet referenced by CT is subject to reuse, only remove it
+        // after grace period as CT might be inserted right after.
+        let Some(value_pair) = value_pair else {
+            stale_bindings.push(vec![(*key, *value)]);
+            continue;
+        };
+        if (value.held_until as u64) > now_secs {
+            continue;
+        }
+        if value_pair.ref_ == BINDING_ORIG_REF_COUNTED
+            || (value.created as u64).saturating_add(GC_GRACE.as_secs()) < now_secs
+        {
+            stale_bindings.push(vec![(*key, *value), (key_pair, *value_pair)]);
+        }
+    }
+
+    with_skel_deleting(skel, |skel| {
+        let maps = skel.maps();
+        let map_binding = maps.map_binding();
+        let map_ct = maps.map_ct();
+
+        // bpf_timer is opaque and not preserved across dump and lookup
+        stats.cts = delete_unchanged_entries(map_ct, &stale_cts, |a, b| {
+            MapCtValue {
+                timer: b.timer,
+                ..*a
+            } == *b
+        })?;
+
+        // BPF timers still update reference counts of bindings on CT expiry
+        for (key, value, fixed) in fixed_bindings.iter() {
+            if !entries_unchanged(map_binding, &[(*key, *value)], PartialEq::eq)? {
+                continue;
+            }
+            map_binding.update(
+                bytemuck::bytes_of(key),
+                bytemuck::bytes_of(fixed),
+                MapFlags::EXIST,
+            )?;
+            stats.fixed_refs += 1;
+        }
+
+        stats.bindings = delete_unchanged_entries(map_binding, &stale_bindings, PartialEq::eq)?;
+
+        // Counters are rebuilt from entries dumped again while new entries are
+        // blocked, so CTs created since the first dump are counted as well.
+        let cts = dump_cts(skel)?;
+        let bindings = dump_bindings(skel)?;
+        rebuild_counters(skel, &cts, &bindings)?;
+
+        Ok(stats)
+    })
+}
+
+/// Returns true if `entries` are still in `map` with values `same` as dumped.
+fn entries_unchanged<K: Pod, V: Pod, F: Fn(&V, &V) -> bool>(
+    map: &libbpf_rs::Map,
+    entries: &[(K, V)],
+    same: F,
+) -> Result<bool> {
+    for (key, value) in entries {
+        let Some(value_raw) = map.lookup(bytemuck::bytes_of(key), MapFlags::ANY)? else {
+            return Ok(false);
+        };
+        if !same(value, &bytemuck::pod_read_unaligned(&value_raw)) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Deletes groups of dumped entries from `map` of which all entries are
+/// unchanged, returns number of deleted entries.
+fn delete_unchanged_entries<K: Pod, V: Pod, G: AsRef<[(K, V)]>, F: Fn(&V, &V) -> bool>(
+    map: &libbpf_rs::Map,
+    groups: &[G],
+    same: F,
+) -> Result<usize> {
+    let mut keys = Vec::new();
+    for group in groups {
+        let group = group.as_ref();
+        if entries_unchanged(map, group, &same)? {
+            keys.extend(group.iter().flat_map(|(key, _)| bytemuck::bytes_of(key)));
+        }
+    }
+
+    let count = keys.len() / core::mem::size_of::<K>();
+    if count != 0 {
+        map.delete_batch(&keys, count as _, MapFlags::ANY, MapFlags::ANY)?;
+    }
+    Ok(count)
+}
+
+/// Rebuilds counters of BPF programs from CT and binding entries remaining in
+/// maps, dumped once for all counters.
+fn rebuild_counters(
+    skel: &mut EinatSkel,
+    cts: &[(skel::MapCtKey, skel::MapCtValue)],
+    bindings: &[(skel::MapBindingKey, skel::MapBindingValue)],
+) -> Result<()> {
+    rebuild_host_sessions(skel, cts)?;
+    rebuild_host_embryonic(skel, cts)?;
+    rebuild_udp_cts(skel, cts);
+    rebuild_host_ports(skel, bindings)?;
+    rebuild_filter_addrs(skel, cts)?;
+    Ok(())
+}
+
+/// Recounts CTs of internal hosts, as CTs deleted by us or carried over in
+/// pinned map are not counted by BPF programs.
+fn rebuild_host_sessions(
+    skel: &EinatSkel,
+    cts: &[(skel::MapCtKey, skel::MapCtValue)],
+) -> Result<()> {
+    use skel::MapHostKey;
+
+    if skel.rodata().MAX_SESSIONS_PER_HOST == 0 {
+        return Ok(());
+    }
+
+    let maps = skel.maps();
+    let map_host_sessions = maps.map_host_sessions();
+
+    let mut counts: HashMap<MapHostKey, u32> = HashMap::new();
+    for (ct_key, ct_value) in cts {
+        let host_key = MapHostKey {
+            if_index: ct_key.if_index,
+            flags: ct_value.flags,
+            addr: ct_value.origin.src_addr,
+            ..Default::default()
+        };
+        *counts.entry(host_key).or_default() += 1;
+    }
+
+    for key in map_host_sessions.keys().collect::<Vec<_>>() {
+        let _ = map_host_sessions.delete(&key);
+    }
+    for (key, count) in counts {
+        map_host_sessions.update(
+            bytemuck::bytes_of(&key),
+            &count.to_ne_bytes(),
+            MapFlags::ANY,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Recounts half-open TCP CTs of internal hosts, likewise.
+fn rebuild_host_embryonic(
+    skel: &EinatSkel,
+    cts: &[(skel::MapCtKey, skel::MapCtValue)],
+) -> Result<()> {
+    use skel::MapHostKey;
+
+    if skel.rodata().MAX_EMBRYONIC_PER_HOST == 0 {
+        return Ok(());
+    }
+
+    let maps = skel.maps();
+    let map_host_embryonic = maps.map_host_embryonic();
+
+    let mut counts: HashMap<MapHostKey, u32> = HashMap::new();
+    for (ct_key, ct_value) in cts {
+        if ct_key.l4proto != libc::IPPROTO_TCP as u8 || ct_value.state != skel::CT_INIT_OUT {
+            continue;
+        }
+        let host_key = MapHostKey {
+            if_index: ct_key.if_index,
+            flags: ct_value.flags,
+            addr: ct_value.origin.src_addr,
+            ..Default::default()
+        };
+        *counts.entry(host_key).or_default() += 1;
+    }
+
+    for key in map_host_embryonic.keys().collect::<Vec<_>>() {
+        let _ = map_host_embryonic.delete(&key);
+    }
+    for (key, count) in counts {
+        map_host_embryonic.update(
+            bytemuck::bytes_of(&key),
+            &count.to_ne_bytes(),
+            MapFlags::ANY,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Recounts UDP CTs, likewise.
+fn rebuild_udp_cts(skel: &mut EinatSkel, cts: &[(skel::MapCtKey, skel::MapCtValue)]) {
+    if skel.rodata().UDP_HIGH_WATER == 0 {
+        return;
+    }
+
+    let count = cts
+        .iter()
+        .filter(|(ct_key, _)| ct_key.l4proto == libc::IPPROTO_UDP as u8)
+        .count();
+    skel.data_mut().g_udp_cts = count as u32;
+}
+
+/// Recounts CTs between external endpoints and remote addresses, as CTs
+/// deleted by us or carried over in pinned map are not counted by BPF programs.
+fn rebuild_filter_addrs(
+    skel: &EinatSkel,
+    cts: &[(skel::MapCtKey, skel::MapCtValue)],
+) -> Result<()> {
+    use skel::MapFilterAddrKey;
+
+    if skel.rodata().FILTER_ADDR_TRACKING == 0 {
+        return Ok(());
+    }
+
+    let maps = skel.maps();
+    let map_filter_addr = maps.map_filter_addr();
+
+    let mut counts: HashMap<MapFilterAddrKey, u32> = HashMap::new();
+    for (ct_key, _) in cts {
+        let key = MapFilterAddrKey {
+            if_index: ct_key.if_index,
+            flags: ct_key.flags,
+            l4proto: ct_key.l4proto,
+            port: ct_key.external.src_port,
+            external_addr: ct_key.external.src_addr,
+            remote_addr: ct_key.external.dst_addr,
+        };
+        *counts.entry(key).or_default() += 1;
+    }
+
+    for key in map_filter_addr.keys().collect::<Vec<_>>() {
+        let _ = map_filter_addr.delete(&key);
+    }
+    for (key, count) in counts {
+        map_filter_addr.update(
+            bytemuck::bytes_of(&key),
+            &count.to_ne_bytes(),
             MapFlags::ANY,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Recounts ports held by internal hosts, as bindings deleted by us or carried
+/// over in pinned map are not counted by BPF programs.
+fn rebuild_host_ports(
+    skel: &EinatSkel,
+    bindings: &[(skel::MapBindingKey, skel::MapBindingValue)],
+) -> Result<()> {
+    use skel::{BindingFlags, HostPorts, MapHostKey, BINDING_ORIG_REF_COUNTED};
+
+    let rodata = skel.rodata();
+    if rodata.PORT_QUOTA_TCP == 0 && rodata.PORT_QUOTA_UDP == 0 && rodata.PORT_QUOTA_ICMP == 0 {
+        return Ok(());
+    }
+
+    let maps = skel.maps();
+    let map_host_ports = maps.map_host_ports();
+
+    let mut counts: HashMap<MapHostKey, HostPorts> = HashMap::new();
+    for (key, value) in bindings {
+        if !key.flags.contains(BindingFlags::ORIG_DIR) {
+            continue;
+        }
+        if value.ref_ != BINDING_ORIG_REF_COUNTED {
+            continue;
+        }
+        let host_key = MapHostKey {
+            if_index: key.if_index,
+            flags: key.flags & (BindingFlags::ADDR_IPV4 | BindingFlags::ADDR_IPV6),
+            addr: key.from_addr,
+            ..Default::default()
+        };
+        let ports = counts.entry(host_key).or_default();
+        match key.l4proto as i32 {
+            libc::IPPROTO_TCP => ports.tcp += 1,
+            libc::IPPROTO_UDP => ports.udp += 1,
+            _ => ports.icmp += 1,
+        }
+    }
+
+    for key in map_host_ports.keys().collect::<Vec<_>>() {
+        let _ = map_host_ports.delete(&key);
+    }
+    for (key, ports) in counts {
+        map_host_ports.update(
+            bytemuck::bytes_of(&key),
+            bytemuck::bytes_of(&ports),
             MapFlags::ANY,
         )?;
     }
@@ -950,6 +4404,9 @@ fn remove_binding_and_ct_entries(skel: &EinatSkel, external_addr: IpAddr) -> Res
     Ok(())
 }
 
+#[cfg(test)]
+mod datapath_tests;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -977,4 +4434,165 @@ mod tests {
         let ranges_d = ExternalRanges::try_from(&ranges_d, false);
         assert!(ranges_d.is_err())
     }
+
+    #[test]
+    fn netmap_prefixes() {
+        let netmap = |internal: &str, external: &str| ConfigNetmap {
+            internal_prefix: internal.parse().unwrap(),
+            external_prefix: external.parse().unwrap(),
+        };
+        assert!(check_netmap(&[
+            netmap("10.0.0.0/24", "203.0.113.0/24"),
+            netmap("10.0.1.0/25", "198.51.100.128/25"),
+        ])
+        .is_ok());
+        assert!(check_netmap(&[netmap("10.0.0.0/24", "203.0.113.0/25")]).is_err());
+        assert!(check_netmap(&[
+            netmap("10.0.0.0/24", "203.0.113.0/24"),
+            netmap("10.0.0.128/25", "198.51.100.128/25"),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn static_binding_ports() {
+        let external: ConfigExternal = toml::from_str(r#"address = "10.0.1.100""#).unwrap();
+        let externals = [External::try_from(&external, &Default::default(), None).unwrap()];
+        let binding = |internal: &str, external_port, proto| ConfigStaticBinding {
+            internal: internal.parse().unwrap(),
+            external_port,
+            proto,
+            external_address: None,
+        };
+        assert!(check_static_bindings(
+            &[
+                binding("192.168.1.5:25565", 25565, IpProtocol::Tcp),
+                binding("192.168.1.5:25565", 25565, IpProtocol::Udp),
+            ],
+            &externals
+        )
+        .is_ok());
+        // out of default port ranges
+        assert!(check_static_bindings(
+            &[binding("192.168.1.5:80", 80, IpProtocol::Tcp)],
+            &externals
+        )
+        .is_err());
+        assert!(check_static_bindings(
+            &[
+                binding("192.168.1.5:25565", 25565, IpProtocol::Tcp),
+                binding("192.168.1.6:25565", 25565, IpProtocol::Tcp),
+            ],
+            &externals
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn external_protocols() {
+        let external = |toml: &str| {
+            let external: ConfigExternal = toml::from_str(toml).unwrap();
+            External::try_from(&external, &Default::default(), None)
+        };
+        let udp_only = external(
+            r#"
+address = "10.0.1.100"
+protocols = ["udp", "icmp"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            udp_only.protocols,
+            ExternalProtocols::UDP | ExternalProtocols::ICMP
+        );
+        assert!(!external_serves(udp_only.protocols, IpProtocol::Tcp));
+        let all = external(r#"address = "10.0.1.100""#).unwrap();
+        assert!(external_serves(all.protocols, IpProtocol::Tcp));
+
+        assert!(external(
+            r#"
+address = "10.0.1.100"
+protocols = []
+"#
+        )
+        .is_err());
+        assert!(external(
+            r#"
+address = "10.0.1.100"
+protocols = ["tcp", "tcp"]
+"#
+        )
+        .is_err());
+        assert!(external(
+            r#"
+address = "10.0.1.100"
+protocols = ["udp"]
+tcp_ranges = ["20000-29999"]
+"#
+        )
+        .is_err());
+        assert!(external(
+            r#"
+address = "10.0.1.100"
+no_snat = true
+protocols = ["udp"]
+"#
+        )
+        .is_err());
+
+        let binding = ConfigStaticBinding {
+            internal: "192.168.1.5:25565".parse().unwrap(),
+            external_port: 25565,
+            proto: IpProtocol::Tcp,
+            external_address: None,
+        };
+        assert!(check_static_bindings(&[binding], &[udp_only]).is_err());
+        assert!(check_static_bindings(&[binding], &[all]).is_ok());
+    }
+
+    #[test]
+    fn external_pool_tables() {
+        let all = ExternalProtocols::empty();
+        let tcp = ExternalProtocols::TCP;
+        let udp = ExternalProtocols::UDP | ExternalProtocols::ICMP;
+        let tables =
+            ExternalPoolTables::new(&[((), 1, tcp), ((), 2, udp), ((), 3, all), ((), 0, tcp)]);
+        assert_eq!(tables.first, [0, 1, 1]);
+        assert_eq!(tables.weight[0][..4], [1, 1, 4, 4]);
+        assert_eq!(tables.weight[1][..4], [0, 2, 5, 5]);
+        assert_eq!(tables.next[0][..4], [2, 2, 3, 0]);
+        assert_eq!(tables.next[1][..4], [1, 2, 1, 1]);
+
+        let tables = ExternalPoolTables::new(&[((), 1, tcp), ((), 1, all)]);
+        assert_eq!(tables.next[1][..2], [1, 1]);
+    }
+
+    #[cfg(feature = "ipv6")]
+    #[test]
+    fn nptv6_adjustment() {
+        let config = ConfigNptv6 {
+            internal_prefix: "fd01:203:405::/48".parse().unwrap(),
+            external_prefix: None,
+            prefix_if_name: None,
+        };
+        let addresses = ["fe80::1".parse().unwrap(), "2001:db8:1::1".parse().unwrap()];
+        let nptv6 = Nptv6::resolve(&config, &addresses, &addresses).unwrap();
+        assert_eq!(nptv6.external_prefix, "2001:db8:1::/48".parse().unwrap());
+        assert_eq!(nptv6.local_addrs, vec![addresses[1]]);
+
+        // prefix delegated to internal interface
+        let prefix_addresses = ["2001:db8:2:1::1".parse().unwrap()];
+        let delegated = Nptv6::resolve(&config, &addresses, &prefix_addresses).unwrap();
+        assert_eq!(
+            delegated.external_prefix,
+            "2001:db8:2::/48".parse().unwrap()
+        );
+        assert!(delegated.local_addrs.is_empty());
+
+        // example of RFC 6296 section 3.6, translating
+        // fd01:203:405:1::1234 to 2001:db8:1:d550::1234
+        let subnet = ones_complement_add(0x0001, nptv6.adjustment());
+        assert_eq!(subnet, 0xd550);
+        assert_eq!(ones_complement_add(subnet, !nptv6.adjustment()), 0x0001);
+    }
 }
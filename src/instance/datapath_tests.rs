@@ -0,0 +1,256 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! Datapath tests feeding synthetic packets through loaded TC programs with
+//! `BPF_PROG_TEST_RUN`, these require root privilege.
+use std::net::SocketAddr;
+
+use super::*;
+use crate::bench::{checksum, l3_offset, pseudo_header_sum, udp_endpoints, udp_packet};
+use crate::config::NetIfId;
+
+/// Passes packet on, returned when translated or not to be translated
+const TC_ACT_UNSPEC: i32 = -1;
+const TC_ACT_SHOT: i32 = 2;
+const ENCAP: PacketEncap = PacketEncap::Ethernet;
+
+const EXTERNAL: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 100);
+const INTERNAL: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 100);
+const REMOTE: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+
+fn if_config() -> ConfigNetIf {
+    ConfigNetIf {
+        interface: NetIfId::Index { if_index: 1 },
+        nat44: true,
+        default_externals: true,
+        ..Default::default()
+    }
+}
+
+fn load_instance() -> Instance {
+    load_instance_with(&if_config())
+}
+
+fn load_instance_with(if_config: &ConfigNetIf) -> Instance {
+    let addresses = IfAddresses {
+        ipv4: vec![EXTERNAL],
+        #[cfg(feature = "ipv6")]
+        ipv6: Vec::new(),
+        attrs: Default::default(),
+        command_outputs: Default::default(),
+        route_sources: Default::default(),
+        nft_set_dests: Default::default(),
+    };
+    InstanceConfig::try_from(
+        1,
+        None,
+        ENCAP,
+        if_config,
+        &ConfigDefaults::default(),
+        &addresses,
+    )
+    .unwrap()
+    .load()
+    .unwrap()
+}
+
+fn packet(src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16)) -> Vec<u8> {
+    udp_packet(&ENCAP, src.into(), dst.into(), b"hello")
+}
+
+/// Returns source and destination of IPv4 UDP packet, after asserting its
+/// checksums are valid.
+fn parse_packet(pkt: &[u8]) -> (SocketAddr, SocketAddr) {
+    let (src, dst) = udp_endpoints(&ENCAP, pkt);
+    let ip = &pkt[l3_offset(&ENCAP)..][..20];
+    let udp = &pkt[l3_offset(&ENCAP) + 20..];
+    assert_eq!(checksum(ip, 0), 0, "invalid IPv4 checksum");
+    let sum = pseudo_header_sum(src.ip(), dst.ip(), ip[9], udp.len());
+    assert_eq!(checksum(udp, sum), 0, "invalid UDP checksum");
+    (src, dst)
+}
+
+#[test]
+#[ignore = "bpf"]
+fn udp_translation() {
+    let inst = load_instance();
+
+    let pkt = packet((INTERNAL, 5000), (REMOTE, 3478));
+    let (ret, out, _) = inst.test_run(false, &pkt, 1).unwrap();
+    assert_eq!(ret, TC_ACT_UNSPEC);
+    let (mapped, dst) = parse_packet(&out);
+    assert_eq!(mapped.ip(), EXTERNAL);
+    assert!((20000..=29999).contains(&mapped.port()));
+    assert_eq!(dst, (REMOTE, 3478).into());
+
+    let bindings = dump_bindings(&inst.skel).unwrap();
+    assert_eq!(bindings.len(), 2, "expecting orig and reverse bindings");
+    assert_eq!(dump_cts(&inst.skel).unwrap().len(), 1);
+
+    // the same mapping is used for other destinations
+    let pkt = packet((INTERNAL, 5000), (REMOTE, 4000));
+    let (_, out, _) = inst.test_run(false, &pkt, 1).unwrap();
+    assert_eq!(parse_packet(&out).0, mapped);
+
+    // replies and inbound from any remote are translated back
+    for remote_port in [3478, 5555] {
+        let pkt = udp_packet(&ENCAP, (REMOTE, remote_port).into(), mapped, b"reply");
+        let (ret, out, _) = inst.test_run(true, &pkt, 1).unwrap();
+        assert_eq!(ret, TC_ACT_UNSPEC);
+        let (src, dst) = parse_packet(&out);
+        assert_eq!(src, (REMOTE, remote_port).into());
+        assert_eq!(dst, (INTERNAL, 5000).into());
+    }
+}
+
+#[test]
+#[ignore = "bpf"]
+fn unsolicited_inbound() {
+    let inst = load_instance();
+
+    // dropped if in NAT port range
+    let pkt = packet((REMOTE, 3478), (EXTERNAL, 25000));
+    let (ret, _, _) = inst.test_run(true, &pkt, 1).unwrap();
+    assert_eq!(ret, TC_ACT_SHOT);
+
+    // passed untouched to local services otherwise
+    let pkt = packet((REMOTE, 3478), (EXTERNAL, 53));
+    let (ret, out, _) = inst.test_run(true, &pkt, 1).unwrap();
+    assert_eq!(ret, TC_ACT_UNSPEC);
+    assert_eq!(out, pkt);
+
+    assert!(dump_bindings(&inst.skel).unwrap().is_empty());
+    assert!(dump_cts(&inst.skel).unwrap().is_empty());
+}
+
+#[test]
+#[ignore = "bpf"]
+fn drop_unsolicited_local_sockets() {
+    let inst = load_instance_with(&ConfigNetIf {
+        drop_unsolicited: true,
+        ..if_config()
+    });
+
+    // reply to ephemeral port of flow initiated by this host is passed
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+    let port = socket.local_addr().unwrap().port();
+    assert!(!(20000..=29999).contains(&port));
+    let pkt = packet((REMOTE, 53), (EXTERNAL, port));
+    let (ret, out, _) = inst.test_run(true, &pkt, 1).unwrap();
+    assert_eq!(ret, TC_ACT_UNSPEC);
+    assert_eq!(out, pkt);
+
+    // dropped once the socket is closed
+    drop(socket);
+    let (ret, _, _) = inst.test_run(true, &pkt, 1).unwrap();
+    assert_eq!(ret, TC_ACT_SHOT);
+}
+
+#[test]
+#[ignore = "bpf"]
+fn restored_binding_held() {
+    let path = std::env::temp_dir().join(format!("einat-snapshot-{}", std::process::id()));
+    let inst = load_instance();
+    let pkt = packet((INTERNAL, 25000), (REMOTE, 3478));
+    let (_, out, _) = inst.test_run(false, &pkt, 1).unwrap();
+    let (mapped, _) = parse_packet(&out);
+    assert_eq!(mapped, (EXTERNAL, 25000).into());
+    BindingSnapshot::new(dump_bindings(&inst.skel).unwrap())
+        .save(&path)
+        .unwrap();
+    drop(inst);
+
+    let mut inst = load_instance_with(&ConfigNetIf {
+        binding_snapshot: Some(path.clone()),
+        ..if_config()
+    });
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(dump_bindings(&inst.skel).unwrap().len(), 2);
+
+    // restored binding without CT is neither reused by another host
+    let pkt = packet((Ipv4Addr::new(192, 168, 1, 101), 25000), (REMOTE, 3478));
+    let (_, out, _) = inst.test_run(false, &pkt, 1).unwrap();
+    assert_ne!(parse_packet(&out).0, mapped);
+
+    // nor collected as garbage
+    let stats = collect_garbage(&mut inst.skel).unwrap();
+    assert_eq!(stats.bindings, 0);
+
+    // and still used by its internal host
+    let pkt = packet((INTERNAL, 25000), (REMOTE, 4000));
+    let (_, out, _) = inst.test_run(false, &pkt, 1).unwrap();
+    assert_eq!(parse_packet(&out).0, mapped);
+}
+
+#[test]
+#[ignore = "bpf"]
+fn garbage_collection() {
+    let mut inst = load_instance();
+
+    let pkt = packet((INTERNAL, 5000), (REMOTE, 3478));
+    inst.test_run(false, &pkt, 1).unwrap();
+
+    // live CT and its bindings are kept
+    let stats = collect_garbage(&mut inst.skel).unwrap();
+    assert_eq!((stats.cts, stats.bindings, stats.fixed_refs), (0, 0, 0));
+    assert_eq!(dump_bindings(&inst.skel).unwrap().len(), 2);
+    assert_eq!(dump_cts(&inst.skel).unwrap().len(), 1);
+
+    // CT and orig binding left dangling by reverse binding are removed
+    let (key_rev, _) = dump_bindings(&inst.skel)
+        .unwrap()
+        .into_iter()
+        .find(|(key, _)| !key.flags.contains(skel::BindingFlags::ORIG_DIR))
+        .unwrap();
+    inst.skel
+        .maps()
+        .map_binding()
+        .delete(bytemuck::bytes_of(&key_rev))
+        .unwrap();
+    let stats = collect_garbage(&mut inst.skel).unwrap();
+    assert_eq!((stats.cts, stats.bindings), (1, 1));
+    assert!(dump_bindings(&inst.skel).unwrap().is_empty());
+    assert!(dump_cts(&inst.skel).unwrap().is_empty());
+}
+
+#[test]
+#[ignore = "bpf"]
+fn deterministic_nat_blocks() {
+    let inst = load_instance_with(&ConfigNetIf {
+        deterministic_nat: Some(ConfigDeterministicNat {
+            internal_network: "192.168.1.100/30".parse().unwrap(),
+            block_size: std::num::NonZeroU16::new(8).unwrap(),
+        }),
+        ..if_config()
+    });
+
+    // the first host of network gets the first block
+    let pkt = packet((INTERNAL, 5000), (REMOTE, 3478));
+    let (_, out, _) = inst.test_run(false, &pkt, 1).unwrap();
+    assert!((20000..=20007).contains(&parse_packet(&out).0.port()));
+
+    // hosts out of network never get ports of any block, even if preferred
+    let pkt = packet((Ipv4Addr::new(192, 168, 2, 1), 20009), (REMOTE, 3478));
+    let (_, out, _) = inst.test_run(false, &pkt, 1).unwrap();
+    assert!((20032..=29999).contains(&parse_packet(&out).0.port()));
+}
+
+#[test]
+#[ignore = "bpf"]
+fn dump_map_fallback() {
+    let inst = load_instance();
+    let pkt = packet((INTERNAL, 5000), (REMOTE, 3478));
+    inst.test_run(false, &pkt, 1).unwrap();
+
+    // walking keys yields the same entries as iterators, if loaded
+    let bindings: HashMap<_, _> = dump_bindings(&inst.skel).unwrap().into_iter().collect();
+    let walked: HashMap<_, _> = walk_map(inst.skel.maps().map_binding())
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(bindings.len(), 2);
+    assert_eq!(walked, bindings);
+
+    let cts: Vec<(skel::MapCtKey, skel::MapCtValue)> = walk_map(inst.skel.maps().map_ct()).unwrap();
+    assert_eq!(cts.len(), 1);
+    assert_eq!(cts, dump_cts(&inst.skel).unwrap());
+}
@@ -1,55 +1,167 @@
 // SPDX-FileCopyrightText: 2023 Huang-Huang Bao
 // SPDX-License-Identifier: GPL-2.0-or-later
+mod bench;
+mod capture;
+#[cfg(feature = "ipv6")]
+mod clat;
 mod config;
+mod control;
+mod doctor;
+#[cfg(feature = "ipv6")]
+mod dslite;
+mod event;
 mod instance;
+mod nat_test;
+mod nft;
+mod probe;
 mod route;
 mod skel;
+mod snapshot;
+mod sync;
+mod trace;
 mod utils;
 
 use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use futures_util::StreamExt;
-use ipnet::Ipv4Net;
 #[cfg(feature = "ipv6")]
 use ipnet::Ipv6Net;
+use ipnet::{IpNet, Ipv4Net};
+use netlink_packet_route::rule::RuleMessage;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::task::JoinHandle;
 use tracing::{debug, error, info, span, warn};
 
-use config::{Config, ConfigNetIf, IpProtocol, NetIfId, ProtoRange};
+use config::{
+    Config, ConfigHairpinRoute, ConfigNetIf, HairpinMode, IpProtocol, NetIfId, ProtoRange,
+    TraceFilter,
+};
 use instance::Instance;
-use route::{HairpinRouting, IfAddresses, MonitorEvent, RouteHelper};
+use route::{HairpinRouting, IfAddresses, MonitorEvent, PacketEncap, RouteHelper};
+use utils::{with_netns, IfLock, NetNs};
 
 const HELP: &str = "\
 einat - An eBPF-based Endpoint-Independent NAT
 
 USAGE:
   einat [OPTIONS]
+  einat save-bindings <pin path> <file>
+  einat doctor [OPTIONS]
+  einat trace [--if <name>] [--filter <expr>] [OPTIONS]
+  einat bench [--repeat <count>]
+  einat nat-test [--stun-server <host:port> ...]
+  einat ctl bpf-log [<level> [<interface>]] [--control <path>]
+  einat ctl binding add <tcp|udp> <external port> <internal address:port> [<interface>]
+  einat ctl binding del <tcp|udp> <external port> [<interface>]
+  einat ctl port reserve <tcp|udp> <external port> <lease> [<interface>]
+  einat ctl port release <tcp|udp> <external port> [<interface>]
+  einat ctl counters [<interface>]
+
+COMMANDS:
+  save-bindings                Save binding snapshot from maps pinned with `--pin-path`
+                               or `pin_path`, see `binding_snapshot` in configuration
+  doctor                       Check kernel and network configuration for interfaces
+                               specified by options, then print a diagnostic report
+  trace                        Run NAT with per-packet trace events of packets matching
+                               `--filter` printed to stdout, on interface `--if` or all
+                               interfaces configured, filter is of primitives tcp, udp,
+                               icmp, host <address>, net <network> and port <port>
+                               joined by `and`, e.g. \"udp and host 192.168.1.100\"
+  bench                        Benchmark per-packet cost of translating established
+                               flows with BPF_PROG_TEST_RUN, repeating each packet
+                               1000000 times by default
+  nat-test                     Detect NAT behavior with STUN servers from behind the NAT,
+                               the first server should support RFC 5780 for detecting
+                               filtering behavior, defaults to stun.l.google.com:19302
+                               and stun.cloudflare.com:3478
+  ctl                          Send request to running einat over control socket, `bpf-log`
+                               sets BPF log level of an interface or all interfaces and
+                               prints resulting log levels, `binding add` and `binding del`
+                               add or remove static bindings, which are not saved to
+                               configuration, `port reserve` reserves external port for local
+                               service until lease(e.g. 60s) expires or is renewed, so it's
+                               not allocated for dynamic bindings, `port release` releases
+                               it, `counters` prints counters of an interface or all
+                               interfaces, interface can be omitted if only one is managed
 
 OPTIONS:
   -h, --help                   Print this message
   -c, --config <file>          Path to configuration file
   -i, --ifname <name>          External network interface name, e.g. eth0
       --ifindex <index>        External network interface index number, e.g. 2
+      --netns <name>           Network namespace of external network interface
       --nat44                  Enable NAT44/NAPT44 for specified network interface
       --nat66                  Enable NAT66/NAPT66 for specified network interface
       --ports <range> ...      External TCP/UDP port ranges, defaults to 20000-29999
       --hairpin-if <name> ...  Hairpin internal network interface names, e.g. lo, lan0
       --bpf-log <level>        BPF tracing log level, 0 to 5, defaults to 0, disabled
+      --pin-path <dir>         Pin binding and CT maps under directory on BPF filesystem
+      --control <path>         Unix socket path of control API, see `control_socket`
+  -v, --verbose                Print debug logs, or trace logs if repeated, i.e. -vv
+      --log-filter <filter>    Comma-separated log filter directives in `RUST_LOG` syntax,
+                               e.g. einat::route=debug
+      --handover               Take over interfaces from running einat instance
+      --print-config           Print effective configuration and probed kernel features, then exit
 ";
 
+/// Addresses of which the valid lifetime would end within this are not used
+const DEFAULT_ADDRESS_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+const DEFAULT_ADDRESS_COMMAND_INTERVAL: Duration = Duration::from_secs(300);
+
+const DEFAULT_NFT_SET_INTERVAL: Duration = Duration::from_secs(30);
+
+/// `address_command` is killed if not exited within this
+const ADDRESS_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DEFAULT_STATE_SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+enum Command {
+    SaveBindings {
+        pin_path: PathBuf,
+        file: PathBuf,
+    },
+    Doctor,
+    NatTest {
+        stun_servers: Vec<String>,
+    },
+    Bench {
+        repeat: u32,
+    },
+    Trace {
+        if_name: Option<String>,
+        filter: TraceFilter,
+    },
+    Ctl {
+        request: Vec<String>,
+    },
+}
+
 #[derive(Default)]
 struct Args {
+    command: Option<Command>,
     config_file: Option<PathBuf>,
     if_index: Option<u32>,
     if_name: Option<String>,
+    netns: Option<String>,
     nat44: bool,
     nat66: bool,
     ports: Vec<ProtoRange>,
     hairpin_if_names: Vec<String>,
     log_level: Option<u8>,
+    pin_path: Option<PathBuf>,
+    control: Option<PathBuf>,
+    verbose: u8,
+    log_filter: Option<String>,
+    handover: bool,
+    print_config: bool,
 }
 
 fn parse_env_args() -> Result<Args> {
@@ -71,6 +183,9 @@ fn parse_env_args() -> Result<Args> {
             Long("ifindex") => {
                 args.if_index = Some(parser.value()?.parse()?);
             }
+            Long("netns") => {
+                args.netns = Some(parser.value()?.parse()?);
+            }
             Long("nat44") => {
                 args.nat44 = true;
             }
@@ -88,6 +203,85 @@ fn parse_env_args() -> Result<Args> {
             Long("bpf-log") => {
                 args.log_level = Some(parser.value()?.parse()?);
             }
+            Long("pin-path") => {
+                args.pin_path = Some(parser.value()?.parse()?);
+            }
+            Long("control") => {
+                args.control = Some(parser.value()?.parse()?);
+            }
+            Short('v') | Long("verbose") => {
+                args.verbose = args.verbose.saturating_add(1);
+            }
+            Long("log-filter") => {
+                args.log_filter = Some(parser.value()?.parse()?);
+            }
+            Long("handover") => {
+                args.handover = true;
+            }
+            Long("print-config") => {
+                args.print_config = true;
+            }
+            Value(cmd) if args.command.is_none() && cmd == "save-bindings" => {
+                args.command = Some(Command::SaveBindings {
+                    pin_path: parser.value()?.parse()?,
+                    file: parser.value()?.parse()?,
+                });
+            }
+            Value(cmd) if args.command.is_none() && cmd == "doctor" => {
+                args.command = Some(Command::Doctor);
+            }
+            Value(cmd) if args.command.is_none() && cmd == "nat-test" => {
+                args.command = Some(Command::NatTest {
+                    stun_servers: Vec::new(),
+                });
+            }
+            Value(cmd) if args.command.is_none() && cmd == "bench" => {
+                args.command = Some(Command::Bench {
+                    repeat: bench::DEFAULT_REPEAT,
+                });
+            }
+            Value(cmd) if args.command.is_none() && cmd == "trace" => {
+                args.command = Some(Command::Trace {
+                    if_name: None,
+                    filter: TraceFilter::default(),
+                });
+            }
+            Value(cmd) if args.command.is_none() && cmd == "ctl" => {
+                args.command = Some(Command::Ctl {
+                    request: Vec::new(),
+                });
+            }
+            Value(word) if matches!(args.command, Some(Command::Ctl { .. })) => {
+                let Some(Command::Ctl { request }) = &mut args.command else {
+                    unreachable!()
+                };
+                request.push(word.parse()?);
+            }
+            Long("if") => {
+                let Some(Command::Trace { if_name, .. }) = &mut args.command else {
+                    return Err(opt.unexpected().into());
+                };
+                *if_name = Some(parser.value()?.parse()?);
+            }
+            Long("filter") => {
+                let Some(Command::Trace { filter, .. }) = &mut args.command else {
+                    return Err(opt.unexpected().into());
+                };
+                *filter = parser.value()?.parse()?;
+            }
+            Long("repeat") => {
+                let Some(Command::Bench { repeat }) = &mut args.command else {
+                    return Err(opt.unexpected().into());
+                };
+                *repeat = parser.value()?.parse()?;
+            }
+            Long("stun-server") => {
+                let Some(Command::NatTest { stun_servers }) = &mut args.command else {
+                    return Err(opt.unexpected().into());
+                };
+                let servers: Result<Vec<_>, _> = parser.values()?.map(|s| s.parse()).collect();
+                stun_servers.extend(servers?);
+            }
             _ => return Err(opt.unexpected().into()),
         }
     }
@@ -95,20 +289,81 @@ fn parse_env_args() -> Result<Args> {
     Ok(args)
 }
 
+/// Network namespace of interfaces, `None` for that of einat itself
+struct NsContext {
+    netns: Option<Arc<NetNs>>,
+    rt_helper: RouteHelper,
+}
+
+/// Interface with `vrrp_address`, which is active only while the address is
+/// assigned, i.e. this host is VRRP master.
+struct VrrpState {
+    config_idx: usize,
+    ns_idx: usize,
+    address: IpAddr,
+    active: bool,
+    rt_helper: RouteHelper,
+}
+
+impl VrrpState {
+    /// Whether the address was assigned or removed since last checked.
+    async fn changed(&self, config: &Config) -> bool {
+        match self.rt_helper.has_address(self.address).await {
+            Ok(active) if active != self.active => {
+                info!(
+                    "VRRP address {} {}, {} on interface {}",
+                    self.address,
+                    if active { "assigned" } else { "removed" },
+                    if active { "activating" } else { "standing by" },
+                    config.interfaces[self.config_idx].interface
+                );
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                error!("failed to query VRRP address {}: {}", self.address, e);
+                false
+            }
+        }
+    }
+}
+
 struct IfContext {
     config_idx: usize,
+    ns_idx: usize,
     if_index: u32,
+    /// Differs from `if_index` if attached to PPPoE lower interface
+    attach_if_index: u32,
+    /// Interface resolved from configuration before replaced by master
+    /// interface, to detect moves of `interface = "auto"` or failover
+    resolved_if_index: u32,
+    lock: Option<IfLock>,
     inst: Instance,
     addresses: IfAddresses,
+    /// When an address of interface is to be excluded for its expiry
+    address_expiry: Option<Instant>,
+    /// When to run `address_command` of externals again
+    next_address_command: Option<Instant>,
+    /// When to query `no_snat_nft_set` again
+    next_nft_set_query: Option<Instant>,
     rt_helper: RouteHelper,
     v4_hairpin_routing: Option<HairpinRouting<Ipv4Net>>,
     #[cfg(feature = "ipv6")]
     v6_hairpin_routing: Option<HairpinRouting<Ipv6Net>>,
+    /// Sysctl path and previous value of `accept_local` to restore on detach
+    accept_local_restore: Option<(String, String)>,
+    /// IP rules of `icmp_on_alloc_failure` to delete on detach
+    reject_rules: Vec<RuleMessage>,
+    #[cfg(feature = "ipv6")]
+    clat: Option<clat::Clat>,
+    #[cfg(feature = "ipv6")]
+    dslite: Option<dslite::DsLite>,
 }
 
 impl IfContext {
     async fn detach(&mut self) -> Result<()> {
         let mut results: Vec<Result<()>> = Vec::new();
+        results.push(self.inst.save_binding_snapshot());
         results.push(self.inst.detach());
 
         if let Some(mut hairpin_routing) = self.v4_hairpin_routing.take() {
@@ -120,72 +375,962 @@ impl IfContext {
             results.push(hairpin_routing.deconfigure().await);
         }
 
+        #[cfg(feature = "ipv6")]
+        if let Some(clat) = &mut self.clat {
+            results.push(clat.deconfigure().await);
+        }
+
+        #[cfg(feature = "ipv6")]
+        if let Some(dslite) = &mut self.dslite {
+            results.push(dslite.deconfigure().await);
+        }
+
+        if let Some((path, value)) = self.accept_local_restore.take() {
+            results.push(with_netns(self.inst.netns(), || {
+                utils::set_sysctl(&path, &value).map(|_| ())
+            }));
+        }
+
+        for rule in core::mem::take(&mut self.reject_rules) {
+            results.push(self.rt_helper.del_rule(rule).await);
+        }
+
         for res in results {
             res?;
         }
         Ok(())
     }
+
+    /// Enables `accept_local` on external interface, as IPv4 packets
+    /// hairpinned in redirect or fwmark mode are received with local source
+    /// address.
+    async fn enable_accept_local(&mut self) -> Result<()> {
+        if self.accept_local_restore.is_some() {
+            return Ok(());
+        }
+        let link_info = self.rt_helper.query_link_info(self.if_index).await?;
+        let if_name = link_info
+            .name()
+            .ok_or_else(|| anyhow!("failed to get name of interface {}", self.if_index))?;
+        let path = format!("net/ipv4/conf/{}/accept_local", if_name);
+        let prev = with_netns(self.inst.netns(), || utils::set_sysctl(&path, "1"))?;
+        if prev != "1" {
+            self.accept_local_restore = Some((path, prev));
+        }
+        Ok(())
+    }
+
+    /// Adds "prohibit" IP rules of packets of new flows that BPF programs
+    /// failed to translate and redirected back to external interface, so
+    /// kernel replies ICMP errors to internal hosts.
+    async fn add_reject_rules(&mut self, config: &Config) -> Result<()> {
+        let if_config = &config.interfaces[self.config_idx];
+        let fwmark = config.defaults.reject_fwmark.get();
+        if if_config.nat44 {
+            let rule = self
+                .rt_helper
+                .add_prohibit_rule(true, fwmark, config.defaults.ipv4_hairpin_rule_pref)
+                .await?;
+            self.reject_rules.push(rule);
+        }
+        #[cfg(feature = "ipv6")]
+        if if_config.nat66 {
+            let rule = self
+                .rt_helper
+                .add_prohibit_rule(false, fwmark, config.defaults.ipv6_hairpin_rule_pref)
+                .await?;
+            self.reject_rules.push(rule);
+        }
+        Ok(())
+    }
+
+    /// Updates CLAT IPv6 address on address changes of CLAT uplink.
+    #[cfg(feature = "ipv6")]
+    async fn reconfigure_clat(&mut self) {
+        let Some(clat) = &mut self.clat else {
+            return;
+        };
+        let res = async {
+            let addresses = self
+                .rt_helper
+                .query_all_addresses(clat.uplink_if_index)
+                .await?;
+            if clat.reconfigure_ipv6_address(&addresses.ipv6).await? {
+                self.inst.set_clat_ipv6_address(clat.ipv6_address);
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        if let Err(e) = res {
+            error!("failed to reconfigure CLAT IPv6 address: {}", e);
+        }
+    }
+
+    /// Updates DS-Lite tunnel source address on address changes of uplink.
+    #[cfg(feature = "ipv6")]
+    async fn reconfigure_dslite(&mut self) {
+        let Some(dslite) = &mut self.dslite else {
+            return;
+        };
+        let res = async {
+            let addresses = self
+                .rt_helper
+                .query_all_addresses(dslite.uplink_if_index)
+                .await?;
+            dslite.reconfigure_ipv6_address(&addresses.ipv6).await
+        }
+        .await;
+        if let Err(e) = res {
+            error!("failed to reconfigure DS-Lite tunnel: {}", e);
+        }
+    }
+
+    /// Whether `interface = "auto"` or failover pair now resolves to another
+    /// interface. Current interface is kept if none could be resolved, e.g.
+    /// there is no default route in the middle of failover.
+    async fn interface_moved(&self, if_config: &ConfigNetIf) -> bool {
+        if !if_config.interface.is_auto() && if_config.failover.is_none() {
+            return false;
+        }
+        match select_if_index(&self.rt_helper, self.inst.netns(), if_config).await {
+            Ok(if_index) if if_index != self.resolved_if_index => {
+                info!(
+                    "external interface moved from {} to {}",
+                    self.resolved_if_index, if_index
+                );
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                debug!("failed to resolve external interface: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Queries external addresses again on changes of addresses or routes.
+    async fn update_addresses(&mut self, if_config: &ConfigNetIf) -> Result<()> {
+        let (mut new_addresses, address_expiry) =
+            query_external_addresses(&self.rt_helper, self.if_index, if_config).await?;
+        self.address_expiry = address_expiry;
+        new_addresses.command_outputs = self.addresses.command_outputs.clone();
+        new_addresses.nft_set_dests = self.addresses.nft_set_dests.clone();
+        self.reconfigure_addresses(new_addresses).await
+    }
+
+    /// Applies changes of external addresses, including those from
+    /// `address_command`.
+    async fn reconfigure_addresses(&mut self, new_addresses: IfAddresses) -> Result<()> {
+        // externals may be matched by address attributes
+        let attrs_changed = new_addresses.attrs != self.addresses.attrs
+            || new_addresses.command_outputs != self.addresses.command_outputs
+            || new_addresses.route_sources != self.addresses.route_sources
+            || new_addresses.nft_set_dests != self.addresses.nft_set_dests;
+        if attrs_changed || new_addresses.ipv4 != self.addresses.ipv4 {
+            debug!(
+                "IPv4 addresses {:?} -> {:?}",
+                self.addresses.ipv4, new_addresses.ipv4
+            );
+            self.inst.reconfigure_v4_addresses(&new_addresses)?;
+        }
+        #[cfg(feature = "ipv6")]
+        if attrs_changed || new_addresses.ipv6 != self.addresses.ipv6 {
+            debug!(
+                "IPv6 addresses {:?} -> {:?}",
+                self.addresses.ipv6, new_addresses.ipv6
+            );
+            self.inst.reconfigure_v6_addresses(&new_addresses)?;
+        }
+        self.addresses = new_addresses;
+
+        if let Some(hairpin_routing) = &mut self.v4_hairpin_routing {
+            if let Err(e) = hairpin_routing
+                .reconfigure_dests(self.inst.v4_hairpin_dests())
+                .await
+            {
+                error!("failed to reconfigure IPv4 hairpin routing: {}", e);
+            }
+        }
+
+        #[cfg(feature = "ipv6")]
+        if let Some(hairpin_routing) = &mut self.v6_hairpin_routing {
+            if let Err(e) = hairpin_routing
+                .reconfigure_dests(self.inst.v6_hairpin_dests())
+                .await
+            {
+                error!("failed to reconfigure IPv6 hairpin routing: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `address_command` of externals again and applies changed outputs.
+    async fn refresh_command_addresses(&mut self, if_config: &ConfigNetIf) -> Result<()> {
+        self.next_address_command = next_address_command(if_config);
+        let command_outputs = run_address_commands(if_config, &self.addresses).await;
+        if command_outputs == self.addresses.command_outputs {
+            return Ok(());
+        }
+        let mut new_addresses = self.addresses.clone();
+        new_addresses.command_outputs = command_outputs;
+        self.reconfigure_addresses(new_addresses).await
+    }
+
+    /// Queries `no_snat_nft_set` again and applies changed elements.
+    async fn refresh_nft_set(&mut self, if_config: &ConfigNetIf) -> Result<()> {
+        self.next_nft_set_query = next_nft_set_query(if_config);
+        let nft_set_dests = query_nft_set(if_config, &self.addresses).await;
+        if nft_set_dests == self.addresses.nft_set_dests {
+            return Ok(());
+        }
+        let mut new_addresses = self.addresses.clone();
+        new_addresses.nft_set_dests = nft_set_dests;
+        self.reconfigure_addresses(new_addresses).await
+    }
+
+    /// Updates hairpinned container bridges and internal interfaces matching
+    /// glob patterns on link changes.
+    async fn reconfigure_dynamic_if_names(&mut self, config: &Config) {
+        let if_config = &config.interfaces[self.config_idx];
+        let rt_helper = &self.rt_helper;
+
+        let hairpin_config = &if_config.ipv4_hairpin_route;
+        if let Some(hairpin_routing) = &mut self.v4_hairpin_routing {
+            if hairpin_config.has_dynamic_if_names() && hairpin_config.mode == HairpinMode::Route {
+                let res = async {
+                    let names = hairpin_internal_if_names(hairpin_config, rt_helper).await?;
+                    hairpin_routing.reconfigure_internal_if_names(names).await
+                }
+                .await;
+                if let Err(e) = res {
+                    error!(
+                        "failed to reconfigure IPv4 hairpin internal interfaces: {}",
+                        e
+                    );
+                }
+            }
+        }
+
+        #[cfg(feature = "ipv6")]
+        {
+            let hairpin_config = &if_config.ipv6_hairpin_route;
+            if let Some(hairpin_routing) = &mut self.v6_hairpin_routing {
+                if hairpin_config.has_dynamic_if_names()
+                    && hairpin_config.mode == HairpinMode::Route
+                {
+                    let res = async {
+                        let names = hairpin_internal_if_names(hairpin_config, rt_helper).await?;
+                        hairpin_routing.reconfigure_internal_if_names(names).await
+                    }
+                    .await;
+                    if let Err(e) = res {
+                        error!(
+                            "failed to reconfigure IPv6 hairpin internal interfaces: {}",
+                            e
+                        );
+                    }
+                }
+            }
+        }
+
+        let bpf_dynamic_if_names = [&if_config.ipv4_hairpin_route, &if_config.ipv6_hairpin_route]
+            .iter()
+            .any(|hairpin_config| {
+                hairpin_config.mode != HairpinMode::Route && hairpin_config.has_dynamic_if_names()
+            });
+        if bpf_dynamic_if_names {
+            let res = async {
+                let names = hairpin_bpf_if_names(if_config, rt_helper).await?;
+                self.inst.attach_hairpin_bpf(&names)
+            }
+            .await;
+            if let Err(e) = res {
+                error!(
+                    "failed to reconfigure hairpin program on internal interfaces: {}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Internal interfaces to attach hairpin program on, of address families with
+/// hairpinning enabled in redirect or fwmark mode.
+async fn hairpin_bpf_if_names(
+    if_config: &ConfigNetIf,
+    rt_helper: &RouteHelper,
+) -> Result<Vec<String>> {
+    let mut if_names = Vec::new();
+    for hairpin_config in [&if_config.ipv4_hairpin_route, &if_config.ipv6_hairpin_route] {
+        if hairpin_config.mode == HairpinMode::Route || hairpin_config.enable == Some(false) {
+            continue;
+        }
+        for if_name in hairpin_internal_if_names(hairpin_config, rt_helper).await? {
+            if if_name == "lo" {
+                // locally generated traffic is hairpinned with IP rules instead
+                if !hairpin_config.local {
+                    warn!(
+                        "hairpin {:?} mode does not support loopback interface, enable `local` instead",
+                        hairpin_config.mode
+                    );
+                }
+                continue;
+            }
+            if !if_names.contains(&if_name) {
+                if_names.push(if_name);
+            }
+        }
+    }
+    Ok(if_names)
 }
 
-async fn daemon(config: &Config, contexts: &mut HashMap<u32, IfContext>) -> Result<JoinHandle<()>> {
-    let (monitor_task, rt_helper, events) = route::spawn_monitor()?;
+async fn hairpin_internal_if_names(
+    hairpin_config: &ConfigHairpinRoute,
+    rt_helper: &RouteHelper,
+) -> Result<Vec<String>> {
+    let mut internal_if_names = Vec::new();
+    let mut link_names = None;
+    for name in &hairpin_config.internal_if_names {
+        if !utils::is_glob(name) {
+            internal_if_names.push(name.clone());
+            continue;
+        }
+        if link_names.is_none() {
+            link_names = Some(rt_helper.query_link_names().await?);
+        }
+        for link_name in link_names.iter().flatten() {
+            if utils::glob_match(name, link_name) && !internal_if_names.contains(link_name) {
+                internal_if_names.push(link_name.clone());
+            }
+        }
+    }
+    if hairpin_config.container_bridges {
+        internal_if_names.extend(rt_helper.query_container_bridges().await?);
+    }
+    // IP rule of loopback interface matches locally generated traffic
+    if hairpin_config.local && !internal_if_names.iter().any(|name| name == "lo") {
+        internal_if_names.push("lo".to_string());
+    }
+    Ok(internal_if_names)
+}
+
+/// Internal interfaces to add hairpin IP rules of, in redirect and fwmark mode
+/// only loopback interface for locally generated traffic.
+fn hairpin_rule_if_names(
+    hairpin_config: &ConfigHairpinRoute,
+    internal_if_names: Vec<String>,
+) -> Vec<String> {
+    match hairpin_config.mode {
+        HairpinMode::Route => internal_if_names,
+        HairpinMode::Redirect | HairpinMode::Fwmark if hairpin_config.local => {
+            vec!["lo".to_string()]
+        }
+        HairpinMode::Redirect | HairpinMode::Fwmark => Vec::new(),
+    }
+}
+
+/// Returns contexts of managed interfaces matching interface name or index
+/// `interface`, or all contexts if `None`, in order of configuration.
+fn select_contexts<'a>(
+    config: &Config,
+    contexts: &'a mut HashMap<(usize, u32), IfContext>,
+    interface: Option<&str>,
+) -> Result<Vec<&'a mut IfContext>> {
+    let mut contexts: Vec<_> = contexts
+        .values_mut()
+        .filter(|ctx| {
+            interface.map_or(true, |name| {
+                let if_config = &config.interfaces[ctx.config_idx];
+                if_config.interface.to_string() == name || name.parse() == Ok(ctx.if_index)
+            })
+        })
+        .collect();
+    if contexts.is_empty() {
+        return Err(anyhow::anyhow!(
+            "interface {} is not managed",
+            interface.unwrap_or_default()
+        ));
+    }
+    contexts.sort_by_key(|ctx| ctx.config_idx);
+    Ok(contexts)
+}
+
+/// Returns context of the only managed interface matching `interface`.
+fn select_context<'a>(
+    config: &Config,
+    contexts: &'a mut HashMap<(usize, u32), IfContext>,
+    interface: Option<&str>,
+) -> Result<&'a mut IfContext> {
+    let mut contexts = select_contexts(config, contexts, interface)?;
+    if contexts.len() > 1 {
+        return Err(anyhow::anyhow!(
+            "multiple interfaces are managed, interface must be specified"
+        ));
+    }
+    Ok(contexts.remove(0))
+}
+
+/// Handles control request against managed interfaces.
+fn handle_request(
+    config: &Config,
+    contexts: &mut HashMap<(usize, u32), IfContext>,
+    request: &control::Request,
+) -> Result<String> {
+    match request {
+        control::Request::BpfLogLevel { level, interface } => {
+            let contexts = select_contexts(config, contexts, interface.as_deref())?;
+
+            let mut response = String::new();
+            for ctx in contexts {
+                let if_id = &config.interfaces[ctx.config_idx].interface;
+                if let Some(level) = *level {
+                    info!("setting BPF log level of interface {} to {}", if_id, level);
+                    let level_set = ctx.inst.set_bpf_log_level(level);
+                    if level_set < level {
+                        warn!(
+                            "BPF log level of interface {} is capped at {} by `bpf_log_max_level`",
+                            if_id, level_set
+                        );
+                    }
+                }
+                writeln!(response, "{} {}", if_id, ctx.inst.bpf_log_level())?;
+            }
+            Ok(response)
+        }
+        control::Request::BindingAdd { binding, interface } => {
+            let ctx = select_context(config, contexts, interface.as_deref())?;
+            let if_id = &config.interfaces[ctx.config_idx].interface;
+            ctx.inst.add_static_binding(*binding)?;
+            info!(
+                "added static binding of {} port {} to {} on interface {}",
+                binding.proto, binding.external_port, binding.internal, if_id
+            );
+            Ok(format!(
+                "{} {} {} {}\n",
+                if_id, binding.proto, binding.external_port, binding.internal
+            ))
+        }
+        control::Request::BindingDel {
+            proto,
+            external_port,
+            interface,
+        } => {
+            let ctx = select_context(config, contexts, interface.as_deref())?;
+            let if_id = &config.interfaces[ctx.config_idx].interface;
+            let mut response = String::new();
+            for binding in ctx.inst.remove_static_binding(*proto, *external_port)? {
+                info!(
+                    "removed static binding of {} port {} to {} on interface {}",
+                    proto, external_port, binding.internal, if_id
+                );
+                writeln!(
+                    response,
+                    "{} {} {} {}",
+                    if_id, proto, external_port, binding.internal
+                )?;
+            }
+            Ok(response)
+        }
+        control::Request::PortReserve {
+            proto,
+            port,
+            lease,
+            interface,
+        } => {
+            let ctx = select_context(config, contexts, interface.as_deref())?;
+            let if_id = &config.interfaces[ctx.config_idx].interface;
+            ctx.inst.reserve_port(*proto, *port, *lease)?;
+            debug!(
+                "reserved {} port {} for {:?} on interface {}",
+                proto, port, lease, if_id
+            );
+            Ok(format!(
+                "{} {} {} {}s\n",
+                if_id,
+                proto,
+                port,
+                lease.as_secs()
+            ))
+        }
+        control::Request::PortRelease {
+            proto,
+            port,
+            interface,
+        } => {
+            let ctx = select_context(config, contexts, interface.as_deref())?;
+            let if_id = &config.interfaces[ctx.config_idx].interface;
+            ctx.inst.release_port(*proto, *port)?;
+            info!(
+                "released reservation of {} port {} on interface {}",
+                proto, port, if_id
+            );
+            Ok(format!("{} {} {}\n", if_id, proto, port))
+        }
+        control::Request::Counters { interface } => {
+            let contexts = select_contexts(config, contexts, interface.as_deref())?;
+
+            let mut response = String::new();
+            for ctx in contexts {
+                let if_id = &config.interfaces[ctx.config_idx].interface;
+                for (name, value) in ctx.inst.counters() {
+                    writeln!(response, "{} {} {}", if_id, name, value)?;
+                }
+            }
+            Ok(response)
+        }
+    }
+}
 
+/// Returns `true` if to be restarted as `interface = "auto"` or failover pair
+/// moved to another interface or VRRP state changed. `carried_bindings` are
+/// bindings of previous interfaces to keep external ports of, by configuration
+/// index, and `sync_server` holds bindings of peer to take over. Links set up
+/// but not yet owned by any context are left in `pending` for the caller to
+/// clean up.
+async fn daemon(
+    config: &Config,
+    handover: bool,
+    contexts: &mut HashMap<(usize, u32), IfContext>,
+    monitor_tasks: &mut Vec<JoinHandle<()>>,
+    mut carried_bindings: HashMap<usize, Vec<(skel::MapBindingKey, skel::MapBindingValue)>>,
+    sync_server: Option<&sync::SyncServer>,
+    #[allow(unused_variables)] pending: &mut PendingLinks,
+) -> Result<bool> {
     // TODO: implement network interface(link) monitoring to attach/detach interface automatically
 
+    let mut namespaces: Vec<NsContext> = Vec::new();
+    let mut ns_ids: Vec<Option<u64>> = Vec::new();
+    let mut events = Vec::new();
     let mut inst_configs = HashMap::with_capacity(config.interfaces.len());
+    let mut vrrp_states = Vec::new();
+    #[cfg(feature = "ipv6")]
+    let mut dslites = HashMap::new();
+    let monitor_routes = config.interfaces.iter().any(|if_config| {
+        if_config.interface.is_auto()
+            || if_config.failover.is_some()
+            || if_config.route_probes().next().is_some()
+    });
+    let monitor_links = config.interfaces.iter().any(|if_config| {
+        if_config.ipv4_hairpin_route.has_dynamic_if_names()
+            || if_config.ipv6_hairpin_route.has_dynamic_if_names()
+    });
 
     for (config_idx, if_config) in config.interfaces.iter().enumerate() {
-        let if_index = if_config.interface.resolve_index()?;
-        let link_info = rt_helper.query_link_info(if_index).await?;
+        let mut netns = if_config.netns.as_deref().map(NetNs::open).transpose()?;
+        // Treat our own network namespace specified explicitly the same as
+        // unspecified, so interfaces in it are not managed twice.
+        if let Some(ns) = &netns {
+            if ns.is_current()? {
+                netns = None;
+            }
+        }
+        let ns_id = netns.as_ref().map(NetNs::id);
+        let ns_idx = if let Some(ns_idx) = ns_ids.iter().position(|id| *id == ns_id) {
+            ns_idx
+        } else {
+            if let Some(name) = &if_config.netns {
+                info!("managing interfaces in network namespace {}", name);
+            }
+            let netns = netns.map(Arc::new);
+            // Netlink sockets are bound to network namespace on creation
+            let (monitor_task, rt_helper, ns_events) = with_netns(netns.as_deref(), || {
+                route::spawn_monitor(monitor_routes, monitor_links)
+            })?;
+            let ns_idx = namespaces.len();
+            monitor_tasks.push(monitor_task);
+            events.push(Box::pin(ns_events.map(move |event| (ns_idx, event))));
+            namespaces.push(NsContext { netns, rt_helper });
+            ns_ids.push(ns_id);
+            ns_idx
+        };
+        let NsContext { netns, rt_helper } = &namespaces[ns_idx];
+
+        if let Some(address) = if_config.vrrp_address {
+            let active = rt_helper.has_address(address).await?;
+            vrrp_states.push(VrrpState {
+                config_idx,
+                ns_idx,
+                address,
+                active,
+                rt_helper: rt_helper.clone(),
+            });
+            if !active {
+                info!(
+                    "standing by on interface {} until VRRP address {} is assigned",
+                    if_config.interface, address
+                );
+                continue;
+            }
+        }
+
+        // CLAT interface is created by us
+        #[cfg(feature = "ipv6")]
+        if let Some(clat_config) = &if_config.clat {
+            let NetIfId::Name { if_name } = &if_config.interface else {
+                return Err(anyhow!("CLAT interface must be specified by `if_name`"));
+            };
+            if !if_config.nat44 {
+                return Err(anyhow!("CLAT interface {} requires `nat44`", if_name));
+            }
+            if handover {
+                return Err(anyhow!(
+                    "handover is not supported with CLAT interface {}",
+                    if_name
+                ));
+            }
+            let clat = clat::Clat::setup(netns.as_deref(), rt_helper, if_name, clat_config).await?;
+            pending.clats.insert(config_idx, clat);
+        }
+        #[cfg(not(feature = "ipv6"))]
+        if if_config.clat.is_some() {
+            return Err(anyhow!("CLAT requires IPv6 support, which is not enabled"));
+        }
+
+        // So is DS-Lite softwire interface
+        #[cfg(feature = "ipv6")]
+        let dslite = if let Some(dslite_config) = &if_config.dslite {
+            let NetIfId::Name { if_name } = &if_config.interface else {
+                return Err(anyhow!("DS-Lite interface must be specified by `if_name`"));
+            };
+            if !if_config.nat44 {
+                return Err(anyhow!("DS-Lite interface {} requires `nat44`", if_name));
+            }
+            if if_config.clat.is_some() {
+                return Err(anyhow!(
+                    "DS-Lite and CLAT are mutually exclusive on interface {}",
+                    if_name
+                ));
+            }
+            if handover {
+                return Err(anyhow!(
+                    "handover is not supported with DS-Lite interface {}",
+                    if_name
+                ));
+            }
+            Some(dslite::DsLite::setup(netns.as_deref(), rt_helper, if_name, dslite_config).await?)
+        } else {
+            None
+        };
+        #[cfg(not(feature = "ipv6"))]
+        if if_config.dslite.is_some() {
+            return Err(anyhow!(
+                "DS-Lite requires IPv6 support, which is not enabled"
+            ));
+        }
+
+        if if_config.failover.is_some()
+            && (if_config.interface.is_auto()
+                || if_config.clat.is_some()
+                || if_config.dslite.is_some())
+        {
+            return Err(anyhow!(
+                "failover is not supported with `interface = \"auto\"`, CLAT or DS-Lite"
+            ));
+        }
+        let if_index = select_if_index(rt_helper, netns.as_deref(), if_config).await?;
+        if if_config.interface.is_auto() {
+            info!("using interface {} of default route", if_index);
+        } else if if_config.failover.is_some() {
+            info!("using interface {} of failover pair", if_index);
+        }
+        let resolved_if_index = if_index;
+        let mut link_info = rt_helper.query_link_info(if_index).await?;
+        // Addresses of bridge or bond live on master interface, and routed
+        // traffic of ports only passes through TC hooks of master.
+        let if_index = if let Some(master_if_index) = link_info.controller() {
+            let master_link_info = rt_helper.query_link_info(master_if_index).await?;
+            if let Some(kind) = master_link_info.aggregate_kind() {
+                warn!(
+                    "interface {} is a port of {} {}, using master interface instead",
+                    if_index, kind, master_if_index
+                );
+                link_info = master_link_info;
+                master_if_index
+            } else {
+                if_index
+            }
+        } else {
+            if_index
+        };
+        if inst_configs.contains_key(&(ns_idx, if_index)) {
+            return Err(anyhow::anyhow!(
+                "interface {} is configured more than once",
+                if_index
+            ));
+        }
+
+        let (attach_if_index, encap) = if let Some(lower) = &if_config.pppoe_lower_if_name {
+            let lower_if_index = with_netns(netns.as_deref(), || {
+                NetIfId::Name {
+                    if_name: lower.clone(),
+                }
+                .resolve_index()
+            })?;
+            let lower_link_info = rt_helper.query_link_info(lower_if_index).await?;
+            if lower_link_info.encap() != PacketEncap::Ethernet {
+                return Err(anyhow::anyhow!(
+                    "PPPoE lower interface {} is not an Ethernet interface",
+                    lower
+                ));
+            }
+            (lower_if_index, PacketEncap::Pppoe)
+        } else {
+            (if_index, link_info.encap())
+        };
+
+        let lock = if handover {
+            if if_config.pin_path.is_none() {
+                return Err(anyhow::anyhow!(
+                    "handover requires `pin_path` to be set for interface {}",
+                    if_index
+                ));
+            }
+            // lock would be taken after previous instance exited
+            IfLock::try_acquire(netns.as_deref(), attach_if_index)?
+        } else {
+            Some(IfLock::acquire(netns.as_deref(), attach_if_index)?)
+        };
 
-        let addresses = rt_helper.query_all_addresses(if_index).await?;
-        let inst_config = instance::InstanceConfig::try_from(
-            if_index,
-            link_info.encap(),
+        let (mut addresses, address_expiry) =
+            query_external_addresses(rt_helper, if_index, if_config).await?;
+        addresses.command_outputs = run_address_commands(if_config, &addresses).await;
+        addresses.nft_set_dests = query_nft_set(if_config, &addresses).await;
+        let mut inst_config = instance::InstanceConfig::try_from(
+            attach_if_index,
+            netns.clone(),
+            encap,
             if_config,
             &config.defaults,
             &addresses,
         )?;
-        inst_configs.insert(if_index, (config_idx, inst_config, addresses));
+        // PPPoE interface instead of its lower interface, so redirected
+        // packets get encapsulated
+        let mac = link_info
+            .address()
+            .and_then(|addr| <[u8; 6]>::try_from(addr.as_slice()).ok());
+        inst_config.set_hairpin_redirect_target(if_index, mac);
+        if let Some(entries) = carried_bindings.remove(&config_idx) {
+            inst_config.set_carried_bindings(entries);
+        } else if let Some(snapshot) =
+            sync_server.and_then(|server| server.take(&if_config.interface.to_string()))
+        {
+            inst_config.set_synced_bindings(snapshot);
+        }
+        #[cfg(feature = "ipv6")]
+        if let Some(prefix_if_name) = if_config
+            .nptv6
+            .as_ref()
+            .and_then(|nptv6| nptv6.prefix_if_name.as_ref())
+        {
+            let prefix_if_index = with_netns(netns.as_deref(), || {
+                NetIfId::Name {
+                    if_name: prefix_if_name.clone(),
+                }
+                .resolve_index()
+            })?;
+            let prefix_addresses = rt_helper.query_all_addresses(prefix_if_index).await?;
+            inst_config.set_nptv6_prefix_source(prefix_if_index, prefix_addresses.ipv6);
+        }
+        #[cfg(feature = "ipv6")]
+        if let Some(clat) = pending.clats.get(&config_idx) {
+            inst_config.set_clat(
+                clat.uplink_if_index,
+                clat.uplink_has_eth,
+                clat.ipv4_address,
+                clat.pref64,
+                clat.ipv6_address,
+            );
+        }
+        #[cfg(feature = "ipv6")]
+        if let Some(dslite) = dslite {
+            dslites.insert((ns_idx, if_index), dslite);
+        }
+        inst_configs.insert(
+            (ns_idx, if_index),
+            (
+                config_idx,
+                attach_if_index,
+                resolved_if_index,
+                lock,
+                inst_config,
+                (
+                    addresses,
+                    address_expiry,
+                    next_address_command(if_config),
+                    next_nft_set_query(if_config),
+                ),
+            ),
+        );
     }
 
     let need_monitor = inst_configs
         .values()
-        .any(|(_, inst_config, _)| !inst_config.is_static());
+        .any(|(_, _, _, _, inst_config, _)| !inst_config.is_static())
+        || config.interfaces.iter().any(|if_config| {
+            if_config.ipv4_hairpin_route.has_dynamic_if_names()
+                || if_config.ipv6_hairpin_route.has_dynamic_if_names()
+                || if_config
+                    .nptv6
+                    .as_ref()
+                    .is_some_and(|nptv6| nptv6.external_prefix.is_none())
+                || if_config.clat.is_some()
+                || if_config.dslite.is_some()
+                || if_config.interface.is_auto()
+                || if_config.failover.is_some()
+                || if_config.vrrp_address.is_some()
+        });
 
     let tasks: Vec<_> = inst_configs
         .into_iter()
-        .map(|(if_index, (config_idx, inst_config, addresses))| {
-            let rt_helper = rt_helper.clone();
-            tokio::task::spawn_blocking(move || -> Result<_> {
-                let inst = inst_config.load()?;
-                Ok(IfContext {
+        .map(
+            |(
+                (ns_idx, if_index),
+                (
                     config_idx,
-                    if_index,
-                    inst,
-                    addresses,
-                    rt_helper,
-                    v4_hairpin_routing: Default::default(),
-                    #[cfg(feature = "ipv6")]
-                    v6_hairpin_routing: Default::default(),
+                    attach_if_index,
+                    resolved_if_index,
+                    lock,
+                    inst_config,
+                    (addresses, address_expiry, next_address_command, next_nft_set_query),
+                ),
+            )| {
+                let rt_helper = namespaces[ns_idx].rt_helper.clone();
+                tokio::task::spawn_blocking(move || -> Result<_> {
+                    let inst = inst_config.load()?;
+                    Ok(IfContext {
+                        config_idx,
+                        ns_idx,
+                        if_index,
+                        attach_if_index,
+                        resolved_if_index,
+                        lock,
+                        inst,
+                        addresses,
+                        address_expiry,
+                        next_address_command,
+                        next_nft_set_query,
+                        rt_helper,
+                        v4_hairpin_routing: Default::default(),
+                        #[cfg(feature = "ipv6")]
+                        v6_hairpin_routing: Default::default(),
+                        accept_local_restore: None,
+                        reject_rules: Vec::new(),
+                        #[cfg(feature = "ipv6")]
+                        clat: None,
+                        #[cfg(feature = "ipv6")]
+                        dslite: None,
+                    })
                 })
-            })
-        })
+            },
+        )
         .collect();
 
     for task in tasks {
-        let ctx = task.await??;
-        contexts.insert(ctx.if_index, ctx);
+        #[allow(unused_mut)]
+        let mut ctx = task.await??;
+        #[cfg(feature = "ipv6")]
+        {
+            ctx.clat = pending.clats.remove(&ctx.config_idx);
+            ctx.dslite = dslites.remove(&(ctx.ns_idx, ctx.if_index));
+        }
+        contexts.insert((ctx.ns_idx, ctx.if_index), ctx);
+    }
+
+    // Hairpin IP rules are not bound to external interface, clean up all
+    // leftovers of previous runs before configuring any new ones.
+    // On handover, these are still in use by previous instance and would be
+    // replaced by ours instead. Neither are those of other instances running
+    // in the same network namespace recognizable, so they are kept then.
+    let mut exclusive_ns = vec![false; namespaces.len()];
+    for (ns_idx, ns) in namespaces.iter().enumerate().filter(|_| !handover) {
+        let own_if_indexes: Vec<_> = contexts
+            .values()
+            .filter(|ctx| ctx.ns_idx == ns_idx)
+            .flat_map(|ctx| [ctx.if_index, ctx.attach_if_index])
+            .collect();
+        let res = async {
+            let exclusive = !IfLock::held_by_others(ns.netns.as_deref(), &own_if_indexes)?;
+            exclusive_ns[ns_idx] = exclusive;
+            let route_protocol = config.defaults.hairpin_route_protocol;
+            ns.rt_helper
+                .cleanup_orphaned::<Ipv4Net>(route_protocol, &own_if_indexes, exclusive)
+                .await?;
+            #[cfg(feature = "ipv6")]
+            ns.rt_helper
+                .cleanup_orphaned::<Ipv6Net>(route_protocol, &own_if_indexes, exclusive)
+                .await?;
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        if let Err(e) = res {
+            warn!("failed to clean up orphaned hairpin routing: {}", e);
+        }
+    }
+    for ctx in contexts.values().filter(|_| !handover) {
+        ctx.inst.detach_stale_hooks()?;
+        let exclusive = exclusive_ns[ctx.ns_idx];
+
+        let if_config = &config.interfaces[ctx.config_idx];
+
+        let hairpin_config = &if_config.ipv4_hairpin_route;
+        let table_id = hairpin_config
+            .table_id
+            .unwrap_or(config.defaults.ipv4_hairpin_table_id)
+            .get();
+        let ip_rule_pref = hairpin_config
+            .ip_rule_pref
+            .unwrap_or(config.defaults.ipv4_hairpin_rule_pref);
+        let mut hairpin_routing = HairpinRouting::<Ipv4Net>::new(
+            ctx.rt_helper.clone(),
+            ctx.if_index,
+            table_id,
+            config.defaults.hairpin_route_protocol,
+        );
+        if let Err(e) = hairpin_routing.cleanup_stale(ip_rule_pref, exclusive).await {
+            warn!("failed to clean up stale IPv4 hairpin routing: {}", e);
+        }
+
+        #[cfg(feature = "ipv6")]
+        {
+            let hairpin_config = &if_config.ipv6_hairpin_route;
+            let table_id = hairpin_config
+                .table_id
+                .unwrap_or(config.defaults.ipv6_hairpin_table_id)
+                .get();
+            let ip_rule_pref = hairpin_config
+                .ip_rule_pref
+                .unwrap_or(config.defaults.ipv6_hairpin_rule_pref);
+            let mut hairpin_routing = HairpinRouting::<Ipv6Net>::new(
+                ctx.rt_helper.clone(),
+                ctx.if_index,
+                table_id,
+                config.defaults.hairpin_route_protocol,
+            );
+            if let Err(e) = hairpin_routing.cleanup_stale(ip_rule_pref, exclusive).await {
+                warn!("failed to clean up stale IPv6 hairpin routing: {}", e);
+            }
+        }
     }
 
     for ctx in contexts.values_mut() {
         ctx.inst.attach()?;
 
         let hairpin_config = &config.interfaces[ctx.config_idx].ipv4_hairpin_route;
-        let internal_if_names = hairpin_config.internal_if_names.clone();
+        let internal_if_names = hairpin_internal_if_names(hairpin_config, &ctx.rt_helper).await?;
+        let has_networks = hairpin_config
+            .internal_networks
+            .iter()
+            .any(|network| matches!(network, IpNet::V4(_)));
         let enable = hairpin_config.enable == Some(true)
-            || hairpin_config.enable != Some(false) && !internal_if_names.is_empty();
-        if enable {
+            || hairpin_config.enable != Some(false)
+                && (!internal_if_names.is_empty()
+                    || hairpin_config.has_dynamic_if_names()
+                    || has_networks);
+        if enable
+            && (hairpin_config.mode != HairpinMode::Redirect
+                || hairpin_config.local
+                || has_networks)
+        {
             let ip_rule_pref = hairpin_config
                 .ip_rule_pref
                 .unwrap_or(config.defaults.ipv4_hairpin_rule_pref);
@@ -202,14 +1347,22 @@ async fn daemon(config: &Config, contexts: &mut HashMap<u32, IfContext>) -> Resu
                 .table_id
                 .unwrap_or(config.defaults.ipv4_hairpin_table_id)
                 .get();
-            let mut hairpin_routing =
-                HairpinRouting::new(rt_helper.clone(), ctx.if_index, table_id);
+            let mut hairpin_routing = HairpinRouting::new(
+                ctx.rt_helper.clone(),
+                ctx.if_index,
+                table_id,
+                config.defaults.hairpin_route_protocol,
+            );
+            if hairpin_config.mode == HairpinMode::Fwmark {
+                hairpin_routing = hairpin_routing.with_fwmark(config.defaults.hairpin_fwmark.get());
+            }
 
             let res = hairpin_routing
                 .configure(
                     ip_rule_pref,
                     local_ip_rule_pref,
-                    internal_if_names,
+                    hairpin_rule_if_names(hairpin_config, internal_if_names),
+                    hairpin_config.internal_networks.clone(),
                     hairpin_config.ip_protocols.clone(),
                     ctx.inst.v4_hairpin_dests(),
                 )
@@ -223,10 +1376,22 @@ async fn daemon(config: &Config, contexts: &mut HashMap<u32, IfContext>) -> Resu
         #[cfg(feature = "ipv6")]
         {
             let hairpin_config = &config.interfaces[ctx.config_idx].ipv6_hairpin_route;
-            let internal_if_names = hairpin_config.internal_if_names.clone();
+            let internal_if_names =
+                hairpin_internal_if_names(hairpin_config, &ctx.rt_helper).await?;
+            let has_networks = hairpin_config
+                .internal_networks
+                .iter()
+                .any(|network| matches!(network, IpNet::V6(_)));
             let enable = hairpin_config.enable == Some(true)
-                || hairpin_config.enable != Some(false) && !internal_if_names.is_empty();
-            if enable {
+                || hairpin_config.enable != Some(false)
+                    && (!internal_if_names.is_empty()
+                        || hairpin_config.has_dynamic_if_names()
+                        || has_networks);
+            if enable
+                && (hairpin_config.mode != HairpinMode::Redirect
+                    || hairpin_config.local
+                    || has_networks)
+            {
                 let ip_rule_pref = hairpin_config
                     .ip_rule_pref
                     .unwrap_or(config.defaults.ipv6_hairpin_rule_pref);
@@ -243,13 +1408,22 @@ async fn daemon(config: &Config, contexts: &mut HashMap<u32, IfContext>) -> Resu
                     .table_id
                     .unwrap_or(config.defaults.ipv6_hairpin_table_id)
                     .get();
-                let mut hairpin_routing =
-                    HairpinRouting::new(rt_helper.clone(), ctx.if_index, table_id);
+                let mut hairpin_routing = HairpinRouting::new(
+                    ctx.rt_helper.clone(),
+                    ctx.if_index,
+                    table_id,
+                    config.defaults.hairpin_route_protocol,
+                );
+                if hairpin_config.mode == HairpinMode::Fwmark {
+                    hairpin_routing =
+                        hairpin_routing.with_fwmark(config.defaults.hairpin_fwmark.get());
+                }
                 let res = hairpin_routing
                     .configure(
                         ip_rule_pref,
                         local_ip_rule_pref,
-                        internal_if_names,
+                        hairpin_rule_if_names(hairpin_config, internal_if_names),
+                        hairpin_config.internal_networks.clone(),
                         hairpin_config.ip_protocols.clone(),
                         ctx.inst.v6_hairpin_dests(),
                     )
@@ -260,101 +1434,447 @@ async fn daemon(config: &Config, contexts: &mut HashMap<u32, IfContext>) -> Resu
                 }
             }
         }
+
+        let if_config = &config.interfaces[ctx.config_idx];
+        let bpf_if_names = hairpin_bpf_if_names(if_config, &ctx.rt_helper).await?;
+        if !bpf_if_names.is_empty() {
+            let res = async {
+                ctx.inst.attach_hairpin_bpf(&bpf_if_names)?;
+                let hairpin_config = &if_config.ipv4_hairpin_route;
+                if if_config.nat44
+                    && hairpin_config.mode != HairpinMode::Route
+                    && hairpin_config.enable != Some(false)
+                {
+                    ctx.enable_accept_local().await?;
+                }
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+            if let Err(e) = res {
+                warn!("failed to attach hairpin program: {}", e);
+            }
+        }
+
+        if if_config.icmp_on_alloc_failure {
+            if let Err(e) = ctx.add_reject_rules(config).await {
+                warn!("failed to add IP rules of `icmp_on_alloc_failure`: {}", e);
+            }
+        }
     }
 
-    drop(rt_helper);
+    drop(namespaces);
 
-    let monitor = async {
-        if !need_monitor {
-            std::future::pending::<()>().await;
-            return Ok(());
+    let (_control_server, mut control_requests) = match &config.defaults.control_socket {
+        Some(path) => {
+            let (server, requests) = control::ControlServer::bind(path, handover)?;
+            (Some(server), Some(requests))
+        }
+        None => (None, None),
+    };
+
+    if handover {
+        // Ask previous instance to exit without detaching, as our TC filters
+        // have atomically replaced theirs.
+        let mut pids = Vec::new();
+        for ctx in contexts.values().filter(|ctx| ctx.lock.is_none()) {
+            let pid = IfLock::holder_pid(ctx.inst.netns(), ctx.attach_if_index)?;
+            if !pids.contains(&pid) {
+                pids.push(pid);
+            }
+        }
+        for pid in pids {
+            info!("handing over from einat instance of PID {}", pid);
+            if unsafe { libc::kill(pid as _, libc::SIGUSR2) } != 0 {
+                warn!(
+                    "failed to signal PID {}: {}",
+                    pid,
+                    std::io::Error::last_os_error()
+                );
+            }
         }
+        for ctx in contexts.values_mut().filter(|ctx| ctx.lock.is_none()) {
+            ctx.lock = Some(
+                IfLock::acquire_timeout(
+                    ctx.inst.netns(),
+                    ctx.attach_if_index,
+                    Duration::from_secs(10),
+                )
+                .await?,
+            );
+        }
+    }
 
-        futures_util::pin_mut!(events);
-        while let Some(event) = events.next().await {
-            let MonitorEvent::ChangeAddress { if_index } = event;
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
 
-            if let Some(ctx) = contexts.get_mut(&if_index) {
-                let new_addresses = ctx.rt_helper.query_all_addresses(if_index).await?;
-                if new_addresses.ipv4 != ctx.addresses.ipv4 {
-                    debug!(
-                        "IPv4 addresses {:?} -> {:?}",
-                        ctx.addresses.ipv4, new_addresses.ipv4
-                    );
-                    ctx.inst.reconfigure_v4_addresses(&new_addresses.ipv4)?;
-                    ctx.addresses.ipv4 = new_addresses.ipv4;
+    let state_sync_peer = config
+        .defaults
+        .state_sync
+        .as_ref()
+        .and_then(|state_sync| Some((state_sync.peer?, state_sync.interval)));
+    let state_sync_interval = |interval: Option<config::Timeout>| {
+        interval.map_or(DEFAULT_STATE_SYNC_INTERVAL, |interval| {
+            Duration::from_nanos(interval.into())
+        })
+    };
+    let mut next_state_sync =
+        state_sync_peer.map(|(_, interval)| Instant::now() + state_sync_interval(interval));
+
+    let monitor = async {
+        let mut events = futures_util::stream::select_all(events);
+        loop {
+            let next_gc = contexts.values().filter_map(|ctx| ctx.inst.next_gc()).min();
+            let next_local_ports_update = contexts
+                .values()
+                .filter_map(|ctx| ctx.inst.next_local_ports_update())
+                .min();
+            let next_address_expiry = contexts
+                .values()
+                .filter_map(|ctx| ctx.address_expiry.map(|t| (t, ctx.ns_idx, ctx.if_index)))
+                .min();
+            let next_address_command = contexts
+                .values()
+                .filter_map(|ctx| ctx.next_address_command)
+                .min();
+            let next_nft_set_query = contexts
+                .values()
+                .filter_map(|ctx| ctx.next_nft_set_query)
+                .min();
+            let (ns_idx, event) = tokio::select! {
+                event = events.next(), if need_monitor => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = sleep_until(next_gc) => {
+                    let now = Instant::now();
+                    for ctx in contexts
+                        .values_mut()
+                        .filter(|ctx| ctx.inst.next_gc().is_some_and(|t| t <= now))
+                    {
+                        if let Err(e) = ctx.inst.collect_garbage() {
+                            error!("failed to collect garbage: {}", e);
+                        }
+                    }
+                    continue;
                 }
-                #[cfg(feature = "ipv6")]
-                if new_addresses.ipv6 != ctx.addresses.ipv6 {
-                    debug!(
-                        "IPv6 addresses {:?} -> {:?}",
-                        ctx.addresses.ipv6, new_addresses.ipv6
-                    );
-                    ctx.inst.reconfigure_v6_addresses(&new_addresses.ipv6)?;
-                    ctx.addresses.ipv6 = new_addresses.ipv6;
+                _ = sleep_until(next_local_ports_update) => {
+                    let now = Instant::now();
+                    for ctx in contexts
+                        .values_mut()
+                        .filter(|ctx| ctx.inst.next_local_ports_update().is_some_and(|t| t <= now))
+                    {
+                        if let Err(e) = ctx.inst.update_local_ports() {
+                            error!("failed to update local ports: {}", e);
+                        }
+                    }
+                    continue;
                 }
-
-                if let Some(hairpin_routing) = &mut ctx.v4_hairpin_routing {
-                    if let Err(e) = hairpin_routing
-                        .reconfigure_dests(ctx.inst.v4_hairpin_dests())
-                        .await
+                _ = sleep_until(next_address_expiry.map(|(t, _, _)| t)) => {
+                    let (_, ns_idx, if_index) = next_address_expiry.unwrap();
+                    (ns_idx, MonitorEvent::ChangeAddress { if_index })
+                }
+                _ = sleep_until(next_address_command) => {
+                    let now = Instant::now();
+                    for ctx in contexts
+                        .values_mut()
+                        .filter(|ctx| ctx.next_address_command.is_some_and(|t| t <= now))
                     {
-                        error!("failed to reconfigure IPv4 hairpin routing: {}", e);
+                        ctx.refresh_command_addresses(&config.interfaces[ctx.config_idx])
+                            .await?;
                     }
+                    continue;
                 }
-
-                #[cfg(feature = "ipv6")]
-                if let Some(hairpin_routing) = &mut ctx.v6_hairpin_routing {
-                    if let Err(e) = hairpin_routing
-                        .reconfigure_dests(ctx.inst.v6_hairpin_dests())
-                        .await
+                _ = sleep_until(next_nft_set_query) => {
+                    let now = Instant::now();
+                    for ctx in contexts
+                        .values_mut()
+                        .filter(|ctx| ctx.next_nft_set_query.is_some_and(|t| t <= now))
                     {
-                        error!("failed to reconfigure IPv6 hairpin routing: {}", e);
+                        ctx.refresh_nft_set(&config.interfaces[ctx.config_idx]).await?;
                     }
+                    continue;
+                }
+                _ = sleep_until(next_state_sync) => {
+                    let (peer, interval) = state_sync_peer.unwrap();
+                    next_state_sync = Some(Instant::now() + state_sync_interval(interval));
+                    // nothing to push while standing by
+                    if contexts.is_empty() {
+                        continue;
+                    }
+                    let mut maps = Vec::new();
+                    for ctx in contexts.values() {
+                        match ctx.inst.binding_map() {
+                            Ok(map) => maps.push((
+                                config.interfaces[ctx.config_idx].interface.to_string(),
+                                map,
+                            )),
+                            Err(e) => error!("failed to open binding map to push: {}", e),
+                        }
+                    }
+                    tokio::spawn(async move {
+                        // dumping large maps takes a while
+                        let dump = tokio::task::spawn_blocking(move || {
+                            maps.into_iter()
+                                .filter_map(|(interface, map)| {
+                                    match snapshot::BindingSnapshot::dump(&map) {
+                                        Ok(snapshot) => Some((interface, snapshot)),
+                                        Err(e) => {
+                                            error!("failed to dump bindings to push: {}", e);
+                                            None
+                                        }
+                                    }
+                                })
+                                .collect::<Vec<_>>()
+                        });
+                        let Ok(interfaces) = dump.await else {
+                            return;
+                        };
+                        if let Err(e) = sync::push(peer, &interfaces).await {
+                            warn!("failed to push state to peer {}: {}", peer, e);
+                        }
+                    });
+                    continue;
+                }
+                _ = sigusr1.recv() => {
+                    info!("running address commands on SIGUSR1");
+                    for ctx in contexts
+                        .values_mut()
+                        .filter(|ctx| ctx.next_address_command.is_some())
+                    {
+                        ctx.refresh_command_addresses(&config.interfaces[ctx.config_idx])
+                            .await?;
+                    }
+                    continue;
+                }
+                pending = recv_request(&mut control_requests) => {
+                    let response = handle_request(config, contexts, &pending.request);
+                    pending.reply(response);
+                    continue;
+                }
+            };
+
+            for ctx in contexts.values().filter(|ctx| ctx.ns_idx == ns_idx) {
+                if ctx
+                    .interface_moved(&config.interfaces[ctx.config_idx])
+                    .await
+                {
+                    return Ok(true);
                 }
             }
+            if matches!(event, MonitorEvent::ChangeAddress { .. }) {
+                for state in vrrp_states.iter().filter(|state| state.ns_idx == ns_idx) {
+                    if state.changed(config).await {
+                        return Ok(true);
+                    }
+                }
+            }
+
+            let if_index = match event {
+                MonitorEvent::ChangeAddress { if_index } => {
+                    #[cfg(feature = "ipv6")]
+                    for ctx in contexts.values_mut().filter(|ctx| {
+                        ctx.ns_idx == ns_idx
+                            && ctx
+                                .clat
+                                .as_ref()
+                                .is_some_and(|clat| clat.uplink_if_index == if_index)
+                    }) {
+                        ctx.reconfigure_clat().await;
+                    }
+                    #[cfg(feature = "ipv6")]
+                    for ctx in contexts.values_mut().filter(|ctx| {
+                        ctx.ns_idx == ns_idx
+                            && ctx
+                                .dslite
+                                .as_ref()
+                                .is_some_and(|dslite| dslite.uplink_if_index == if_index)
+                    }) {
+                        ctx.reconfigure_dslite().await;
+                    }
+                    #[cfg(feature = "ipv6")]
+                    for ctx in contexts.values_mut().filter(|ctx| {
+                        ctx.ns_idx == ns_idx && ctx.inst.nptv6_prefix_if_index() == Some(if_index)
+                    }) {
+                        match ctx.rt_helper.query_all_addresses(if_index).await {
+                            Ok(addresses) => {
+                                ctx.inst.reconfigure_nptv6_prefix_addresses(&addresses.ipv6)
+                            }
+                            Err(e) => error!("failed to query NPTv6 prefix addresses: {}", e),
+                        }
+                    }
+                    if_index
+                }
+                MonitorEvent::ChangeLink => {
+                    for ctx in contexts.values_mut().filter(|ctx| ctx.ns_idx == ns_idx) {
+                        ctx.reconfigure_dynamic_if_names(config).await;
+                    }
+                    continue;
+                }
+                MonitorEvent::ChangeRoute => {
+                    for ctx in contexts.values_mut().filter(|ctx| {
+                        ctx.ns_idx == ns_idx
+                            && config.interfaces[ctx.config_idx]
+                                .route_probes()
+                                .next()
+                                .is_some()
+                    }) {
+                        ctx.update_addresses(&config.interfaces[ctx.config_idx])
+                            .await?;
+                    }
+                    continue;
+                }
+            };
+
+            if let Some(ctx) = contexts.get_mut(&(ns_idx, if_index)) {
+                ctx.update_addresses(&config.interfaces[ctx.config_idx])
+                    .await?;
+            }
         }
 
-        Result::<()>::Ok(())
+        Result::<_>::Ok(false)
     };
 
     let mut sigint = signal(SignalKind::interrupt())?;
     let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
 
-    tokio::select! {
+    let (handed_over, restart) = tokio::select! {
         _ = sigint.recv() => {
-            Result::<()>::Ok(())
+            Result::<_>::Ok((false, false))
         }
         _ = sigterm.recv() => {
-            Result::<()>::Ok(())
+            Result::<_>::Ok((false, false))
+        }
+        _ = sigusr2.recv() => {
+            Result::<_>::Ok((true, false))
         }
         res = monitor => {
-            res
+            res.map(|restart| (false, restart))
         }
     }?;
 
-    Ok(monitor_task)
-}
+    if handed_over {
+        info!("handed over to new einat instance, exiting without cleanup");
+        // TC filters and hairpin routing are now owned by new instance
+        contexts.clear();
+    }
 
-async fn daemon_guard(config: &Config) -> Result<()> {
-    let mut contexts: HashMap<u32, IfContext> = HashMap::with_capacity(config.interfaces.len());
+    Ok(restart)
+}
 
-    let res = daemon(config, &mut contexts).await;
+/// CLAT interfaces set up by us that are not yet owned by any interface
+/// context, deconfigured if starting up fails in between.
+#[derive(Default)]
+struct PendingLinks {
+    #[cfg(feature = "ipv6")]
+    clats: HashMap<usize, clat::Clat>,
+}
 
-    for ctx in contexts.values_mut() {
-        if let Err(e) = ctx.detach().await {
-            error!("failed to cleanup context: {}", e);
-        };
+impl PendingLinks {
+    async fn deconfigure(&mut self) {
+        #[cfg(feature = "ipv6")]
+        for (_, mut clat) in self.clats.drain() {
+            if let Err(e) = clat.deconfigure().await {
+                error!("failed to cleanup CLAT interface: {}", e);
+            }
+        }
     }
+}
 
-    res?.abort();
-    Ok(())
+async fn daemon_guard(config: &Config, handover: bool) -> Result<()> {
+    let mut contexts: HashMap<(usize, u32), IfContext> =
+        HashMap::with_capacity(config.interfaces.len());
+    let mut monitor_tasks = Vec::new();
+
+    // kept across restarts, so peer state is taken over on becoming active
+    let sync_server = match &config.defaults.state_sync {
+        Some(config::ConfigStateSync {
+            listen: Some(listen),
+            peer,
+            ..
+        }) => {
+            let Some(peer) = peer else {
+                return Err(anyhow!("`peer` of state sync is required with `listen`"));
+            };
+            Some(sync::SyncServer::bind(*listen, peer.ip()).await?)
+        }
+        _ => None,
+    };
+
+    let mut handover = handover;
+    let mut carried_bindings = HashMap::new();
+    let mut pending = PendingLinks::default();
+    loop {
+        let res = daemon(
+            config,
+            handover,
+            &mut contexts,
+            &mut monitor_tasks,
+            std::mem::take(&mut carried_bindings),
+            sync_server.as_ref(),
+            &mut pending,
+        )
+        .await;
+        pending.deconfigure().await;
+
+        // detached before attaching to the new interface on restart
+        let restart = matches!(res, Ok(true));
+        for ctx in contexts.values_mut() {
+            let preserve_ports = config.interfaces[ctx.config_idx]
+                .failover
+                .as_ref()
+                .is_some_and(|failover| failover.preserve_ports);
+            if restart && preserve_ports {
+                match ctx.inst.dump_bindings() {
+                    Ok(entries) => {
+                        carried_bindings.insert(ctx.config_idx, entries);
+                    }
+                    Err(e) => error!("failed to dump bindings to carry over: {}", e),
+                }
+            }
+            if let Err(e) = ctx.detach().await {
+                error!("failed to cleanup context: {}", e);
+            };
+        }
+        contexts.clear();
+
+        for task in monitor_tasks.drain(..) {
+            task.abort();
+        }
+
+        match res {
+            Ok(true) => {
+                info!("restarting on changes of external interfaces");
+                handover = false;
+            }
+            res => return res.map(|_| ()),
+        }
+    }
 }
 
-fn tracing_init() -> Result<()> {
+/// Initializes logging with max level raised by `verbose`, and directives of
+/// `log_filter` added to those of `RUST_LOG`.
+fn tracing_init(verbose: u8, log_filter: Option<&str>) -> Result<()> {
     use libbpf_rs::PrintLevel;
+    use tracing::level_filters::LevelFilter;
+    use tracing_subscriber::EnvFilter;
 
-    tracing_subscriber::fmt::init();
+    let level = match verbose {
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let mut filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
+    if let Some(log_filter) = log_filter {
+        for directive in log_filter.split(',').filter(|s| !s.is_empty()) {
+            let directive = directive.parse().map_err(|e| {
+                anyhow::anyhow!("Invalid log filter directive {}: {}", directive, e)
+            })?;
+            filter = filter.add_directive(directive);
+        }
+    }
+    tracing_subscriber::fmt().with_env_filter(filter).init();
 
     libbpf_rs::set_print(Some((PrintLevel::Debug, |level, msg| {
         let span = span!(tracing::Level::DEBUG, "libbpf");
@@ -372,9 +1892,45 @@ fn tracing_init() -> Result<()> {
 }
 
 fn main() -> Result<()> {
-    tracing_init()?;
+    let mut args = parse_env_args()?;
+
+    tracing_init(args.verbose, args.log_filter.as_deref())?;
+
+    if let Some(Command::SaveBindings { pin_path, file }) = &args.command {
+        let snapshot = snapshot::BindingSnapshot::dump_pinned(pin_path)?;
+        snapshot.save(file)?;
+        info!("saved {} binding entries", snapshot.entries.len());
+        return Ok(());
+    }
+
+    if let Some(Command::Bench { repeat }) = args.command {
+        return bench::run(repeat);
+    }
+
+    if let Some(Command::NatTest { stun_servers }) = &args.command {
+        let stun_servers = if stun_servers.is_empty() {
+            nat_test::DEFAULT_STUN_SERVERS
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        } else {
+            stun_servers.clone()
+        };
+        let report = nat_test::run(&stun_servers)?;
+        report.print();
+        std::process::exit(if report.is_full_cone() { 0 } else { 1 });
+    }
 
-    let args = parse_env_args()?;
+    if let Some(Command::Trace {
+        if_name: Some(if_name),
+        ..
+    }) = &args.command
+    {
+        // trace interface also specifies interface to run NAT on if none
+        if args.config_file.is_none() && args.if_name.is_none() && args.if_index.is_none() {
+            args.if_name = Some(if_name.clone());
+        }
+    }
 
     let mut config: Config = if let Some(config_path) = &args.config_file {
         let text = std::fs::read_to_string(config_path)?;
@@ -383,6 +1939,20 @@ fn main() -> Result<()> {
         Config::default()
     };
 
+    if let Some(path) = args.control {
+        config.defaults.control_socket = Some(path);
+    }
+
+    if let Some(Command::Ctl { request }) = &args.command {
+        let Some(path) = &config.defaults.control_socket else {
+            return Err(anyhow::anyhow!(
+                "Control socket is not specified with `--control` or `control_socket`"
+            ));
+        };
+        print!("{}", control::request(path, &request.join(" "))?);
+        return Ok(());
+    }
+
     if args.if_index.is_some() || args.if_name.is_some() {
         if args.config_file.is_some() {
             return Err(anyhow::anyhow!(
@@ -413,7 +1983,11 @@ fn main() -> Result<()> {
 
         let hairpin_route = config::ConfigHairpinRoute {
             enable: None,
+            mode: Default::default(),
             internal_if_names: args.hairpin_if_names,
+            container_bridges: false,
+            local: false,
+            internal_networks: Vec::new(),
             ip_rule_pref: None,
             table_id: None,
             ip_protocols: vec![IpProtocol::Tcp, IpProtocol::Udp],
@@ -421,7 +1995,9 @@ fn main() -> Result<()> {
 
         let if_config = ConfigNetIf {
             interface,
+            netns: args.netns,
             bpf_log_level: args.log_level,
+            pin_path: args.pin_path,
             nat44,
             nat66,
             default_externals: true,
@@ -433,6 +2009,38 @@ fn main() -> Result<()> {
         config.interfaces = vec![if_config];
     }
 
+    if let Some(Command::Trace { if_name, filter }) = &args.command {
+        enable_trace(&mut config, if_name.as_deref(), filter)?;
+    }
+
+    let features = probe::KernelFeatures::get();
+    let decisions = features.degrade(&mut config);
+
+    if args.print_config {
+        println!("{:#?}", config);
+        println!("{:#?}", features);
+        for decision in decisions {
+            println!("{}", decision);
+        }
+        return Ok(());
+    }
+    for decision in decisions {
+        warn!("{}", decision);
+    }
+
+    if let Some(Command::Doctor) = args.command {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let report = rt.block_on(doctor::run(&config));
+        report.print();
+        std::process::exit(if report.failed() { 1 } else { 0 });
+    }
+
+    if let Some(path) = &config.defaults.bpf_object_path {
+        instance::use_bpf_object(path)?;
+    }
+
     if config.interfaces.is_empty() {
         return Err(anyhow::anyhow!("No network interface specified"));
     }
@@ -441,5 +2049,207 @@ fn main() -> Result<()> {
         .enable_all()
         .build()?;
 
-    rt.block_on(daemon_guard(&config))
+    rt.block_on(daemon_guard(&config, args.handover))
+}
+
+/// Enables trace events of packets matching `filter` on interface `if_name`, or
+/// all configured interfaces if `None`.
+fn enable_trace(config: &mut Config, if_name: Option<&str>, filter: &TraceFilter) -> Result<()> {
+    let mut found = false;
+    for if_config in &mut config.interfaces {
+        let matched = match (if_name, &if_config.interface) {
+            (None, _) => true,
+            (Some(name), NetIfId::Name { if_name }) => name == if_name,
+            (Some(name), NetIfId::Auto { .. }) => name == "auto",
+            (Some(_), NetIfId::Index { .. }) => false,
+        };
+        if matched {
+            if_config.trace = Some(filter.clone());
+            found = true;
+        }
+    }
+    if !found {
+        return Err(anyhow::anyhow!(
+            "Interface {} to trace is not configured",
+            if_name.unwrap_or_default()
+        ));
+    }
+    Ok(())
+}
+
+/// Receives next control request, or waits forever if control API is disabled.
+async fn recv_request(
+    requests: &mut Option<tokio::sync::mpsc::Receiver<control::Pending>>,
+) -> control::Pending {
+    if let Some(requests) = requests {
+        if let Some(pending) = requests.recv().await {
+            return pending;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Queries addresses of external interface, excluding those about to expire
+/// unless disabled, and source addresses of `match_route` externals. Also
+/// returns when the next address is to be excluded.
+async fn query_external_addresses(
+    rt_helper: &RouteHelper,
+    if_index: u32,
+    if_config: &ConfigNetIf,
+) -> Result<(IfAddresses, Option<Instant>)> {
+    let margin = if_config
+        .address_expiry_margin
+        .map_or(DEFAULT_ADDRESS_EXPIRY_MARGIN, |margin| {
+            Duration::from_nanos(margin.into())
+        });
+    let (mut addresses, next_expiry) = if margin.is_zero() {
+        (rt_helper.query_all_addresses(if_index).await?, None)
+    } else {
+        let (addresses, next_expiry) = rt_helper.query_addresses_expiring(if_index, margin).await?;
+        (addresses, next_expiry.map(|d| Instant::now() + d))
+    };
+    for dest in if_config.route_probes() {
+        match rt_helper.query_route_source(dest).await? {
+            Some((oif, source)) if oif == if_index => {
+                addresses.route_sources.insert(dest, source);
+            }
+            _ => debug!("{} is not routed via interface {}", dest, if_index),
+        }
+    }
+    Ok((addresses, next_expiry))
+}
+
+/// Resolves interface to perform NAT on, which is the backup interface of
+/// failover pair if the configured one is gone or not live while backup is.
+async fn select_if_index(
+    rt_helper: &RouteHelper,
+    netns: Option<&NetNs>,
+    if_config: &ConfigNetIf,
+) -> Result<u32> {
+    let if_index = rt_helper
+        .resolve_if_index(netns, &if_config.interface)
+        .await;
+    let Some(failover) = &if_config.failover else {
+        return if_index;
+    };
+    if let Ok(if_index) = if_index {
+        if rt_helper.is_if_live(if_index).await? {
+            return Ok(if_index);
+        }
+    }
+    let backup_if_index = with_netns(netns, || {
+        NetIfId::Name {
+            if_name: failover.backup_if_name.clone(),
+        }
+        .resolve_index()
+    });
+    match (if_index, backup_if_index) {
+        (Ok(if_index), Ok(backup_if_index)) => {
+            if rt_helper.is_if_live(backup_if_index).await? {
+                Ok(backup_if_index)
+            } else {
+                Ok(if_index)
+            }
+        }
+        (Ok(if_index), Err(_)) => Ok(if_index),
+        (Err(_), Ok(backup_if_index)) => Ok(backup_if_index),
+        (Err(e), Err(_)) => Err(e),
+    }
+}
+
+fn next_address_command(if_config: &ConfigNetIf) -> Option<Instant> {
+    if_config.address_commands().next()?;
+    let interval = if_config
+        .address_command_interval
+        .map_or(DEFAULT_ADDRESS_COMMAND_INTERVAL, |interval| {
+            Duration::from_nanos(interval.into())
+        });
+    Some(Instant::now() + interval)
+}
+
+fn next_nft_set_query(if_config: &ConfigNetIf) -> Option<Instant> {
+    if_config.no_snat_nft_set.as_ref()?;
+    let interval = if_config
+        .nft_set_interval
+        .map_or(DEFAULT_NFT_SET_INTERVAL, |interval| {
+            Duration::from_nanos(interval.into())
+        });
+    Some(Instant::now() + interval)
+}
+
+/// Queries elements of `no_snat_nft_set`, the previous elements in
+/// `addresses` are kept if the query fails.
+async fn query_nft_set(if_config: &ConfigNetIf, addresses: &IfAddresses) -> Vec<IpNet> {
+    let Some(set) = &if_config.no_snat_nft_set else {
+        return Vec::new();
+    };
+    match nft::query_set(set).await {
+        Ok(networks) => {
+            debug!("nftables set {} has {:?}", set.name, networks);
+            networks
+        }
+        Err(e) => {
+            warn!("failed to query nftables set {}: {:#}", set.name, e);
+            addresses.nft_set_dests.clone()
+        }
+    }
+}
+
+/// Runs `address_command` of externals, the previous output in `addresses`
+/// is kept if a command fails.
+async fn run_address_commands(
+    if_config: &ConfigNetIf,
+    addresses: &IfAddresses,
+) -> HashMap<String, IpAddr> {
+    let mut outputs = HashMap::new();
+    for command in if_config.address_commands() {
+        if outputs.contains_key(command) {
+            continue;
+        }
+        match run_address_command(command).await {
+            Ok(address) => {
+                debug!("address command {} printed {}", command, address);
+                outputs.insert(command.to_string(), address);
+            }
+            Err(e) => {
+                warn!("address command {} failed: {:#}", command, e);
+                if let Some(&address) = addresses.command_outputs.get(command) {
+                    outputs.insert(command.to_string(), address);
+                }
+            }
+        }
+    }
+    outputs
+}
+
+/// Runs shell command and parses the first word of its output as address.
+async fn run_address_command(command: &str) -> Result<IpAddr> {
+    let child = tokio::process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(ADDRESS_COMMAND_TIMEOUT, child)
+        .await
+        .map_err(|_| anyhow!("timed out"))??;
+    if !output.status.success() {
+        return Err(anyhow!("exited with {}", output.status));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let word = stdout
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow!("no output"))?;
+    word.parse()
+        .with_context(|| format!("invalid address {:?}", word))
+}
+
+/// Sleeps until `deadline`, or forever if there is none.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
 }
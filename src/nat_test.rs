@@ -0,0 +1,336 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! NAT behavior discovery with STUN, see RFC 5780
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+pub const DEFAULT_STUN_SERVERS: &[&str] = &["stun.l.google.com:19302", "stun.cloudflare.com:3478"];
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+/// RFC 3489 predecessor of OTHER-ADDRESS
+const ATTR_CHANGED_ADDRESS: u16 = 0x0005;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_OTHER_ADDRESS: u16 = 0x802c;
+const CHANGE_IP: u32 = 0x04;
+const CHANGE_PORT: u32 = 0x02;
+
+const RETRANSMISSIONS: usize = 3;
+const RETRANSMISSION_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Behavior {
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+    /// Not endpoint-independent, but unable to tell which dependent kind
+    Dependent,
+    Undetermined,
+}
+
+impl Display for Behavior {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Behavior::EndpointIndependent => "Endpoint-Independent",
+            Behavior::AddressDependent => "Address-Dependent",
+            Behavior::AddressAndPortDependent => "Address and Port-Dependent",
+            Behavior::Dependent => "Address or Address and Port-Dependent",
+            Behavior::Undetermined => "Undetermined",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct BindingResponse {
+    mapped: Option<SocketAddr>,
+    other: Option<SocketAddr>,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub local: SocketAddr,
+    pub mapped: SocketAddr,
+    pub mapping: Behavior,
+    pub filtering: Behavior,
+}
+
+impl Report {
+    /// Returns true if behaviors satisfy requirements of RFC 4787 for full
+    /// cone NAT, which is what einat implements.
+    pub fn is_full_cone(&self) -> bool {
+        self.mapping == Behavior::EndpointIndependent
+            && self.filtering == Behavior::EndpointIndependent
+    }
+
+    fn nat_type(&self) -> &'static str {
+        use Behavior::*;
+        if self.local == self.mapped {
+            return "No NAT";
+        }
+        match (self.mapping, self.filtering) {
+            (EndpointIndependent, EndpointIndependent) => "Full Cone",
+            (EndpointIndependent, AddressDependent) => "Restricted Cone",
+            (EndpointIndependent, AddressAndPortDependent) => "Port Restricted Cone",
+            (EndpointIndependent, _) => "Cone",
+            (Undetermined, _) => "Unknown",
+            _ => "Symmetric",
+        }
+    }
+
+    pub fn print(&self) {
+        println!("Local address:      {}", self.local);
+        println!("Mapped address:     {}", self.mapped);
+        println!("Mapping behavior:   {}", self.mapping);
+        println!("Filtering behavior: {}", self.filtering);
+        println!("NAT type:           {}", self.nat_type());
+    }
+}
+
+/// Probes NAT behavior against STUN servers, the first server should support
+/// RFC 5780 NAT behavior discovery for determining filtering behavior.
+pub fn run(servers: &[String]) -> Result<Report> {
+    let mut server_addrs = Vec::new();
+    for server in servers {
+        let addrs = server
+            .to_socket_addrs()
+            .with_context(|| format!("failed to resolve STUN server {}", server))?;
+        // stick with address family of the first server
+        let family = server_addrs.first().map(SocketAddr::is_ipv4);
+        if let Some(addr) = addrs
+            .into_iter()
+            .find(|addr| family.map_or(true, |v4| addr.is_ipv4() == v4))
+        {
+            server_addrs.push(addr);
+        }
+    }
+    let Some(&primary) = server_addrs.first() else {
+        return Err(anyhow!("no STUN server specified"));
+    };
+
+    let bind_ip = if primary.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+    // find out local address used for reaching the server, the socket for
+    // probing can't be connected as responses come from other addresses
+    let local_ip = {
+        let socket = UdpSocket::bind((bind_ip, 0))?;
+        socket.connect(primary)?;
+        socket.local_addr()?.ip()
+    };
+    let socket = UdpSocket::bind((local_ip, 0))?;
+    socket.set_read_timeout(Some(RETRANSMISSION_TIMEOUT))?;
+    let local = socket.local_addr()?;
+
+    let resp = binding_request(&socket, primary, 0)?
+        .ok_or_else(|| anyhow!("no response from STUN server {}", primary))?;
+    let mapped = resp
+        .mapped
+        .ok_or_else(|| anyhow!("no mapped address from STUN server {}", primary))?;
+
+    let mapping = if let Some(other) = resp.other {
+        // RFC 5780 section 4.3
+        let resp2 = binding_request(&socket, SocketAddr::new(other.ip(), primary.port()), 0)?;
+        match resp2.and_then(|r| r.mapped) {
+            None => Behavior::Undetermined,
+            Some(mapped2) if mapped2 == mapped => Behavior::EndpointIndependent,
+            Some(mapped2) => match binding_request(&socket, other, 0)?.and_then(|r| r.mapped) {
+                None => Behavior::Dependent,
+                Some(mapped3) if mapped3 == mapped2 => Behavior::AddressDependent,
+                Some(_) => Behavior::AddressAndPortDependent,
+            },
+        }
+    } else {
+        let mut behavior = Behavior::Undetermined;
+        for &server in server_addrs.iter().skip(1) {
+            if server.ip() == primary.ip() {
+                continue;
+            }
+            match binding_request(&socket, server, 0)?.and_then(|r| r.mapped) {
+                Some(mapped2) if mapped2 == mapped => behavior = Behavior::EndpointIndependent,
+                Some(_) => {
+                    behavior = Behavior::Dependent;
+                    break;
+                }
+                None => (),
+            }
+        }
+        behavior
+    };
+
+    let filtering = if resp.other.is_some() {
+        // RFC 5780 section 4.4
+        if binding_request(&socket, primary, CHANGE_IP | CHANGE_PORT)?.is_some() {
+            Behavior::EndpointIndependent
+        } else if binding_request(&socket, primary, CHANGE_PORT)?.is_some() {
+            Behavior::AddressDependent
+        } else {
+            Behavior::AddressAndPortDependent
+        }
+    } else {
+        Behavior::Undetermined
+    };
+
+    Ok(Report {
+        local,
+        mapped,
+        mapping,
+        filtering,
+    })
+}
+
+/// Sends binding request with retransmissions, returns `None` on timeout.
+fn binding_request(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    change: u32,
+) -> Result<Option<BindingResponse>> {
+    let txn_id = new_transaction_id()?;
+    let request = encode_request(&txn_id, change);
+    let mut buf = [0u8; 1500];
+
+    for _ in 0..RETRANSMISSIONS {
+        socket.send_to(&request, server)?;
+        loop {
+            let len = match socket.recv_from(&mut buf) {
+                Ok((len, _)) => len,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(e) => return Err(e.into()),
+            };
+            // ignore responses of retransmitted or previous requests
+            if let Some(resp) = decode_response(&buf[..len], &txn_id) {
+                return Ok(Some(resp));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn new_transaction_id() -> Result<[u8; 12]> {
+    let mut id = [0u8; 12];
+    let res = unsafe { libc::getrandom(id.as_mut_ptr().cast(), id.len(), 0) };
+    if res != id.len() as isize {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(id)
+}
+
+fn encode_request(txn_id: &[u8; 12], change: u32) -> Vec<u8> {
+    let attrs_len: u16 = if change != 0 { 8 } else { 0 };
+    let mut msg = Vec::with_capacity(20 + attrs_len as usize);
+    msg.extend(BINDING_REQUEST.to_be_bytes());
+    msg.extend(attrs_len.to_be_bytes());
+    msg.extend(MAGIC_COOKIE.to_be_bytes());
+    msg.extend(txn_id);
+    if change != 0 {
+        msg.extend(ATTR_CHANGE_REQUEST.to_be_bytes());
+        msg.extend(4u16.to_be_bytes());
+        msg.extend(change.to_be_bytes());
+    }
+    msg
+}
+
+fn decode_response(msg: &[u8], txn_id: &[u8; 12]) -> Option<BindingResponse> {
+    if msg.len() < 20
+        || u16::from_be_bytes([msg[0], msg[1]]) != BINDING_RESPONSE
+        || msg[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || msg[8..20] != txn_id[..]
+    {
+        return None;
+    }
+    let len = u16::from_be_bytes([msg[2], msg[3]]) as usize;
+    let mut attrs = msg.get(20..20 + len)?;
+
+    let mut resp = BindingResponse::default();
+    let mut mapped = None;
+    while attrs.len() >= 4 {
+        let ty = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + len)?;
+        match ty {
+            ATTR_XOR_MAPPED_ADDRESS => resp.mapped = decode_address(value, Some(txn_id)),
+            ATTR_MAPPED_ADDRESS => mapped = decode_address(value, None),
+            ATTR_OTHER_ADDRESS | ATTR_CHANGED_ADDRESS => {
+                resp.other = resp.other.or(decode_address(value, None))
+            }
+            _ => (),
+        }
+        // attributes are padded to 4 bytes
+        attrs = attrs.get((4 + len + 3) & !3..).unwrap_or_default();
+    }
+    // prefer XOR-MAPPED-ADDRESS, MAPPED-ADDRESS is for RFC 3489 servers
+    resp.mapped = resp.mapped.or(mapped);
+    Some(resp)
+}
+
+/// Decodes address attribute, XOR-ed with magic cookie and transaction ID if
+/// `xor_txn_id` is specified.
+fn decode_address(value: &[u8], xor_txn_id: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    let family = *value.get(1)?;
+    let mut port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+    let mut xor_key = [0u8; 16];
+    if let Some(txn_id) = xor_txn_id {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+        xor_key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        xor_key[4..].copy_from_slice(txn_id);
+    }
+    let ip = match family {
+        0x01 => {
+            let mut ip: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            ip.iter_mut().zip(xor_key).for_each(|(b, k)| *b ^= k);
+            IpAddr::from(ip)
+        }
+        0x02 => {
+            let mut ip: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            ip.iter_mut().zip(xor_key).for_each(|(b, k)| *b ^= k);
+            IpAddr::from(ip)
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stun_codec() {
+        let txn_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let request = encode_request(&txn_id, CHANGE_IP | CHANGE_PORT);
+        assert_eq!(request.len(), 28);
+        assert_eq!(&request[2..4], &[0, 8]);
+        assert_eq!(&request[8..20], &txn_id);
+
+        let mut resp = vec![0x01, 0x01, 0, 24];
+        resp.extend(MAGIC_COOKIE.to_be_bytes());
+        resp.extend(txn_id);
+        // XOR-MAPPED-ADDRESS 192.0.2.1:32853
+        resp.extend([
+            0x00, 0x20, 0, 8, 0, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+        ]);
+        // OTHER-ADDRESS 198.51.100.2:3479
+        resp.extend([0x80, 0x2c, 0, 8, 0, 0x01, 0x0d, 0x97, 198, 51, 100, 2]);
+
+        let decoded = decode_response(&resp, &txn_id).unwrap();
+        assert_eq!(decoded.mapped, Some("192.0.2.1:32853".parse().unwrap()));
+        assert_eq!(decoded.other, Some("198.51.100.2:3479".parse().unwrap()));
+
+        assert_eq!(decode_response(&resp, &[0; 12]), None);
+    }
+}
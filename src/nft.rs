@@ -0,0 +1,132 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! Mirroring of nftables sets, queried by running `nft --json list set`
+//! periodically, so NAT exemptions can be driven by firewall tooling that
+//! already maintains these sets.
+use std::net::IpAddr;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use ipnet::{IpNet, Ipv4Subnets, Ipv6Subnets};
+use serde_json::Value;
+
+use crate::config::ConfigNftSet;
+
+/// `nft` is killed if not exited within this
+const NFT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns networks of elements in set, with address ranges split into
+/// covering prefixes.
+pub async fn query_set(set: &ConfigNftSet) -> Result<Vec<IpNet>> {
+    let child = tokio::process::Command::new("nft")
+        .args(["--json", "list", "set", &set.family, &set.table, &set.name])
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(NFT_TIMEOUT, child)
+        .await
+        .map_err(|_| anyhow!("timed out"))?
+        .context("failed to run nft")?;
+    if !output.status.success() {
+        return Err(anyhow!("nft exited with {}", output.status));
+    }
+    parse_set(&output.stdout)
+}
+
+fn parse_set(json: &[u8]) -> Result<Vec<IpNet>> {
+    let root: Value = serde_json::from_slice(json).context("invalid JSON output of nft")?;
+    let set = root["nftables"]
+        .as_array()
+        .and_then(|objects| objects.iter().find_map(|object| object.get("set")))
+        .ok_or_else(|| anyhow!("no set in output of nft"))?;
+    let Some(elems) = set.get("elem").and_then(Value::as_array) else {
+        // empty set
+        return Ok(Vec::new());
+    };
+
+    let mut networks = Vec::new();
+    for elem in elems {
+        parse_elem(elem, &mut networks)
+            .with_context(|| format!("unsupported set element {}", elem))?;
+    }
+    Ok(networks)
+}
+
+fn parse_elem(elem: &Value, networks: &mut Vec<IpNet>) -> Result<()> {
+    if let Some(addr) = elem.as_str() {
+        networks.push(IpNet::from(addr.parse::<IpAddr>()?));
+    } else if let Some(prefix) = elem.get("prefix") {
+        let addr: IpAddr = prefix["addr"]
+            .as_str()
+            .ok_or_else(|| anyhow!("no prefix address"))?
+            .parse()?;
+        let len = prefix["len"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("no prefix length"))?;
+        networks.push(IpNet::new(addr, len.try_into()?)?.trunc());
+    } else if let Some(range) = elem.get("range") {
+        let bound = |i: usize| -> Result<IpAddr> {
+            Ok(range[i]
+                .as_str()
+                .ok_or_else(|| anyhow!("no range bound"))?
+                .parse()?)
+        };
+        match (bound(0)?, bound(1)?) {
+            (IpAddr::V4(start), IpAddr::V4(end)) => {
+                networks.extend(Ipv4Subnets::new(start, end, 0).map(IpNet::V4));
+            }
+            (IpAddr::V6(start), IpAddr::V6(end)) => {
+                networks.extend(Ipv6Subnets::new(start, end, 0).map(IpNet::V6));
+            }
+            _ => return Err(anyhow!("mismatched range bounds")),
+        }
+    } else if let Some(inner) = elem.get("elem") {
+        // element with timeout, counter or comment
+        parse_elem(&inner["val"], networks)?;
+    } else {
+        return Err(anyhow!("not an address"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_elements() {
+        let json = br#"{"nftables": [
+            {"metainfo": {"version": "1.0.9", "json_schema_version": 1}},
+            {"set": {"family": "inet", "name": "no_snat", "table": "nat",
+                "type": "ipv4_addr", "handle": 3, "flags": ["interval"],
+                "elem": [
+                    "192.0.2.1",
+                    {"prefix": {"addr": "198.51.100.0", "len": 24}},
+                    {"range": ["203.0.113.8", "203.0.113.23"]},
+                    {"elem": {"val": "192.0.2.9", "timeout": 60, "expires": 42}}
+                ]}}
+        ]}"#;
+        let networks: Vec<String> = parse_set(json)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            networks,
+            [
+                "192.0.2.1/32",
+                "198.51.100.0/24",
+                "203.0.113.8/29",
+                "203.0.113.16/29",
+                "192.0.2.9/32"
+            ]
+        );
+
+        let empty = br#"{"nftables": [{"set": {"family": "ip", "name": "s",
+            "table": "t", "type": "ipv4_addr", "handle": 1}}]}"#;
+        assert!(parse_set(empty).unwrap().is_empty());
+        assert!(parse_set(br#"{"nftables": []}"#).is_err());
+    }
+}
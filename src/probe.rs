@@ -0,0 +1,172 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! Probing of optional kernel features at startup, so functionality depending
+//! on missing features is disabled with a warning instead of failing the load
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Context, Result};
+use libbpf_rs::btf::types::{Enum, Struct};
+use libbpf_rs::{Btf, MapFlags, MapHandle, MapType};
+use tracing::debug;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy)]
+pub struct KernelFeatures {
+    /// Kernel BTF at `/sys/kernel/btf/vmlinux`, required for CO-RE relocations
+    /// unless a custom BTF is provided
+    pub vmlinux_btf: bool,
+    /// `BPF_FIB_LOOKUP_SRC` flag of `bpf_fib_lookup()`, Linux kernel>=6.7
+    pub fib_lookup_src: bool,
+    /// Batch operations on hash maps, Linux kernel>=5.6
+    pub batch_ops: bool,
+    /// BPF map element iterators, Linux kernel>=5.9, which attach to kernel
+    /// BTF and are not usable with custom BTF
+    pub map_elem_iter: bool,
+    /// TCX attachment, Linux kernel>=6.6. Not used for now, legacy TC filters
+    /// are attached instead.
+    #[allow(dead_code)]
+    pub tcx: bool,
+}
+
+impl KernelFeatures {
+    /// Returns features of running kernel, probed on first call.
+    pub fn get() -> &'static Self {
+        static FEATURES: OnceLock<KernelFeatures> = OnceLock::new();
+        FEATURES.get_or_init(Self::probe)
+    }
+
+    fn probe() -> Self {
+        let btf = Btf::from_vmlinux()
+            .map_err(|e| debug!("failed to load kernel BTF: {}", e))
+            .ok();
+        let enum_value_exists = |name: &str| {
+            btf.as_ref().is_some_and(|btf| {
+                btf.type_by_kind::<Enum>().any(|e| {
+                    e.iter()
+                        .any(|member| member.name.map(|n| n.to_bytes()) == Some(name.as_bytes()))
+                })
+            })
+        };
+
+        let features = Self {
+            vmlinux_btf: btf.is_some(),
+            fib_lookup_src: enum_value_exists("BPF_FIB_LOOKUP_SRC"),
+            batch_ops: probe_batch_ops(),
+            map_elem_iter: btf.as_ref().is_some_and(|btf| {
+                btf.type_by_name::<Struct>("bpf_iter__bpf_map_elem")
+                    .is_some()
+            }),
+            tcx: enum_value_exists("BPF_TCX_INGRESS"),
+        };
+        debug!("kernel features: {:?}", features);
+        features
+    }
+
+    /// Disables functionality in `config` depending on missing features,
+    /// returning the decisions made.
+    pub fn degrade(&self, config: &mut Config) -> Vec<String> {
+        let mut decisions = Vec::new();
+
+        if !self.fib_lookup_src {
+            for if_config in config.interfaces.iter_mut() {
+                if if_config.bpf_fib_lookup_external == Some(true) {
+                    if_config.bpf_fib_lookup_external = Some(false);
+                    decisions.push(format!(
+                        "disabled `bpf_fib_lookup_external` of interface {}, which requires Linux kernel>=6.7",
+                        if_config.interface
+                    ));
+                }
+            }
+        }
+
+        if !self.vmlinux_btf && config.defaults.btf_path.is_none() {
+            match embedded_btf() {
+                Ok(Some(path)) => {
+                    decisions.push(format!(
+                        "kernel BTF not available, using embedded min core BTF {}",
+                        path.display()
+                    ));
+                    config.defaults.btf_path = Some(path);
+                }
+                Ok(None) => decisions.push(
+                    "kernel BTF not available and no embedded BTF matches running kernel, \
+                     consider specifying `btf_path`"
+                        .into(),
+                ),
+                Err(e) => decisions.push(format!(
+                    "kernel BTF not available, failed to extract embedded BTF: {:#}",
+                    e
+                )),
+            }
+        }
+
+        if !self.batch_ops {
+            decisions
+                .push("batch map operations not supported, updating entries one by one".into());
+        }
+        if !self.map_elem_iter {
+            decisions.push("BPF map iterators not available, walking map keys instead".into());
+        }
+
+        decisions
+    }
+}
+
+/// Min core BTF blobs embedded at build time, keyed by kernel release.
+static EMBEDDED_BTFS: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/btfs.rs"));
+
+pub fn kernel_release() -> Result<String> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::uname(&mut uts) };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to get kernel release");
+    }
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) };
+    Ok(release.to_string_lossy().into_owned())
+}
+
+/// Resolves `btf_path` in config to a BTF file, which could be a directory
+/// containing BTF files named `<kernel release>.btf`.
+pub fn resolve_btf_path(path: &Path) -> Result<PathBuf> {
+    if !path.is_dir() {
+        return Ok(path.to_owned());
+    }
+    let file = path.join(format!("{}.btf", kernel_release()?));
+    if !file.exists() {
+        return Err(anyhow!(
+            "no BTF for running kernel found in {}, expecting {}",
+            path.display(),
+            file.display()
+        ));
+    }
+    Ok(file)
+}
+
+/// Extracts embedded BTF matching running kernel to a temporary file.
+fn embedded_btf() -> Result<Option<PathBuf>> {
+    if EMBEDDED_BTFS.is_empty() {
+        return Ok(None);
+    }
+    let release = kernel_release()?;
+    let Some((_, btf)) = EMBEDDED_BTFS.iter().find(|(r, _)| *r == release) else {
+        return Ok(None);
+    };
+    let path = std::env::temp_dir().join(format!("einat-{}.btf", release));
+    std::fs::write(&path, btf).with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(Some(path))
+}
+
+fn probe_batch_ops() -> bool {
+    let opts = libbpf_sys::bpf_map_create_opts {
+        sz: core::mem::size_of::<libbpf_sys::bpf_map_create_opts>() as _,
+        ..Default::default()
+    };
+    let Ok(map) = MapHandle::create(MapType::Hash, Some("einat_probe"), 4, 4, 1, &opts) else {
+        return false;
+    };
+    map.update_batch(&[0; 4], &[0; 4], 1, MapFlags::ANY, MapFlags::ANY)
+        .is_ok()
+}
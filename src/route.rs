@@ -1,21 +1,29 @@
 // SPDX-FileCopyrightText: 2023 Huang-Huang Bao
 // SPDX-License-Identifier: GPL-2.0-or-later
 use std::cmp::Ordering;
+use std::collections::HashMap;
 #[cfg(feature = "ipv6")]
 use std::net::Ipv6Addr;
 use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
 
 use anyhow::Result;
 use futures_util::{Stream, StreamExt, TryStreamExt};
-use ipnet::Ipv4Net;
 #[cfg(feature = "ipv6")]
 use ipnet::Ipv6Net;
-use netlink_packet_core::NetlinkPayload;
+use ipnet::{IpNet, Ipv4Net};
+use netlink_packet_core::{NetlinkMessage, NetlinkPayload, NLM_F_REQUEST};
+#[cfg(feature = "ipv6")]
+use netlink_packet_route::address::{AddressFlag, AddressHeaderFlag, AddressMessage};
+#[cfg(feature = "ipv6")]
+use netlink_packet_route::link::InfoData;
 use netlink_packet_route::{
     address::AddressAttribute,
-    link::{InfoKind, LinkAttribute, LinkInfo as AttrLinkInfo, LinkLayerType, LinkMessage},
-    neighbour::{NeighbourMessage, NeighbourState},
-    route::{RouteAddress, RouteAttribute, RouteMessage, RouteProtocol},
+    link::{
+        InfoKind, LinkAttribute, LinkFlag, LinkInfo as AttrLinkInfo, LinkLayerType, LinkMessage,
+    },
+    neighbour::{NeighbourAddress, NeighbourAttribute, NeighbourMessage, NeighbourState},
+    route::{RouteAddress, RouteAttribute, RouteHeader, RouteMessage, RouteProtocol, RouteType},
     rule::{RuleAction, RuleAttribute, RuleMessage},
     AddressFamily, IpProtocol as RouteIpProtocol, RouteNetlinkMessage,
 };
@@ -24,8 +32,10 @@ use rtnetlink::{new_connection, Handle, IpVersion, NeighbourAddRequest, RouteAdd
 use tokio::task::JoinHandle;
 use tracing::warn;
 
-use crate::config::IpProtocol;
-use crate::utils::IpNetwork;
+use crate::config::{IpProtocol, NetIfId};
+#[cfg(feature = "ipv6")]
+use crate::utils::is_global_unicast;
+use crate::utils::{with_netns, IpNetwork, NetNs};
 
 impl From<IpProtocol> for RouteIpProtocol {
     fn from(value: IpProtocol) -> Self {
@@ -52,6 +62,8 @@ const fn nl_mgrp(group: u32) -> u32 {
 pub enum PacketEncap {
     BareIp,
     Ethernet,
+    /// PPPoE session on Ethernet
+    Pppoe,
     Unsupported,
     Unknown,
 }
@@ -64,6 +76,60 @@ pub struct IfAddresses {
     pub ipv4: Vec<Ipv4Addr>,
     #[cfg(feature = "ipv6")]
     pub ipv6: Vec<Ipv6Addr>,
+    pub attrs: HashMap<IpAddr, AddressAttrs>,
+    /// Outputs of `address_command` of externals, not queried from
+    /// interface but kept here as they are resolved the same way
+    pub command_outputs: HashMap<String, IpAddr>,
+    /// Kernel-selected source addresses of routes towards `match_route`
+    /// destinations, only of those routed via this interface
+    pub route_sources: HashMap<IpAddr, IpAddr>,
+    /// Elements of `no_snat_nft_set`, likewise
+    pub nft_set_dests: Vec<IpNet>,
+}
+
+/// Attributes of interface address to match external addresses by
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AddressAttrs {
+    pub label: Option<String>,
+    /// `RT_SCOPE_*`
+    pub scope: u8,
+    /// `IFA_F_*`
+    pub flags: u32,
+}
+
+impl IfAddresses {
+    /// Removes addresses of which the remaining valid lifetime is within
+    /// `margin`, so external address is switched and bindings of them are
+    /// drained before they are gone. Addresses are kept if no other address
+    /// (global address for IPv6) of the same family would be left. Returns
+    /// time until the next address falls within `margin`.
+    fn exclude_expiring(
+        &mut self,
+        lifetimes: &[(IpAddr, Duration)],
+        margin: Duration,
+    ) -> Option<Duration> {
+        let expiring = |addr: IpAddr| {
+            lifetimes
+                .iter()
+                .any(|&(address, valid)| address == addr && valid <= margin)
+        };
+        if self.ipv4.iter().any(|addr| !expiring((*addr).into())) {
+            self.ipv4.retain(|addr| !expiring((*addr).into()));
+        }
+        #[cfg(feature = "ipv6")]
+        if self
+            .ipv6
+            .iter()
+            .any(|addr| is_global_unicast(addr) && !expiring((*addr).into()))
+        {
+            self.ipv6.retain(|addr| !expiring((*addr).into()));
+        }
+        lifetimes
+            .iter()
+            .filter(|(_, valid)| *valid > margin)
+            .map(|(_, valid)| *valid - margin)
+            .min()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -72,6 +138,16 @@ pub struct RouteHelper {
 }
 
 impl LinkInfo {
+    pub fn name(&self) -> Option<&str> {
+        self.0.attributes.iter().find_map(|attr| {
+            if let LinkAttribute::IfName(name) = attr {
+                Some(name.as_str())
+            } else {
+                None
+            }
+        })
+    }
+
     pub fn address(&self) -> Option<&Vec<u8>> {
         self.0.attributes.iter().find_map(|attr| {
             if let LinkAttribute::Address(addr) = attr {
@@ -82,6 +158,50 @@ impl LinkInfo {
         })
     }
 
+    #[cfg(feature = "ipv6")]
+    pub fn mtu(&self) -> Option<u32> {
+        self.0.attributes.iter().find_map(|attr| {
+            if let &LinkAttribute::Mtu(mtu) = attr {
+                Some(mtu)
+            } else {
+                None
+            }
+        })
+    }
+
+    #[cfg(feature = "ipv6")]
+    pub fn is_ip6tnl(&self) -> bool {
+        matches!(self.kind(), Some(InfoKind::Other(kind)) if kind == "ip6tnl")
+    }
+
+    /// Whether link is up and has carrier.
+    pub fn is_running(&self) -> bool {
+        self.0.header.flags.contains(&LinkFlag::Up)
+            && self.0.header.flags.contains(&LinkFlag::LowerUp)
+    }
+
+    /// Index of master interface if this is enslaved, e.g. a bridge port.
+    pub fn controller(&self) -> Option<u32> {
+        self.0.attributes.iter().find_map(|attr| {
+            if let &LinkAttribute::Controller(index) = attr {
+                Some(index)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns link kind name if this aggregates traffic of its ports, i.e.
+    /// bridge, bond or team.
+    pub fn aggregate_kind(&self) -> Option<String> {
+        match self.kind()? {
+            InfoKind::Bridge => Some("bridge".to_string()),
+            InfoKind::Bond => Some("bond".to_string()),
+            InfoKind::Other(kind) if kind == "team" => Some(kind.clone()),
+            _ => None,
+        }
+    }
+
     fn kind(&self) -> Option<&InfoKind> {
         let infos = self.0.attributes.iter().find_map(|attr| {
             if let LinkAttribute::LinkInfo(addr) = attr {
@@ -106,6 +226,10 @@ impl LinkInfo {
             LinkLayerType::Loopback => Ethernet,
             LinkLayerType::None => BareIp,
             LinkLayerType::Ppp => BareIp,
+            // ipip, ip6tnl and sit tunnels
+            LinkLayerType::Tunnel => BareIp,
+            LinkLayerType::Tunnel6 => BareIp,
+            LinkLayerType::Sit => BareIp,
             LinkLayerType::Ipgre => Unsupported,
             LinkLayerType::Ip6gre => Unsupported,
             LinkLayerType::Netlink => Unsupported,
@@ -137,6 +261,7 @@ impl LinkInfo {
             // most tunnel has just bare IP
             InfoKind::IpTun => BareIp,
             InfoKind::SitTun => BareIp,
+            InfoKind::Other(kind) if kind == "ip6tnl" => BareIp,
             InfoKind::GreTun => Unsupported,
             InfoKind::GreTun6 => Unsupported,
             InfoKind::Vti => Unknown,
@@ -163,7 +288,8 @@ impl LinkInfo {
             return encap;
         }
 
-        if self.address().is_some() {
+        // Tunnels have their IP address of endpoint as link address
+        if self.address().is_some_and(|addr| addr.len() == 6) {
             PacketEncap::Ethernet
         } else {
             PacketEncap::Unknown
@@ -172,6 +298,8 @@ impl LinkInfo {
 }
 
 const ROUTE_LOCAL_TABLE_ID: u32 = 255;
+/// Lifetime of permanent addresses in `struct ifa_cacheinfo`
+const INFINITY_LIFE_TIME: u32 = u32::MAX;
 
 impl RouteHelper {
     pub async fn query_link_info(&self, if_index: u32) -> Result<LinkInfo> {
@@ -190,7 +318,378 @@ impl RouteHelper {
         Ok(LinkInfo(link))
     }
 
+    /// Removes hairpin routes marked with `route_protocol` in any route table
+    /// left by previous runs, e.g. of interfaces no longer configured or
+    /// previously configured route tables.
+    ///
+    /// If `exclusive`, i.e. no other einat instance is running in the network
+    /// namespace, all marked routes and hairpin IP rules pointing to route
+    /// tables emptied by this are removed. Otherwise only routes on
+    /// `own_if_indexes` are removed.
+    pub async fn cleanup_orphaned<N: RouteIpNetwork>(
+        &self,
+        route_protocol: u8,
+        own_if_indexes: &[u32],
+        exclusive: bool,
+    ) -> Result<()> {
+        let route_protocol = RouteProtocol::from(route_protocol);
+
+        let mut orphaned_routes = Vec::new();
+        let mut tables_in_use = Vec::new();
+        let mut s = self.handle.route().get(N::IP_VERSION).execute();
+        while let Some(route) = s.try_next().await? {
+            let orphaned = route.header.protocol == route_protocol
+                && route_output_if_index(&route)
+                    .is_some_and(|if_index| exclusive || own_if_indexes.contains(&if_index));
+            if orphaned {
+                orphaned_routes.push(route);
+            } else {
+                tables_in_use.push(route_table_id(&route));
+            }
+        }
+
+        let mut emptied_tables = Vec::new();
+        for route in &orphaned_routes {
+            let table_id = route_table_id(route);
+            if !tables_in_use.contains(&table_id) && !emptied_tables.contains(&table_id) {
+                emptied_tables.push(table_id);
+            }
+        }
+
+        let mut orphaned_rules = Vec::new();
+        if exclusive {
+            let mut s = self.handle.rule().get(N::IP_VERSION).execute();
+            while let Some(rule) = s.try_next().await? {
+                if rule_is_hairpin(&rule) && emptied_tables.contains(&rule_table_id(&rule)) {
+                    orphaned_rules.push(rule);
+                }
+            }
+        }
+
+        if orphaned_routes.is_empty() {
+            return Ok(());
+        }
+        warn!(
+            "removing {} orphaned hairpin routes and {} IP rules left by previous run",
+            orphaned_routes.len(),
+            orphaned_rules.len(),
+        );
+        for rule in orphaned_rules {
+            if let Err(e) = self.handle.rule().del(rule).execute().await {
+                warn!("failed to delete orphaned rule: {}", e);
+            }
+        }
+        for route in orphaned_routes {
+            if let Err(e) = self.handle.route().del(route).execute().await {
+                warn!("failed to delete orphaned route: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "ipv6")]
+    pub async fn set_link_up(&self, if_index: u32, mtu: Option<u32>) -> Result<()> {
+        let mut req = self.handle.link().set(if_index).up();
+        if let Some(mtu) = mtu {
+            req = req.mtu(mtu);
+        }
+        req.execute().await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "ipv6")]
+    /// Adds address to interface, returns the address message to delete it
+    /// with. IPv6 addresses are added without duplicate address detection.
+    pub async fn add_address(
+        &self,
+        if_index: u32,
+        address: IpAddr,
+        prefix_len: u8,
+    ) -> Result<AddressMessage> {
+        let mut req = self
+            .handle
+            .address()
+            .add(if_index, address, prefix_len)
+            .replace();
+        if address.is_ipv6() {
+            req.message_mut()
+                .header
+                .flags
+                .push(AddressHeaderFlag::Nodad);
+        }
+        let msg = req.message_mut().clone();
+        req.execute().await?;
+        Ok(msg)
+    }
+
+    #[cfg(feature = "ipv6")]
+    pub async fn del_address(&self, address: AddressMessage) -> Result<()> {
+        self.handle.address().del(address).execute().await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "ipv6")]
+    /// Adds IPv4 default route via interface, lower `metric` is preferred.
+    pub async fn add_v4_default_route(&self, if_index: u32, metric: u32) -> Result<()> {
+        self.handle
+            .route()
+            .add()
+            .v4()
+            .output_interface(if_index)
+            .priority(metric)
+            .protocol(RouteProtocol::Static)
+            .replace()
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "ipv6")]
+    /// Creates ip6tnl interface in IPv4-in-IPv6 mode bound to `link_if_index`,
+    /// or updates tunnel parameters of it if `if_index` is specified.
+    pub async fn set_ip4ip6_tunnel(
+        &self,
+        if_name: &str,
+        if_index: Option<u32>,
+        link_if_index: u32,
+        local: Ipv6Addr,
+        remote: Ipv6Addr,
+    ) -> Result<()> {
+        let link_info = LinkAttribute::LinkInfo(vec![
+            AttrLinkInfo::Kind(InfoKind::Other("ip6tnl".to_string())),
+            AttrLinkInfo::Data(InfoData::Other(ip4ip6_tunnel_info_data(
+                link_if_index,
+                local,
+                remote,
+            ))),
+        ]);
+        if let Some(if_index) = if_index {
+            let mut req = self.handle.link().set(if_index);
+            req.message_mut().attributes.push(link_info);
+            req.execute().await?;
+        } else {
+            let mut req = self.handle.link().add().name(if_name.to_string());
+            req.message_mut().attributes.push(link_info);
+            req.execute().await?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "ipv6")]
+    pub async fn del_link(&self, if_index: u32) -> Result<()> {
+        self.handle.link().del(if_index).execute().await?;
+        Ok(())
+    }
+
+    pub async fn query_link_names(&self) -> Result<Vec<String>> {
+        let mut links = self.handle.link().get().execute();
+
+        let mut res = Vec::new();
+        while let Some(link) = links.try_next().await? {
+            res.extend(link.attributes.into_iter().find_map(|attr| {
+                if let LinkAttribute::IfName(name) = attr {
+                    Some(name)
+                } else {
+                    None
+                }
+            }));
+        }
+        res.sort();
+        Ok(res)
+    }
+
+    /// Queries names of bridge interfaces created by Docker or Podman for
+    /// container networks.
+    pub async fn query_container_bridges(&self) -> Result<Vec<String>> {
+        let mut links = self.handle.link().get().execute();
+
+        let mut res = Vec::new();
+        while let Some(link) = links.try_next().await? {
+            let mut if_name = None;
+            let mut is_bridge = false;
+            for attr in link.attributes {
+                match attr {
+                    LinkAttribute::IfName(name) => if_name = Some(name),
+                    LinkAttribute::LinkInfo(infos) => {
+                        is_bridge = infos
+                            .iter()
+                            .any(|info| matches!(info, AttrLinkInfo::Kind(InfoKind::Bridge)))
+                    }
+                    _ => (),
+                }
+            }
+            if let Some(if_name) = if_name {
+                if is_bridge && is_container_bridge_name(&if_name) {
+                    res.push(if_name);
+                }
+            }
+        }
+        res.sort();
+        Ok(res)
+    }
+
+    /// Looks up route towards `dest` like `ip route get`, returns outgoing
+    /// interface and preferred source address, or `None` if unreachable.
+    pub async fn query_route_source(&self, dest: IpAddr) -> Result<Option<(u32, IpAddr)>> {
+        let mut msg = RouteMessage::default();
+        let (family, prefix_len, address) = match dest {
+            IpAddr::V4(v4) => (AddressFamily::Inet, 32, RouteAddress::Inet(v4)),
+            IpAddr::V6(v6) => (AddressFamily::Inet6, 128, RouteAddress::Inet6(v6)),
+        };
+        msg.header.address_family = family;
+        msg.header.destination_prefix_length = prefix_len;
+        msg.attributes.push(RouteAttribute::Destination(address));
+
+        // not a dump request, which lists routes instead of looking up
+        let mut req = NetlinkMessage::from(RouteNetlinkMessage::GetRoute(msg));
+        req.header.flags = NLM_F_REQUEST;
+        let mut responses = self.handle.clone().request(req)?;
+        while let Some(msg) = responses.next().await {
+            match msg.payload {
+                NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewRoute(msg)) => {
+                    let mut oif = None;
+                    let mut source = None;
+                    for attr in msg.attributes {
+                        match attr {
+                            RouteAttribute::Oif(index) => oif = Some(index),
+                            RouteAttribute::PrefSource(RouteAddress::Inet(v4)) => {
+                                source = Some(IpAddr::V4(v4))
+                            }
+                            RouteAttribute::PrefSource(RouteAddress::Inet6(v6)) => {
+                                source = Some(IpAddr::V6(v6))
+                            }
+                            _ => (),
+                        }
+                    }
+                    return Ok(oif.zip(source));
+                }
+                NetlinkPayload::Error(e) => {
+                    let unreachable = e.code.is_some_and(|code| {
+                        [-libc::ENETUNREACH, -libc::EHOSTUNREACH].contains(&code.get())
+                    });
+                    if unreachable {
+                        return Ok(None);
+                    }
+                    return Err(rtnetlink::Error::NetlinkError(e).into());
+                }
+                _ => (),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Resolves index of interface, `interface = "auto"` is resolved to the
+    /// output interface of default route.
+    pub async fn resolve_if_index(
+        &self,
+        netns: Option<&NetNs>,
+        interface: &NetIfId,
+    ) -> Result<u32> {
+        if !interface.is_auto() {
+            return with_netns(netns, || interface.resolve_index());
+        }
+        self.query_default_route_if_index()
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("no default route to select interface from"))
+    }
+
+    /// Queries output interface of default route in main table with the
+    /// lowest metric, IPv4 default route is preferred over IPv6 one.
+    pub async fn query_default_route_if_index(&self) -> Result<Option<u32>> {
+        let lowest =
+            |routes: Vec<(u32, u32)>| routes.into_iter().min().map(|(_, if_index)| if_index);
+        #[allow(unused_mut)]
+        let mut res = lowest(self.query_default_routes::<Ipv4Net>().await?);
+        #[cfg(feature = "ipv6")]
+        if res.is_none() {
+            res = lowest(self.query_default_routes::<Ipv6Net>().await?);
+        }
+        Ok(res)
+    }
+
+    /// Whether interface is running and holds a default route in main table
+    /// of any metric, so it's usable for outbound traffic.
+    pub async fn is_if_live(&self, if_index: u32) -> Result<bool> {
+        if !self.query_link_info(if_index).await?.is_running() {
+            return Ok(false);
+        }
+        #[allow(unused_mut)]
+        let mut routes = self.query_default_routes::<Ipv4Net>().await?;
+        #[cfg(feature = "ipv6")]
+        routes.extend(self.query_default_routes::<Ipv6Net>().await?);
+        Ok(routes.iter().any(|&(_, oif)| oif == if_index))
+    }
+
+    /// Returns metrics and output interfaces of default routes in main table.
+    async fn query_default_routes<N: RouteIpNetwork>(&self) -> Result<Vec<(u32, u32)>> {
+        let mut res = Vec::new();
+        let mut s = self.handle.route().get(N::IP_VERSION).execute();
+        while let Some(route) = s.try_next().await? {
+            if route.header.destination_prefix_length != 0
+                || route.header.kind != RouteType::Unicast
+                || route_table_id(&route) != RouteHeader::RT_TABLE_MAIN as u32
+            {
+                continue;
+            }
+            let Some(if_index) = route_output_if_index(&route) else {
+                continue;
+            };
+            let metric = route
+                .attributes
+                .iter()
+                .find_map(|attr| {
+                    if let RouteAttribute::Priority(metric) = attr {
+                        Some(*metric)
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or_default();
+            res.push((metric, if_index));
+        }
+        Ok(res)
+    }
+
+    /// Whether address is assigned to any interface, e.g. virtual address of
+    /// VRRP on master.
+    pub async fn has_address(&self, address: IpAddr) -> Result<bool> {
+        let mut addresses = self.handle.address().get().execute();
+        while let Some(msg) = addresses.try_next().await? {
+            let found = msg.attributes.iter().any(|attr| {
+                matches!(attr, AddressAttribute::Local(addr) | AddressAttribute::Address(addr)
+                    if *addr == address)
+            });
+            if found {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
     pub async fn query_all_addresses(&self, if_index: u32) -> Result<IfAddresses> {
+        Ok(self.query_addresses_with_lifetimes(if_index).await?.0)
+    }
+
+    /// Queries addresses of interface, excluding those of which the valid
+    /// lifetime would end within `margin`, see
+    /// [`IfAddresses::exclude_expiring`]. Also returns time until the next
+    /// address would be excluded.
+    pub async fn query_addresses_expiring(
+        &self,
+        if_index: u32,
+        margin: Duration,
+    ) -> Result<(IfAddresses, Option<Duration>)> {
+        let (mut addresses, lifetimes) = self.query_addresses_with_lifetimes(if_index).await?;
+        let next_expiry = addresses.exclude_expiring(&lifetimes, margin);
+        Ok((addresses, next_expiry))
+    }
+
+    /// Queries addresses of interface, along with remaining valid lifetimes
+    /// of those not permanent.
+    async fn query_addresses_with_lifetimes(
+        &self,
+        if_index: u32,
+    ) -> Result<(IfAddresses, Vec<(IpAddr, Duration)>)> {
         let mut addresses = self
             .handle
             .address()
@@ -199,6 +698,9 @@ impl RouteHelper {
             .execute();
 
         let mut res = IfAddresses::default();
+        let mut lifetimes = Vec::new();
+        #[cfg(feature = "ipv6")]
+        let mut ipv6_ranks = Vec::new();
 
         while let Some(msg) = addresses.try_next().await? {
             #[cfg(feature = "ipv6")]
@@ -219,27 +721,96 @@ impl RouteHelper {
                 // Thus we prefer local address if it's found in returned attributes.
                 let mut local_address = None;
                 let mut address = None;
+                let mut valid_lifetime = None;
+                let mut label = None;
+                let mut flags = Vec::new();
                 for attr in msg.attributes {
                     match attr {
                         AddressAttribute::Local(addr) => local_address = Some(addr),
                         AddressAttribute::Address(addr) => address = Some(addr),
+                        AddressAttribute::CacheInfo(info)
+                            if info.ifa_valid != INFINITY_LIFE_TIME =>
+                        {
+                            valid_lifetime = Some(Duration::from_secs(info.ifa_valid.into()))
+                        }
+                        AddressAttribute::Label(addr_label) => label = Some(addr_label),
+                        AddressAttribute::Flags(addr_flags) => flags = addr_flags,
                         _ => (),
                     }
                 }
 
                 #[allow(clippy::collapsible_match)]
                 if let Some(addr) = local_address.or(address) {
+                    if let Some(valid_lifetime) = valid_lifetime {
+                        lifetimes.push((addr, valid_lifetime));
+                    }
+                    res.attrs.insert(
+                        addr,
+                        AddressAttrs {
+                            label,
+                            scope: msg.header.scope.into(),
+                            flags: flags.iter().fold(0, |acc, &flag| acc | u32::from(flag)),
+                        },
+                    );
                     match addr {
                         IpAddr::V4(addr) => res.ipv4.push(addr),
                         #[cfg(feature = "ipv6")]
-                        IpAddr::V6(addr) => res.ipv6.push(addr),
+                        IpAddr::V6(addr) => {
+                            if let Some(rank) = ipv6_address_rank(&flags) {
+                                ipv6_ranks.push((rank, addr));
+                            }
+                        }
                         #[allow(unreachable_patterns)]
                         _ => (),
                     }
                 }
             }
         }
-        Ok(res)
+        #[cfg(feature = "ipv6")]
+        {
+            // stable sort, so kernel order is kept within the same rank
+            ipv6_ranks.sort_by_key(|(rank, _)| *rank);
+            res.ipv6 = ipv6_ranks.into_iter().map(|(_, addr)| addr).collect();
+        }
+        Ok((res, lifetimes))
+    }
+
+    /// Adds IP rule making kernel reply ICMP administratively prohibited
+    /// errors to packets with `fwmark`, returns the rule message to delete it
+    /// with.
+    pub async fn add_prohibit_rule(
+        &self,
+        is_ipv4: bool,
+        fwmark: u32,
+        priority: u32,
+    ) -> Result<RuleMessage> {
+        let mut req = self
+            .handle
+            .rule()
+            .add()
+            .fw_mark(fwmark)
+            .priority(priority)
+            .action(RuleAction::Prohibit);
+        req.message_mut().header.table = RouteHeader::RT_TABLE_UNSPEC;
+        req.message_mut().header.family = if is_ipv4 {
+            AddressFamily::Inet
+        } else {
+            AddressFamily::Inet6
+        };
+        rule_set_protocol_kernel(req.message_mut());
+
+        let rule = req.message_mut().clone();
+        if let Err(e) = req.execute().await {
+            if !route_err_is_exist(&e) {
+                return Err(anyhow::anyhow!(e));
+            }
+        }
+        Ok(rule)
+    }
+
+    pub async fn del_rule(&self, rule: RuleMessage) -> Result<()> {
+        self.handle.rule().del(rule).execute().await?;
+        Ok(())
     }
 
     async fn local_ip_rules(&self, is_ipv4: bool) -> Result<Vec<(RuleMessage, u32)>> {
@@ -253,7 +824,7 @@ impl RouteHelper {
         let mut res = Vec::new();
 
         while let Some(rule) = s.try_next().await? {
-            if rule.header.table == ROUTE_LOCAL_TABLE_ID as _
+            if rule.header.table == ROUTE_LOCAL_TABLE_ID as u8
                 && rule.header.action == RuleAction::ToTable
                 && rule
                     .attributes
@@ -318,11 +889,32 @@ impl RouteHelper {
     }
 }
 
+#[allow(clippy::enum_variant_names)]
 pub enum MonitorEvent {
-    ChangeAddress { if_index: u32 },
+    ChangeAddress {
+        if_index: u32,
+    },
+    /// Links added, removed or renamed, only monitored if requested
+    ChangeLink,
+    /// Routes of main table changed, only monitored if requested
+    ChangeRoute,
 }
 
-pub trait RouteIpNetwork: IpNetwork + Copy + Eq {
+/// Default bridge names of Docker(`docker0`, `br-<network ID>`) and
+/// Podman(`podman<N>`, `cni-podman<N>`) networks.
+fn is_container_bridge_name(name: &str) -> bool {
+    if name == "docker0" {
+        return true;
+    }
+    if let Some(id) = name.strip_prefix("br-") {
+        return id.len() == 12 && id.bytes().all(|c| c.is_ascii_hexdigit());
+    }
+    name.strip_prefix("cni-podman")
+        .or_else(|| name.strip_prefix("podman"))
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|c| c.is_ascii_digit()))
+}
+
+pub trait RouteIpNetwork: IpNetwork + Copy + Eq + std::fmt::Display {
     const FAMILY: AddressFamily;
     const IP_VERSION: IpVersion;
     const IS_IPV4: bool;
@@ -335,7 +927,11 @@ pub trait RouteIpNetwork: IpNetwork + Copy + Eq {
 
     fn neigh_add(&self, if_index: u32, handle: &Handle) -> NeighbourAddRequest;
 
+    fn rule_set_source(&self, rule: &mut RuleMessage);
+
     fn from_route_address(address: &RouteAddress, prefix_len: u8) -> Option<Self>;
+
+    fn from_neigh_address(address: &NeighbourAddress) -> Option<Self>;
 }
 
 impl RouteIpNetwork for Ipv4Net {
@@ -361,6 +957,12 @@ impl RouteIpNetwork for Ipv4Net {
         handle.neighbours().add(if_index, IpAddr::V4(self.addr()))
     }
 
+    fn rule_set_source(&self, rule: &mut RuleMessage) {
+        rule.header.src_len = self.prefix_len();
+        rule.attributes
+            .push(RuleAttribute::Source(self.addr().into()));
+    }
+
     fn from_route_address(address: &RouteAddress, prefix_len: u8) -> Option<Self> {
         if prefix_len > Self::LEN {
             return None;
@@ -371,6 +973,14 @@ impl RouteIpNetwork for Ipv4Net {
             None
         }
     }
+
+    fn from_neigh_address(address: &NeighbourAddress) -> Option<Self> {
+        if let NeighbourAddress::Inet(v4) = address {
+            Some(Self::from_addr(*v4))
+        } else {
+            None
+        }
+    }
 }
 
 #[cfg(feature = "ipv6")]
@@ -397,6 +1007,12 @@ impl RouteIpNetwork for Ipv6Net {
         handle.neighbours().add(if_index, IpAddr::V6(self.addr()))
     }
 
+    fn rule_set_source(&self, rule: &mut RuleMessage) {
+        rule.header.src_len = self.prefix_len();
+        rule.attributes
+            .push(RuleAttribute::Source(self.addr().into()));
+    }
+
     fn from_route_address(address: &RouteAddress, prefix_len: u8) -> Option<Self> {
         if prefix_len > Self::LEN {
             return None;
@@ -407,12 +1023,21 @@ impl RouteIpNetwork for Ipv6Net {
             None
         }
     }
+
+    fn from_neigh_address(address: &NeighbourAddress) -> Option<Self> {
+        if let NeighbourAddress::Inet6(v6) = address {
+            Some(Self::from_addr(*v6))
+        } else {
+            None
+        }
+    }
 }
 
 struct RouteDescriber<N> {
     destination: N,
     output_if_index: u32,
     table_id: u32,
+    protocol: RouteProtocol,
 }
 
 impl<N: RouteIpNetwork> RouteDescriber<N> {
@@ -420,6 +1045,7 @@ impl<N: RouteIpNetwork> RouteDescriber<N> {
         Some(self.destination) == route_destination(route)
             && self.table_id == route_table_id(route)
             && Some(self.output_if_index) == route_output_if_index(route)
+            && self.protocol == route.header.protocol
     }
 }
 
@@ -427,7 +1053,14 @@ pub struct HairpinRouting<N> {
     rt_helper: RouteHelper,
     external_if_index: u32,
     table_id: u32,
+    /// Routing protocol marker of our routes
+    route_protocol: RouteProtocol,
+    ip_rule_pref: u32,
+    local_ip_rule_pref: u32,
+    ip_protocols: Vec<IpProtocol>,
     hairpin_dests: Vec<N>,
+    /// Match packets marked by hairpin BPF program instead of input interfaces
+    fwmark: Option<u32>,
     rules: Vec<RuleMessage>,
     routes: Vec<RouteDescriber<N>>,
     neighs: Vec<NeighbourMessage>,
@@ -435,12 +1068,22 @@ pub struct HairpinRouting<N> {
 }
 
 impl<N: RouteIpNetwork> HairpinRouting<N> {
-    pub fn new(rt_helper: RouteHelper, external_if_index: u32, table_id: u32) -> Self {
+    pub fn new(
+        rt_helper: RouteHelper,
+        external_if_index: u32,
+        table_id: u32,
+        route_protocol: u8,
+    ) -> Self {
         Self {
             rt_helper,
             external_if_index,
             table_id,
+            route_protocol: RouteProtocol::from(route_protocol),
+            ip_rule_pref: Default::default(),
+            local_ip_rule_pref: Default::default(),
+            ip_protocols: Default::default(),
             hairpin_dests: Default::default(),
+            fwmark: Default::default(),
             rules: Default::default(),
             routes: Default::default(),
             neighs: Default::default(),
@@ -448,6 +1091,14 @@ impl<N: RouteIpNetwork> HairpinRouting<N> {
         }
     }
 
+    /// Routes packets with `fwmark` to hairpin route table with a single IP
+    /// rule per IP protocol, in addition to rules of internal interfaces,
+    /// which would be only loopback interface for locally generated traffic.
+    pub fn with_fwmark(mut self, fwmark: u32) -> Self {
+        self.fwmark = Some(fwmark);
+        self
+    }
+
     fn handle(&self) -> &Handle {
         &self.rt_helper.handle
     }
@@ -457,6 +1108,7 @@ impl<N: RouteIpNetwork> HairpinRouting<N> {
         ip_rule_pref: u32,
         local_ip_rule_pref: u32,
         mut internal_if_names: Vec<String>,
+        internal_networks: Vec<IpNet>,
         mut ip_protocols: Vec<IpProtocol>,
         hairpin_dests: Vec<N>,
     ) -> Result<()> {
@@ -464,29 +1116,93 @@ impl<N: RouteIpNetwork> HairpinRouting<N> {
 
         self.reconfigure_dests(hairpin_dests).await?;
 
+        ip_protocols.sort();
+        ip_protocols.dedup();
+        self.ip_rule_pref = ip_rule_pref;
+        self.local_ip_rule_pref = local_ip_rule_pref;
+        self.ip_protocols = ip_protocols;
+
+        let mut selectors = Vec::new();
+        if let Some(fwmark) = self.fwmark {
+            selectors.push(RuleSelector::Fwmark(fwmark));
+        }
+        selectors.extend(
+            internal_networks
+                .into_iter()
+                .filter_map(N::from_ip_net)
+                .map(RuleSelector::Source),
+        );
+        if !selectors.is_empty() {
+            self.rt_helper
+                .deprioritize_local_ip_rule(N::IS_IPV4, self.local_ip_rule_pref)
+                .await?;
+        }
+        for selector in selectors {
+            for protocol in self.ip_protocols.clone() {
+                self.add_rule(selector, protocol.into(), self.ip_rule_pref)
+                    .await?;
+            }
+        }
+
+        internal_if_names.sort();
         internal_if_names.dedup();
+        self.add_internal_if_names(internal_if_names).await
+    }
+
+    async fn add_internal_if_names(&mut self, internal_if_names: Vec<String>) -> Result<()> {
         if !internal_if_names.is_empty() {
             self.rt_helper
-                .deprioritize_local_ip_rule(N::IS_IPV4, local_ip_rule_pref)
+                .deprioritize_local_ip_rule(N::IS_IPV4, self.local_ip_rule_pref)
                 .await?;
         }
 
-        ip_protocols.dedup();
         for iif_name in internal_if_names {
-            for &protocol in ip_protocols.iter() {
-                self.add_rule(&iif_name, protocol.into(), ip_rule_pref)
-                    .await?;
+            for protocol in self.ip_protocols.clone() {
+                self.add_rule(
+                    RuleSelector::Iif(&iif_name),
+                    protocol.into(),
+                    self.ip_rule_pref,
+                )
+                .await?;
             }
         }
 
         Ok(())
     }
 
+    /// Adds or deletes IP rules so that exactly `internal_if_names` are
+    /// hairpinned.
+    pub async fn reconfigure_internal_if_names(
+        &mut self,
+        mut internal_if_names: Vec<String>,
+    ) -> Result<()> {
+        internal_if_names.sort();
+        internal_if_names.dedup();
+
+        let mut rules = Vec::with_capacity(self.rules.len());
+        for rule in core::mem::take(&mut self.rules) {
+            // rules of fwmark or internal networks are kept as is
+            let keep = rule_iif_name(&rule).map_or(true, |name| internal_if_names.contains(name));
+            if keep {
+                rules.push(rule);
+            } else if let Err(e) = self.handle().rule().del(rule).execute().await {
+                warn!("failed to delete IP rule: {}", e);
+            }
+        }
+
+        internal_if_names
+            .retain(|name| !rules.iter().any(|rule| rule_iif_name(rule) == Some(name)));
+        self.rules = rules;
+
+        self.add_internal_if_names(internal_if_names).await
+    }
+
     pub async fn configure(
         &mut self,
         ip_rule_pref: u32,
         local_ip_rule_pref: u32,
         internal_if_names: Vec<String>,
+        internal_networks: Vec<IpNet>,
         ip_protocols: Vec<IpProtocol>,
         hairpin_dests: Vec<N>,
     ) -> Result<()> {
@@ -495,6 +1211,7 @@ impl<N: RouteIpNetwork> HairpinRouting<N> {
                 ip_rule_pref,
                 local_ip_rule_pref,
                 internal_if_names,
+                internal_networks,
                 ip_protocols,
                 hairpin_dests,
             )
@@ -510,16 +1227,21 @@ impl<N: RouteIpNetwork> HairpinRouting<N> {
             .handle()
             .route()
             .add()
-            .replace()
             .table_id(self.table_id)
-            .output_interface(self.external_if_index);
+            .output_interface(self.external_if_index)
+            .protocol(self.route_protocol);
 
-        dest.route_add_set_dest(req, None).execute().await?;
+        // `replace` flag is reset on setting address family, set it afterwards
+        dest.route_add_set_dest(req, None)
+            .replace()
+            .execute()
+            .await?;
 
         self.routes.push(RouteDescriber {
             destination: dest,
             output_if_index: self.external_if_index,
             table_id: self.table_id,
+            protocol: self.route_protocol,
         });
 
         if let Some(ll_addr) = self.get_ll_addr().await? {
@@ -565,15 +1287,21 @@ impl<N: RouteIpNetwork> HairpinRouting<N> {
 
     async fn add_rule(
         &mut self,
-        iif_name: &str,
+        selector: RuleSelector<'_, N>,
         ip_protocol: RouteIpProtocol,
         priority: u32,
     ) -> Result<()> {
-        let mut req = self
-            .handle()
-            .rule()
-            .add()
-            .input_interface(iif_name.to_string())
+        let req = self.handle().rule().add();
+        let req = match selector {
+            RuleSelector::Iif(iif_name) => req.input_interface(iif_name.to_string()),
+            RuleSelector::Fwmark(fwmark) => req.fw_mark(fwmark),
+            RuleSelector::Source(network) => {
+                let mut req = req;
+                network.rule_set_source(req.message_mut());
+                req
+            }
+        };
+        let mut req = req
             .table_id(self.table_id)
             .priority(priority)
             .action(RuleAction::ToTable);
@@ -590,8 +1318,8 @@ impl<N: RouteIpNetwork> HairpinRouting<N> {
                 return Err(anyhow::anyhow!(e));
             }
             warn!(
-                "overwriting existing IP route rule, from iif {} lookup {} pref {}",
-                &iif_name, self.table_id, priority
+                "overwriting existing IP route rule, {} lookup {} pref {}",
+                selector, self.table_id, priority
             );
         }
 
@@ -651,6 +1379,110 @@ impl<N: RouteIpNetwork> HairpinRouting<N> {
         res
     }
 
+    /// Removes IP rules, routes and neighbour entries left behind by a previous
+    /// run that exited without deconfiguring, e.g. crashed.
+    ///
+    /// Stale rules are recognized by lookup table, priority and the
+    /// `protocol kernel` marker we set on them, and stale routes by lookup table,
+    /// output interface and routing protocol. As rules are not bound to a
+    /// specific external interface, this must be done before any hairpin
+    /// routing is configured, and rules are only removed if `exclusive`, i.e.
+    /// no other einat instance is running in the network namespace, as they
+    /// might be in use by it.
+    pub async fn cleanup_stale(&mut self, ip_rule_pref: u32, exclusive: bool) -> Result<()> {
+        assert!(self.rules.is_empty() && self.routes.is_empty());
+
+        let mut stale_rules = Vec::new();
+        let mut s = self.handle().rule().get(N::IP_VERSION).execute();
+        while let Some(rule) = s.try_next().await? {
+            if exclusive
+                && rule_is_hairpin(&rule)
+                && rule_table_id(&rule) == self.table_id
+                && rule_priority(&rule) == ip_rule_pref
+            {
+                stale_rules.push(rule);
+            }
+        }
+
+        let mut stale_routes = Vec::new();
+        let mut s = self.handle().route().get(N::IP_VERSION).execute();
+        while let Some(route) = s.try_next().await? {
+            // routes added by previous versions have no routing protocol set
+            if route_table_id(&route) == self.table_id
+                && route_output_if_index(&route) == Some(self.external_if_index)
+                && [self.route_protocol, RouteProtocol::Boot].contains(&route.header.protocol)
+            {
+                stale_routes.push(route);
+            }
+        }
+
+        if stale_rules.is_empty() && stale_routes.is_empty() {
+            return Ok(());
+        }
+        warn!(
+            "removing {} stale IP rules and {} stale routes of table {} left by previous run",
+            stale_rules.len(),
+            stale_routes.len(),
+            self.table_id
+        );
+
+        for rule in stale_rules {
+            if let Err(e) = self.handle().rule().del(rule).execute().await {
+                warn!("failed to delete stale rule: {}", e);
+            }
+        }
+
+        let mut stale_dests = Vec::new();
+        for route in stale_routes {
+            if let Some(dest) = route_destination::<N>(&route) {
+                stale_dests.push(dest);
+            }
+            if let Err(e) = self.handle().route().del(route).execute().await {
+                warn!("failed to delete stale route: {}", e);
+            }
+        }
+
+        // Hairpin neighbour entries point to our own link address
+        let Some(ll_addr) = self.get_ll_addr().await? else {
+            return Ok(());
+        };
+        let mut stale_neighs = Vec::new();
+        let mut s = self
+            .handle()
+            .neighbours()
+            .get()
+            .set_family(N::IP_VERSION)
+            .execute();
+        while let Some(neigh) = s.try_next().await? {
+            if neigh.header.ifindex != self.external_if_index
+                || neigh.header.state != NeighbourState::Permanent
+            {
+                continue;
+            }
+            let mut dest = None;
+            let mut neigh_ll_addr = None;
+            for attr in neigh.attributes.iter() {
+                match attr {
+                    NeighbourAttribute::Destination(addr) => dest = N::from_neigh_address(addr),
+                    NeighbourAttribute::LinkLocalAddress(addr) => neigh_ll_addr = Some(addr),
+                    _ => (),
+                }
+            }
+            if neigh_ll_addr == Some(&ll_addr)
+                && dest.is_some_and(|dest| stale_dests.contains(&dest))
+            {
+                stale_neighs.push(neigh);
+            }
+        }
+        for neigh in stale_neighs {
+            if let Err(e) = self.handle().neighbours().del(neigh).execute().await {
+                warn!("failed to delete stale neigh entry: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn deconfigure(&mut self) -> Result<()> {
         for rule in core::mem::take(&mut self.rules) {
             let _ = self.handle().rule().del(rule).execute().await;
@@ -662,7 +1494,10 @@ impl<N: RouteIpNetwork> HairpinRouting<N> {
 }
 
 /// This must be called from Tokio context.
-pub fn spawn_monitor() -> Result<(
+pub fn spawn_monitor(
+    monitor_routes: bool,
+    monitor_links: bool,
+) -> Result<(
     JoinHandle<()>,
     RouteHelper,
     impl Stream<Item = MonitorEvent>,
@@ -670,9 +1505,19 @@ pub fn spawn_monitor() -> Result<(
     let (mut conn, handle, mut group_messages) = new_connection()?;
 
     #[cfg(feature = "ipv6")]
-    let groups = nl_mgrp(libc::RTNLGRP_IPV4_IFADDR) | nl_mgrp(libc::RTNLGRP_IPV6_IFADDR);
+    let mut groups = nl_mgrp(libc::RTNLGRP_IPV4_IFADDR) | nl_mgrp(libc::RTNLGRP_IPV6_IFADDR);
     #[cfg(not(feature = "ipv6"))]
-    let groups = nl_mgrp(libc::RTNLGRP_IPV4_IFADDR);
+    let mut groups = nl_mgrp(libc::RTNLGRP_IPV4_IFADDR);
+    if monitor_links {
+        groups |= nl_mgrp(libc::RTNLGRP_LINK);
+    }
+    if monitor_routes {
+        groups |= nl_mgrp(libc::RTNLGRP_IPV4_ROUTE);
+        #[cfg(feature = "ipv6")]
+        {
+            groups |= nl_mgrp(libc::RTNLGRP_IPV6_ROUTE);
+        }
+    }
 
     let group_addr = SocketAddr::new(0, groups);
     conn.socket_mut().socket_mut().bind(&group_addr)?;
@@ -680,6 +1525,9 @@ pub fn spawn_monitor() -> Result<(
     let task = tokio::spawn(conn);
 
     let events = async_stream::stream!({
+        // Names of links seen, link messages are also sent on changes of
+        // state or flags, which don't matter to consumers
+        let mut link_names: HashMap<u32, String> = HashMap::new();
         while let Some((msg, _)) = group_messages.next().await {
             if let NetlinkPayload::InnerMessage(msg) = msg.payload {
                 match msg {
@@ -690,6 +1538,31 @@ pub fn spawn_monitor() -> Result<(
                             if_index: msg.header.index,
                         };
                     }
+                    RouteNetlinkMessage::NewLink(msg) => {
+                        let if_name = msg.attributes.into_iter().find_map(|attr| {
+                            if let LinkAttribute::IfName(name) = attr {
+                                Some(name)
+                            } else {
+                                None
+                            }
+                        });
+                        if let Some(if_name) = if_name {
+                            let prev = link_names.insert(msg.header.index, if_name.clone());
+                            if prev.as_ref() != Some(&if_name) {
+                                yield MonitorEvent::ChangeLink;
+                            }
+                        }
+                    }
+                    RouteNetlinkMessage::DelLink(msg) => {
+                        link_names.remove(&msg.header.index);
+                        yield MonitorEvent::ChangeLink;
+                    }
+                    // hairpin routes of our own are in other tables
+                    RouteNetlinkMessage::NewRoute(msg) | RouteNetlinkMessage::DelRoute(msg)
+                        if msg.header.table == RouteHeader::RT_TABLE_MAIN =>
+                    {
+                        yield MonitorEvent::ChangeRoute;
+                    }
                     _ => (),
                 }
             }
@@ -699,6 +1572,30 @@ pub fn spawn_monitor() -> Result<(
     Ok((task, RouteHelper { handle }, events))
 }
 
+/// Ranks IPv6 address by its flags for being chosen as external address,
+/// lower is preferred. Returns `None` if it's not usable as source address
+/// yet or at all, i.e. tentative or failed duplicate address detection.
+///
+/// Stable addresses, including mngtmpaddr ones privacy addresses are
+/// generated from, are preferred over temporary privacy addresses, which are
+/// rotated frequently. Deprecated addresses are the last resort.
+#[cfg(feature = "ipv6")]
+fn ipv6_address_rank(flags: &[AddressFlag]) -> Option<u8> {
+    let has = |flag| flags.contains(&flag);
+    if has(AddressFlag::Dadfailed) || (has(AddressFlag::Tentative) && !has(AddressFlag::Optimistic))
+    {
+        return None;
+    }
+    // IFA_F_TEMPORARY shares the value of IFA_F_SECONDARY
+    let rank = match (has(AddressFlag::Deprecated), has(AddressFlag::Secondary)) {
+        (false, false) => 0,
+        (false, true) => 1,
+        (true, false) => 2,
+        (true, true) => 3,
+    };
+    Some(rank)
+}
+
 fn route_err_is_exist(e: &rtnetlink::Error) -> bool {
     if let rtnetlink::Error::NetlinkError(e) = e {
         if let Some(code) = e.code {
@@ -710,6 +1607,56 @@ fn route_err_is_exist(e: &rtnetlink::Error) -> bool {
     false
 }
 
+/// Encodes IFLA_IPTUN_* attributes of ip6tnl interface, which are not
+/// supported by netlink-packet-route. All of them are required on update as
+/// missing ones are reset to zero by kernel.
+#[cfg(feature = "ipv6")]
+fn ip4ip6_tunnel_info_data(link_if_index: u32, local: Ipv6Addr, remote: Ipv6Addr) -> Vec<u8> {
+    const IFLA_IPTUN_LINK: u16 = 1;
+    const IFLA_IPTUN_LOCAL: u16 = 2;
+    const IFLA_IPTUN_REMOTE: u16 = 3;
+    const IFLA_IPTUN_TTL: u16 = 4;
+    const IFLA_IPTUN_ENCAP_LIMIT: u16 = 6;
+    const IFLA_IPTUN_FLAGS: u16 = 8;
+    const IFLA_IPTUN_PROTO: u16 = 9;
+    // AFTRs are not expected to process encapsulation limit option
+    const IP6_TNL_F_IGN_ENCAP_LIMIT: u32 = 0x1;
+    const HOP_LIMIT: u8 = 64;
+
+    let mut buf = Vec::new();
+    let mut push_nla = |kind: u16, value: &[u8]| {
+        buf.extend((4 + value.len() as u16).to_ne_bytes());
+        buf.extend(kind.to_ne_bytes());
+        buf.extend(value);
+        buf.resize((buf.len() + 3) & !3, 0);
+    };
+    push_nla(IFLA_IPTUN_LINK, &link_if_index.to_ne_bytes());
+    push_nla(IFLA_IPTUN_LOCAL, &local.octets());
+    push_nla(IFLA_IPTUN_REMOTE, &remote.octets());
+    push_nla(IFLA_IPTUN_TTL, &[HOP_LIMIT]);
+    push_nla(IFLA_IPTUN_ENCAP_LIMIT, &[0]);
+    push_nla(IFLA_IPTUN_FLAGS, &IP6_TNL_F_IGN_ENCAP_LIMIT.to_ne_bytes());
+    push_nla(IFLA_IPTUN_PROTO, &[libc::IPPROTO_IPIP as u8]);
+    buf
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RuleSelector<'a, N> {
+    Iif(&'a str),
+    Fwmark(u32),
+    Source(N),
+}
+
+impl<N: std::fmt::Display> std::fmt::Display for RuleSelector<'_, N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleSelector::Iif(iif_name) => write!(f, "from iif {}", iif_name),
+            RuleSelector::Fwmark(fwmark) => write!(f, "from all fwmark {:#x}", fwmark),
+            RuleSelector::Source(network) => write!(f, "from {}", network),
+        }
+    }
+}
+
 fn rule_set_protocol_kernel(rule: &mut RuleMessage) {
     rule.attributes
         .push(RuleAttribute::Protocol(RouteProtocol::Kernel));
@@ -740,6 +1687,52 @@ fn route_output_if_index(route: &RouteMessage) -> Option<u32> {
     })
 }
 
+fn rule_table_id(rule: &RuleMessage) -> u32 {
+    let table_id = rule.attributes.iter().find_map(|attr| {
+        if let RuleAttribute::Table(table_id) = attr {
+            Some(*table_id)
+        } else {
+            None
+        }
+    });
+    table_id.unwrap_or(rule.header.table as _)
+}
+
+/// Whether the rule looks like hairpin IP rule added by us.
+fn rule_is_hairpin(rule: &RuleMessage) -> bool {
+    rule.header.action == RuleAction::ToTable
+        && rule
+            .attributes
+            .contains(&RuleAttribute::Protocol(RouteProtocol::Kernel))
+        && rule.attributes.iter().any(|attr| {
+            matches!(
+                attr,
+                RuleAttribute::Iifname(_) | RuleAttribute::FwMark(_) | RuleAttribute::Source(_)
+            )
+        })
+}
+
+fn rule_iif_name(rule: &RuleMessage) -> Option<&String> {
+    rule.attributes.iter().find_map(|attr| {
+        if let RuleAttribute::Iifname(name) = attr {
+            Some(name)
+        } else {
+            None
+        }
+    })
+}
+
+fn rule_priority(rule: &RuleMessage) -> u32 {
+    let priority = rule.attributes.iter().find_map(|attr| {
+        if let RuleAttribute::Priority(priority) = attr {
+            Some(*priority)
+        } else {
+            None
+        }
+    });
+    priority.unwrap_or(0)
+}
+
 fn route_table_id(route: &RouteMessage) -> u32 {
     let table_id = route.attributes.iter().find_map(|attr| {
         if let RouteAttribute::Table(table_id) = attr {
@@ -762,11 +1755,76 @@ mod tests {
             .unwrap()
     }
 
+    #[test]
+    fn container_bridge_name() {
+        assert!(is_container_bridge_name("docker0"));
+        assert!(is_container_bridge_name("br-0123456789ab"));
+        assert!(is_container_bridge_name("podman0"));
+        assert!(is_container_bridge_name("cni-podman12"));
+        assert!(!is_container_bridge_name("br-lan"));
+        assert!(!is_container_bridge_name("podman"));
+        assert!(!is_container_bridge_name("eth0"));
+    }
+
+    #[test]
+    fn expiring_addresses() {
+        let secs = Duration::from_secs;
+        let mut addresses = IfAddresses {
+            ipv4: vec![Ipv4Addr::new(192, 168, 1, 2), Ipv4Addr::new(192, 168, 1, 3)],
+            ..Default::default()
+        };
+        let lifetimes = [
+            (IpAddr::from(addresses.ipv4[0]), secs(30)),
+            (IpAddr::from(addresses.ipv4[1]), secs(3600)),
+        ];
+        let next = addresses.exclude_expiring(&lifetimes, secs(60));
+        assert_eq!(addresses.ipv4, vec![Ipv4Addr::new(192, 168, 1, 3)]);
+        assert_eq!(next, Some(secs(3540)));
+
+        // the only address left is kept
+        let next = addresses.exclude_expiring(&[(lifetimes[1].0, secs(10))], secs(60));
+        assert_eq!(addresses.ipv4, vec![Ipv4Addr::new(192, 168, 1, 3)]);
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    #[cfg(feature = "ipv6")]
+    fn ipv6_address_ranking() {
+        use AddressFlag::*;
+        assert_eq!(ipv6_address_rank(&[Permanent]), Some(0));
+        assert_eq!(ipv6_address_rank(&[Managetempaddr, Noprefixroute]), Some(0));
+        assert_eq!(ipv6_address_rank(&[Secondary]), Some(1));
+        assert_eq!(ipv6_address_rank(&[Deprecated, Managetempaddr]), Some(2));
+        assert_eq!(ipv6_address_rank(&[Secondary, Deprecated]), Some(3));
+        assert_eq!(ipv6_address_rank(&[Tentative, Optimistic]), Some(0));
+        assert_eq!(ipv6_address_rank(&[Tentative]), None);
+        assert_eq!(ipv6_address_rank(&[Dadfailed, Tentative]), None);
+    }
+
+    #[test]
+    #[cfg(feature = "ipv6")]
+    fn ip4ip6_tunnel_info() {
+        let data = ip4ip6_tunnel_info_data(
+            2,
+            "2001:db8::2".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+        );
+        assert_eq!(data.len(), 8 + 20 + 20 + 8 * 4);
+        // IFLA_IPTUN_LINK
+        assert_eq!(data[..4], [8u16.to_ne_bytes(), 1u16.to_ne_bytes()].concat());
+        // IFLA_IPTUN_PROTO of IPPROTO_IPIP, padded
+        assert_eq!(
+            data[72..76],
+            [5u16.to_ne_bytes(), 9u16.to_ne_bytes()].concat()
+        );
+        assert_eq!(data[76..], [4, 0, 0, 0]);
+    }
+
     #[test]
     #[ignore = "netlink"]
     fn get_link() {
         new_async_rt().block_on(async {
-            let (_, rt_helper, _) = spawn_monitor().unwrap();
+            let (_, rt_helper, _) = spawn_monitor(false, false).unwrap();
             tokio::time::timeout(std::time::Duration::from_secs(1), async {
                 rt_helper.query_link_info(1).await.unwrap();
             })
@@ -779,7 +1837,7 @@ mod tests {
     #[ignore = "netlink"]
     fn get_addr() {
         new_async_rt().block_on(async {
-            let (_, rt_helper, _) = spawn_monitor().unwrap();
+            let (_, rt_helper, _) = spawn_monitor(false, false).unwrap();
             tokio::time::timeout(std::time::Duration::from_secs(1), async {
                 rt_helper.query_all_addresses(1).await.unwrap();
             })
@@ -788,11 +1846,22 @@ mod tests {
         });
     }
 
+    #[test]
+    #[ignore = "netlink"]
+    fn get_route_source() {
+        new_async_rt().block_on(async {
+            let (_, rt_helper, _) = spawn_monitor(false, false).unwrap();
+            let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+            let res = rt_helper.query_route_source(localhost).await.unwrap();
+            assert_eq!(res, Some((1, localhost)));
+        })
+    }
+
     #[test]
     #[ignore = "netlink"]
     fn get_local_rule() {
         new_async_rt().block_on(async {
-            let (_, rt_helper, _) = spawn_monitor().unwrap();
+            let (_, rt_helper, _) = spawn_monitor(false, false).unwrap();
             let rules = rt_helper.local_ip_rules(true).await.unwrap();
             dbg!(rules);
         })
@@ -802,7 +1871,7 @@ mod tests {
     #[ignore = "netlink"]
     fn get_routes() {
         new_async_rt().block_on(async {
-            let (_, rt_helper, _) = spawn_monitor().unwrap();
+            let (_, rt_helper, _) = spawn_monitor(false, false).unwrap();
             let req = rt_helper.handle.route().get(IpVersion::V4);
             let mut routes = req.execute();
             while let Some(route) = routes.try_next().await.unwrap() {
@@ -5,6 +5,7 @@ include!(concat!(env!("OUT_DIR"), "/einat.skel.rs"));
 #[cfg(feature = "ipv6")]
 use std::net::Ipv6Addr;
 use std::net::{IpAddr, Ipv4Addr};
+use std::sync::OnceLock;
 
 use bitflags::bitflags;
 use bytemuck::{Pod, Zeroable};
@@ -12,7 +13,28 @@ use ipnet::Ipv4Net;
 #[cfg(feature = "ipv6")]
 use ipnet::Ipv6Net;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
+/// Object name of generated skeleton
+pub const OBJECT_NAME: &str = "einat_bpf";
+
+static OBJECT_DATA: OnceLock<&'static [u8]> = OnceLock::new();
+
+/// Returns BPF object opened by skeleton, which is the embedded one unless
+/// replaced with [`set_object_data`].
+pub fn object_data() -> &'static [u8] {
+    OBJECT_DATA.get().copied().unwrap_or(imp::DATA)
+}
+
+/// Returns BPF object embedded at build time.
+pub fn embedded_object_data() -> &'static [u8] {
+    imp::DATA
+}
+
+/// Replaces BPF object opened by skeleton, returns false if already replaced.
+pub fn set_object_data(data: &'static [u8]) -> bool {
+    OBJECT_DATA.set(data).is_ok()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default, Zeroable, Pod)]
 #[repr(transparent)]
 pub struct InetAddr {
     #[cfg(feature = "ipv6")]
@@ -59,6 +81,21 @@ bitflags! {
     #[repr(transparent)]
     pub struct ExternalFlags: u8 {
         const NO_SNAT = 0b10;
+        const FILTER_ADF = 0b100;
+        const FILTER_APDF = 0b1000;
+        const ONE_TO_ONE = 0b10000;
+        const LOCAL = 0b100000;
+    }
+}
+
+bitflags! {
+    /// Protocols served by external address, empty for all protocols
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct ExternalProtocols: u8 {
+        const TCP = 0b1;
+        const UDP = 0b10;
+        const ICMP = 0b100;
     }
 }
 
@@ -74,12 +111,32 @@ pub struct ExternalConfig {
     pub icmp_range: PortRanges,
     pub icmp_in_range: PortRanges,
     pub icmp_out_range: PortRanges,
+    pub excluded_range: PortRanges,
     pub tcp_range_len: u8,
     pub udp_range_len: u8,
     pub icmp_range_len: u8,
     pub icmp_in_range_len: u8,
     pub icmp_out_range_len: u8,
+    pub excluded_range_len: u8,
     pub flags: ExternalFlags,
+    pub protocols: ExternalProtocols,
+}
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct NetmapFlags: u8 {
+        const ORIG_DIR = 0b1;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct NetmapValue {
+    pub to_prefix: [u8; 4],
+    pub mask: [u8; 4],
+    pub flags: NetmapFlags,
+    pub _pad: [u8; 3],
 }
 
 bitflags! {
@@ -88,6 +145,10 @@ bitflags! {
     pub struct DestFlags: u8 {
         const HAIRPIN = 0b01;
         const NO_SNAT = 0b10;
+        const FILTER_OVERRIDE = 0b100;
+        const FILTER_ADF = 0b1000;
+        const FILTER_APDF = 0b10000;
+        const DROP = 0b100000;
     }
 }
 
@@ -95,10 +156,13 @@ bitflags! {
 #[repr(C)]
 pub struct DestConfig {
     pub flags: DestFlags,
+    pub _pad: [u8; 7],
+    pub timeout_udp: u64,
+    pub timeout_tcp_est: u64,
 }
 
 bitflags! {
-    #[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default, Zeroable, Pod)]
     #[repr(transparent)]
     pub struct BindingFlags: u8 {
         const ORIG_DIR = 0b001;
@@ -107,7 +171,7 @@ bitflags! {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default, Zeroable, Pod)]
 #[repr(C)]
 pub struct MapBindingKey {
     pub if_index: u32,
@@ -117,6 +181,14 @@ pub struct MapBindingKey {
     pub from_addr: InetAddr,
 }
 
+/// `ref_` of orig direction binding referenced by CTs
+pub const BINDING_ORIG_REF_COUNTED: u32 = u32::MAX;
+
+/// `state` of CT initiated by inbound packet and not yet replied
+pub const CT_INIT_IN: u32 = 0;
+/// `state` of CT initiated by outbound packet and not yet replied
+pub const CT_INIT_OUT: u32 = 1;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
 #[repr(C)]
 pub struct MapBindingValue {
@@ -127,6 +199,8 @@ pub struct MapBindingValue {
     pub use_: u32,
     pub ref_: u32,
     pub seq: u32,
+    pub created: u32,
+    pub held_until: u32,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
@@ -139,6 +213,174 @@ pub struct MapCtKey {
     pub external: InetTuple,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct MapCtValue {
+    pub origin: InetTuple,
+    pub flags: BindingFlags,
+    pub pkts: u8,
+    pub _pad: [u8; 2],
+    pub state: u32,
+    pub seq: u32,
+    pub expires: u64,
+    pub timer: [u64; 2],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct MapFilterAddrKey {
+    pub if_index: u32,
+    pub flags: BindingFlags,
+    pub l4proto: u8,
+    pub port: u16,
+    pub external_addr: InetAddr,
+    pub remote_addr: InetAddr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct MapLocalPortKey {
+    pub l4proto: u8,
+    pub _pad: u8,
+    pub port: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct MapHostKey {
+    pub if_index: u32,
+    pub flags: BindingFlags,
+    pub _pad: [u8; 3],
+    pub addr: InetAddr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct HostPorts {
+    pub tcp: u32,
+    pub udp: u32,
+    pub icmp: u32,
+    pub _pad: u32,
+    pub last_overflow: u64,
+}
+
+pub const EVENT_PORT_QUOTA_EXCEEDED: u32 = 1;
+pub const EVENT_EXTERNAL_SPILLOVER: u32 = 2;
+pub const EVENT_TRACE: u32 = 3;
+pub const EVENT_LOG: u32 = 4;
+
+pub const MAX_EXTERNAL_POOL: usize = 16;
+pub const EXTERNAL_POOL_PROTOS: usize = 3;
+#[cfg(feature = "ipv6")]
+pub const MAX_NPT_LOCAL_ADDRS: usize = 8;
+
+pub const EXTERNAL_SELECT_FIRST: u8 = 0;
+pub const EXTERNAL_SELECT_HASH: u8 = 1;
+pub const EXTERNAL_SELECT_ROUND_ROBIN: u8 = 2;
+
+pub const PORT_ALLOC_RANDOM: u8 = 0;
+pub const PORT_ALLOC_SEQUENTIAL: u8 = 1;
+pub const PORT_ALLOC_HASHED: u8 = 2;
+
+pub const UNMATCHED_RST_DROP: u8 = 0;
+pub const UNMATCHED_RST_PASS: u8 = 1;
+pub const UNMATCHED_RST_REPLY: u8 = 2;
+
+pub const TCP_TEARDOWN_TRANS: u8 = 0;
+pub const TCP_TEARDOWN_TIME_WAIT: u8 = 1;
+pub const TCP_TEARDOWN_CLOSE: u8 = 2;
+pub const TCP_TEARDOWN_IGNORE: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct Event {
+    pub type_: u32,
+    pub if_index: u32,
+    pub flags: BindingFlags,
+    pub l4proto: u8,
+    pub _pad: u16,
+    pub addr: InetAddr,
+}
+
+pub const TRACE_PARSE: u8 = 0;
+pub const TRACE_BINDING: u8 = 1;
+pub const TRACE_CT: u8 = 2;
+pub const TRACE_VERDICT: u8 = 3;
+
+pub const TRACE_F_INGRESS: u8 = 1 << 0;
+pub const TRACE_F_IPV4: u8 = 1 << 1;
+
+pub const TRACE_R_TRANSLATED: u8 = 1;
+pub const TRACE_R_HAIRPIN: u8 = 2;
+pub const TRACE_R_NOT_EXTERNAL: u8 = 3;
+pub const TRACE_R_PASS_NAT: u8 = 4;
+pub const TRACE_R_FRAGMENT: u8 = 5;
+pub const TRACE_R_OUT_OF_RANGE: u8 = 6;
+pub const TRACE_R_NO_BINDING: u8 = 7;
+pub const TRACE_R_BINDING_FAILED: u8 = 8;
+pub const TRACE_R_NO_CT: u8 = 9;
+pub const TRACE_R_REWRITE_FAILED: u8 = 10;
+pub const TRACE_R_DEST_BLOCKED: u8 = 11;
+
+/// Results of CT lookup
+pub const LK_CT_ERROR_NEW: i32 = 0;
+pub const LK_CT_NONE: i32 = 1;
+pub const LK_CT_EXIST: i32 = 2;
+pub const LK_CT_NEW: i32 = 3;
+
+/// Packet types of parsed packet
+pub const PKT_CONNLESS: u8 = 0;
+pub const PKT_TCP_DATA: u8 = 1;
+pub const PKT_TCP_SYN: u8 = 2;
+pub const PKT_TCP_RST: u8 = 3;
+pub const PKT_TCP_FIN: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct TraceEvent {
+    pub type_: u32,
+    pub if_index: u32,
+    pub stage: u8,
+    pub flags: u8,
+    pub l4proto: u8,
+    pub pkt_type: u8,
+    pub value: i32,
+    pub tuple: InetTuple,
+    pub addr: InetAddr,
+    /// Big-endian
+    pub port: u16,
+    pub reason: u8,
+    pub _pad: u8,
+}
+
+pub const BPF_LOG_LEVEL_ERROR: u8 = 1;
+pub const BPF_LOG_LEVEL_WARN: u8 = 2;
+pub const BPF_LOG_LEVEL_INFO: u8 = 3;
+pub const BPF_LOG_LEVEL_DEBUG: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Zeroable, Pod)]
+#[repr(C)]
+pub struct LogEvent {
+    pub type_: u32,
+    pub level: u8,
+    pub _pad: [u8; 3],
+    pub topic: [u8; 32],
+    pub msg: [u8; 128],
+}
+
+pub const CAPTURE_F_INGRESS: u8 = 1 << 0;
+pub const CAPTURE_F_TRANSLATED: u8 = 1 << 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct CaptureMeta {
+    pub if_index: u32,
+    pub len: u32,
+    pub cap_len: u32,
+    pub flags: u8,
+    pub _pad: [u8; 3],
+}
+
 impl From<Ipv4Addr> for InetAddr {
     #[cfg(feature = "ipv6")]
     fn from(value: Ipv4Addr) -> Self {
@@ -178,6 +420,20 @@ impl From<IpAddr> for InetAddr {
     }
 }
 
+impl InetAddr {
+    /// Converts to IP address of family indicated by `flags`.
+    pub fn to_ip(self, flags: BindingFlags) -> IpAddr {
+        #[cfg(feature = "ipv6")]
+        if flags.contains(BindingFlags::ADDR_IPV6) {
+            return Ipv6Addr::from(self.inner).into();
+        }
+        let _ = flags;
+        let mut octets = [0; 4];
+        octets.copy_from_slice(&self.inner[..4]);
+        Ipv4Addr::from(octets).into()
+    }
+}
+
 impl From<Ipv4Net> for Ipv4LpmKey {
     fn from(value: Ipv4Net) -> Self {
         Self {
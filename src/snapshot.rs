@@ -0,0 +1,184 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! On-disk snapshot of binding map, so NAT mappings could survive reboots
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use libbpf_rs::MapHandle;
+
+use crate::skel::{MapBindingKey, MapBindingValue};
+
+const MAGIC: &[u8; 8] = b"EINATBS1";
+// Entries reserved upfront on reading, as entry count of untrusted input is
+// only verified by reading through entries
+const MAX_PREALLOC_ENTRIES: usize = 4096;
+
+#[derive(Debug)]
+pub struct BindingSnapshot {
+    pub time: SystemTime,
+    pub entries: Vec<(MapBindingKey, MapBindingValue)>,
+}
+
+impl BindingSnapshot {
+    /// Takes snapshot of binding map entries.
+    pub fn new<I: IntoIterator<Item = (MapBindingKey, MapBindingValue)>>(entries: I) -> Self {
+        Self {
+            time: SystemTime::now(),
+            // Skip bindings not referenced by any CT, which are free to reuse
+            entries: entries
+                .into_iter()
+                .filter(|(_, value)| value.ref_ != 0)
+                .collect(),
+        }
+    }
+
+    pub fn dump(map_binding: &MapHandle) -> Result<Self> {
+        let mut entries = Vec::new();
+        for key_raw in map_binding.keys() {
+            // entry might be deleted during iteration
+            let Some(value_raw) = map_binding.lookup(&key_raw, libbpf_rs::MapFlags::ANY)? else {
+                continue;
+            };
+            entries.push((
+                *bytemuck::from_bytes(&key_raw),
+                *bytemuck::from_bytes(&value_raw),
+            ));
+        }
+
+        Ok(Self::new(entries))
+    }
+
+    /// Dumps binding map pinned under `pin_path`.
+    pub fn dump_pinned<P: AsRef<Path>>(pin_path: P) -> Result<Self> {
+        let map_binding = MapHandle::from_pinned_path(pin_path.as_ref().join("map_binding"))?;
+        Self::dump(&map_binding)
+    }
+
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<()> {
+        let secs = self
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        w.write_all(MAGIC)?;
+        w.write_all(&secs.to_le_bytes())?;
+        // Size of address differs on whether IPv6 feature is enabled
+        w.write_all(&(size_of::<MapBindingKey>() as u32).to_le_bytes())?;
+        w.write_all(&(size_of::<MapBindingValue>() as u32).to_le_bytes())?;
+        w.write_all(&(self.entries.len() as u32).to_le_bytes())?;
+        for (key, value) in self.entries.iter() {
+            w.write_all(bytemuck::bytes_of(key))?;
+            w.write_all(bytemuck::bytes_of(value))?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut r: R) -> Result<Self> {
+        fn read_u32<R: Read>(r: &mut R) -> Result<u32> {
+            let mut buf = [0; 4];
+            r.read_exact(&mut buf)?;
+            Ok(u32::from_le_bytes(buf))
+        }
+
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(anyhow!("not a binding snapshot file"));
+        }
+
+        let mut secs = [0; 8];
+        r.read_exact(&mut secs)?;
+        let time = SystemTime::UNIX_EPOCH
+            .checked_add(Duration::from_secs(u64::from_le_bytes(secs)))
+            .ok_or_else(|| anyhow!("invalid binding snapshot time"))?;
+
+        let key_size = read_u32(&mut r)? as usize;
+        let value_size = read_u32(&mut r)? as usize;
+        if key_size != size_of::<MapBindingKey>() || value_size != size_of::<MapBindingValue>() {
+            return Err(anyhow!(
+                "binding snapshot is incompatible with this build of einat"
+            ));
+        }
+
+        let len = read_u32(&mut r)? as usize;
+        let mut entries = Vec::with_capacity(len.min(MAX_PREALLOC_ENTRIES));
+        for _ in 0..len {
+            let mut key = MapBindingKey::default();
+            let mut value = MapBindingValue::default();
+            r.read_exact(bytemuck::bytes_of_mut(&mut key))?;
+            r.read_exact(bytemuck::bytes_of_mut(&mut value))?;
+            entries.push((key, value));
+        }
+
+        Ok(Self { time, entries })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Write to temporary file first so that we won't leave a broken
+        // snapshot on failure.
+        let tmp_path = path.with_extension("tmp");
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+        self.write_to(&mut file)?;
+        file.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Self::read_from(std::io::BufReader::new(file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::skel::{BindingFlags, InetAddr};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn snapshot_round_trip() {
+        let key = MapBindingKey {
+            if_index: 2,
+            flags: BindingFlags::ORIG_DIR | BindingFlags::ADDR_IPV4,
+            l4proto: 17,
+            from_port: 12345u16.to_be(),
+            from_addr: InetAddr::from(Ipv4Addr::new(192, 168, 1, 2)),
+        };
+        let value = MapBindingValue {
+            to_addr: InetAddr::from(Ipv4Addr::new(10, 0, 0, 1)),
+            to_port: 23456u16.to_be(),
+            flags: BindingFlags::ADDR_IPV4,
+            seq: 42,
+            ..Default::default()
+        };
+        let snapshot = BindingSnapshot {
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000),
+            entries: vec![(key, value)],
+        };
+
+        let mut buf = Vec::new();
+        snapshot.write_to(&mut buf).unwrap();
+        let loaded = BindingSnapshot::read_from(buf.as_slice()).unwrap();
+        assert_eq!(snapshot.time, loaded.time);
+        assert_eq!(snapshot.entries, loaded.entries);
+
+        assert!(BindingSnapshot::read_from(&buf[1..]).is_err());
+
+        let mut crafted = buf.clone();
+        crafted[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(BindingSnapshot::read_from(crafted.as_slice()).is_err());
+
+        let mut crafted = buf.clone();
+        crafted[24..28].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(BindingSnapshot::read_from(crafted.as_slice()).is_err());
+    }
+}
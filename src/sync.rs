@@ -0,0 +1,193 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! Binding state synchronization between active/standby routers.
+//!
+//! Like external cache of conntrackd, the active instance periodically pushes
+//! bindings of all its interfaces to peer over TCP, and the standby instance
+//! keeps the latest bindings of each interface, which are inserted once it
+//! takes over the interface, e.g. becomes VRRP master. External ports of
+//! established sessions are kept this way, and CTs are recreated by following
+//! packets of these sessions.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::snapshot::BindingSnapshot;
+
+const MAGIC: &[u8; 8] = b"EINATSY1";
+const MAX_MESSAGE_LEN: u64 = 64 << 20;
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+const SERVE_TIMEOUT: Duration = Duration::from_secs(30);
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+type PeerState = Arc<Mutex<HashMap<String, BindingSnapshot>>>;
+
+/// Receives bindings pushed by peer, the listener is closed on drop.
+#[derive(Debug)]
+pub struct SyncServer {
+    state: PeerState,
+    task: JoinHandle<()>,
+}
+
+impl SyncServer {
+    /// Listens on `listen`, only accepting connections from `peer`. Peer
+    /// connections are served one at a time.
+    pub async fn bind(listen: SocketAddr, peer: IpAddr) -> Result<Self> {
+        let listener = TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("failed to bind state sync socket {}", listen))?;
+        info!("receiving state of peer on {}", listen);
+
+        let state = PeerState::default();
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((_, addr)) if addr.ip() != peer => {
+                            warn!("rejected state sync connection from {}", addr);
+                        }
+                        Ok((stream, addr)) => {
+                            let res = tokio::time::timeout(SERVE_TIMEOUT, serve(stream, &state))
+                                .await
+                                .map_err(|_| anyhow!("timed out"))
+                                .and_then(|res| res);
+                            if let Err(e) = res {
+                                warn!("failed to receive state from {}: {}", addr, e);
+                            }
+                        }
+                        Err(e) => {
+                            // e.g. running out of file descriptors, which might
+                            // be transient
+                            warn!("failed to accept state sync connection: {}", e);
+                            tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Self { state, task })
+    }
+
+    /// Takes the latest bindings of interface received from peer.
+    pub fn take(&self, interface: &str) -> Option<BindingSnapshot> {
+        self.state.lock().unwrap().remove(interface)
+    }
+}
+
+impl Drop for SyncServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(stream: TcpStream, state: &PeerState) -> Result<()> {
+    let mut buf = Vec::new();
+    stream.take(MAX_MESSAGE_LEN).read_to_end(&mut buf).await?;
+    let interfaces = decode(&buf)?;
+    let mut state = state.lock().unwrap();
+    for (interface, snapshot) in interfaces {
+        debug!(
+            "received {} binding entries of interface {} from peer",
+            snapshot.entries.len(),
+            interface
+        );
+        state.insert(interface, snapshot);
+    }
+    Ok(())
+}
+
+/// Pushes bindings of interfaces to peer listening on `peer`.
+pub async fn push(peer: SocketAddr, interfaces: &[(String, BindingSnapshot)]) -> Result<()> {
+    let message = encode(interfaces)?;
+    tokio::time::timeout(PUSH_TIMEOUT, async {
+        let mut stream = TcpStream::connect(peer).await?;
+        stream.write_all(&message).await?;
+        stream.shutdown().await
+    })
+    .await
+    .map_err(|_| anyhow!("timed out"))??;
+    Ok(())
+}
+
+fn encode(interfaces: &[(String, BindingSnapshot)]) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&(interfaces.len() as u32).to_le_bytes());
+    for (interface, snapshot) in interfaces {
+        buf.extend_from_slice(&(interface.len() as u16).to_le_bytes());
+        buf.extend_from_slice(interface.as_bytes());
+        snapshot.write_to(&mut buf)?;
+    }
+    Ok(buf)
+}
+
+fn decode(mut buf: &[u8]) -> Result<Vec<(String, BindingSnapshot)>> {
+    fn split<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+        if buf.len() < len {
+            return Err(anyhow!("truncated state sync message"));
+        }
+        let (head, tail) = buf.split_at(len);
+        *buf = tail;
+        Ok(head)
+    }
+
+    if split(&mut buf, MAGIC.len())? != MAGIC {
+        return Err(anyhow!("not a state sync message"));
+    }
+
+    let len = u32::from_le_bytes(split(&mut buf, 4)?.try_into()?);
+    let mut interfaces = Vec::new();
+    for _ in 0..len {
+        let name_len = u16::from_le_bytes(split(&mut buf, 2)?.try_into()?);
+        let name = split(&mut buf, name_len as usize)?;
+        let snapshot = BindingSnapshot::read_from(&mut buf)?;
+        interfaces.push((String::from_utf8(name.to_vec())?, snapshot));
+    }
+    Ok(interfaces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::skel::{MapBindingKey, MapBindingValue};
+    use std::time::SystemTime;
+
+    #[test]
+    fn message_round_trip() {
+        let snapshot = |port: u16| BindingSnapshot {
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000),
+            entries: vec![(
+                MapBindingKey {
+                    from_port: port.to_be(),
+                    ..Default::default()
+                },
+                MapBindingValue::default(),
+            )],
+        };
+        let interfaces = vec![
+            ("eth0".to_string(), snapshot(1)),
+            ("eth1".to_string(), snapshot(2)),
+        ];
+
+        let buf = encode(&interfaces).unwrap();
+        let decoded = decode(&buf).unwrap();
+        assert_eq!(decoded.len(), 2);
+        for ((name, snapshot), (decoded_name, decoded_snapshot)) in interfaces.iter().zip(&decoded)
+        {
+            assert_eq!(name, decoded_name);
+            assert_eq!(snapshot.time, decoded_snapshot.time);
+            assert_eq!(snapshot.entries, decoded_snapshot.entries);
+        }
+
+        assert!(decode(&buf[..buf.len() - 1]).is_err());
+    }
+}
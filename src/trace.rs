@@ -0,0 +1,160 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! Formatting of per-packet trace events emitted by BPF programs in trace mode
+use std::net::{IpAddr, SocketAddr};
+
+use crate::skel::{self, BindingFlags, InetAddr, TraceEvent};
+
+const TC_ACT_UNSPEC: i32 = -1;
+const TC_ACT_OK: i32 = 0;
+const TC_ACT_SHOT: i32 = 2;
+const TC_ACT_REDIRECT: i32 = 7;
+
+/// Prints trace event to stdout, so the event stream is separated from logs.
+pub fn print_event(event: &TraceEvent) {
+    println!("{}", format_event(event));
+}
+
+fn to_ip(addr: InetAddr, is_ipv4: bool) -> IpAddr {
+    let flags = if is_ipv4 {
+        BindingFlags::ADDR_IPV4
+    } else {
+        BindingFlags::ADDR_IPV6
+    };
+    addr.to_ip(flags)
+}
+
+fn protocol_name(l4proto: u8) -> String {
+    match l4proto as i32 {
+        libc::IPPROTO_TCP => "TCP".to_string(),
+        libc::IPPROTO_UDP => "UDP".to_string(),
+        libc::IPPROTO_ICMP => "ICMP".to_string(),
+        libc::IPPROTO_ICMPV6 => "ICMPv6".to_string(),
+        l4proto => format!("proto {}", l4proto),
+    }
+}
+
+fn pkt_type_name(pkt_type: u8) -> &'static str {
+    match pkt_type {
+        skel::PKT_CONNLESS => "connless",
+        skel::PKT_TCP_DATA => "data",
+        skel::PKT_TCP_SYN => "SYN",
+        skel::PKT_TCP_RST => "RST",
+        skel::PKT_TCP_FIN => "FIN",
+        _ => "unknown",
+    }
+}
+
+fn reason_name(reason: u8) -> &'static str {
+    match reason {
+        skel::TRACE_R_TRANSLATED => "translated",
+        skel::TRACE_R_HAIRPIN => "hairpin",
+        skel::TRACE_R_NOT_EXTERNAL => "not external",
+        skel::TRACE_R_PASS_NAT => "NAT bypassed for destination",
+        skel::TRACE_R_FRAGMENT => "fragment",
+        skel::TRACE_R_OUT_OF_RANGE => "port out of NAT range",
+        skel::TRACE_R_NO_BINDING => "no binding",
+        skel::TRACE_R_BINDING_FAILED => "binding failed",
+        skel::TRACE_R_NO_CT => "no CT",
+        skel::TRACE_R_REWRITE_FAILED => "header rewrite failed",
+        skel::TRACE_R_DEST_BLOCKED => "destination blocked",
+        _ => "unknown",
+    }
+}
+
+fn format_event(event: &TraceEvent) -> String {
+    let is_ipv4 = event.flags & skel::TRACE_F_IPV4 != 0;
+    let direction = if event.flags & skel::TRACE_F_INGRESS != 0 {
+        "ingress"
+    } else {
+        "egress "
+    };
+    let detail = match event.stage {
+        skel::TRACE_PARSE => {
+            let tuple = &event.tuple;
+            let src = SocketAddr::new(to_ip(tuple.src_addr, is_ipv4), u16::from_be(tuple.src_port));
+            let dst = SocketAddr::new(to_ip(tuple.dst_addr, is_ipv4), u16::from_be(tuple.dst_port));
+            format!(
+                "parse   {} {} -> {} {}",
+                protocol_name(event.l4proto),
+                src,
+                dst,
+                pkt_type_name(event.pkt_type)
+            )
+        }
+        skel::TRACE_BINDING => match event.value {
+            TC_ACT_OK => format!(
+                "binding {}",
+                SocketAddr::new(to_ip(event.addr, is_ipv4), u16::from_be(event.port))
+            ),
+            TC_ACT_UNSPEC => "binding none".to_string(),
+            _ => "binding failed".to_string(),
+        },
+        skel::TRACE_CT => {
+            let result = match event.value {
+                skel::LK_CT_EXIST => "exist",
+                skel::LK_CT_NEW => "new",
+                skel::LK_CT_NONE => "none",
+                skel::LK_CT_ERROR_NEW => "failed",
+                _ => "unknown",
+            };
+            format!("ct      {}", result)
+        }
+        skel::TRACE_VERDICT => {
+            let action = match event.value {
+                TC_ACT_UNSPEC | TC_ACT_OK => "pass".to_string(),
+                TC_ACT_SHOT => "drop".to_string(),
+                TC_ACT_REDIRECT => "redirect".to_string(),
+                value => format!("action {}", value),
+            };
+            format!("verdict {} ({})", action, reason_name(event.reason))
+        }
+        stage => format!("stage {}", stage),
+    };
+    format!("if {} {} {}", event.if_index, direction, detail)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::skel::InetTuple;
+
+    #[test]
+    fn format() {
+        let mut event = TraceEvent {
+            type_: skel::EVENT_TRACE,
+            if_index: 2,
+            stage: skel::TRACE_PARSE,
+            flags: skel::TRACE_F_IPV4,
+            l4proto: libc::IPPROTO_UDP as _,
+            pkt_type: skel::PKT_CONNLESS,
+            tuple: InetTuple {
+                src_addr: Ipv4Addr::new(192, 168, 1, 100).into(),
+                dst_addr: Ipv4Addr::new(10, 0, 1, 1).into(),
+                src_port: 5000u16.to_be(),
+                dst_port: 3478u16.to_be(),
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            format_event(&event),
+            "if 2 egress  parse   UDP 192.168.1.100:5000 -> 10.0.1.1:3478 connless"
+        );
+
+        event.stage = skel::TRACE_BINDING;
+        event.addr = Ipv4Addr::new(10, 0, 1, 100).into();
+        event.port = 20000u16.to_be();
+        assert_eq!(
+            format_event(&event),
+            "if 2 egress  binding 10.0.1.100:20000"
+        );
+
+        event.stage = skel::TRACE_VERDICT;
+        event.flags |= skel::TRACE_F_INGRESS;
+        event.value = TC_ACT_SHOT;
+        event.reason = skel::TRACE_R_NO_CT;
+        assert_eq!(format_event(&event), "if 2 ingress verdict drop (no CT)");
+    }
+}
@@ -1,13 +1,22 @@
 // SPDX-FileCopyrightText: 2023 Huang-Huang Bao
 // SPDX-License-Identifier: GPL-2.0-or-later
 //! Model for configuration variables and maps of our eBPF application
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
 #[cfg(feature = "ipv6")]
 use std::net::Ipv6Addr;
 use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use ipnet::Ipv4Net;
+use anyhow::{anyhow, Context, Result};
 #[cfg(feature = "ipv6")]
 use ipnet::Ipv6Net;
+use ipnet::{IpNet, Ipv4Net};
 use prefix_trie::{map::Iter as PrefixMapIter, Prefix, PrefixMap};
 
 pub enum MapChange<'a, P, T> {
@@ -83,6 +92,8 @@ pub trait IpNetwork: Sized {
 
     fn from_ip_addr(addr: IpAddr) -> Option<Self>;
 
+    fn from_ip_net(net: IpNet) -> Option<Self>;
+
     fn unspecified() -> Self;
 }
 
@@ -118,6 +129,14 @@ impl IpNetwork for Ipv4Net {
         }
     }
 
+    fn from_ip_net(net: IpNet) -> Option<Self> {
+        if let IpNet::V4(v4) = net {
+            Some(v4.trunc())
+        } else {
+            None
+        }
+    }
+
     fn unspecified() -> Self {
         Self::from_addr(Ipv4Addr::UNSPECIFIED)
     }
@@ -156,16 +175,426 @@ impl IpNetwork for Ipv6Net {
         }
     }
 
+    fn from_ip_net(net: IpNet) -> Option<Self> {
+        if let IpNet::V6(v6) = net {
+            Some(v6.trunc())
+        } else {
+            None
+        }
+    }
+
     fn unspecified() -> Self {
         Self::from_addr(Ipv6Addr::UNSPECIFIED)
     }
 }
 
+const LOCK_DIR: &str = "/run/einat";
+
+/// Advisory lock preventing multiple einat processes from attaching to the
+/// same network interface, released on drop or process exit.
+///
+/// The lock file contains PID of the holder process.
+#[derive(Debug)]
+pub struct IfLock {
+    _file: File,
+}
+
+impl IfLock {
+    fn path(netns: Option<&NetNs>, if_index: u32) -> PathBuf {
+        if let Some(netns) = netns {
+            // interface indexes are per network namespace
+            Path::new(LOCK_DIR).join(format!("netns{}-{}.lock", netns.ino, if_index))
+        } else {
+            Path::new(LOCK_DIR).join(format!("{}.lock", if_index))
+        }
+    }
+
+    /// Returns `None` if the lock is held by another process.
+    pub fn try_acquire(netns: Option<&NetNs>, if_index: u32) -> Result<Option<Self>> {
+        std::fs::create_dir_all(LOCK_DIR)?;
+        let path = Self::path(netns, if_index);
+        let mut file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+
+        let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if res != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Ok(None);
+            }
+            return Err(anyhow!("failed to lock {}: {}", path.display(), err));
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+
+        Ok(Some(Self { _file: file }))
+    }
+
+    pub fn acquire(netns: Option<&NetNs>, if_index: u32) -> Result<Self> {
+        Self::try_acquire(netns, if_index)?.ok_or_else(|| {
+            anyhow!(
+                "interface {} is already managed by another einat instance, lock file {}",
+                if_index,
+                Self::path(netns, if_index).display()
+            )
+        })
+    }
+
+    /// Waits for the lock to be released by another process, e.g. one being
+    /// handed over from, for up to `timeout`.
+    pub async fn acquire_timeout(
+        netns: Option<&NetNs>,
+        if_index: u32,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let start = Instant::now();
+        loop {
+            if let Some(lock) = Self::try_acquire(netns, if_index)? {
+                return Ok(lock);
+            }
+            if start.elapsed() > timeout {
+                return Self::acquire(netns, if_index);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Whether any interface in `netns` other than `own_if_indexes` is locked
+    /// by another einat process.
+    pub fn held_by_others(netns: Option<&NetNs>, own_if_indexes: &[u32]) -> Result<bool> {
+        let entries = match std::fs::read_dir(LOCK_DIR) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let prefix = netns
+            .map(|netns| format!("netns{}-", netns.ino))
+            .unwrap_or_default();
+        for entry in entries {
+            let name = entry?.file_name();
+            let if_index = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|name| name.strip_suffix(".lock"))
+                .and_then(|if_index| if_index.parse::<u32>().ok());
+            let Some(if_index) = if_index else {
+                continue;
+            };
+            if !own_if_indexes.contains(&if_index) && Self::try_acquire(netns, if_index)?.is_none()
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    pub fn holder_pid(netns: Option<&NetNs>, if_index: u32) -> Result<u32> {
+        let path = Self::path(netns, if_index);
+        let pid = std::fs::read_to_string(&path)?;
+        pid.trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid PID in lock file {}", path.display()))
+    }
+}
+
+const NETNS_RUN_DIR: &str = "/run/netns";
+
+/// Network namespace opened by name as `ip netns` does, or by path.
+#[derive(Debug)]
+pub struct NetNs {
+    file: File,
+    ino: u64,
+}
+
+/// Switches current thread back to original network namespace on drop.
+#[must_use]
+pub struct NetNsGuard {
+    orig: File,
+}
+
+impl NetNs {
+    pub fn open(name: &str) -> Result<Self> {
+        let path = if name.contains('/') {
+            PathBuf::from(name)
+        } else {
+            Path::new(NETNS_RUN_DIR).join(name)
+        };
+        let file = File::open(&path)
+            .map_err(|e| anyhow!("failed to open netns {}: {}", path.display(), e))?;
+        let ino = file.metadata()?.ino();
+        Ok(Self { file, ino })
+    }
+
+    /// Identity of network namespace, which is the same for namespace opened
+    /// by different names or paths.
+    pub fn id(&self) -> u64 {
+        self.ino
+    }
+
+    /// Returns true if this is network namespace of current thread.
+    pub fn is_current(&self) -> Result<bool> {
+        let current = std::fs::metadata("/proc/thread-self/ns/net")?;
+        Ok(current.ino() == self.ino)
+    }
+
+    /// Switches current thread into this network namespace, sockets created
+    /// afterwards stay in this namespace even after switching back.
+    pub fn enter(&self) -> Result<NetNsGuard> {
+        let orig = File::open("/proc/thread-self/ns/net")?;
+        setns_net(&self.file)?;
+        Ok(NetNsGuard { orig })
+    }
+}
+
+impl Drop for NetNsGuard {
+    fn drop(&mut self) {
+        // We can't continue in a wrong network namespace
+        setns_net(&self.orig).expect("failed to restore network namespace");
+    }
+}
+
+/// Runs `f` with current thread switched into `netns` if specified.
+pub fn with_netns<T, F: FnOnce() -> Result<T>>(netns: Option<&NetNs>, f: F) -> Result<T> {
+    let _guard = netns.map(NetNs::enter).transpose()?;
+    f()
+}
+
+/// Whether the address is a global unicast address, excluding link-local and
+/// unique local addresses.
+#[cfg(feature = "ipv6")]
+pub fn is_global_unicast(addr: &Ipv6Addr) -> bool {
+    let first = addr.segments()[0];
+    !(addr.is_unspecified()
+        || addr.is_loopback()
+        || addr.is_multicast()
+        // link-local
+        || first & 0xffc0 == 0xfe80
+        // unique local
+        || first & 0xfe00 == 0xfc00)
+}
+
+/// Returns current time of `CLOCK_MONOTONIC` in nanoseconds, the same clock
+/// source as `bpf_ktime_get_ns()`.
+pub fn monotonic_now_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Returns total usable main memory in bytes.
+pub fn total_memory() -> Result<u64> {
+    let mut info: libc::sysinfo = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::sysinfo(&mut info) };
+    if res != 0 {
+        return Err(anyhow!(
+            "failed to query system memory: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(info.totalram as u64 * info.mem_unit as u64)
+}
+
+/// Writes `value` to sysctl `path` relative to `/proc/sys` and returns the
+/// previous value.
+pub fn set_sysctl(path: &str, value: &str) -> Result<String> {
+    let path = format!("/proc/sys/{}", path);
+    let prev = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {}", path))?
+        .trim()
+        .to_string();
+    if prev != value {
+        std::fs::write(&path, value).with_context(|| format!("failed to write {}", path))?;
+    }
+    Ok(prev)
+}
+
+/// Whether `pattern` contains wildcard `*` or `?`.
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Matches `name` against `pattern` with wildcard `*` matching any sequence of
+/// characters and `?` matching any single character.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // position of last `*` in pattern and the name position it matched up to
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Returns (IP protocol number, port) of TCP sockets in LISTEN state and
+/// unconnected UDP sockets not bound to loopback addresses, in network
+/// namespace of the calling thread.
+pub fn local_listening_ports() -> Result<HashSet<(u8, u16)>> {
+    let mut ports = HashSet::new();
+    for (file, l4proto) in [
+        ("tcp", libc::IPPROTO_TCP),
+        ("tcp6", libc::IPPROTO_TCP),
+        ("udp", libc::IPPROTO_UDP),
+        ("udp6", libc::IPPROTO_UDP),
+    ] {
+        let path = format!("/proc/thread-self/net/{}", file);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            // IPv6 disabled
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(anyhow!("failed to read {}: {}", path, e)),
+        };
+        let is_tcp = l4proto == libc::IPPROTO_TCP;
+        ports.extend(
+            parse_listening_ports(&content, is_tcp)
+                .into_iter()
+                .map(|port| (l4proto as u8, port)),
+        );
+    }
+    Ok(ports)
+}
+
+/// Returns ephemeral port range the host stack allocates local ports of
+/// sockets from, in network namespace of the calling thread.
+pub fn local_port_range() -> Result<RangeInclusive<u16>> {
+    const PATH: &str = "/proc/sys/net/ipv4/ip_local_port_range";
+    let content =
+        std::fs::read_to_string(PATH).with_context(|| format!("failed to read {}", PATH))?;
+    parse_local_port_range(&content)
+        .ok_or_else(|| anyhow!("invalid local port range {:?}", content.trim()))
+}
+
+fn parse_local_port_range(content: &str) -> Option<RangeInclusive<u16>> {
+    let mut ports = content.split_whitespace().map(str::parse::<u16>);
+    let start = ports.next()?.ok()?;
+    let end = ports.next()?.ok()?;
+    (start <= end).then_some(start..=end)
+}
+
+/// Parses local ports of listening sockets from content of
+/// `/proc/net/{tcp,udp}[6]`.
+fn parse_listening_ports(content: &str, is_tcp: bool) -> Vec<u16> {
+    const TCP_LISTEN: &str = "0A";
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            let (local_addr, local_port) = fields.next()?.split_once(':')?;
+            let (_, remote_port) = fields.next()?.split_once(':')?;
+            let state = fields.next()?;
+            let listening = if is_tcp {
+                state == TCP_LISTEN
+            } else {
+                remote_port == "0000"
+            };
+            if !listening || parse_proc_net_addr(local_addr)?.is_loopback() {
+                return None;
+            }
+            u16::from_str_radix(local_port, 16).ok()
+        })
+        .collect()
+}
+
+/// Parses address of `/proc/net` socket tables, printed as hexadecimal 32-bit
+/// words in host byte order.
+fn parse_proc_net_addr(hex: &str) -> Option<IpAddr> {
+    let mut octets = Vec::with_capacity(16);
+    for i in (0..hex.len()).step_by(8) {
+        let word = u32::from_str_radix(hex.get(i..i + 8)?, 16).ok()?;
+        octets.extend(word.to_ne_bytes());
+    }
+    match octets.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(octets).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(octets).ok()?)),
+        _ => None,
+    }
+}
+
+fn setns_net(file: &File) -> Result<()> {
+    let res = unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) };
+    if res != 0 {
+        return Err(anyhow!(
+            "failed to switch network namespace: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use ipnet::Ipv4Net;
 
+    #[test]
+    fn glob() {
+        assert!(glob_match("lan*", "lan"));
+        assert!(glob_match("lan*", "lan0"));
+        assert!(glob_match("br-*", "br-0123456789ab"));
+        assert!(glob_match("veth?", "veth1"));
+        assert!(glob_match("*a*b", "xaab"));
+        assert!(glob_match("eth0", "eth0"));
+        assert!(!glob_match("lan*", "wlan0"));
+        assert!(!glob_match("veth?", "veth10"));
+        assert!(!glob_match("*a*b", "xaba"));
+        assert!(is_glob("br-*"));
+        assert!(!is_glob("eth0"));
+    }
+
+    #[test]
+    fn listening_ports() {
+        let hex = |octets: [u8; 4]| format!("{:08X}", u32::from_ne_bytes(octets));
+        let content = format!(
+            "  sl  local_address rem_address   st tx_queue rx_queue\n\
+             0: {}:0016 00000000:0000 0A 00000000:00000000\n\
+             1: {}:0035 00000000:0000 0A 00000000:00000000\n\
+             2: {}:6DE0 {}:0016 01 00000000:00000000\n",
+            hex([0, 0, 0, 0]),
+            hex([127, 0, 0, 1]),
+            hex([10, 0, 1, 100]),
+            hex([10, 0, 1, 1])
+        );
+        assert_eq!(parse_listening_ports(&content, true), vec![22]);
+        // connected UDP sockets are not listening
+        assert_eq!(parse_listening_ports(&content, false), vec![22]);
+    }
+
+    #[test]
+    fn local_port_range() {
+        assert_eq!(
+            parse_local_port_range("32768\t60999\n"),
+            Some(32768..=60999)
+        );
+        assert_eq!(parse_local_port_range("60999\t32768\n"), None);
+        assert_eq!(parse_local_port_range("32768\n"), None);
+    }
+
     #[test]
     fn map_diff() {
         let mut map_a = PrefixMap::<Ipv4Net, String>::new();
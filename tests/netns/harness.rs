@@ -0,0 +1,239 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! Test network built from network namespaces and veth pairs, with einat
+//! running on WAN interface of the router namespace.
+//!
+//! ```text
+//!  server (10.0.1.1, 10.0.1.2)
+//!    | wan0
+//!    | wan 10.0.1.100
+//!  router (br-lan 192.168.1.1)
+//!    | lan1                   | lan2
+//!    | eth0                   | eth0
+//!  device1 (192.168.1.100)  device2 (192.168.1.200)
+//! ```
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const SERVER_ADDR: &str = "10.0.1.1";
+pub const SERVER_ALT_ADDR: &str = "10.0.1.2";
+pub const WAN_ADDR: &str = "10.0.1.100";
+pub const DEVICE1_ADDR: &str = "192.168.1.100";
+pub const DEVICE2_ADDR: &str = "192.168.1.200";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn ip(netns: &str, args: &str) {
+    let status = Command::new("ip")
+        .args(["-n", netns])
+        .args(args.split_whitespace())
+        .status()
+        .expect("failed to run ip");
+    assert!(status.success(), "ip -n {} {} failed", netns, args);
+}
+
+pub fn sysctl(netns: &str, key: &str, value: &str) {
+    let status = Command::new("ip")
+        .args(["netns", "exec", netns, "sysctl", "-qw"])
+        .arg(format!("{}={}", key, value))
+        .status()
+        .expect("failed to run sysctl");
+    assert!(status.success(), "sysctl {}={} failed", key, value);
+}
+
+/// Runs `f` in a thread switched into network namespace `netns`.
+pub fn in_netns<T: Send + 'static, F: FnOnce() -> T + Send + 'static>(netns: &str, f: F) -> T {
+    let file = File::open(format!("/run/netns/{}", netns)).expect("failed to open netns");
+    std::thread::spawn(move || {
+        let res = unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) };
+        assert_eq!(res, 0, "failed to enter netns");
+        f()
+    })
+    .join()
+    .unwrap()
+}
+
+/// Creates UDP socket in network namespace `netns`, sockets stay in namespace
+/// they were created in.
+pub fn udp_socket(netns: &str, addr: &str) -> UdpSocket {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let socket = in_netns(netns, move || UdpSocket::bind(addr).unwrap());
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    socket
+}
+
+/// Sends `msg` to `to` and waits for a datagram, returning its source.
+pub fn send_recv(socket: &UdpSocket, to: &str, msg: &[u8]) -> (Vec<u8>, SocketAddr) {
+    socket.send_to(msg, to).unwrap();
+    recv(socket)
+}
+
+pub fn recv(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+    let mut buf = [0u8; 1500];
+    let (len, from) = socket
+        .recv_from(&mut buf)
+        .expect("no datagram received in time");
+    (buf[..len].to_vec(), from)
+}
+
+/// Replies source address of each received datagram back to its sender.
+pub fn spawn_echo_server(socket: UdpSocket) {
+    socket.set_read_timeout(None).unwrap();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        while let Ok((_, from)) = socket.recv_from(&mut buf) {
+            let _ = socket.send_to(from.to_string().as_bytes(), from);
+        }
+    });
+}
+
+pub struct TestNet {
+    pub server: String,
+    pub router: String,
+    pub device1: String,
+    pub device2: String,
+    einat: Option<Child>,
+    logs: Arc<Mutex<Vec<String>>>,
+    config_path: PathBuf,
+}
+
+impl TestNet {
+    pub fn new(name: &str) -> Self {
+        let ns = |role: &str| format!("einat-{}-{}-{}", name, role, std::process::id());
+        let net = Self {
+            server: ns("s"),
+            router: ns("r"),
+            device1: ns("d1"),
+            device2: ns("d2"),
+            einat: None,
+            logs: Default::default(),
+            config_path: std::env::temp_dir().join(format!(
+                "einat-{}-{}.toml",
+                name,
+                std::process::id()
+            )),
+        };
+        for netns in [&net.server, &net.router, &net.device1, &net.device2] {
+            let status = Command::new("ip")
+                .args(["netns", "add", netns])
+                .status()
+                .unwrap();
+            assert!(status.success(), "failed to create netns {}", netns);
+            ip(netns, "link set lo up");
+        }
+
+        let (server, router) = (&net.server, &net.router);
+        ip(
+            router,
+            &format!("link add wan type veth peer name wan0 netns {}", server),
+        );
+        ip(server, &format!("addr add {}/24 dev wan0", SERVER_ADDR));
+        ip(server, &format!("addr add {}/24 dev wan0", SERVER_ALT_ADDR));
+        ip(server, "link set wan0 up");
+        ip(router, &format!("addr add {}/24 dev wan", WAN_ADDR));
+        ip(router, "link set wan up");
+        ip(
+            router,
+            &format!("route add default via {} dev wan", SERVER_ADDR),
+        );
+
+        ip(router, "link add br-lan type bridge");
+        ip(router, "addr add 192.168.1.1/24 dev br-lan");
+        ip(router, "link set br-lan up");
+        for (i, (device, addr)) in [(&net.device1, DEVICE1_ADDR), (&net.device2, DEVICE2_ADDR)]
+            .into_iter()
+            .enumerate()
+        {
+            let lan = format!("lan{}", i + 1);
+            ip(
+                router,
+                &format!("link add {} type veth peer name eth0 netns {}", lan, device),
+            );
+            ip(router, &format!("link set {} master br-lan up", lan));
+            ip(device, &format!("addr add {}/24 dev eth0", addr));
+            ip(device, "link set eth0 up");
+            ip(device, "route add default via 192.168.1.1 dev eth0");
+        }
+        sysctl(router, "net.ipv4.ip_forward", "1");
+
+        net
+    }
+
+    /// Starts einat with interface configuration `if_config` in TOML for WAN
+    /// interface of router, and waits for it to be ready.
+    pub fn start_einat(&mut self, if_config: &str) {
+        let config = format!(
+            "[[interfaces]]\nif_name = \"wan\"\nnetns = \"{}\"\n{}",
+            self.router, if_config
+        );
+        std::fs::write(&self.config_path, config).unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_einat"))
+            .arg("-c")
+            .arg(&self.config_path)
+            .env("RUST_LOG", "info")
+            .env("NO_COLOR", "1")
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to start einat");
+        let stdout = child.stdout.take().unwrap();
+        let logs = self.logs.clone();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                eprintln!("einat: {}", line);
+                logs.lock().unwrap().push(line);
+            }
+        });
+        self.einat = Some(child);
+
+        self.wait_log("setting default external IPv4 address", 1);
+        // give hairpin routing configuration a moment
+        std::thread::sleep(Duration::from_millis(300));
+    }
+
+    /// Waits until `pattern` has been logged by einat `count` times.
+    pub fn wait_log(&mut self, pattern: &str, count: usize) {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let n = self
+                .logs
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|line| line.contains(pattern))
+                .count();
+            if n >= count {
+                return;
+            }
+            if let Some(status) = self.einat.as_mut().unwrap().try_wait().unwrap() {
+                panic!("einat exited with {}", status);
+            }
+            assert!(
+                Instant::now() < deadline,
+                "timed out waiting for log {:?}",
+                pattern
+            );
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for TestNet {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.einat.take() {
+            unsafe { libc::kill(child.id() as _, libc::SIGTERM) };
+            let _ = child.wait();
+        }
+        let _ = std::fs::remove_file(&self.config_path);
+        for netns in [&self.server, &self.router, &self.device1, &self.device2] {
+            let _ = Command::new("ip").args(["netns", "delete", netns]).status();
+        }
+    }
+}
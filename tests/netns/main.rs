@@ -0,0 +1,104 @@
+// SPDX-FileCopyrightText: 2023 Huang-Huang Bao
+// SPDX-License-Identifier: GPL-2.0-or-later
+//! End-to-end tests running einat in network namespaces, these require root
+//! privilege and are ignored by default. Run with
+//! `cargo test --test netns -- --ignored`.
+mod harness;
+
+use std::net::SocketAddr;
+
+use harness::*;
+
+const NAT44: &str = "nat44 = true\n";
+
+fn mapped_addr(reply: &[u8]) -> SocketAddr {
+    std::str::from_utf8(reply).unwrap().parse().unwrap()
+}
+
+fn assert_translated(mapped: SocketAddr, external_addr: &str) {
+    assert_eq!(mapped.ip().to_string(), external_addr);
+    assert!(
+        (20000..=29999).contains(&mapped.port()),
+        "mapped port {} out of range",
+        mapped.port()
+    );
+}
+
+#[test]
+#[ignore = "netns"]
+fn translation() {
+    let mut net = TestNet::new("trans");
+    spawn_echo_server(udp_socket(&net.server, "10.0.1.1:3478"));
+    spawn_echo_server(udp_socket(&net.server, "10.0.1.2:3478"));
+    net.start_einat(NAT44);
+
+    let device = udp_socket(&net.device1, "0.0.0.0:0");
+    let (reply, from) = send_recv(&device, "10.0.1.1:3478", b"hello");
+    assert_eq!(from.to_string(), "10.0.1.1:3478");
+    let mapped = mapped_addr(&reply);
+    assert_translated(mapped, WAN_ADDR);
+
+    // endpoint-independent mapping
+    let (reply, _) = send_recv(&device, "10.0.1.2:3478", b"hello");
+    assert_eq!(mapped_addr(&reply), mapped);
+
+    // endpoint-independent filtering
+    let other = udp_socket(&net.server, "10.0.1.2:4000");
+    other.send_to(b"inbound", mapped).unwrap();
+    let (msg, from) = recv(&device);
+    assert_eq!(msg, b"inbound");
+    assert_eq!(from.to_string(), "10.0.1.2:4000");
+}
+
+#[test]
+#[ignore = "netns"]
+fn hairpinning() {
+    let mut net = TestNet::new("hairpin");
+    spawn_echo_server(udp_socket(&net.server, "10.0.1.1:3478"));
+    net.start_einat("nat44 = true\nipv4_hairpin_route.internal_if_names = [\"br-lan\"]\n");
+
+    let device1 = udp_socket(&net.device1, "0.0.0.0:0");
+    let device2 = udp_socket(&net.device2, "0.0.0.0:0");
+    let (reply, _) = send_recv(&device1, "10.0.1.1:3478", b"hello");
+    let mapped1 = mapped_addr(&reply);
+    let (reply, _) = send_recv(&device2, "10.0.1.1:3478", b"hello");
+    let mapped2 = mapped_addr(&reply);
+
+    // device2 reaches device1 through external address, seeing each other
+    // as their external addresses
+    device2.send_to(b"hairpin", mapped1).unwrap();
+    let (msg, from) = recv(&device1);
+    assert_eq!(msg, b"hairpin");
+    assert_eq!(from, mapped2);
+
+    device1.send_to(b"reply", from).unwrap();
+    let (msg, from) = recv(&device2);
+    assert_eq!(msg, b"reply");
+    assert_eq!(from, mapped1);
+}
+
+#[test]
+#[ignore = "netns"]
+fn address_change() {
+    let mut net = TestNet::new("addr");
+    spawn_echo_server(udp_socket(&net.server, "10.0.1.1:3478"));
+    net.start_einat(NAT44);
+
+    let device = udp_socket(&net.device1, "0.0.0.0:0");
+    let (reply, _) = send_recv(&device, "10.0.1.1:3478", b"hello");
+    assert_translated(mapped_addr(&reply), WAN_ADDR);
+
+    sysctl(&net.router, "net.ipv4.conf.wan.promote_secondaries", "1");
+    ip(&net.router, "addr add 10.0.1.101/24 dev wan");
+    ip(&net.router, &format!("addr del {}/24 dev wan", WAN_ADDR));
+    ip(
+        &net.router,
+        &format!("route replace default via {} dev wan", SERVER_ADDR),
+    );
+    net.wait_log("setting default external IPv4 address 10.0.1.101", 1);
+
+    // new flows are translated to the new address
+    let device = udp_socket(&net.device1, "0.0.0.0:0");
+    let (reply, _) = send_recv(&device, "10.0.1.1:3478", b"hello");
+    assert_translated(mapped_addr(&reply), "10.0.1.101");
+}